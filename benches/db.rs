@@ -0,0 +1,100 @@
+use backend_rspass::{db::SqliteStore, store::VaultStore};
+use criterion::{criterion_group, criterion_main, Criterion};
+use rusqlite::{params, Connection};
+use std::fs;
+use uuid::Uuid;
+
+// Size of a vault with a few hundred entries
+const VAULT_SIZE: usize = 100 * 1024;
+
+struct Fixture {
+    path: String,
+    db: SqliteStore,
+    user_id: String,
+    vault: String,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let path = format!("./bench_{}.db", Uuid::new_v4());
+        let db = SqliteStore::open(&path).unwrap();
+        db.migrate().unwrap();
+        let user_id = db
+            .user_register("bench@example.com", "hash123", None)
+            .unwrap();
+        let vault = "x".repeat(VAULT_SIZE);
+        db.data_update(&user_id, &vault, None, "bench", 0).unwrap();
+        Fixture {
+            path,
+            db,
+            user_id,
+            vault,
+        }
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = fs::remove_file(format!("{}{}", self.path, suffix));
+        }
+    }
+}
+
+// How every request accessed the database before the pool, for comparison
+fn open_per_call_get(path: &str, user_id: &str) -> String {
+    let mut conn = Connection::open(path).unwrap();
+    let tx = conn.transaction().unwrap();
+    let data = tx
+        .query_row(
+            "SELECT encrypted_data FROM users WHERE id = ?1",
+            params![user_id],
+            |row| row.get(0),
+        )
+        .unwrap();
+    tx.commit().unwrap();
+    data
+}
+
+fn open_per_call_update(path: &str, user_id: &str, encrypted_data: &str) {
+    let mut conn = Connection::open(path).unwrap();
+    let tx = conn.transaction().unwrap();
+    tx.execute(
+        "UPDATE users SET encrypted_data = ?1 WHERE id = ?2",
+        params![encrypted_data, user_id],
+    )
+    .unwrap();
+    tx.commit().unwrap();
+}
+
+fn bench_fetch(c: &mut Criterion) {
+    let fixture = Fixture::new();
+    let mut group = c.benchmark_group("fetch");
+    group.bench_function("open_per_call", |b| {
+        b.iter(|| open_per_call_get(&fixture.path, &fixture.user_id))
+    });
+    group.bench_function("pooled", |b| {
+        b.iter(|| fixture.db.data_get(&fixture.user_id).unwrap())
+    });
+    group.finish();
+}
+
+fn bench_update(c: &mut Criterion) {
+    let fixture = Fixture::new();
+    let mut group = c.benchmark_group("update");
+    group.bench_function("open_per_call", |b| {
+        b.iter(|| open_per_call_update(&fixture.path, &fixture.user_id, &fixture.vault))
+    });
+    group.bench_function("pooled", |b| {
+        b.iter(|| {
+            fixture
+                .db
+                .data_update(&fixture.user_id, &fixture.vault, None, "bench", 0)
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_fetch, bench_update);
+criterion_main!(benches);
@@ -0,0 +1,159 @@
+use actix_web::web::Bytes;
+use futures_util::Stream;
+use log::error;
+use std::{
+    collections::HashSet,
+    env, io,
+    io::SeekFrom,
+    path::PathBuf,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncSeekExt, ReadBuf},
+};
+
+use crate::config::env_param;
+
+// Bytes a user may keep in attachments, counted by their declared sizes
+pub fn attachment_quota() -> u64 {
+    env_param("ATTACHMENT_QUOTA", 100 * 1024 * 1024)
+}
+
+// Uploads left incomplete for longer than this are removed
+pub fn upload_ttl() -> usize {
+    env_param("ATTACHMENT_UPLOAD_TTL", 24 * 3600)
+}
+
+// Encrypted attachment contents, one file per attachment in a directory per user. The
+// metadata lives in the store, a file is only trusted up to the progress recorded there
+pub struct BlobStore {
+    dir: PathBuf,
+    uploads: Mutex<HashSet<String>>,
+}
+
+// Held while an upload writes to its file, released on drop
+pub struct UploadGuard<'a> {
+    blobs: &'a BlobStore,
+    key: String,
+}
+
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        self.blobs.uploads.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl BlobStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Result<Self, String> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir).map_err(|e| {
+            format!(
+                "Failed to create attachment directory {}: {}",
+                dir.display(),
+                e
+            )
+        })?;
+        Ok(Self {
+            dir,
+            uploads: Mutex::new(HashSet::new()),
+        })
+    }
+
+    pub fn from_env() -> Result<Self, String> {
+        Self::new(env::var("ATTACHMENT_DIR").unwrap_or_else(|_| "./attachments".to_string()))
+    }
+
+    // Ids are generated by the server, so they are safe as file names
+    fn path(&self, user_id: &str, id: &str) -> PathBuf {
+        self.dir.join(user_id).join(id)
+    }
+
+    // Claims the file of an attachment, so two requests never write to it at once
+    pub fn begin_upload(&self, user_id: &str, id: &str) -> Option<UploadGuard<'_>> {
+        let key = format!("{}/{}", user_id, id);
+        if !self.uploads.lock().unwrap().insert(key.clone()) {
+            return None;
+        }
+        Some(UploadGuard { blobs: self, key })
+    }
+
+    // Opens the file for writing at offset. Bytes past it were never recorded as received,
+    // so they are dropped and sent again
+    pub async fn open_upload(&self, user_id: &str, id: &str, offset: u64) -> io::Result<fs::File> {
+        let path = self.path(user_id, id);
+        fs::create_dir_all(self.dir.join(user_id)).await?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(&path)
+            .await?;
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+        Ok(file)
+    }
+
+    pub async fn open(&self, user_id: &str, id: &str, size: u64) -> io::Result<FileStream> {
+        let file = fs::File::open(self.path(user_id, id)).await?;
+        Ok(FileStream {
+            file,
+            remaining: size,
+            buf: vec![0; 64 * 1024].into_boxed_slice(),
+        })
+    }
+
+    // A missing file is fine, the upload may not have started
+    pub async fn remove(&self, user_id: &str, id: &str) {
+        match fs::remove_file(self.path(user_id, id)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => error!("Failed to remove attachment {} of {}: {}", id, user_id, e),
+        }
+    }
+
+    pub async fn remove_user(&self, user_id: &str) {
+        match fs::remove_dir_all(self.dir.join(user_id)).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => error!("Failed to remove attachments of {}: {}", user_id, e),
+        }
+    }
+}
+
+// Body of an attachment download, read from its file in chunks
+pub struct FileStream {
+    file: fs::File,
+    remaining: u64,
+    buf: Box<[u8]>,
+}
+
+impl Stream for FileStream {
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.remaining == 0 {
+            return Poll::Ready(None);
+        }
+        let len = this.buf.len().min(this.remaining as usize);
+        let mut buf = ReadBuf::new(&mut this.buf[..len]);
+        match Pin::new(&mut this.file).poll_read(cx, &mut buf) {
+            Poll::Ready(Ok(())) => {
+                let filled = buf.filled();
+                if filled.is_empty() {
+                    // Shorter than recorded, the response can not be completed
+                    let e = io::Error::new(io::ErrorKind::UnexpectedEof, "attachment truncated");
+                    this.remaining = 0;
+                    return Poll::Ready(Some(Err(e.into())));
+                }
+                this.remaining -= filled.len() as u64;
+                Poll::Ready(Some(Ok(Bytes::copy_from_slice(filled))))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e.into()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -1,110 +1,425 @@
-use actix_web::{dev::ServiceRequest, error, Error, HttpMessage};
+use actix_web::{dev::ServiceRequest, error, http::header, web, Error, HttpMessage, HttpResponse};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
 use jsonwebtoken::{
-    decode, encode, errors::Error as JwtError, DecodingKey, EncodingKey, Header, Validation,
+    decode, decode_header, encode, errors::Error as JwtError, jwk::JwkSet, Header, Validation,
 };
-use log::{debug, info, warn};
-use std::sync::LazyLock;
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    collections::HashSet,
     env,
-    sync::Mutex,
+    sync::RwLock,
     time::{SystemTime, UNIX_EPOCH},
 };
 use uuid::Uuid;
 
-use crate::db::user_exists;
+use crate::keys::{JwtKey, Keyring};
+use crate::store::{is_busy, Database, StoreError, VaultStore};
 
-// Store blacklisted tokens
-static BLACKLIST: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+// Lifetime of an access token in seconds
+pub const ACCESS_TOKEN_TTL: usize = 3600; // 1 hour
 
-#[derive(Debug, Serialize, Deserialize)]
+// Lifetime of the token between password check and second factor
+pub const PENDING_TOKEN_TTL: usize = 300; // 5 minutes
+
+// Lifetime of a ticket that opens a notification stream, it only has to reach the URL
+pub const STREAM_TICKET_TTL: usize = 30;
+const PURPOSE_2FA_PENDING: &str = "2fa_pending";
+const PURPOSE_VERIFY_EMAIL: &str = "verify_email";
+const PURPOSE_CHANGE_EMAIL: &str = "change_email";
+const PURPOSE_STREAM_TICKET: &str = "stream_ticket";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
-    pub sub: String,   // email
+    pub sub: String,   // user id
     pub exp: usize,    // expiration time
     pub nonce: String, // random nonce
+    pub stamp: String, // security stamp of the user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purpose: Option<String>, // set for tokens that do not grant API access
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_email: Option<String>, // address an email change moves the account to
 }
 
 pub struct JwtAuth {
-    encoding_key: EncodingKey,
-    decoding_key: DecodingKey,
+    keyring: RwLock<Keyring>,
+    keyring_dir: Option<String>,
+}
+
+impl Default for JwtAuth {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl JwtAuth {
+    // Loads the keyring from JWT_KEYRING_DIR if set, otherwise uses the single key from
+    // JWT_PRIVATE_KEY_FILE or JWT_SECRET
     pub fn new() -> Self {
-        let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+        if let Ok(dir) = env::var("JWT_KEYRING_DIR") {
+            return match Keyring::load_dir(&dir) {
+                Ok(keyring) => Self::from_keyring(keyring, Some(dir)),
+                Err(e) => panic!("{}", e),
+            };
+        }
 
-        if secret.len() < 16 {
-            panic!("JWT_SECRET must be at least 16 characters long");
+        let kid = env::var("JWT_KEY_ID").ok();
+        let key = match env::var("JWT_PRIVATE_KEY_FILE") {
+            Ok(path) => JwtKey::from_private_pem_file(&path, kid),
+            Err(_) => {
+                let secret = env::var("JWT_SECRET").expect("JWT_SECRET must be set");
+                JwtKey::from_secret(&secret, kid)
+            }
+        };
+        match key {
+            Ok(key) => Self::from_key(key),
+            Err(e) => panic!("{}", e),
         }
+    }
+
+    pub fn from_key(key: JwtKey) -> Self {
+        Self::from_keyring(Keyring::new(key, Vec::new()), None)
+    }
+
+    // A keyring loaded from a directory can be reloaded later on
+    pub fn from_keyring(keyring: Keyring, keyring_dir: Option<String>) -> Self {
+        log_keyring(&keyring);
         JwtAuth {
-            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
-            decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+            keyring: RwLock::new(keyring),
+            keyring_dir,
+        }
+    }
+
+    // Re-reads the keyring directory, picking up newly added or removed keys
+    pub fn reload(&self) -> Result<(), String> {
+        let Some(dir) = &self.keyring_dir else {
+            return Ok(());
+        };
+        let keyring = Keyring::load_dir(dir)?;
+        let mut current = self.keyring.write().unwrap();
+        if current.kids() != keyring.kids() {
+            log_keyring(&keyring);
         }
+        *current = keyring;
+        Ok(())
     }
 
-    pub fn generate_token(&self, email: &str) -> Result<String, JwtError> {
-        let expiration = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as usize
-            + 3600; // 1 hour
+    // Public keys for other services to verify tokens, symmetric keys are never published
+    pub fn jwks(&self) -> JwkSet {
+        self.keyring.read().unwrap().jwks()
+    }
+
+    fn decode_claims(&self, token: &str, validate_exp: bool) -> Result<Claims, JwtError> {
+        let header = decode_header(token)?;
+        let keyring = self.keyring.read().unwrap();
+        let Some(key) = keyring.find(header.kid.as_deref()) else {
+            return Err(JwtError::from(
+                jsonwebtoken::errors::ErrorKind::InvalidToken,
+            ));
+        };
+        let mut validation = Validation::new(key.algorithm);
+        validation.validate_exp = validate_exp;
+        Ok(decode::<Claims>(token, &key.decoding_key, &validation)?.claims)
+    }
+
+    pub fn generate_token(&self, user_id: &str, stamp: &str) -> Result<String, JwtError> {
+        self.generate_token_with_nonce(user_id, &Uuid::new_v4().to_string(), stamp)
+    }
+
+    // Refreshed access tokens keep the nonce of their refresh token family
+    pub fn generate_token_with_nonce(
+        &self,
+        user_id: &str,
+        nonce: &str,
+        stamp: &str,
+    ) -> Result<String, JwtError> {
+        let expiration = current_timestamp() + ACCESS_TOKEN_TTL;
 
         let my_claims = Claims {
-            sub: email.to_string(),
+            sub: user_id.to_string(),
             exp: expiration,
+            nonce: nonce.to_string(),
+            stamp: stamp.to_string(),
+            purpose: None,
+            new_email: None,
+        };
+        self.encode_claims(&my_claims)
+    }
+
+    // Proves the password was checked, only accepted by the second login step
+    pub fn generate_pending_token(&self, user_id: &str, stamp: &str) -> Result<String, JwtError> {
+        let my_claims = Claims {
+            sub: user_id.to_string(),
+            exp: current_timestamp() + PENDING_TOKEN_TTL,
             nonce: Uuid::new_v4().to_string(),
+            stamp: stamp.to_string(),
+            purpose: Some(PURPOSE_2FA_PENDING.to_string()),
+            new_email: None,
         };
+        self.encode_claims(&my_claims)
+    }
 
-        encode(&Header::default(), &my_claims, &self.encoding_key)
+    // Sent by mail to prove ownership of the address, stale after a password change
+    pub fn generate_verification_token(
+        &self,
+        user_id: &str,
+        stamp: &str,
+    ) -> Result<String, JwtError> {
+        let my_claims = Claims {
+            sub: user_id.to_string(),
+            exp: current_timestamp() + email_verification_ttl(),
+            nonce: Uuid::new_v4().to_string(),
+            stamp: stamp.to_string(),
+            purpose: Some(PURPOSE_VERIFY_EMAIL.to_string()),
+            new_email: None,
+        };
+        self.encode_claims(&my_claims)
     }
 
-    pub fn is_blacklisted(&self, token: &str) -> bool {
-        let blacklist = BLACKLIST.lock().unwrap();
-        blacklist.contains(token)
+    // Sent to the new address, proves it belongs to the owner of the account
+    pub fn generate_email_change_token(
+        &self,
+        user_id: &str,
+        new_email: &str,
+        stamp: &str,
+    ) -> Result<String, JwtError> {
+        let my_claims = Claims {
+            sub: user_id.to_string(),
+            exp: current_timestamp() + email_verification_ttl(),
+            nonce: Uuid::new_v4().to_string(),
+            stamp: stamp.to_string(),
+            purpose: Some(PURPOSE_CHANGE_EMAIL.to_string()),
+            new_email: Some(new_email.to_string()),
+        };
+        self.encode_claims(&my_claims)
     }
 
-    pub fn blacklist_token(&self, token: &str) {
-        debug!("The following token is being blacklisted: {}", token);
-        let mut blacklist = BLACKLIST.lock().unwrap();
-        blacklist.insert(token.to_string());
-    }
-
-    pub fn cleanup_blacklist(&self) {
-        let mut blacklist = BLACKLIST.lock().unwrap();
-        let current_time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as usize;
-
-        blacklist.retain(|token| {
-            if let Ok(claims) = self.validate_token(token) {
-                claims.exp > current_time
-            } else {
-                false
+    // Stands in for the access token where browsers can not set a header, like a WebSocket
+    // or an EventSource, and belongs to the same session
+    pub fn generate_stream_ticket(
+        &self,
+        user_id: &str,
+        nonce: &str,
+        stamp: &str,
+    ) -> Result<String, JwtError> {
+        let my_claims = Claims {
+            sub: user_id.to_string(),
+            exp: current_timestamp() + STREAM_TICKET_TTL,
+            nonce: nonce.to_string(),
+            stamp: stamp.to_string(),
+            purpose: Some(PURPOSE_STREAM_TICKET.to_string()),
+            new_email: None,
+        };
+        self.encode_claims(&my_claims)
+    }
+
+    fn encode_claims(&self, claims: &Claims) -> Result<String, JwtError> {
+        let keyring = self.keyring.read().unwrap();
+        let key = keyring.active();
+        let mut header = Header::new(key.algorithm);
+        header.kid = key.kid.clone();
+        encode(&header, claims, &key.encoding_key)
+    }
+
+    // Revocations are keyed by nonce, so the signature is checked but expiry is not
+    fn decode_nonce(&self, token: &str) -> Result<String, JwtError> {
+        Ok(self.decode_claims(token, false)?.nonce)
+    }
+
+    pub fn is_blacklisted(&self, db: &dyn VaultStore, token: &str) -> bool {
+        let Ok(nonce) = self.decode_nonce(token) else {
+            return false; // Rejected by validate_token anyway
+        };
+        match db.token_is_revoked(&nonce) {
+            Ok(revoked) => revoked,
+            Err(e) => {
+                error!("Failed to check token revocation: {}", e);
+                true
             }
-        });
-        debug!(
-            "Blacklist cleanup completed. Current size: {}",
-            blacklist.len()
-        );
-    }
-
-    pub fn validate_token(&self, token: &str) -> Result<Claims, JwtError> {
-        let validation = Validation::default();
-        let token_data = decode::<Claims>(token, &self.decoding_key, &validation)?;
-        let email = &token_data.claims.sub;
-        info!("validate_token email: {}", email);
-        match user_exists(email) {
-            Ok(true) => Ok(token_data.claims),
-            Ok(false) | Err(_) => Err(JwtError::from(
+        }
+    }
+
+    pub fn blacklist_token(&self, db: &dyn VaultStore, token: &str) -> Result<(), StoreError> {
+        debug!("The following token is being blacklisted: {}", token);
+        let Ok(nonce) = self.decode_nonce(token) else {
+            return Ok(());
+        };
+        // Tokens refreshed within the same family share the nonce, so keep the
+        // revocation until every token that could carry it has expired
+        db.token_revoke(&nonce, current_timestamp() + ACCESS_TOKEN_TTL)
+    }
+
+    pub fn cleanup_blacklist(&self, db: &dyn VaultStore) {
+        match db.revoked_tokens_cleanup(current_timestamp()) {
+            Ok(deleted) => debug!(
+                "Blacklist cleanup completed. Removed {} expired entries",
+                deleted
+            ),
+            Err(e) => error!("Blacklist cleanup failed: {}", e),
+        }
+    }
+
+    pub fn validate_token(&self, db: &dyn VaultStore, token: &str) -> Result<Claims, JwtError> {
+        self.validate_token_for(db, token, None)
+    }
+
+    pub fn validate_pending_token(
+        &self,
+        db: &dyn VaultStore,
+        token: &str,
+    ) -> Result<Claims, JwtError> {
+        self.validate_token_for(db, token, Some(PURPOSE_2FA_PENDING))
+    }
+
+    pub fn validate_verification_token(
+        &self,
+        db: &dyn VaultStore,
+        token: &str,
+    ) -> Result<Claims, JwtError> {
+        self.validate_token_for(db, token, Some(PURPOSE_VERIFY_EMAIL))
+    }
+
+    pub fn validate_email_change_token(
+        &self,
+        db: &dyn VaultStore,
+        token: &str,
+    ) -> Result<Claims, JwtError> {
+        self.validate_token_for(db, token, Some(PURPOSE_CHANGE_EMAIL))
+    }
+
+    pub fn validate_stream_ticket(
+        &self,
+        db: &dyn VaultStore,
+        token: &str,
+    ) -> Result<Claims, JwtError> {
+        self.validate_token_for(db, token, Some(PURPOSE_STREAM_TICKET))
+    }
+
+    fn validate_token_for(
+        &self,
+        db: &dyn VaultStore,
+        token: &str,
+        purpose: Option<&str>,
+    ) -> Result<Claims, JwtError> {
+        let claims = self.decode_claims(token, true)?;
+        if claims.purpose.as_deref() != purpose {
+            return Err(JwtError::from(
+                jsonwebtoken::errors::ErrorKind::InvalidToken,
+            ));
+        }
+        let user_id = &claims.sub;
+        info!("validate_token user: {}", user_id);
+        // Tokens issued before the last password change or logout everywhere are stale
+        match db.user_security_stamp(user_id) {
+            Ok(Some(stamp)) if stamp == claims.stamp => Ok(claims),
+            Ok(_) | Err(_) => Err(JwtError::from(
                 jsonwebtoken::errors::ErrorKind::InvalidToken,
             )),
         }
     }
 }
 
+fn log_keyring(keyring: &Keyring) {
+    let active = keyring.active();
+    info!(
+        "Signing JWTs with {:?}, key id: {}, known key ids: {:?}",
+        active.algorithm,
+        active.kid.as_deref().unwrap_or("none"),
+        keyring.kids()
+    );
+}
+
+pub fn current_timestamp() -> usize {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as usize
+}
+
+pub fn refresh_token_ttl() -> usize {
+    env::var("REFRESH_TOKEN_TTL")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(30 * 24 * 3600) // 30 days
+}
+
+pub fn email_verification_ttl() -> usize {
+    env::var("EMAIL_VERIFICATION_TTL")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(2 * 24 * 3600) // 2 days
+}
+
+// Opaque refresh token, only its hash is stored server-side
+pub fn generate_refresh_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+pub fn hash_refresh_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// Outcome of checking a bearer token against the database
+pub enum TokenCheck {
+    Valid(Claims),
+    Blacklisted,
+    Invalid,
+    Revoked,
+}
+
+// Checks the token and its session, which counts as seen
+pub fn check_token(
+    jwt_auth: &JwtAuth,
+    db: &dyn VaultStore,
+    token: &str,
+) -> Result<TokenCheck, StoreError> {
+    if jwt_auth.is_blacklisted(db, token) {
+        debug!("Token is blacklisted: {}", token);
+        return Ok(TokenCheck::Blacklisted);
+    }
+    let Ok(claims) = jwt_auth.validate_token(db, token) else {
+        return Ok(TokenCheck::Invalid);
+    };
+    if !db.session_touch(&claims.nonce, current_timestamp())? {
+        debug!("Session of token was revoked: {}", token);
+        return Ok(TokenCheck::Revoked);
+    }
+    Ok(TokenCheck::Valid(claims))
+}
+
+// Checks a stream ticket and its session, like check_token does for access tokens
+pub fn check_stream_ticket(
+    jwt_auth: &JwtAuth,
+    db: &dyn VaultStore,
+    ticket: &str,
+) -> Result<TokenCheck, StoreError> {
+    let Ok(claims) = jwt_auth.validate_stream_ticket(db, ticket) else {
+        return Ok(TokenCheck::Invalid);
+    };
+    if !session_active(db, &claims.sub, &claims.nonce, &claims.stamp)? {
+        debug!("Session of stream ticket was revoked: {}", ticket);
+        return Ok(TokenCheck::Revoked);
+    }
+    Ok(TokenCheck::Valid(claims))
+}
+
+// Whether a session is still signed in, whatever its access token. Logouts revoke the
+// nonce, revocations delete the session and password changes rotate the stamp
+pub fn session_active(
+    db: &dyn VaultStore,
+    user_id: &str,
+    nonce: &str,
+    stamp: &str,
+) -> Result<bool, StoreError> {
+    if db.token_is_revoked(nonce)? {
+        return Ok(false);
+    }
+    if db.user_security_stamp(user_id)?.as_deref() != Some(stamp) {
+        return Ok(false);
+    }
+    db.session_touch(nonce, current_timestamp())
+}
+
 pub async fn validator(
     req: ServiceRequest,
     credentials: Option<BearerAuth>,
@@ -112,22 +427,42 @@ pub async fn validator(
     let Some(credentials) = credentials else {
         return Err((error::ErrorUnauthorized("No bearer token provided"), req));
     };
-    let jwt_auth = JwtAuth::new();
-    let token = credentials.token();
+    let Some(jwt_auth) = req.app_data::<web::Data<JwtAuth>>().cloned() else {
+        error!("JwtAuth is not registered as app data");
+        return Err((
+            error::ErrorInternalServerError("Missing JWT configuration"),
+            req,
+        ));
+    };
+    let Some(db) = req.app_data::<web::Data<Database>>().cloned() else {
+        error!("Database is not registered as app data");
+        return Err((error::ErrorInternalServerError("Missing database"), req));
+    };
+    let token = credentials.token().to_string();
 
-    if jwt_auth.is_blacklisted(token) {
-        debug!("Token is blacklisted: {}", token);
-        return Err((error::ErrorUnauthorized("Token is blacklisted"), req));
-    }
-    match jwt_auth.validate_token(token) {
-        Ok(claims) => {
+    let checked = db.run(move |db| check_token(&jwt_auth, db, &token)).await;
+    match checked {
+        Ok(TokenCheck::Valid(claims)) => {
             info!("JWT Validation successful!");
             req.extensions_mut().insert(claims);
             Ok(req)
         }
-        Err(_) => {
+        Ok(TokenCheck::Blacklisted) => Err((error::ErrorUnauthorized("Token is blacklisted"), req)),
+        Ok(TokenCheck::Invalid) => {
             warn!("Invalid JWT token");
             Err((error::ErrorUnauthorized("Invalid token"), req))
         }
+        Ok(TokenCheck::Revoked) => Err((error::ErrorUnauthorized("Session revoked"), req)),
+        Err(e) if is_busy(&e) => {
+            warn!("Database is busy, rejecting request: {}", e);
+            let response = HttpResponse::ServiceUnavailable()
+                .insert_header((header::RETRY_AFTER, "1"))
+                .finish();
+            Err((error::InternalError::from_response(e, response).into(), req))
+        }
+        Err(e) => {
+            error!("Failed to look up session: {}", e);
+            Err((error::ErrorInternalServerError("Database Error"), req))
+        }
     }
 }
@@ -0,0 +1,10 @@
+use std::{env, str::FromStr};
+
+// Reads a numeric setting from the environment, unset or unparsable values fall back to
+// the default
+pub fn env_param<T: FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(default)
+}
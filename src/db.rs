@@ -1,121 +1,1172 @@
-use log::{error, info};
-use rusqlite::{params, Connection, Result};
-use std::{env, path::Path, process};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{
+    params, Connection, ErrorCode, OptionalExtension, Transaction, TransactionBehavior,
+};
+use std::{env, time::Duration};
+use uuid::Uuid;
+
+use crate::migrations::{migrate, schema_version};
+use crate::models::KdfParams;
+use crate::store::{
+    normalize_email, Attachment, AttachmentCreate, ItemChange, ItemsUpdate, LoginFailures,
+    RefreshTokenStatus, Result, Session, StoreError, Totp, Vault, VaultItem, VaultSnapshot,
+    VaultStore, VaultUpdate, WebauthnCeremony, WebauthnCredential, SESSION_TOUCH_INTERVAL,
+};
 
 pub fn get_db_path() -> String {
     env::var("DB_FILE").unwrap_or_else(|_| "./database.db".to_string())
 }
 
-fn get_connection() -> Result<Connection> {
-    let db_path = get_db_path();
-    Connection::open(db_path)
+// Upper bound of open connections, requests wait for a free one beyond that
+pub(crate) fn pool_size() -> u32 {
+    env::var("DB_POOL_SIZE")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .filter(|&size| size > 0)
+        .unwrap_or(8)
 }
 
-pub fn initialize_database() -> Result<()> {
-    let db_path = get_db_path();
-
-    // Attempt to open the database
-    match get_connection() {
-        Ok(conn) => {
-            info!("Database at {} opened successfully.", db_path);
-            conn.execute(
-                "CREATE TABLE IF NOT EXISTS users (
-                    email TEXT PRIMARY KEY,
-                    password_hash TEXT NOT NULL,
-                    encrypted_data TEXT DEFAULT ''
-                );",
-                [],
-            )?;
-            info!("Database initialized successfully.");
-        }
-        Err(e) => {
-            if Path::new(&db_path).exists() {
-                error!("Failed to open existing database: {}", e);
-            } else {
-                error!("Database file not found, and creation failed: {}", e);
+// How long a statement waits for the write lock of another connection
+pub(crate) const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+// Prepared statements kept per connection, enough for every query of this module
+const STATEMENT_CACHE_CAPACITY: usize = 128;
+
+// Applied to every new connection of the pool. WAL lets readers proceed while one
+// connection writes, which the default rollback journal does not
+fn configure_connection(conn: &mut Connection) -> rusqlite::Result<()> {
+    conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    conn.pragma_update(None, "foreign_keys", "ON")?;
+    conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+    Ok(())
+}
+
+// A locked database is reported as busy, like a full queue, so callers handle both alike
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        match e {
+            rusqlite::Error::SqliteFailure(error, _) if error.code == ErrorCode::DatabaseBusy => {
+                StoreError::Busy(e.to_string())
             }
-            process::exit(1);
+            e => StoreError::Backend(e.to_string()),
         }
     }
-    Ok(())
 }
 
-pub fn user_exists(email: &str) -> Result<bool> {
-    let mut conn = get_connection()?;
-    let tx = conn.transaction()?;
-    let exists: bool = tx.query_row(
-        "SELECT EXISTS(SELECT 1 FROM users WHERE email = ?1)",
-        params![email],
-        |row| row.get(0),
-    )?;
-    tx.commit()?;
-    Ok(exists)
+// Pool of SQLite connections to one database file
+#[derive(Clone)]
+pub struct SqliteStore {
+    pool: Pool<SqliteConnectionManager>,
 }
 
-pub fn user_login(email: &str, password_hash: &str) -> Result<bool> {
-    let mut conn = get_connection()?;
-    let tx = conn.transaction()?;
-    let exists: bool = tx.query_row(
-        "SELECT EXISTS(SELECT 1 FROM users WHERE email = ?1 AND password_hash = ?2)", 
-        params![email, password_hash],
-        |row| row.get(0),
-    )?;
-    tx.commit()?;
-    Ok(exists)
+impl SqliteStore {
+    pub fn open(path: &str) -> std::result::Result<Self, String> {
+        let manager = SqliteConnectionManager::file(path).with_init(configure_connection);
+        let pool = Pool::builder()
+            .max_size(pool_size())
+            .connection_timeout(BUSY_TIMEOUT)
+            .build(manager)
+            .map_err(|e| e.to_string())?;
+        Ok(SqliteStore { pool })
+    }
+
+    fn connection(&self) -> Result<PooledConnection<SqliteConnectionManager>> {
+        self.pool
+            .get()
+            .map_err(|e| StoreError::Busy(format!("No database connection available: {}", e)))
+    }
 }
 
-pub fn user_register(email: &str, password_hash: &str) -> Result<()> {
-    let mut conn = get_connection()?;
-    let tx = conn.transaction()?;
-    tx.execute(
-        "INSERT INTO users (email, password_hash) VALUES (?1, ?2)",
-        params![email, password_hash],
-    )?;
-    tx.commit()?;
+fn set_kdf(tx: &Transaction, user_id: &str, kdf: &KdfParams) -> rusqlite::Result<()> {
+    tx.prepare_cached(
+        "UPDATE users SET kdf_algorithm = ?1, kdf_iterations = ?2, kdf_memory = ?3,
+         kdf_parallelism = ?4, kdf_salt = ?5 WHERE id = ?6",
+    )?
+    .execute(params![
+        kdf.algorithm,
+        kdf.iterations,
+        kdf.memory,
+        kdf.parallelism,
+        kdf.salt,
+        user_id
+    ])?;
     Ok(())
 }
 
-pub fn user_changepwd(email: &str, password_hash: &str) -> Result<()> {
-    let mut conn = get_connection()?;
-    let tx = conn.transaction()?;
-    tx.execute(
-        "UPDATE users SET password_hash = ?1 WHERE email = ?2",
-        params![password_hash, email],
-    )?;
-    tx.commit()?;
+// A new stamp invalidates every access token issued before, so all refresh tokens and
+// every session except the one to keep are dropped as well
+fn rotate_security_stamp(
+    tx: &Transaction,
+    user_id: &str,
+    keep_nonce: Option<&str>,
+) -> rusqlite::Result<()> {
+    tx.prepare_cached("UPDATE users SET security_stamp = ?1 WHERE id = ?2")?
+        .execute(params![Uuid::new_v4().to_string(), user_id])?;
+    tx.prepare_cached("DELETE FROM refresh_tokens WHERE user_id = ?1")?
+        .execute(params![user_id])?;
+    tx.prepare_cached("DELETE FROM sessions WHERE user_id = ?1 AND nonce IS NOT ?2")?
+        .execute(params![user_id, keep_nonce])?;
     Ok(())
 }
 
-pub fn user_delete(email: &str) -> Result<()> {
-    let mut conn = get_connection()?;
-    let tx = conn.transaction()?;
-    tx.execute(
-        "DELETE FROM users WHERE email = ?1", 
-        params![email]
-    )?;
-    tx.commit()?;
+fn delete_totp(tx: &Transaction, user_id: &str) -> rusqlite::Result<()> {
+    tx.prepare_cached("DELETE FROM totp WHERE user_id = ?1")?
+        .execute(params![user_id])?;
+    tx.prepare_cached("DELETE FROM recovery_codes WHERE user_id = ?1")?
+        .execute(params![user_id])?;
     Ok(())
 }
 
-pub fn data_get(email: &str) -> Result<String> {
-    let mut conn = get_connection()?;
-    let tx = conn.transaction()?;
-    let encrypted_data: String = tx.query_row(
-        "SELECT encrypted_data FROM users WHERE email = ?1",
-        params![email],
-        |row| row.get(0),
-    )?;
-    tx.commit()?;
-    Ok(encrypted_data)
+fn webauthn_credential_from_row(row: &rusqlite::Row) -> rusqlite::Result<WebauthnCredential> {
+    Ok(WebauthnCredential {
+        credential_id: row.get(0)?,
+        name: row.get(1)?,
+        public_key: row.get(2)?,
+        algorithm: row.get(3)?,
+        sign_count: row.get(4)?,
+        created_at: row.get(5)?,
+        last_used: row.get(6)?,
+    })
 }
 
-pub fn data_update(email: &str, encrypted_data: &str) -> Result<()> {
-    let mut conn = get_connection()?;
-    let tx = conn.transaction()?;
-    tx.execute(
-        "UPDATE users SET encrypted_data = ?1 WHERE email = ?2",
-        params![encrypted_data, email],
-    )?;
-    tx.commit()?;  
-    Ok(())
+fn login_failures_from_row(row: &rusqlite::Row) -> rusqlite::Result<LoginFailures> {
+    Ok(LoginFailures {
+        kind: row.get(0)?,
+        subject: row.get(1)?,
+        failures: row.get(2)?,
+        last_failure: row.get(3)?,
+        locked_until: row.get(4)?,
+    })
+}
+
+fn attachment_from_row(row: &rusqlite::Row) -> rusqlite::Result<Attachment> {
+    Ok(Attachment {
+        id: row.get(0)?,
+        item_id: row.get(1)?,
+        size: row.get(2)?,
+        received: row.get(3)?,
+        created_at: row.get(4)?,
+    })
+}
+
+impl VaultStore for SqliteStore {
+    fn migrate(&self) -> std::result::Result<Vec<u32>, String> {
+        let mut conn = self.connection().map_err(|e| e.to_string())?;
+        migrate(&mut conn)
+    }
+
+    fn schema_version(&self) -> Result<u32> {
+        Ok(schema_version(&*self.connection()?)?)
+    }
+
+    fn user_id(&self, email: &str) -> Result<Option<String>> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let id: Option<String> = tx
+            .prepare_cached("SELECT id FROM users WHERE email = ?1")?
+            .query_row(params![normalize_email(email)], |row| row.get(0))
+            .optional()?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    fn user_email(&self, user_id: &str) -> Result<Option<String>> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let email: Option<String> = tx
+            .prepare_cached("SELECT email FROM users WHERE id = ?1")?
+            .query_row(params![user_id], |row| row.get(0))
+            .optional()?;
+        tx.commit()?;
+        Ok(email)
+    }
+
+    fn user_password_hash(&self, user_id: &str) -> Result<Option<String>> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let password_hash: Option<String> = tx
+            .prepare_cached("SELECT password_hash FROM users WHERE id = ?1")?
+            .query_row(params![user_id], |row| row.get(0))
+            .optional()?;
+        tx.commit()?;
+        Ok(password_hash)
+    }
+
+    fn user_rehash_password(&self, user_id: &str, password_hash: &str) -> Result<()> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        tx.prepare_cached("UPDATE users SET password_hash = ?1 WHERE id = ?2")?
+            .execute(params![password_hash, user_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn user_register(
+        &self,
+        email: &str,
+        password_hash: &str,
+        kdf: Option<&KdfParams>,
+    ) -> Result<String> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let user_id = Uuid::new_v4().to_string();
+        tx.prepare_cached(
+            "INSERT INTO users (id, email, password_hash, security_stamp, email_verified)
+             VALUES (?1, ?2, ?3, ?4, 0)",
+        )?
+        .execute(params![
+            user_id,
+            normalize_email(email),
+            password_hash,
+            Uuid::new_v4().to_string()
+        ])?;
+        if let Some(kdf) = kdf {
+            set_kdf(&tx, &user_id, kdf)?;
+        }
+        tx.commit()?;
+        Ok(user_id)
+    }
+
+    fn user_kdf(&self, user_id: &str) -> Result<Option<KdfParams>> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let kdf = tx
+            .prepare_cached(
+                "SELECT kdf_algorithm, kdf_iterations, kdf_memory, kdf_parallelism, kdf_salt
+                 FROM users WHERE id = ?1",
+            )?
+            .query_row(params![user_id], |row| {
+                let algorithm: Option<String> = row.get(0)?;
+                Ok(match algorithm {
+                    Some(algorithm) => KdfParams {
+                        algorithm,
+                        iterations: row.get(1)?,
+                        memory: row.get(2)?,
+                        parallelism: row.get(3)?,
+                        salt: row.get(4)?,
+                    },
+                    None => KdfParams::default(),
+                })
+            })
+            .optional()?;
+        tx.commit()?;
+        Ok(kdf)
+    }
+
+    fn user_email_verified(&self, user_id: &str) -> Result<Option<bool>> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let verified: Option<bool> = tx
+            .prepare_cached("SELECT email_verified FROM users WHERE id = ?1")?
+            .query_row(params![user_id], |row| row.get(0))
+            .optional()?;
+        tx.commit()?;
+        Ok(verified)
+    }
+
+    fn user_verify_email(&self, user_id: &str) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let updated = tx
+            .prepare_cached("UPDATE users SET email_verified = 1 WHERE id = ?1")?
+            .execute(params![user_id])?;
+        tx.commit()?;
+        Ok(updated > 0)
+    }
+
+    fn user_security_stamp(&self, user_id: &str) -> Result<Option<String>> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let stamp: Option<String> = tx
+            .prepare_cached("SELECT security_stamp FROM users WHERE id = ?1")?
+            .query_row(params![user_id], |row| row.get(0))
+            .optional()?;
+        tx.commit()?;
+        Ok(stamp)
+    }
+
+    fn user_changepwd(
+        &self,
+        user_id: &str,
+        password_hash: &str,
+        kdf: Option<&KdfParams>,
+        current_nonce: &str,
+    ) -> Result<()> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        tx.prepare_cached("UPDATE users SET password_hash = ?1 WHERE id = ?2")?
+            .execute(params![password_hash, user_id])?;
+        if let Some(kdf) = kdf {
+            set_kdf(&tx, user_id, kdf)?;
+        }
+        rotate_security_stamp(&tx, user_id, Some(current_nonce))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn user_change_email(
+        &self,
+        user_id: &str,
+        new_email: &str,
+        password_hash: Option<&str>,
+        kdf: Option<&KdfParams>,
+        current_nonce: &str,
+    ) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let new_email = normalize_email(new_email);
+        let taken: bool = tx
+            .prepare_cached("SELECT EXISTS(SELECT 1 FROM users WHERE email = ?1)")?
+            .query_row(params![new_email], |row| row.get(0))?;
+        if taken {
+            return Ok(false);
+        }
+        let old_email: String = tx
+            .prepare_cached("SELECT email FROM users WHERE id = ?1")?
+            .query_row(params![user_id], |row| row.get(0))?;
+        rotate_security_stamp(&tx, user_id, Some(current_nonce))?;
+        tx.prepare_cached("UPDATE users SET email = ?1, email_verified = 1 WHERE id = ?2")?
+            .execute(params![new_email, user_id])?;
+        tx.prepare_cached("DELETE FROM webauthn_challenges WHERE user_id = ?1")?
+            .execute(params![user_id])?;
+        tx.prepare_cached("DELETE FROM login_failures WHERE kind = 'email' AND subject = ?1")?
+            .execute(params![old_email])?;
+        if let Some(password_hash) = password_hash {
+            tx.prepare_cached("UPDATE users SET password_hash = ?1 WHERE id = ?2")?
+                .execute(params![password_hash, user_id])?;
+        }
+        if let Some(kdf) = kdf {
+            set_kdf(&tx, user_id, kdf)?;
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn user_logout_all(&self, user_id: &str) -> Result<()> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        rotate_security_stamp(&tx, user_id, None)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn user_delete(&self, user_id: &str) -> Result<()> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        rotate_security_stamp(&tx, user_id, None)?;
+        delete_totp(&tx, user_id)?;
+        for table in [
+            "webauthn_credentials",
+            "webauthn_challenges",
+            "vault_history",
+            "vault_items",
+            "attachments",
+        ] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE user_id = ?1", table),
+                params![user_id],
+            )?;
+        }
+        tx.prepare_cached("DELETE FROM users WHERE id = ?1")?
+            .execute(params![user_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn data_get(&self, user_id: &str) -> Result<Vault> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let vault = tx
+            .prepare_cached("SELECT encrypted_data, revision FROM users WHERE id = ?1")?
+            .query_row(params![user_id], |row| {
+                Ok(Vault {
+                    encrypted_data: row.get(0)?,
+                    revision: row.get(1)?,
+                })
+            })?;
+        tx.commit()?;
+        Ok(vault)
+    }
+
+    fn data_update(
+        &self,
+        user_id: &str,
+        encrypted_data: &str,
+        base_revision: Option<u64>,
+        session_id: &str,
+        now: usize,
+    ) -> Result<VaultUpdate> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        // Compared and written in one statement, so concurrent updates cannot both match
+        let updated: Option<u64> = tx
+            .prepare_cached(
+                "UPDATE users SET encrypted_data = ?1, revision = revision + 1
+                 WHERE id = ?2 AND (?3 IS NULL OR revision = ?3) RETURNING revision",
+            )?
+            .query_row(params![encrypted_data, user_id, base_revision], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let status = match updated {
+            Some(revision) => {
+                tx.prepare_cached(
+                    "INSERT INTO vault_history
+                     (user_id, revision, encrypted_data, size, created_at, session_id, device_name)
+                     VALUES (?1, ?2, ?3, ?4, ?5, ?6,
+                        (SELECT device_name FROM sessions WHERE nonce = ?6))",
+                )?
+                .execute(params![
+                    user_id,
+                    revision,
+                    encrypted_data,
+                    encrypted_data.len(),
+                    now,
+                    session_id
+                ])?;
+                VaultUpdate::Updated { revision }
+            }
+            None => VaultUpdate::Conflict {
+                revision: tx
+                    .prepare_cached("SELECT revision FROM users WHERE id = ?1")?
+                    .query_row(params![user_id], |row| row.get(0))?,
+            },
+        };
+        tx.commit()?;
+        Ok(status)
+    }
+
+    fn vault_history_list(&self, user_id: &str) -> Result<Vec<VaultSnapshot>> {
+        let conn = self.connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT revision, size, created_at, session_id, device_name
+             FROM vault_history WHERE user_id = ?1 ORDER BY revision DESC",
+        )?;
+        let snapshots = stmt
+            .query_map(params![user_id], |row| {
+                Ok(VaultSnapshot {
+                    revision: row.get(0)?,
+                    size: row.get(1)?,
+                    created_at: row.get(2)?,
+                    session_id: row.get(3)?,
+                    device_name: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(snapshots)
+    }
+
+    fn vault_history_get(&self, user_id: &str, revision: u64) -> Result<Option<Vault>> {
+        let conn = self.connection()?;
+        let vault = conn
+            .prepare_cached(
+                "SELECT encrypted_data, revision FROM vault_history
+                 WHERE user_id = ?1 AND revision = ?2",
+            )?
+            .query_row(params![user_id, revision], |row| {
+                Ok(Vault {
+                    encrypted_data: row.get(0)?,
+                    revision: row.get(1)?,
+                })
+            })
+            .optional()?;
+        Ok(vault)
+    }
+
+    fn vault_history_prune(
+        &self,
+        user_id: &str,
+        keep: usize,
+        created_before: usize,
+    ) -> Result<usize> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let deleted = tx
+            .prepare_cached(
+                "DELETE FROM vault_history WHERE user_id = ?1 AND (created_at < ?3
+                    OR revision NOT IN (SELECT revision FROM vault_history
+                        WHERE user_id = ?1 ORDER BY revision DESC LIMIT ?2))",
+            )?
+            .execute(params![user_id, keep, created_before])?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn vault_history_cleanup(&self, created_before: usize) -> Result<usize> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let deleted = tx
+            .prepare_cached("DELETE FROM vault_history WHERE created_at < ?1")?
+            .execute(params![created_before])?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn items_changed(&self, user_id: &str, since: u64) -> Result<(u64, Vec<VaultItem>)> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let revision: u64 = tx
+            .prepare_cached("SELECT items_revision FROM users WHERE id = ?1")?
+            .query_row(params![user_id], |row| row.get(0))?;
+        let items = tx
+            .prepare_cached(
+                "SELECT id, revision, encrypted_data FROM vault_items
+                 WHERE user_id = ?1 AND revision > ?2 AND revision <= ?3
+                 ORDER BY revision, id",
+            )?
+            .query_map(params![user_id, since, revision], |row| {
+                Ok(VaultItem {
+                    id: row.get(0)?,
+                    revision: row.get(1)?,
+                    encrypted_data: row.get(2)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        tx.commit()?;
+        Ok((revision, items))
+    }
+
+    fn items_apply(&self, user_id: &str, changes: &[ItemChange]) -> Result<ItemsUpdate> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        // Taking the new revision first holds the write lock while the items are compared
+        let revision: u64 = tx
+            .prepare_cached(
+                "UPDATE users SET items_revision = items_revision + 1 WHERE id = ?1
+                 RETURNING items_revision",
+            )?
+            .query_row(params![user_id], |row| row.get(0))?;
+        let mut ids = Vec::new();
+        for change in changes {
+            let current: u64 = tx
+                .prepare_cached("SELECT revision FROM vault_items WHERE user_id = ?1 AND id = ?2")?
+                .query_row(params![user_id, change.id], |row| row.get(0))
+                .optional()?
+                .unwrap_or(0);
+            if current != change.base_revision {
+                ids.push(change.id.clone());
+            }
+        }
+        if !ids.is_empty() {
+            // Dropping the transaction rolls back the new revision
+            return Ok(ItemsUpdate::Conflict {
+                revision: revision - 1,
+                ids,
+            });
+        }
+        let mut attachments = Vec::new();
+        for change in changes {
+            tx.prepare_cached(
+                "INSERT INTO vault_items (user_id, id, revision, encrypted_data)
+                 VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (user_id, id) DO UPDATE
+                 SET revision = excluded.revision, encrypted_data = excluded.encrypted_data",
+            )?
+            .execute(params![user_id, change.id, revision, change.encrypted_data])?;
+            if change.encrypted_data.is_none() {
+                let removed = tx
+                    .prepare_cached(
+                        "DELETE FROM attachments WHERE user_id = ?1 AND item_id = ?2 RETURNING id",
+                    )?
+                    .query_map(params![user_id, change.id], |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<String>>>()?;
+                attachments.extend(removed);
+            }
+        }
+        tx.commit()?;
+        Ok(ItemsUpdate::Applied {
+            revision,
+            attachments,
+        })
+    }
+
+    fn attachment_create(
+        &self,
+        user_id: &str,
+        attachment: &Attachment,
+        quota: u64,
+    ) -> Result<AttachmentCreate> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let item: Option<bool> = tx
+            .prepare_cached(
+                "SELECT encrypted_data IS NOT NULL FROM vault_items WHERE user_id = ?1 AND id = ?2",
+            )?
+            .query_row(params![user_id, attachment.item_id], |row| row.get(0))
+            .optional()?;
+        if item != Some(true) {
+            return Ok(AttachmentCreate::ItemNotFound);
+        }
+        let usage: u64 = tx
+            .prepare_cached("SELECT COALESCE(SUM(size), 0) FROM attachments WHERE user_id = ?1")?
+            .query_row(params![user_id], |row| row.get(0))?;
+        if usage + attachment.size > quota {
+            return Ok(AttachmentCreate::QuotaExceeded { usage });
+        }
+        tx.prepare_cached(
+            "INSERT INTO attachments (user_id, id, item_id, size, received, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?
+        .execute(params![
+            user_id,
+            attachment.id,
+            attachment.item_id,
+            attachment.size,
+            attachment.received,
+            attachment.created_at
+        ])?;
+        tx.commit()?;
+        Ok(AttachmentCreate::Created)
+    }
+
+    fn attachment_get(&self, user_id: &str, id: &str) -> Result<Option<Attachment>> {
+        let conn = self.connection()?;
+        let attachment = conn
+            .prepare_cached(
+                "SELECT id, item_id, size, received, created_at FROM attachments
+                 WHERE user_id = ?1 AND id = ?2",
+            )?
+            .query_row(params![user_id, id], attachment_from_row)
+            .optional()?;
+        Ok(attachment)
+    }
+
+    fn attachments_list(&self, user_id: &str) -> Result<Vec<Attachment>> {
+        let conn = self.connection()?;
+        let attachments = conn
+            .prepare_cached(
+                "SELECT id, item_id, size, received, created_at FROM attachments
+                 WHERE user_id = ?1 ORDER BY created_at, id",
+            )?
+            .query_map(params![user_id], attachment_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(attachments)
+    }
+
+    fn attachment_received(&self, user_id: &str, id: &str, received: u64) -> Result<()> {
+        self.connection()?
+            .prepare_cached("UPDATE attachments SET received = ?3 WHERE user_id = ?1 AND id = ?2")?
+            .execute(params![user_id, id, received])?;
+        Ok(())
+    }
+
+    fn attachment_delete(&self, user_id: &str, id: &str) -> Result<bool> {
+        let deleted = self
+            .connection()?
+            .prepare_cached("DELETE FROM attachments WHERE user_id = ?1 AND id = ?2")?
+            .execute(params![user_id, id])?;
+        Ok(deleted > 0)
+    }
+
+    fn attachments_cleanup(&self, created_before: usize) -> Result<Vec<(String, String)>> {
+        let conn = self.connection()?;
+        let removed = conn
+            .prepare_cached(
+                "DELETE FROM attachments WHERE received < size AND created_at < ?1
+                 RETURNING user_id, id",
+            )?
+            .query_map(params![created_before], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(removed)
+    }
+
+    fn refresh_token_store(
+        &self,
+        token_hash: &str,
+        user_id: &str,
+        family_id: &str,
+        expires_at: usize,
+    ) -> Result<()> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        tx.prepare_cached(
+            "INSERT INTO refresh_tokens (token_hash, user_id, family_id, expires_at)
+             VALUES (?1, ?2, ?3, ?4)",
+        )?
+        .execute(params![token_hash, user_id, family_id, expires_at])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn refresh_token_consume(&self, token_hash: &str, now: usize) -> Result<RefreshTokenStatus> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let row: Option<(String, String, usize, bool)> = tx
+            .prepare_cached(
+                "SELECT user_id, family_id, expires_at, used FROM refresh_tokens
+                 WHERE token_hash = ?1",
+            )?
+            .query_row(params![token_hash], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .optional()?;
+
+        let status = match row {
+            None => RefreshTokenStatus::Unknown,
+            Some((user_id, family_id, _, true)) => {
+                tx.prepare_cached("DELETE FROM refresh_tokens WHERE family_id = ?1")?
+                    .execute(params![family_id])?;
+                RefreshTokenStatus::Reused { user_id, family_id }
+            }
+            Some((_, _, expires_at, false)) if expires_at <= now => RefreshTokenStatus::Expired,
+            Some((user_id, family_id, _, false)) => {
+                tx.prepare_cached("UPDATE refresh_tokens SET used = 1 WHERE token_hash = ?1")?
+                    .execute(params![token_hash])?;
+                RefreshTokenStatus::Valid { user_id, family_id }
+            }
+        };
+        tx.commit()?;
+        Ok(status)
+    }
+
+    fn refresh_tokens_cleanup(&self, now: usize) -> Result<usize> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let deleted = tx
+            .prepare_cached("DELETE FROM refresh_tokens WHERE expires_at <= ?1")?
+            .execute(params![now])?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn token_revoke(&self, nonce: &str, expires_at: usize) -> Result<()> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        tx.prepare_cached(
+            "INSERT INTO revoked_tokens (nonce, expires_at) VALUES (?1, ?2)
+             ON CONFLICT(nonce) DO UPDATE SET expires_at = MAX(expires_at, excluded.expires_at)",
+        )?
+        .execute(params![nonce, expires_at])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn token_is_revoked(&self, nonce: &str) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let revoked: bool = tx
+            .prepare_cached("SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE nonce = ?1)")?
+            .query_row(params![nonce], |row| row.get(0))?;
+        tx.commit()?;
+        Ok(revoked)
+    }
+
+    fn revoked_tokens_cleanup(&self, now: usize) -> Result<usize> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let deleted = tx
+            .prepare_cached("DELETE FROM revoked_tokens WHERE expires_at <= ?1")?
+            .execute(params![now])?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn session_create(&self, user_id: &str, session: &Session) -> Result<()> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        tx.prepare_cached(
+            "INSERT INTO sessions (nonce, user_id, device_name, user_agent, ip, created_at, last_seen)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?
+        .execute(params![
+            session.nonce,
+            user_id,
+            session.device_name,
+            session.user_agent,
+            session.ip,
+            session.created_at,
+            session.last_seen
+        ])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn session_touch(&self, nonce: &str, now: usize) -> Result<bool> {
+        let conn = self.connection()?;
+        let stale = now.saturating_sub(SESSION_TOUCH_INTERVAL);
+        // Read first, an UPDATE takes the write lock even if it matches no row
+        let last_seen: Option<usize> = conn
+            .prepare_cached("SELECT last_seen FROM sessions WHERE nonce = ?1")?
+            .query_row(params![nonce], |row| row.get(0))
+            .optional()?;
+        match last_seen {
+            None => Ok(false),
+            Some(last_seen) if last_seen >= stale => Ok(true),
+            Some(_) => {
+                conn.prepare_cached(
+                    "UPDATE sessions SET last_seen = ?1 WHERE nonce = ?2 AND last_seen < ?3",
+                )?
+                .execute(params![now, nonce, stale])?;
+                Ok(true)
+            }
+        }
+    }
+
+    fn sessions_list(&self, user_id: &str) -> Result<Vec<Session>> {
+        let conn = self.connection()?;
+        let mut stmt = conn.prepare_cached(
+            "SELECT nonce, device_name, user_agent, ip, created_at, last_seen
+             FROM sessions WHERE user_id = ?1 ORDER BY created_at",
+        )?;
+        let sessions = stmt
+            .query_map(params![user_id], |row| {
+                Ok(Session {
+                    nonce: row.get(0)?,
+                    device_name: row.get(1)?,
+                    user_agent: row.get(2)?,
+                    ip: row.get(3)?,
+                    created_at: row.get(4)?,
+                    last_seen: row.get(5)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(sessions)
+    }
+
+    fn session_delete(&self, user_id: &str, nonce: &str) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let deleted = tx
+            .prepare_cached("DELETE FROM sessions WHERE user_id = ?1 AND nonce = ?2")?
+            .execute(params![user_id, nonce])?;
+        tx.prepare_cached("DELETE FROM refresh_tokens WHERE user_id = ?1 AND family_id = ?2")?
+            .execute(params![user_id, nonce])?;
+        tx.commit()?;
+        Ok(deleted > 0)
+    }
+
+    fn sessions_delete_others(&self, user_id: &str, keep_nonce: &str) -> Result<Vec<String>> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let nonces = {
+            let mut stmt =
+                tx.prepare_cached("SELECT nonce FROM sessions WHERE user_id = ?1 AND nonce != ?2")?;
+            let rows = stmt.query_map(params![user_id, keep_nonce], |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<String>>>()?
+        };
+        tx.prepare_cached("DELETE FROM sessions WHERE user_id = ?1 AND nonce != ?2")?
+            .execute(params![user_id, keep_nonce])?;
+        tx.prepare_cached("DELETE FROM refresh_tokens WHERE user_id = ?1 AND family_id != ?2")?
+            .execute(params![user_id, keep_nonce])?;
+        tx.commit()?;
+        Ok(nonces)
+    }
+
+    fn totp_begin(&self, user_id: &str, secret: &str) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let changed = tx
+            .prepare_cached(
+                "INSERT INTO totp (user_id, secret) VALUES (?1, ?2)
+                 ON CONFLICT(user_id) DO UPDATE SET secret = excluded.secret, last_step = 0
+                 WHERE enabled = 0",
+            )?
+            .execute(params![user_id, secret])?;
+        tx.commit()?;
+        Ok(changed == 1)
+    }
+
+    fn totp_get(&self, user_id: &str) -> Result<Option<Totp>> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let totp = tx
+            .prepare_cached("SELECT secret, enabled, last_step FROM totp WHERE user_id = ?1")?
+            .query_row(params![user_id], |row| {
+                Ok(Totp {
+                    secret: row.get(0)?,
+                    enabled: row.get(1)?,
+                    last_step: row.get(2)?,
+                })
+            })
+            .optional()?;
+        tx.commit()?;
+        Ok(totp)
+    }
+
+    fn totp_use_step(&self, user_id: &str, step: u64) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let changed = tx
+            .prepare_cached("UPDATE totp SET last_step = ?1 WHERE user_id = ?2 AND last_step < ?1")?
+            .execute(params![step, user_id])?;
+        tx.commit()?;
+        Ok(changed == 1)
+    }
+
+    fn totp_enable(
+        &self,
+        user_id: &str,
+        step: u64,
+        recovery_code_hashes: &[String],
+    ) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let changed = tx
+            .prepare_cached(
+                "UPDATE totp SET enabled = 1, last_step = ?1
+                 WHERE user_id = ?2 AND enabled = 0 AND last_step < ?1",
+            )?
+            .execute(params![step, user_id])?;
+        if changed == 1 {
+            tx.prepare_cached("DELETE FROM recovery_codes WHERE user_id = ?1")?
+                .execute(params![user_id])?;
+            for code_hash in recovery_code_hashes {
+                tx.prepare_cached(
+                    "INSERT INTO recovery_codes (code_hash, user_id) VALUES (?1, ?2)",
+                )?
+                .execute(params![code_hash, user_id])?;
+            }
+        }
+        tx.commit()?;
+        Ok(changed == 1)
+    }
+
+    fn totp_disable(&self, user_id: &str) -> Result<()> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        delete_totp(&tx, user_id)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn recovery_code_consume(&self, user_id: &str, code_hash: &str) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let changed = tx
+            .prepare_cached("DELETE FROM recovery_codes WHERE code_hash = ?1 AND user_id = ?2")?
+            .execute(params![code_hash, user_id])?;
+        tx.commit()?;
+        Ok(changed == 1)
+    }
+
+    fn webauthn_credential_add(
+        &self,
+        user_id: &str,
+        credential: &WebauthnCredential,
+    ) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let changed = tx
+            .prepare_cached(
+                "INSERT INTO webauthn_credentials
+                 (credential_id, user_id, name, public_key, algorithm, sign_count, created_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT(credential_id) DO NOTHING",
+            )?
+            .execute(params![
+                credential.credential_id,
+                user_id,
+                credential.name,
+                credential.public_key,
+                credential.algorithm,
+                credential.sign_count,
+                credential.created_at
+            ])?;
+        tx.commit()?;
+        Ok(changed == 1)
+    }
+
+    fn webauthn_credentials_list(&self, user_id: &str) -> Result<Vec<WebauthnCredential>> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let credentials = {
+            let mut stmt = tx.prepare_cached(
+                "SELECT credential_id, name, public_key, algorithm, sign_count, created_at, last_used
+                 FROM webauthn_credentials WHERE user_id = ?1 ORDER BY created_at",
+            )?;
+            let rows = stmt.query_map(params![user_id], webauthn_credential_from_row)?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        tx.commit()?;
+        Ok(credentials)
+    }
+
+    fn webauthn_credential_get(
+        &self,
+        user_id: &str,
+        credential_id: &str,
+    ) -> Result<Option<WebauthnCredential>> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let credential = tx
+            .prepare_cached(
+                "SELECT credential_id, name, public_key, algorithm, sign_count, created_at, last_used
+                 FROM webauthn_credentials WHERE credential_id = ?1 AND user_id = ?2",
+            )?
+            .query_row(params![credential_id, user_id], webauthn_credential_from_row)
+            .optional()?;
+        tx.commit()?;
+        Ok(credential)
+    }
+
+    fn webauthn_credential_used(
+        &self,
+        credential_id: &str,
+        sign_count: u32,
+        now: usize,
+    ) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let changed = tx
+            .prepare_cached(
+                "UPDATE webauthn_credentials SET sign_count = ?1, last_used = ?2
+                 WHERE credential_id = ?3 AND (sign_count < ?1 OR ?1 = 0)",
+            )?
+            .execute(params![sign_count, now, credential_id])?;
+        tx.commit()?;
+        Ok(changed == 1)
+    }
+
+    fn webauthn_credential_delete(&self, user_id: &str, credential_id: &str) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let changed = tx
+            .prepare_cached(
+                "DELETE FROM webauthn_credentials WHERE credential_id = ?1 AND user_id = ?2",
+            )?
+            .execute(params![credential_id, user_id])?;
+        tx.commit()?;
+        Ok(changed == 1)
+    }
+
+    fn webauthn_challenge_store(&self, challenge: &str, ceremony: &WebauthnCeremony) -> Result<()> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        tx.prepare_cached(
+            "INSERT INTO webauthn_challenges (challenge, user_id, purpose, pending_nonce, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?
+        .execute(params![
+            challenge,
+            ceremony.user_id,
+            ceremony.purpose,
+            ceremony.pending_nonce,
+            ceremony.expires_at
+        ])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn webauthn_challenge_consume(
+        &self,
+        challenge: &str,
+        now: usize,
+    ) -> Result<Option<WebauthnCeremony>> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let ceremony = tx
+            .prepare_cached(
+                "DELETE FROM webauthn_challenges WHERE challenge = ?1
+                 RETURNING user_id, purpose, pending_nonce, expires_at",
+            )?
+            .query_row(params![challenge], |row| {
+                Ok(WebauthnCeremony {
+                    user_id: row.get(0)?,
+                    purpose: row.get(1)?,
+                    pending_nonce: row.get(2)?,
+                    expires_at: row.get(3)?,
+                })
+            })
+            .optional()?;
+        tx.commit()?;
+        Ok(ceremony.filter(|ceremony| ceremony.expires_at > now))
+    }
+
+    fn webauthn_challenges_cleanup(&self, now: usize) -> Result<usize> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let deleted = tx
+            .prepare_cached("DELETE FROM webauthn_challenges WHERE expires_at <= ?1")?
+            .execute(params![now])?;
+        tx.commit()?;
+        Ok(deleted)
+    }
+
+    fn login_failures_get(&self, kind: &str, subject: &str) -> Result<Option<LoginFailures>> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let failures = tx
+            .prepare_cached(
+                "SELECT kind, subject, failures, last_failure, locked_until
+                 FROM login_failures WHERE kind = ?1 AND subject = ?2",
+            )?
+            .query_row(params![kind, subject], login_failures_from_row)
+            .optional()?;
+        tx.commit()?;
+        Ok(failures)
+    }
+
+    fn login_failure_record(
+        &self,
+        kind: &str,
+        subject: &str,
+        now: usize,
+        window: usize,
+    ) -> Result<u32> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let failures = tx
+            .prepare_cached(
+                "INSERT INTO login_failures (kind, subject, failures, last_failure)
+                 VALUES (?1, ?2, 1, ?3)
+                 ON CONFLICT(kind, subject) DO UPDATE SET
+                    failures = CASE WHEN last_failure + ?4 < ?3 THEN 1 ELSE failures + 1 END,
+                    last_failure = ?3
+                 RETURNING failures",
+            )?
+            .query_row(params![kind, subject, now, window], |row| row.get(0))?;
+        tx.commit()?;
+        Ok(failures)
+    }
+
+    fn login_lock(&self, kind: &str, subject: &str, locked_until: usize) -> Result<()> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        tx.prepare_cached(
+            "UPDATE login_failures SET locked_until = ?1 WHERE kind = ?2 AND subject = ?3",
+        )?
+        .execute(params![locked_until, kind, subject])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn login_failures_reset(&self, kind: &str, subject: &str) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let changed = tx
+            .prepare_cached("DELETE FROM login_failures WHERE kind = ?1 AND subject = ?2")?
+            .execute(params![kind, subject])?;
+        tx.commit()?;
+        Ok(changed == 1)
+    }
+
+    fn login_failures_list(&self) -> Result<Vec<LoginFailures>> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let failures = {
+            let mut stmt = tx.prepare_cached(
+                "SELECT kind, subject, failures, last_failure, locked_until
+                 FROM login_failures ORDER BY locked_until DESC, failures DESC",
+            )?;
+            let rows = stmt.query_map([], login_failures_from_row)?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()?
+        };
+        tx.commit()?;
+        Ok(failures)
+    }
+
+    fn login_failures_cleanup(&self, now: usize, window: usize) -> Result<usize> {
+        let mut conn = self.connection()?;
+        let tx = conn.transaction()?;
+        let deleted = tx
+            .prepare_cached(
+                "DELETE FROM login_failures WHERE last_failure + ?2 < ?1 AND locked_until <= ?1",
+            )?
+            .execute(params![now, window])?;
+        tx.commit()?;
+        Ok(deleted)
+    }
 }
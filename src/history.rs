@@ -0,0 +1,24 @@
+use crate::{
+    config::env_param,
+    store::{Result, VaultStore},
+};
+
+// Snapshots kept per user, the current revision included
+pub fn max_snapshots() -> usize {
+    env_param("VAULT_HISTORY_COUNT", 20).max(1)
+}
+
+// Snapshots older than this are removed, whatever their number
+pub fn max_age() -> usize {
+    env_param("VAULT_HISTORY_MAX_AGE", 90 * 24 * 3600)
+}
+
+// Drops the snapshots of a user that fall outside of the retention
+pub fn prune_history(db: &dyn VaultStore, user_id: &str, now: usize) -> Result<usize> {
+    db.vault_history_prune(user_id, max_snapshots(), now.saturating_sub(max_age()))
+}
+
+// Drops expired snapshots of all users, for those who stopped syncing
+pub fn history_cleanup(db: &dyn VaultStore, now: usize) -> Result<usize> {
+    db.vault_history_cleanup(now.saturating_sub(max_age()))
+}
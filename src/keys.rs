@@ -0,0 +1,346 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ed25519_dalek::pkcs8::{spki::der::pem::LineEnding, DecodePrivateKey, EncodePrivateKey};
+use jsonwebtoken::{
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+        EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+        PublicKeyUse,
+    },
+    Algorithm, DecodingKey, EncodingKey,
+};
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use sha2::{Digest, Sha256};
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::auth::current_timestamp;
+
+// A key used to sign and verify JWTs
+pub struct JwtKey {
+    pub kid: Option<String>,
+    pub algorithm: Algorithm,
+    pub encoding_key: EncodingKey,
+    pub decoding_key: DecodingKey,
+    pub jwk: Option<Jwk>, // public part, only for asymmetric keys
+}
+
+impl JwtKey {
+    pub fn from_secret(secret: &str, kid: Option<String>) -> Result<Self, String> {
+        if secret.len() < 16 {
+            return Err("JWT_SECRET must be at least 16 characters long".to_string());
+        }
+        Ok(JwtKey {
+            kid,
+            algorithm: Algorithm::HS256,
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+            jwk: None,
+        })
+    }
+
+    // Accepts PKCS#8 encoded Ed25519 (EdDSA) or P-256 (ES256) private keys
+    pub fn from_private_pem(pem: &str, kid: Option<String>) -> Result<Self, String> {
+        if let Ok(signing_key) = ed25519_dalek::SigningKey::from_pkcs8_pem(pem) {
+            let x = URL_SAFE_NO_PAD.encode(signing_key.verifying_key().to_bytes());
+            let thumbprint = format!(r#"{{"crv":"Ed25519","kty":"OKP","x":"{}"}}"#, x);
+            let params = AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: x.clone(),
+            });
+            return Ok(Self::asymmetric(
+                kid.unwrap_or_else(|| jwk_thumbprint(&thumbprint)),
+                Algorithm::EdDSA,
+                EncodingKey::from_ed_pem(pem.as_bytes()).map_err(|e| e.to_string())?,
+                DecodingKey::from_ed_components(&x).map_err(|e| e.to_string())?,
+                params,
+            ));
+        }
+
+        if let Ok(secret_key) = p256::SecretKey::from_pkcs8_pem(pem) {
+            let point = secret_key.public_key().to_encoded_point(false);
+            let (Some(x), Some(y)) = (point.x(), point.y()) else {
+                return Err("Invalid P-256 public key".to_string());
+            };
+            let x = URL_SAFE_NO_PAD.encode(x);
+            let y = URL_SAFE_NO_PAD.encode(y);
+            let thumbprint = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+            let params = AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                key_type: EllipticCurveKeyType::EC,
+                curve: EllipticCurve::P256,
+                x: x.clone(),
+                y: y.clone(),
+            });
+            return Ok(Self::asymmetric(
+                kid.unwrap_or_else(|| jwk_thumbprint(&thumbprint)),
+                Algorithm::ES256,
+                EncodingKey::from_ec_pem(pem.as_bytes()).map_err(|e| e.to_string())?,
+                DecodingKey::from_ec_components(&x, &y).map_err(|e| e.to_string())?,
+                params,
+            ));
+        }
+
+        Err("Unsupported private key, expected a PKCS#8 Ed25519 or P-256 key".to_string())
+    }
+
+    pub fn from_private_pem_file(path: &str, kid: Option<String>) -> Result<Self, String> {
+        let pem = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read private key {}: {}", path, e))?;
+        Self::from_private_pem(&pem, kid).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn asymmetric(
+        kid: String,
+        algorithm: Algorithm,
+        encoding_key: EncodingKey,
+        decoding_key: DecodingKey,
+        params: AlgorithmParameters,
+    ) -> Self {
+        let key_algorithm = match algorithm {
+            Algorithm::EdDSA => KeyAlgorithm::EdDSA,
+            _ => KeyAlgorithm::ES256,
+        };
+        let jwk = Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(key_algorithm),
+                key_id: Some(kid.clone()),
+                ..Default::default()
+            },
+            algorithm: params,
+        };
+        JwtKey {
+            kid: Some(kid),
+            algorithm,
+            encoding_key,
+            decoding_key,
+            jwk: Some(jwk),
+        }
+    }
+}
+
+// Ordered set of keys: the active key signs new tokens, retired keys only verify old ones
+pub struct Keyring {
+    active: JwtKey,
+    previous: Vec<JwtKey>,
+}
+
+impl Keyring {
+    pub fn new(active: JwtKey, previous: Vec<JwtKey>) -> Self {
+        Keyring { active, previous }
+    }
+
+    // Loads every *.pem (Ed25519/P-256) and *.key (HS256 secret) file, named by its key id.
+    // The key named in the active file signs, without one the file that sorts last does.
+    pub fn load_dir(dir: &str) -> Result<Self, String> {
+        let mut keys = key_paths(dir)?
+            .iter()
+            .map(|path| load_key_file(path))
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some(newest) = keys.pop() else {
+            return Err(format!("Keyring {} does not contain any keys", dir));
+        };
+        let mut keyring = Keyring::new(newest, keys);
+        if let Some(kid) = read_active_kid(dir)? {
+            if keyring.active.kid.as_deref() != Some(kid.as_str()) {
+                let index = keyring
+                    .previous
+                    .iter()
+                    .position(|key| key.kid.as_deref() == Some(kid.as_str()))
+                    .ok_or_else(|| format!("Active key {} is not in keyring {}", kid, dir))?;
+                // Newer keys than the active one are published, but only verify until promoted
+                let active = keyring.previous.remove(index);
+                keyring.promote(active);
+            }
+        }
+        Ok(keyring)
+    }
+
+    pub fn active(&self) -> &JwtKey {
+        &self.active
+    }
+
+    // Tokens without a kid predate key ids and can only belong to the active key
+    pub fn find(&self, kid: Option<&str>) -> Option<&JwtKey> {
+        match kid {
+            None => Some(&self.active),
+            Some(kid) => std::iter::once(&self.active)
+                .chain(self.previous.iter())
+                .find(|key| key.kid.as_deref() == Some(kid)),
+        }
+    }
+
+    // Key of the file that sorts last, the one rotate-key added most recently
+    fn newest_kid(&self) -> Option<&str> {
+        std::iter::once(&self.active)
+            .chain(self.previous.iter())
+            .filter_map(|key| key.kid.as_deref())
+            .max()
+    }
+
+    pub fn kids(&self) -> Vec<String> {
+        std::iter::once(&self.active)
+            .chain(self.previous.iter())
+            .filter_map(|key| key.kid.clone())
+            .collect()
+    }
+
+    // Makes a new key the signing key, the current one stays available for verification
+    pub fn promote(&mut self, key: JwtKey) {
+        let retired = std::mem::replace(&mut self.active, key);
+        self.previous.push(retired);
+    }
+
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: std::iter::once(&self.active)
+                .chain(self.previous.iter())
+                .filter_map(|key| key.jwk.clone())
+                .collect(),
+        }
+    }
+}
+
+// Key files of a keyring, ordered by name
+fn key_paths(dir: &str) -> Result<Vec<PathBuf>, String> {
+    let entries =
+        fs::read_dir(dir).map_err(|e| format!("Failed to read keyring {}: {}", dir, e))?;
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("pem") | Some("key")
+            )
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn load_key_file(path: &Path) -> Result<JwtKey, String> {
+    let kid = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(str::to_string);
+    let path_str = path.display().to_string();
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("key") => {
+            let secret = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read secret {}: {}", path_str, e))?;
+            JwtKey::from_secret(secret.trim(), kid).map_err(|e| format!("{}: {}", path_str, e))
+        }
+        _ => JwtKey::from_private_pem_file(&path_str, kid),
+    }
+}
+
+// Seconds between reloads of the keyring by the running servers
+pub fn reload_interval() -> u64 {
+    env::var("KEYRING_RELOAD_INTERVAL")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(60)
+}
+
+// Holds the key id of the signing key, it has no key extension so it is not loaded as a key
+const ACTIVE_FILE: &str = "active";
+
+fn read_active_kid(dir: &str) -> Result<Option<String>, String> {
+    let path = Path::new(dir).join(ACTIVE_FILE);
+    match fs::read_to_string(&path) {
+        Ok(kid) => Ok(Some(kid.trim().to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("Failed to read {}: {}", path.display(), e)),
+    }
+}
+
+// Replaced through a rename, so a reload never reads half of it
+fn write_active_kid(dir: &str, kid: &str) -> Result<(), String> {
+    let path = Path::new(dir).join(ACTIVE_FILE);
+    let temp = Path::new(dir).join(format!("{}.tmp", ACTIVE_FILE));
+    fs::write(&temp, format!("{}\n", kid))
+        .and_then(|_| fs::rename(&temp, &path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+// Records the current signing key as the active one, so keys added later only verify until
+// they are promoted. A keyring without keys is left as it is, its first key signs right away
+pub fn pin_active_key(dir: &str) -> Result<(), String> {
+    if read_active_kid(dir)?.is_some() || key_paths(dir)?.is_empty() {
+        return Ok(());
+    }
+    let keyring = Keyring::load_dir(dir)?;
+    match keyring.active().kid.as_deref() {
+        Some(kid) => write_active_kid(dir, kid),
+        None => Err("The active key has no key id".to_string()),
+    }
+}
+
+// Makes a published key the signing key, by default the newest one. Servers that have not
+// reloaded since the key was added would reject its tokens, so that has to be long enough ago
+pub fn promote_key_file(dir: &str, kid: Option<&str>, force: bool) -> Result<String, String> {
+    let keyring = Keyring::load_dir(dir)?;
+    let kid = match kid {
+        Some(kid) => kid.to_string(),
+        None => keyring
+            .newest_kid()
+            .ok_or("The newest key has no key id")?
+            .to_string(),
+    };
+    if keyring.find(Some(&kid)).is_none() {
+        return Err(format!("Key {} is not in keyring {}", kid, dir));
+    }
+    if keyring.active().kid.as_deref() == Some(kid.as_str()) {
+        return Err(format!("Key {} is already the signing key", kid));
+    }
+    let published = key_file_age(dir, &kid)?;
+    let interval = reload_interval();
+    if !force && published < interval {
+        return Err(format!(
+            "Key {} was published {} seconds ago, promote it once all servers reloaded after {} seconds, or pass --force",
+            kid, published, interval
+        ));
+    }
+    write_active_kid(dir, &kid)?;
+    Ok(kid)
+}
+
+// Seconds since the file of a key was written
+fn key_file_age(dir: &str, kid: &str) -> Result<u64, String> {
+    let path = ["pem", "key"]
+        .iter()
+        .map(|ext| Path::new(dir).join(format!("{}.{}", kid, ext)))
+        .find(|path| path.exists())
+        .ok_or(format!("Key file of {} not found", kid))?;
+    let modified = fs::metadata(&path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(modified.elapsed().map(|age| age.as_secs()).unwrap_or(0))
+}
+
+// Writes a new Ed25519 key into the keyring, named so that it sorts after all existing keys
+pub fn generate_key_file(dir: &str) -> Result<String, String> {
+    let signing_key = ed25519_dalek::SigningKey::from_bytes(&rand::random::<[u8; 32]>());
+    let pem = signing_key
+        .to_pkcs8_pem(LineEnding::LF)
+        .map_err(|e| format!("Failed to encode private key: {}", e))?;
+    let path = Path::new(dir).join(format!("{}.pem", current_timestamp()));
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(&path)
+        .and_then(|mut file| file.write_all(pem.as_bytes()))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(path.display().to_string())
+}
+
+// RFC 7638 thumbprint over the required public members in lexicographic order
+fn jwk_thumbprint(canonical_jwk: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(canonical_jwk.as_bytes()))
+}
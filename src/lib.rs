@@ -1,4 +1,19 @@
+pub mod attachments;
 pub mod auth;
+pub mod config;
 pub mod db;
+pub mod history;
+pub mod keys;
+pub mod mailer;
+pub mod memory;
+pub mod migrations;
 pub mod models;
+pub mod notify;
+pub mod password;
+#[cfg(feature = "postgres")]
+pub mod pg;
 pub mod routes;
+pub mod store;
+pub mod throttle;
+pub mod totp;
+pub mod webauthn;
@@ -0,0 +1,119 @@
+use lettre::{
+    message::Mailbox, transport::smtp::authentication::Credentials, Message, SmtpTransport,
+    Transport,
+};
+use log::info;
+use std::{env, fs, path::PathBuf, sync::Arc};
+use uuid::Uuid;
+
+use crate::auth::current_timestamp;
+
+// Delivers plain text mails, sending is blocking and should run off the async workers
+pub trait Mailer: Send + Sync {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String>;
+}
+
+// Writes mails to the log only, for development setups
+pub struct LogMailer;
+
+impl Mailer for LogMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        info!("Mail to {}: {}\n{}", to, subject, body);
+        Ok(())
+    }
+}
+
+// Drops every mail as a file into a directory, for tests and local pickup
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: &str) -> Result<Self, String> {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Failed to create mail directory {}: {}", dir, e))?;
+        Ok(FileMailer {
+            dir: PathBuf::from(dir),
+        })
+    }
+}
+
+impl Mailer for FileMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let path = self
+            .dir
+            .join(format!("{}-{}.eml", current_timestamp(), Uuid::new_v4()));
+        let content = format!("To: {}\nSubject: {}\n\n{}\n", to, subject, body);
+        fs::write(&path, content)
+            .map_err(|e| format!("Failed to write mail {}: {}", path.display(), e))
+    }
+}
+
+pub struct SmtpMailer {
+    transport: SmtpTransport,
+    from: Mailbox,
+}
+
+impl SmtpMailer {
+    // SMTP_TLS selects "starttls" (default), implicit "tls" or "none" for local relays
+    pub fn from_env() -> Result<Self, String> {
+        let host = env::var("SMTP_HOST").map_err(|_| "SMTP_HOST must be set".to_string())?;
+        let from = env::var("MAIL_FROM")
+            .map_err(|_| "MAIL_FROM must be set".to_string())?
+            .parse::<Mailbox>()
+            .map_err(|e| format!("Invalid MAIL_FROM: {}", e))?;
+        let tls = env::var("SMTP_TLS").unwrap_or_else(|_| "starttls".to_string());
+
+        let mut builder = match tls.as_str() {
+            "starttls" => SmtpTransport::starttls_relay(&host),
+            "tls" => SmtpTransport::relay(&host),
+            "none" => Ok(SmtpTransport::builder_dangerous(&host)),
+            _ => return Err(format!("Invalid SMTP_TLS: {}", tls)),
+        }
+        .map_err(|e| format!("Invalid SMTP_HOST {}: {}", host, e))?;
+        if let Some(port) = env::var("SMTP_PORT").ok().and_then(|val| val.parse().ok()) {
+            builder = builder.port(port);
+        }
+        if let (Ok(username), Ok(password)) = (env::var("SMTP_USERNAME"), env::var("SMTP_PASSWORD"))
+        {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        Ok(SmtpMailer {
+            transport: builder.build(),
+            from,
+        })
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn send(&self, to: &str, subject: &str, body: &str) -> Result<(), String> {
+        let to = to
+            .parse::<Mailbox>()
+            .map_err(|e| format!("Invalid recipient {}: {}", to, e))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(subject)
+            .body(body.to_string())
+            .map_err(|e| format!("Failed to build mail: {}", e))?;
+        self.transport
+            .send(&message)
+            .map(|_| ())
+            .map_err(|e| format!("Failed to send mail: {}", e))
+    }
+}
+
+// Selects the backend from MAILER: "log" (default), "file" or "smtp"
+pub fn mailer_from_env() -> Result<Arc<dyn Mailer>, String> {
+    let backend = env::var("MAILER").unwrap_or_else(|_| "log".to_string());
+    info!("Sending mails with the {} backend", backend);
+    match backend.as_str() {
+        "log" => Ok(Arc::new(LogMailer)),
+        "file" => {
+            let dir = env::var("MAIL_DIR").unwrap_or_else(|_| "./mail".to_string());
+            Ok(Arc::new(FileMailer::new(&dir)?))
+        }
+        "smtp" => Ok(Arc::new(SmtpMailer::from_env()?)),
+        _ => Err(format!("Unknown MAILER backend: {}", backend)),
+    }
+}
@@ -1,11 +1,12 @@
 use actix_cors::Cors;
-use actix_web::{middleware::Logger, web, App, HttpServer};
+use actix_web::{http::header::ETAG, middleware::Logger, web, App, HttpServer};
 use actix_web_httpauth::middleware::HttpAuthentication;
 use dotenvy::dotenv;
 use env_logger::Env;
-use log::info;
-use std::{env, sync::Arc};
+use log::{error, info};
+use std::{env, process, sync::Arc};
 use tokio::{
+    signal::unix::{signal, SignalKind},
     spawn,
     time::{self, Duration},
 };
@@ -14,9 +15,20 @@ use utoipa_actix_web::{scope, AppExt};
 use utoipa_swagger_ui::SwaggerUi;
 
 use backend_rspass::{
-    auth::{validator, JwtAuth},
-    db::initialize_database,
+    attachments::{upload_ttl, BlobStore},
+    auth::{current_timestamp, validator, JwtAuth},
+    config::env_param,
+    history::history_cleanup,
+    keys::{generate_key_file, pin_active_key, promote_key_file, reload_interval},
+    mailer::mailer_from_env,
+    migrations::{latest_version, MIGRATIONS},
+    notify::Notifier,
     routes::*,
+    store::{
+        database_url, initialize_database, normalize_email, open_database, open_migrated_database,
+        Database,
+    },
+    throttle::failure_window,
 };
 
 fn get_server_config() -> (String, String) {
@@ -25,11 +37,8 @@ fn get_server_config() -> (String, String) {
     (host, port)
 }
 
-async fn run_blacklist_cleanup(jwt_auth: Arc<JwtAuth>) {
-    let interval_seconds = env::var("CLEANUP_INTERVAL")
-        .ok()
-        .and_then(|val| val.parse().ok())
-        .unwrap_or(600);
+async fn run_blacklist_cleanup(jwt_auth: Arc<JwtAuth>, db: Database, blobs: Arc<BlobStore>) {
+    let interval_seconds = env_param("CLEANUP_INTERVAL", 600);
 
     info!("Cleanup Interval is set to: {} seconds", interval_seconds);
 
@@ -38,35 +47,271 @@ async fn run_blacklist_cleanup(jwt_auth: Arc<JwtAuth>) {
     loop {
         interval.tick().await;
         info!("Running blacklist cleanup...");
-        jwt_auth.cleanup_blacklist();
+        let auth = jwt_auth.clone();
+        let cleanup = db.run(move |db| {
+            auth.cleanup_blacklist(db);
+            Ok(())
+        });
+        if let Err(e) = cleanup.await {
+            error!("Blacklist cleanup failed: {}", e);
+        }
+        match db
+            .run(|db| db.refresh_tokens_cleanup(current_timestamp()))
+            .await
+        {
+            Ok(deleted) => info!("Removed {} expired refresh tokens", deleted),
+            Err(e) => error!("Refresh token cleanup failed: {}", e),
+        }
+        match db
+            .run(|db| db.webauthn_challenges_cleanup(current_timestamp()))
+            .await
+        {
+            Ok(deleted) => info!("Removed {} expired WebAuthn challenges", deleted),
+            Err(e) => error!("WebAuthn challenge cleanup failed: {}", e),
+        }
+        match db
+            .run(|db| db.login_failures_cleanup(current_timestamp(), failure_window()))
+            .await
+        {
+            Ok(deleted) => info!("Removed {} stale login failure counters", deleted),
+            Err(e) => error!("Login failure cleanup failed: {}", e),
+        }
+        match db.run(|db| history_cleanup(db, current_timestamp())).await {
+            Ok(deleted) => info!("Removed {} expired vault snapshots", deleted),
+            Err(e) => error!("Vault history cleanup failed: {}", e),
+        }
+        let created_before = current_timestamp().saturating_sub(upload_ttl());
+        match db
+            .run(move |db| db.attachments_cleanup(created_before))
+            .await
+        {
+            Ok(abandoned) => {
+                for (user_id, id) in &abandoned {
+                    blobs.remove(user_id, id).await;
+                }
+                info!("Removed {} abandoned attachment uploads", abandoned.len());
+            }
+            Err(e) => error!("Attachment cleanup failed: {}", e),
+        }
     }
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
+// Reloads the keyring periodically and on SIGHUP, so promoted keys need no restart
+async fn run_keyring_reload(jwt_auth: Arc<JwtAuth>) {
+    let interval_seconds = reload_interval();
+
+    info!(
+        "Keyring reload Interval is set to: {} seconds",
+        interval_seconds
+    );
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    let mut interval = time::interval(Duration::from_secs(interval_seconds));
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = hangup.recv() => info!("Received SIGHUP, reloading keyring..."),
+        }
+        if let Err(e) = jwt_auth.reload() {
+            error!("Keyring reload failed, keeping current keys: {}", e);
+        }
+    }
+}
+
+// Admin commands, run instead of the server
+fn run_command(command: &str, args: &[String]) -> std::io::Result<()> {
+    match command {
+        "rotate-key" => {
+            let Ok(dir) = env::var("JWT_KEYRING_DIR") else {
+                error!("rotate-key requires JWT_KEYRING_DIR to be set");
+                process::exit(1);
+            };
+            // The current key keeps signing until every server has seen the new one
+            match pin_active_key(&dir).and_then(|_| generate_key_file(&dir)) {
+                Ok(path) => {
+                    info!("Generated new key {}, it only verifies for now", path);
+                    info!(
+                        "Once all servers reloaded, in {} seconds or on SIGHUP, run promote-key",
+                        reload_interval()
+                    );
+                    Ok(())
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        "promote-key" => {
+            let Ok(dir) = env::var("JWT_KEYRING_DIR") else {
+                error!("promote-key requires JWT_KEYRING_DIR to be set");
+                process::exit(1);
+            };
+            let force = args.iter().any(|arg| arg == "--force");
+            let kid = args.iter().find(|arg| *arg != "--force");
+            match promote_key_file(&dir, kid.map(String::as_str), force) {
+                Ok(kid) => {
+                    info!("Promoted key {} to signing key", kid);
+                    info!("Running servers pick it up on their next reload or on SIGHUP");
+                    Ok(())
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        "migrate" => match args.first().map(String::as_str) {
+            None => {
+                if let Err(e) = initialize_database() {
+                    error!("Failed to migrate database: {}", e);
+                    process::exit(1);
+                }
+                Ok(())
+            }
+            Some("status") => {
+                let version = open_database(&database_url())
+                    .and_then(|db| db.schema_version().map_err(|e| e.to_string()))
+                    .unwrap_or_else(|e| {
+                        error!("Failed to open database: {}", e);
+                        process::exit(1);
+                    });
+                println!("{:<8} {:<8} DESCRIPTION", "VERSION", "STATUS");
+                for migration in MIGRATIONS {
+                    let status = if migration.version <= version {
+                        "applied"
+                    } else {
+                        "pending"
+                    };
+                    println!(
+                        "{:<8} {:<8} {}",
+                        migration.version, status, migration.description
+                    );
+                }
+                if version > latest_version() {
+                    println!("Database is at version {}, newer than this binary", version);
+                }
+                Ok(())
+            }
+            Some(other) => {
+                error!("Unknown argument: {}. Usage: migrate [status]", other);
+                process::exit(1);
+            }
+        },
+        "lockouts" => {
+            let db = open_migrated_database().unwrap_or_else(|e| {
+                error!("Failed to open database: {}", e);
+                process::exit(1);
+            });
+            let failures = db.login_failures_list().unwrap_or_else(|e| {
+                error!("Failed to list login failures: {}", e);
+                process::exit(1);
+            });
+            let now = current_timestamp();
+            println!(
+                "{:<6} {:<40} {:>8} {:>14}",
+                "KIND", "SUBJECT", "FAILURES", "LOCKED (SECS)"
+            );
+            for failure in failures {
+                println!(
+                    "{:<6} {:<40} {:>8} {:>14}",
+                    failure.kind,
+                    failure.subject,
+                    failure.failures,
+                    failure.locked_until.saturating_sub(now)
+                );
+            }
+            Ok(())
+        }
+        "unlock" => {
+            let (Some(kind), Some(subject)) = (args.first(), args.get(1)) else {
+                error!("Usage: unlock <email|ip> <subject>");
+                process::exit(1);
+            };
+            let db = open_migrated_database().unwrap_or_else(|e| {
+                error!("Failed to open database: {}", e);
+                process::exit(1);
+            });
+            // Email subjects are recorded normalized, like the stored addresses
+            let subject = match kind.as_str() {
+                "email" => normalize_email(subject),
+                _ => subject.clone(),
+            };
+            match db.login_failures_reset(kind, &subject) {
+                Ok(true) => info!("Unlocked logins of {} {}", kind, subject),
+                Ok(false) => info!("No failed logins recorded for {} {}", kind, subject),
+                Err(e) => {
+                    error!("Failed to unlock: {}", e);
+                    process::exit(1);
+                }
+            }
+            Ok(())
+        }
+        _ => {
+            error!(
+                "Unknown command: {}. Available commands: rotate-key, promote-key, migrate, lockouts, unlock",
+                command
+            );
+            process::exit(1);
+        }
+    }
+}
+
+// Stores block, so the database is opened and migrated before the runtime starts
+fn main() -> std::io::Result<()> {
     dotenv().ok();
     let log_level = env::var("LOG_LEVEL").unwrap_or_else(|_| "info".to_string());
     env_logger::Builder::from_env(Env::default().default_filter_or(log_level)).init();
 
-    if let Err(e) = initialize_database() {
-        panic!("Failed to initialize test database: {}", e);
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some((command, args)) = args.split_first() {
+        return run_command(command, args);
     }
 
+    let db =
+        initialize_database().unwrap_or_else(|e| panic!("Failed to initialize database: {}", e));
+
+    actix_web::rt::System::new().block_on(serve(db))
+}
+
+async fn serve(db: Database) -> std::io::Result<()> {
     let (host, port) = get_server_config();
     info!("Starting server at {}:{}", host, port);
 
     // Create JWT auth instance to share across workers
     let jwt_auth = Arc::new(JwtAuth::new());
 
+    let mailer = mailer_from_env().unwrap_or_else(|e| panic!("{}", e));
+
+    // Notification streams are held by the workers, changes are published across them
+    let notifier = Arc::new(Notifier::new());
+
+    let blobs = Arc::new(BlobStore::from_env().unwrap_or_else(|e| panic!("{}", e)));
+
     // Spawn cleanup task
     let cleanup_auth = jwt_auth.clone();
-    spawn(async move { run_blacklist_cleanup(cleanup_auth).await });
+    let cleanup_db = db.clone();
+    let cleanup_blobs = blobs.clone();
+    spawn(async move { run_blacklist_cleanup(cleanup_auth, cleanup_db, cleanup_blobs).await });
+
+    if env::var("JWT_KEYRING_DIR").is_ok() {
+        let reload_auth = jwt_auth.clone();
+        spawn(async move { run_keyring_reload(reload_auth).await });
+    }
 
     HttpServer::new(move || {
         let cors = Cors::default()
             .allow_any_origin()
             .allow_any_method()
             .allow_any_header()
+            .expose_headers([ETAG])
             .max_age(3600);
 
         let auth = HttpAuthentication::with_fn(validator);
@@ -75,23 +320,87 @@ async fn main() -> std::io::Result<()> {
             .wrap(Logger::default())
             .wrap(cors)
             .app_data(web::Data::from(jwt_auth.clone()))
+            .app_data(web::Data::from(mailer.clone()))
+            .app_data(web::Data::from(notifier.clone()))
+            .app_data(web::Data::from(blobs.clone()))
+            .app_data(web::Data::new(db.clone()))
             .into_utoipa_app()
             .service(route_health)
+            .service(route_jwks)
             .service(route_email)
             .service(route_login)
+            .service(route_login_2fa)
+            .service(route_webauthn_login_begin)
+            .service(route_webauthn_login)
             .service(route_register)
+            .service(route_verify_email)
+            .service(route_refresh)
+            .service(route_notifications)
             .service(
                 scope("/api/v1/account")
                     .wrap(auth.clone())
                     .route("/changepwd", web::post().to(route_changepwd))
+                    .route("/verify-email", web::post().to(route_verify_email_resend))
+                    .route("/change-email", web::post().to(route_change_email))
+                    .route(
+                        "/change-email/confirm",
+                        web::post().to(route_change_email_confirm),
+                    )
                     .route("/logout", web::get().to(route_logout))
-                    .route("/delete", web::get().to(route_delete)),
+                    .route("/logout-all", web::get().to(route_logout_all))
+                    .route("/delete", web::get().to(route_delete))
+                    .route("/sessions", web::get().to(route_sessions))
+                    .route("/sessions", web::delete().to(route_sessions_revoke_others))
+                    .route("/sessions/{id}", web::delete().to(route_session_revoke))
+                    .route("/2fa/totp", web::post().to(route_totp_setup))
+                    .route("/2fa/totp/confirm", web::post().to(route_totp_confirm))
+                    .route("/2fa/totp/disable", web::post().to(route_totp_disable))
+                    .route("/2fa/webauthn", web::get().to(route_webauthn_credentials))
+                    .route(
+                        "/2fa/webauthn/register/begin",
+                        web::post().to(route_webauthn_register_begin),
+                    )
+                    .route(
+                        "/2fa/webauthn/register",
+                        web::post().to(route_webauthn_register),
+                    )
+                    .route(
+                        "/2fa/webauthn/{id}",
+                        web::delete().to(route_webauthn_credential_delete),
+                    ),
             )
             .service(
                 scope("/api/v1/sync")
                     .wrap(auth)
                     .route("/fetch", web::get().to(route_fetch))
-                    .route("/update", web::post().to(route_update)),
+                    .route("/update", web::post().to(route_update))
+                    .route(
+                        "/notifications/ticket",
+                        web::post().to(route_notifications_ticket),
+                    )
+                    .route("/changes", web::get().to(route_changes))
+                    .route("/changes", web::post().to(route_changes_apply))
+                    .route("/history", web::get().to(route_history))
+                    .route("/history/{revision}", web::get().to(route_history_get))
+                    .route(
+                        "/history/{revision}/restore",
+                        web::post().to(route_history_restore),
+                    )
+                    .route("/attachments", web::post().to(route_attachment_create))
+                    .route("/attachments", web::get().to(route_attachments))
+                    .route("/attachments/{id}", web::get().to(route_attachment_get))
+                    .route(
+                        "/attachments/{id}",
+                        web::delete().to(route_attachment_delete),
+                    )
+                    .route(
+                        "/attachments/{id}/content",
+                        web::put().to(route_attachment_upload),
+                    )
+                    .route(
+                        "/attachments/{id}/content",
+                        web::get().to(route_attachment_download),
+                    ),
             )
             .split_for_parts();
 
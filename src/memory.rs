@@ -0,0 +1,904 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard},
+};
+use uuid::Uuid;
+
+use crate::migrations::latest_version;
+use crate::models::KdfParams;
+use crate::store::{
+    normalize_email, Attachment, AttachmentCreate, ItemChange, ItemsUpdate, LoginFailures,
+    RefreshTokenStatus, Result, Session, StoreError, Totp, Vault, VaultItem, VaultSnapshot,
+    VaultStore, VaultUpdate, WebauthnCeremony, WebauthnCredential, SESSION_TOUCH_INTERVAL,
+};
+
+struct User {
+    id: String,
+    email: String,
+    password_hash: String,
+    encrypted_data: String,
+    revision: u64,
+    items_revision: u64,
+    security_stamp: String,
+    kdf: Option<KdfParams>,
+    email_verified: bool,
+}
+
+struct RefreshToken {
+    user_id: String,
+    family_id: String,
+    expires_at: usize,
+    used: bool,
+}
+
+#[derive(Default)]
+struct State {
+    users: Vec<User>,
+    refresh_tokens: HashMap<String, RefreshToken>,
+    revoked_tokens: HashMap<String, usize>,
+    sessions: Vec<(String, Session)>, // with the user id, in order of creation
+    totp: HashMap<String, Totp>,
+    recovery_codes: HashMap<String, String>, // code hash to user id
+    webauthn_credentials: Vec<(String, WebauthnCredential)>,
+    webauthn_challenges: HashMap<String, WebauthnCeremony>,
+    login_failures: HashMap<(String, String), LoginFailures>,
+    vault_history: Vec<(String, VaultSnapshot, String)>, // with the user id and the data
+    vault_items: HashMap<(String, String), VaultItem>,   // by user id and item id
+    attachments: Vec<(String, Attachment)>,              // with the user id
+}
+
+impl State {
+    fn user(&self, user_id: &str) -> Option<&User> {
+        self.users.iter().find(|user| user.id == user_id)
+    }
+
+    fn user_mut(&mut self, user_id: &str) -> Result<&mut User> {
+        self.users
+            .iter_mut()
+            .find(|user| user.id == user_id)
+            .ok_or_else(|| StoreError::Backend(format!("Unknown account {}", user_id)))
+    }
+
+    // Same as the SQLite store: a new stamp, no refresh tokens and only the kept session
+    fn rotate_security_stamp(&mut self, user_id: &str, keep_nonce: Option<&str>) {
+        if let Ok(user) = self.user_mut(user_id) {
+            user.security_stamp = Uuid::new_v4().to_string();
+        }
+        self.refresh_tokens
+            .retain(|_, token| token.user_id != user_id);
+        self.sessions.retain(|(owner, session)| {
+            owner != user_id || Some(session.nonce.as_str()) == keep_nonce
+        });
+    }
+
+    fn delete_totp(&mut self, user_id: &str) {
+        self.totp.remove(user_id);
+        self.recovery_codes.retain(|_, owner| owner != user_id);
+    }
+}
+
+// Keeps everything in process memory, for tests and embedded use. Nothing survives a
+// restart, and every call holds one lock, so calls are atomic like transactions
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        // A panic while holding the lock cannot leave a call half applied, every call
+        // validates before it mutates
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl VaultStore for MemoryStore {
+    // Every store starts out empty at the latest schema
+    fn migrate(&self) -> std::result::Result<Vec<u32>, String> {
+        Ok(Vec::new())
+    }
+
+    fn schema_version(&self) -> Result<u32> {
+        Ok(latest_version())
+    }
+
+    fn user_id(&self, email: &str) -> Result<Option<String>> {
+        let email = normalize_email(email);
+        Ok(self
+            .state()
+            .users
+            .iter()
+            .find(|user| user.email == email)
+            .map(|user| user.id.clone()))
+    }
+
+    fn user_email(&self, user_id: &str) -> Result<Option<String>> {
+        Ok(self.state().user(user_id).map(|user| user.email.clone()))
+    }
+
+    fn user_password_hash(&self, user_id: &str) -> Result<Option<String>> {
+        Ok(self
+            .state()
+            .user(user_id)
+            .map(|user| user.password_hash.clone()))
+    }
+
+    fn user_rehash_password(&self, user_id: &str, password_hash: &str) -> Result<()> {
+        if let Ok(user) = self.state().user_mut(user_id) {
+            user.password_hash = password_hash.to_string();
+        }
+        Ok(())
+    }
+
+    fn user_register(
+        &self,
+        email: &str,
+        password_hash: &str,
+        kdf: Option<&KdfParams>,
+    ) -> Result<String> {
+        let mut state = self.state();
+        let email = normalize_email(email);
+        if state.users.iter().any(|user| user.email == email) {
+            return Err(StoreError::Backend(format!(
+                "An account with the email {} exists already",
+                email
+            )));
+        }
+        let user_id = Uuid::new_v4().to_string();
+        state.users.push(User {
+            id: user_id.clone(),
+            email,
+            password_hash: password_hash.to_string(),
+            encrypted_data: String::new(),
+            revision: 0,
+            items_revision: 0,
+            security_stamp: Uuid::new_v4().to_string(),
+            kdf: kdf.cloned(),
+            email_verified: false,
+        });
+        Ok(user_id)
+    }
+
+    fn user_kdf(&self, user_id: &str) -> Result<Option<KdfParams>> {
+        Ok(self
+            .state()
+            .user(user_id)
+            .map(|user| user.kdf.clone().unwrap_or_default()))
+    }
+
+    fn user_email_verified(&self, user_id: &str) -> Result<Option<bool>> {
+        Ok(self.state().user(user_id).map(|user| user.email_verified))
+    }
+
+    fn user_verify_email(&self, user_id: &str) -> Result<bool> {
+        let mut state = self.state();
+        let Ok(user) = state.user_mut(user_id) else {
+            return Ok(false);
+        };
+        user.email_verified = true;
+        Ok(true)
+    }
+
+    fn user_security_stamp(&self, user_id: &str) -> Result<Option<String>> {
+        Ok(self
+            .state()
+            .user(user_id)
+            .map(|user| user.security_stamp.clone()))
+    }
+
+    fn user_changepwd(
+        &self,
+        user_id: &str,
+        password_hash: &str,
+        kdf: Option<&KdfParams>,
+        current_nonce: &str,
+    ) -> Result<()> {
+        let mut state = self.state();
+        if let Ok(user) = state.user_mut(user_id) {
+            user.password_hash = password_hash.to_string();
+            if let Some(kdf) = kdf {
+                user.kdf = Some(kdf.clone());
+            }
+        }
+        state.rotate_security_stamp(user_id, Some(current_nonce));
+        Ok(())
+    }
+
+    fn user_change_email(
+        &self,
+        user_id: &str,
+        new_email: &str,
+        password_hash: Option<&str>,
+        kdf: Option<&KdfParams>,
+        current_nonce: &str,
+    ) -> Result<bool> {
+        let mut state = self.state();
+        let new_email = normalize_email(new_email);
+        if state.users.iter().any(|user| user.email == new_email) {
+            return Ok(false);
+        }
+        let user = state.user_mut(user_id)?;
+        let old_email = std::mem::replace(&mut user.email, new_email);
+        user.email_verified = true;
+        if let Some(password_hash) = password_hash {
+            user.password_hash = password_hash.to_string();
+        }
+        if let Some(kdf) = kdf {
+            user.kdf = Some(kdf.clone());
+        }
+        state.rotate_security_stamp(user_id, Some(current_nonce));
+        state
+            .webauthn_challenges
+            .retain(|_, ceremony| ceremony.user_id != user_id);
+        state
+            .login_failures
+            .remove(&("email".to_string(), old_email));
+        Ok(true)
+    }
+
+    fn user_logout_all(&self, user_id: &str) -> Result<()> {
+        self.state().rotate_security_stamp(user_id, None);
+        Ok(())
+    }
+
+    fn user_delete(&self, user_id: &str) -> Result<()> {
+        let mut state = self.state();
+        state.rotate_security_stamp(user_id, None);
+        state.delete_totp(user_id);
+        state
+            .webauthn_credentials
+            .retain(|(owner, _)| owner != user_id);
+        state
+            .webauthn_challenges
+            .retain(|_, ceremony| ceremony.user_id != user_id);
+        state.vault_history.retain(|(owner, _, _)| owner != user_id);
+        state.vault_items.retain(|(owner, _), _| owner != user_id);
+        state.attachments.retain(|(owner, _)| owner != user_id);
+        state.users.retain(|user| user.id != user_id);
+        Ok(())
+    }
+
+    fn data_get(&self, user_id: &str) -> Result<Vault> {
+        let mut state = self.state();
+        let user = state.user_mut(user_id)?;
+        Ok(Vault {
+            encrypted_data: user.encrypted_data.clone(),
+            revision: user.revision,
+        })
+    }
+
+    fn data_update(
+        &self,
+        user_id: &str,
+        encrypted_data: &str,
+        base_revision: Option<u64>,
+        session_id: &str,
+        now: usize,
+    ) -> Result<VaultUpdate> {
+        let mut state = self.state();
+        let user = state.user_mut(user_id)?;
+        if base_revision.is_some_and(|base| base != user.revision) {
+            return Ok(VaultUpdate::Conflict {
+                revision: user.revision,
+            });
+        }
+        user.encrypted_data = encrypted_data.to_string();
+        user.revision += 1;
+        let revision = user.revision;
+        let device_name = state
+            .sessions
+            .iter()
+            .find(|(_, session)| session.nonce == session_id)
+            .and_then(|(_, session)| session.device_name.clone());
+        state.vault_history.push((
+            user_id.to_string(),
+            VaultSnapshot {
+                revision,
+                size: encrypted_data.len(),
+                created_at: now,
+                session_id: Some(session_id.to_string()),
+                device_name,
+            },
+            encrypted_data.to_string(),
+        ));
+        Ok(VaultUpdate::Updated { revision })
+    }
+
+    fn vault_history_list(&self, user_id: &str) -> Result<Vec<VaultSnapshot>> {
+        Ok(self
+            .state()
+            .vault_history
+            .iter()
+            .rev()
+            .filter(|(owner, _, _)| owner == user_id)
+            .map(|(_, snapshot, _)| snapshot.clone())
+            .collect())
+    }
+
+    fn vault_history_get(&self, user_id: &str, revision: u64) -> Result<Option<Vault>> {
+        Ok(self
+            .state()
+            .vault_history
+            .iter()
+            .find(|(owner, snapshot, _)| owner == user_id && snapshot.revision == revision)
+            .map(|(_, snapshot, encrypted_data)| Vault {
+                encrypted_data: encrypted_data.clone(),
+                revision: snapshot.revision,
+            }))
+    }
+
+    fn vault_history_prune(
+        &self,
+        user_id: &str,
+        keep: usize,
+        created_before: usize,
+    ) -> Result<usize> {
+        let mut state = self.state();
+        let before = state.vault_history.len();
+        // Snapshots are appended in order of their revision
+        let mut newer = state
+            .vault_history
+            .iter()
+            .filter(|(owner, _, _)| owner == user_id)
+            .count();
+        state.vault_history.retain(|(owner, snapshot, _)| {
+            if owner != user_id {
+                return true;
+            }
+            newer -= 1;
+            newer < keep && snapshot.created_at >= created_before
+        });
+        Ok(before - state.vault_history.len())
+    }
+
+    fn vault_history_cleanup(&self, created_before: usize) -> Result<usize> {
+        let mut state = self.state();
+        let before = state.vault_history.len();
+        state
+            .vault_history
+            .retain(|(_, snapshot, _)| snapshot.created_at >= created_before);
+        Ok(before - state.vault_history.len())
+    }
+
+    fn items_changed(&self, user_id: &str, since: u64) -> Result<(u64, Vec<VaultItem>)> {
+        let mut state = self.state();
+        let revision = state.user_mut(user_id)?.items_revision;
+        let mut items: Vec<VaultItem> = state
+            .vault_items
+            .iter()
+            .filter(|((owner, _), item)| owner == user_id && item.revision > since)
+            .map(|(_, item)| item.clone())
+            .collect();
+        items.sort_by(|a, b| (a.revision, &a.id).cmp(&(b.revision, &b.id)));
+        Ok((revision, items))
+    }
+
+    fn items_apply(&self, user_id: &str, changes: &[ItemChange]) -> Result<ItemsUpdate> {
+        let mut state = self.state();
+        let current = state.user_mut(user_id)?.items_revision;
+        let ids: Vec<String> = changes
+            .iter()
+            .filter(|change| {
+                let key = (user_id.to_string(), change.id.clone());
+                let revision = state.vault_items.get(&key).map_or(0, |item| item.revision);
+                revision != change.base_revision
+            })
+            .map(|change| change.id.clone())
+            .collect();
+        if !ids.is_empty() {
+            return Ok(ItemsUpdate::Conflict {
+                revision: current,
+                ids,
+            });
+        }
+        let user = state.user_mut(user_id)?;
+        user.items_revision += 1;
+        let revision = user.items_revision;
+        let mut attachments = Vec::new();
+        for change in changes {
+            state.vault_items.insert(
+                (user_id.to_string(), change.id.clone()),
+                VaultItem {
+                    id: change.id.clone(),
+                    revision,
+                    encrypted_data: change.encrypted_data.clone(),
+                },
+            );
+            if change.encrypted_data.is_none() {
+                state.attachments.retain(|(owner, attachment)| {
+                    let removed = owner == user_id && attachment.item_id == change.id;
+                    if removed {
+                        attachments.push(attachment.id.clone());
+                    }
+                    !removed
+                });
+            }
+        }
+        Ok(ItemsUpdate::Applied {
+            revision,
+            attachments,
+        })
+    }
+
+    fn attachment_create(
+        &self,
+        user_id: &str,
+        attachment: &Attachment,
+        quota: u64,
+    ) -> Result<AttachmentCreate> {
+        let mut state = self.state();
+        let key = (user_id.to_string(), attachment.item_id.clone());
+        if state
+            .vault_items
+            .get(&key)
+            .is_none_or(|item| item.encrypted_data.is_none())
+        {
+            return Ok(AttachmentCreate::ItemNotFound);
+        }
+        let usage: u64 = state
+            .attachments
+            .iter()
+            .filter(|(owner, _)| owner == user_id)
+            .map(|(_, attachment)| attachment.size)
+            .sum();
+        if usage + attachment.size > quota {
+            return Ok(AttachmentCreate::QuotaExceeded { usage });
+        }
+        state
+            .attachments
+            .push((user_id.to_string(), attachment.clone()));
+        Ok(AttachmentCreate::Created)
+    }
+
+    fn attachment_get(&self, user_id: &str, id: &str) -> Result<Option<Attachment>> {
+        Ok(self
+            .state()
+            .attachments
+            .iter()
+            .find(|(owner, attachment)| owner == user_id && attachment.id == id)
+            .map(|(_, attachment)| attachment.clone()))
+    }
+
+    fn attachments_list(&self, user_id: &str) -> Result<Vec<Attachment>> {
+        Ok(self
+            .state()
+            .attachments
+            .iter()
+            .filter(|(owner, _)| owner == user_id)
+            .map(|(_, attachment)| attachment.clone())
+            .collect())
+    }
+
+    fn attachment_received(&self, user_id: &str, id: &str, received: u64) -> Result<()> {
+        if let Some((_, attachment)) = self
+            .state()
+            .attachments
+            .iter_mut()
+            .find(|(owner, attachment)| owner == user_id && attachment.id == id)
+        {
+            attachment.received = received;
+        }
+        Ok(())
+    }
+
+    fn attachment_delete(&self, user_id: &str, id: &str) -> Result<bool> {
+        let mut state = self.state();
+        let before = state.attachments.len();
+        state
+            .attachments
+            .retain(|(owner, attachment)| owner != user_id || attachment.id != id);
+        Ok(state.attachments.len() < before)
+    }
+
+    fn attachments_cleanup(&self, created_before: usize) -> Result<Vec<(String, String)>> {
+        let mut state = self.state();
+        let mut removed = Vec::new();
+        state.attachments.retain(|(owner, attachment)| {
+            let stale =
+                attachment.received < attachment.size && attachment.created_at < created_before;
+            if stale {
+                removed.push((owner.clone(), attachment.id.clone()));
+            }
+            !stale
+        });
+        Ok(removed)
+    }
+
+    fn refresh_token_store(
+        &self,
+        token_hash: &str,
+        user_id: &str,
+        family_id: &str,
+        expires_at: usize,
+    ) -> Result<()> {
+        let mut state = self.state();
+        if state.refresh_tokens.contains_key(token_hash) {
+            return Err(StoreError::Backend("Duplicate refresh token".to_string()));
+        }
+        state.refresh_tokens.insert(
+            token_hash.to_string(),
+            RefreshToken {
+                user_id: user_id.to_string(),
+                family_id: family_id.to_string(),
+                expires_at,
+                used: false,
+            },
+        );
+        Ok(())
+    }
+
+    fn refresh_token_consume(&self, token_hash: &str, now: usize) -> Result<RefreshTokenStatus> {
+        let mut state = self.state();
+        let Some(token) = state.refresh_tokens.get_mut(token_hash) else {
+            return Ok(RefreshTokenStatus::Unknown);
+        };
+        let user_id = token.user_id.clone();
+        let family_id = token.family_id.clone();
+        if token.used {
+            state
+                .refresh_tokens
+                .retain(|_, token| token.family_id != family_id);
+            return Ok(RefreshTokenStatus::Reused { user_id, family_id });
+        }
+        if token.expires_at <= now {
+            return Ok(RefreshTokenStatus::Expired);
+        }
+        token.used = true;
+        Ok(RefreshTokenStatus::Valid { user_id, family_id })
+    }
+
+    fn refresh_tokens_cleanup(&self, now: usize) -> Result<usize> {
+        let mut state = self.state();
+        let before = state.refresh_tokens.len();
+        state
+            .refresh_tokens
+            .retain(|_, token| token.expires_at > now);
+        Ok(before - state.refresh_tokens.len())
+    }
+
+    fn token_revoke(&self, nonce: &str, expires_at: usize) -> Result<()> {
+        let mut state = self.state();
+        let stored = state
+            .revoked_tokens
+            .entry(nonce.to_string())
+            .or_insert(expires_at);
+        *stored = (*stored).max(expires_at);
+        Ok(())
+    }
+
+    fn token_is_revoked(&self, nonce: &str) -> Result<bool> {
+        Ok(self.state().revoked_tokens.contains_key(nonce))
+    }
+
+    fn revoked_tokens_cleanup(&self, now: usize) -> Result<usize> {
+        let mut state = self.state();
+        let before = state.revoked_tokens.len();
+        state
+            .revoked_tokens
+            .retain(|_, &mut expires_at| expires_at > now);
+        Ok(before - state.revoked_tokens.len())
+    }
+
+    fn session_create(&self, user_id: &str, session: &Session) -> Result<()> {
+        let mut state = self.state();
+        if state
+            .sessions
+            .iter()
+            .any(|(_, existing)| existing.nonce == session.nonce)
+        {
+            return Err(StoreError::Backend("Duplicate session".to_string()));
+        }
+        state.sessions.push((user_id.to_string(), session.clone()));
+        Ok(())
+    }
+
+    fn session_touch(&self, nonce: &str, now: usize) -> Result<bool> {
+        let mut state = self.state();
+        let Some((_, session)) = state
+            .sessions
+            .iter_mut()
+            .find(|(_, session)| session.nonce == nonce)
+        else {
+            return Ok(false);
+        };
+        if session.last_seen < now.saturating_sub(SESSION_TOUCH_INTERVAL) {
+            session.last_seen = now;
+        }
+        Ok(true)
+    }
+
+    fn sessions_list(&self, user_id: &str) -> Result<Vec<Session>> {
+        let mut sessions: Vec<Session> = self
+            .state()
+            .sessions
+            .iter()
+            .filter(|(owner, _)| owner == user_id)
+            .map(|(_, session)| session.clone())
+            .collect();
+        sessions.sort_by_key(|session| session.created_at);
+        Ok(sessions)
+    }
+
+    fn session_delete(&self, user_id: &str, nonce: &str) -> Result<bool> {
+        let mut state = self.state();
+        let before = state.sessions.len();
+        state
+            .sessions
+            .retain(|(owner, session)| owner != user_id || session.nonce != nonce);
+        state
+            .refresh_tokens
+            .retain(|_, token| token.user_id != user_id || token.family_id != nonce);
+        Ok(state.sessions.len() < before)
+    }
+
+    fn sessions_delete_others(&self, user_id: &str, keep_nonce: &str) -> Result<Vec<String>> {
+        let mut state = self.state();
+        let (others, kept) = std::mem::take(&mut state.sessions)
+            .into_iter()
+            .partition(|(owner, session)| owner == user_id && session.nonce != keep_nonce);
+        state.sessions = kept;
+        state
+            .refresh_tokens
+            .retain(|_, token| token.user_id != user_id || token.family_id == keep_nonce);
+        Ok(others
+            .into_iter()
+            .map(|(_, session): (String, Session)| session.nonce)
+            .collect())
+    }
+
+    fn totp_begin(&self, user_id: &str, secret: &str) -> Result<bool> {
+        let mut state = self.state();
+        if state.totp.get(user_id).is_some_and(|totp| totp.enabled) {
+            return Ok(false);
+        }
+        state.totp.insert(
+            user_id.to_string(),
+            Totp {
+                secret: secret.to_string(),
+                enabled: false,
+                last_step: 0,
+            },
+        );
+        Ok(true)
+    }
+
+    fn totp_get(&self, user_id: &str) -> Result<Option<Totp>> {
+        Ok(self.state().totp.get(user_id).cloned())
+    }
+
+    fn totp_use_step(&self, user_id: &str, step: u64) -> Result<bool> {
+        let mut state = self.state();
+        match state.totp.get_mut(user_id) {
+            Some(totp) if totp.last_step < step => {
+                totp.last_step = step;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn totp_enable(
+        &self,
+        user_id: &str,
+        step: u64,
+        recovery_code_hashes: &[String],
+    ) -> Result<bool> {
+        let mut state = self.state();
+        match state.totp.get_mut(user_id) {
+            Some(totp) if !totp.enabled && totp.last_step < step => {
+                totp.enabled = true;
+                totp.last_step = step;
+            }
+            _ => return Ok(false),
+        }
+        state.recovery_codes.retain(|_, owner| owner != user_id);
+        for code_hash in recovery_code_hashes {
+            state
+                .recovery_codes
+                .insert(code_hash.clone(), user_id.to_string());
+        }
+        Ok(true)
+    }
+
+    fn totp_disable(&self, user_id: &str) -> Result<()> {
+        self.state().delete_totp(user_id);
+        Ok(())
+    }
+
+    fn recovery_code_consume(&self, user_id: &str, code_hash: &str) -> Result<bool> {
+        let mut state = self.state();
+        if state.recovery_codes.get(code_hash).map(String::as_str) != Some(user_id) {
+            return Ok(false);
+        }
+        state.recovery_codes.remove(code_hash);
+        Ok(true)
+    }
+
+    fn webauthn_credential_add(
+        &self,
+        user_id: &str,
+        credential: &WebauthnCredential,
+    ) -> Result<bool> {
+        let mut state = self.state();
+        if state
+            .webauthn_credentials
+            .iter()
+            .any(|(_, existing)| existing.credential_id == credential.credential_id)
+        {
+            return Ok(false);
+        }
+        let mut credential = credential.clone();
+        credential.last_used = None;
+        state
+            .webauthn_credentials
+            .push((user_id.to_string(), credential));
+        Ok(true)
+    }
+
+    fn webauthn_credentials_list(&self, user_id: &str) -> Result<Vec<WebauthnCredential>> {
+        let mut credentials: Vec<WebauthnCredential> = self
+            .state()
+            .webauthn_credentials
+            .iter()
+            .filter(|(owner, _)| owner == user_id)
+            .map(|(_, credential)| credential.clone())
+            .collect();
+        credentials.sort_by_key(|credential| credential.created_at);
+        Ok(credentials)
+    }
+
+    fn webauthn_credential_get(
+        &self,
+        user_id: &str,
+        credential_id: &str,
+    ) -> Result<Option<WebauthnCredential>> {
+        Ok(self
+            .state()
+            .webauthn_credentials
+            .iter()
+            .find(|(owner, credential)| {
+                owner == user_id && credential.credential_id == credential_id
+            })
+            .map(|(_, credential)| credential.clone()))
+    }
+
+    fn webauthn_credential_used(
+        &self,
+        credential_id: &str,
+        sign_count: u32,
+        now: usize,
+    ) -> Result<bool> {
+        let mut state = self.state();
+        let credential = state
+            .webauthn_credentials
+            .iter_mut()
+            .map(|(_, credential)| credential)
+            .find(|credential| credential.credential_id == credential_id);
+        match credential {
+            Some(credential) if credential.sign_count < sign_count || sign_count == 0 => {
+                credential.sign_count = sign_count;
+                credential.last_used = Some(now);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn webauthn_credential_delete(&self, user_id: &str, credential_id: &str) -> Result<bool> {
+        let mut state = self.state();
+        let before = state.webauthn_credentials.len();
+        state.webauthn_credentials.retain(|(owner, credential)| {
+            owner != user_id || credential.credential_id != credential_id
+        });
+        Ok(state.webauthn_credentials.len() < before)
+    }
+
+    fn webauthn_challenge_store(&self, challenge: &str, ceremony: &WebauthnCeremony) -> Result<()> {
+        let mut state = self.state();
+        if state.webauthn_challenges.contains_key(challenge) {
+            return Err(StoreError::Backend("Duplicate challenge".to_string()));
+        }
+        state
+            .webauthn_challenges
+            .insert(challenge.to_string(), ceremony.clone());
+        Ok(())
+    }
+
+    fn webauthn_challenge_consume(
+        &self,
+        challenge: &str,
+        now: usize,
+    ) -> Result<Option<WebauthnCeremony>> {
+        Ok(self
+            .state()
+            .webauthn_challenges
+            .remove(challenge)
+            .filter(|ceremony| ceremony.expires_at > now))
+    }
+
+    fn webauthn_challenges_cleanup(&self, now: usize) -> Result<usize> {
+        let mut state = self.state();
+        let before = state.webauthn_challenges.len();
+        state
+            .webauthn_challenges
+            .retain(|_, ceremony| ceremony.expires_at > now);
+        Ok(before - state.webauthn_challenges.len())
+    }
+
+    fn login_failures_get(&self, kind: &str, subject: &str) -> Result<Option<LoginFailures>> {
+        Ok(self
+            .state()
+            .login_failures
+            .get(&(kind.to_string(), subject.to_string()))
+            .cloned())
+    }
+
+    fn login_failure_record(
+        &self,
+        kind: &str,
+        subject: &str,
+        now: usize,
+        window: usize,
+    ) -> Result<u32> {
+        let mut state = self.state();
+        let entry = state
+            .login_failures
+            .entry((kind.to_string(), subject.to_string()))
+            .or_insert_with(|| LoginFailures {
+                kind: kind.to_string(),
+                subject: subject.to_string(),
+                failures: 0,
+                last_failure: now,
+                locked_until: 0,
+            });
+        if entry.last_failure + window < now {
+            entry.failures = 0;
+        }
+        entry.failures += 1;
+        entry.last_failure = now;
+        Ok(entry.failures)
+    }
+
+    fn login_lock(&self, kind: &str, subject: &str, locked_until: usize) -> Result<()> {
+        let mut state = self.state();
+        if let Some(failures) = state
+            .login_failures
+            .get_mut(&(kind.to_string(), subject.to_string()))
+        {
+            failures.locked_until = locked_until;
+        }
+        Ok(())
+    }
+
+    fn login_failures_reset(&self, kind: &str, subject: &str) -> Result<bool> {
+        Ok(self
+            .state()
+            .login_failures
+            .remove(&(kind.to_string(), subject.to_string()))
+            .is_some())
+    }
+
+    fn login_failures_list(&self) -> Result<Vec<LoginFailures>> {
+        let mut failures: Vec<LoginFailures> =
+            self.state().login_failures.values().cloned().collect();
+        failures.sort_by(|a, b| {
+            b.locked_until
+                .cmp(&a.locked_until)
+                .then(b.failures.cmp(&a.failures))
+        });
+        Ok(failures)
+    }
+
+    fn login_failures_cleanup(&self, now: usize, window: usize) -> Result<usize> {
+        let mut state = self.state();
+        let before = state.login_failures.len();
+        state.login_failures.retain(|_, failures| {
+            failures.last_failure + window >= now || failures.locked_until > now
+        });
+        Ok(before - state.login_failures.len())
+    }
+}
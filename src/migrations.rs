@@ -0,0 +1,507 @@
+use log::info;
+use rusqlite::{params, Connection, Result, Transaction, TransactionBehavior};
+use uuid::Uuid;
+
+use crate::store::normalize_email;
+
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    apply: fn(&Transaction) -> Result<()>,
+    // The same change in the dialect of PostgreSQL, see pg.rs
+    #[cfg_attr(not(feature = "postgres"), allow(dead_code))]
+    pub(crate) postgres: &'static str,
+}
+
+// Applied in order of their version, which SQLite keeps in PRAGMA user_version. Released
+// migrations must never change, schema changes are appended as a new version
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Baseline schema, upgrades databases from before versioned migrations",
+        apply: baseline,
+        postgres: POSTGRES_BASELINE,
+    },
+    Migration {
+        version: 2,
+        description: "Revision of the vault, for updates conditional on it",
+        apply: add_vault_revision,
+        postgres: "ALTER TABLE users ADD COLUMN revision BIGINT NOT NULL DEFAULT 0;",
+    },
+    Migration {
+        version: 3,
+        description: "Snapshots of earlier vault revisions",
+        apply: add_vault_history,
+        postgres: POSTGRES_VAULT_HISTORY,
+    },
+    Migration {
+        version: 4,
+        description: "Separately encrypted vault items, for syncing only what changed",
+        apply: add_vault_items,
+        postgres: POSTGRES_VAULT_ITEMS,
+    },
+    Migration {
+        version: 5,
+        description: "Encrypted file attachments of vault items",
+        apply: add_attachments,
+        postgres: POSTGRES_ATTACHMENTS,
+    },
+];
+
+// Schema version this binary migrates to
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+pub fn schema_version(conn: &Connection) -> Result<u32> {
+    conn.query_row("PRAGMA user_version", [], |row| row.get(0))
+}
+
+// Applies the pending migrations, each in its own transaction together with its version
+// bump, and returns the versions that were applied. A database that was migrated by a
+// newer binary is left alone, its schema is unknown to this one
+pub fn migrate(conn: &mut Connection) -> std::result::Result<Vec<u32>, String> {
+    let current = schema_version(conn).map_err(|e| e.to_string())?;
+    if current > latest_version() {
+        return Err(format!(
+            "Database schema version {} is newer than version {} of this binary",
+            current,
+            latest_version()
+        ));
+    }
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > current)
+    {
+        let done = apply(conn, migration)
+            .map_err(|e| format!("Migration {} failed: {}", migration.version, e))?;
+        if done {
+            applied.push(migration.version);
+        }
+    }
+    Ok(applied)
+}
+
+fn apply(conn: &mut Connection, migration: &Migration) -> Result<bool> {
+    // The write lock is taken up front, so servers starting at the same time take turns
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+    if schema_version(&tx)? >= migration.version {
+        return Ok(false); // Applied by another process in the meantime
+    }
+    info!(
+        "Applying migration {}: {}",
+        migration.version, migration.description
+    );
+    (migration.apply)(&tx)?;
+    tx.pragma_update(None, "user_version", migration.version)?;
+    tx.commit()?;
+    Ok(true)
+}
+
+// Tables of the baseline schema. Every table that belongs to an account refers to it by
+// users.id, which never changes, unlike the email address
+const USERS_TABLE: &str = "CREATE TABLE IF NOT EXISTS users (
+    id TEXT NOT NULL PRIMARY KEY,
+    email TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    encrypted_data TEXT DEFAULT '',
+    security_stamp TEXT NOT NULL DEFAULT '',
+    kdf_algorithm TEXT,
+    kdf_iterations INTEGER,
+    kdf_memory INTEGER,
+    kdf_parallelism INTEGER,
+    kdf_salt TEXT,
+    email_verified INTEGER NOT NULL DEFAULT 0
+);";
+
+const REFRESH_TOKENS_TABLE: &str = "CREATE TABLE IF NOT EXISTS refresh_tokens (
+    token_hash TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    family_id TEXT NOT NULL,
+    expires_at INTEGER NOT NULL,
+    used INTEGER NOT NULL DEFAULT 0
+);";
+
+const SESSIONS_TABLE: &str = "CREATE TABLE IF NOT EXISTS sessions (
+    nonce TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    device_name TEXT,
+    user_agent TEXT,
+    ip TEXT,
+    created_at INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL
+);";
+
+const REVOKED_TOKENS_TABLE: &str = "CREATE TABLE IF NOT EXISTS revoked_tokens (
+    nonce TEXT PRIMARY KEY,
+    expires_at INTEGER NOT NULL
+);";
+
+const TOTP_TABLE: &str = "CREATE TABLE IF NOT EXISTS totp (
+    user_id TEXT PRIMARY KEY,
+    secret TEXT NOT NULL,
+    enabled INTEGER NOT NULL DEFAULT 0,
+    last_step INTEGER NOT NULL DEFAULT 0
+);";
+
+const RECOVERY_CODES_TABLE: &str = "CREATE TABLE IF NOT EXISTS recovery_codes (
+    code_hash TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL
+);";
+
+const WEBAUTHN_CREDENTIALS_TABLE: &str = "CREATE TABLE IF NOT EXISTS webauthn_credentials (
+    credential_id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    name TEXT,
+    public_key BLOB NOT NULL,
+    algorithm INTEGER NOT NULL,
+    sign_count INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL,
+    last_used INTEGER
+);";
+
+const WEBAUTHN_CHALLENGES_TABLE: &str = "CREATE TABLE IF NOT EXISTS webauthn_challenges (
+    challenge TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    purpose TEXT NOT NULL,
+    pending_nonce TEXT,
+    expires_at INTEGER NOT NULL
+);";
+
+const LOGIN_FAILURES_TABLE: &str = "CREATE TABLE IF NOT EXISTS login_failures (
+    kind TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    failures INTEGER NOT NULL,
+    last_failure INTEGER NOT NULL,
+    locked_until INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (kind, subject)
+);";
+
+// PostgreSQL databases start at the baseline, there are none from earlier releases.
+// Flags are booleans and numbers 64 bit, as SQLite stores them
+const POSTGRES_BASELINE: &str = "
+CREATE TABLE users (
+    id TEXT NOT NULL PRIMARY KEY,
+    email TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    encrypted_data TEXT NOT NULL DEFAULT '',
+    security_stamp TEXT NOT NULL DEFAULT '',
+    kdf_algorithm TEXT,
+    kdf_iterations BIGINT,
+    kdf_memory BIGINT,
+    kdf_parallelism BIGINT,
+    kdf_salt TEXT,
+    email_verified BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE TABLE refresh_tokens (
+    token_hash TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    family_id TEXT NOT NULL,
+    expires_at BIGINT NOT NULL,
+    used BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE TABLE sessions (
+    nonce TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    device_name TEXT,
+    user_agent TEXT,
+    ip TEXT,
+    created_at BIGINT NOT NULL,
+    last_seen BIGINT NOT NULL
+);
+CREATE TABLE revoked_tokens (
+    nonce TEXT PRIMARY KEY,
+    expires_at BIGINT NOT NULL
+);
+CREATE TABLE totp (
+    user_id TEXT PRIMARY KEY,
+    secret TEXT NOT NULL,
+    enabled BOOLEAN NOT NULL DEFAULT FALSE,
+    last_step BIGINT NOT NULL DEFAULT 0
+);
+CREATE TABLE recovery_codes (
+    code_hash TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL
+);
+CREATE TABLE webauthn_credentials (
+    credential_id TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    name TEXT,
+    public_key BYTEA NOT NULL,
+    algorithm BIGINT NOT NULL,
+    sign_count BIGINT NOT NULL DEFAULT 0,
+    created_at BIGINT NOT NULL,
+    last_used BIGINT
+);
+CREATE TABLE webauthn_challenges (
+    challenge TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    purpose TEXT NOT NULL,
+    pending_nonce TEXT,
+    expires_at BIGINT NOT NULL
+);
+CREATE TABLE login_failures (
+    kind TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    failures BIGINT NOT NULL,
+    last_failure BIGINT NOT NULL,
+    locked_until BIGINT NOT NULL DEFAULT 0,
+    PRIMARY KEY (kind, subject)
+);
+";
+
+const VAULT_HISTORY_TABLE: &str = "CREATE TABLE vault_history (
+    user_id TEXT NOT NULL,
+    revision INTEGER NOT NULL,
+    encrypted_data TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    session_id TEXT,
+    device_name TEXT,
+    PRIMARY KEY (user_id, revision)
+);";
+
+const POSTGRES_VAULT_HISTORY: &str = "
+CREATE TABLE vault_history (
+    user_id TEXT NOT NULL,
+    revision BIGINT NOT NULL,
+    encrypted_data TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    created_at BIGINT NOT NULL,
+    session_id TEXT,
+    device_name TEXT,
+    PRIMARY KEY (user_id, revision)
+);
+INSERT INTO vault_history (user_id, revision, encrypted_data, size, created_at)
+    SELECT id, revision, encrypted_data, octet_length(encrypted_data),
+        EXTRACT(EPOCH FROM now())::BIGINT
+    FROM users WHERE encrypted_data != '';
+";
+
+// Items take the vault revision of the change that wrote them. Deleted ones are kept as
+// tombstones without data, so clients syncing later learn about the deletion
+const VAULT_ITEMS_TABLE: &str = "CREATE TABLE vault_items (
+    user_id TEXT NOT NULL,
+    id TEXT NOT NULL,
+    revision INTEGER NOT NULL,
+    encrypted_data TEXT,
+    PRIMARY KEY (user_id, id)
+);";
+
+const VAULT_ITEMS_INDEX: &str =
+    "CREATE INDEX vault_items_revision ON vault_items (user_id, revision);";
+
+const POSTGRES_VAULT_ITEMS: &str = "
+ALTER TABLE users ADD COLUMN items_revision BIGINT NOT NULL DEFAULT 0;
+CREATE TABLE vault_items (
+    user_id TEXT NOT NULL,
+    id TEXT NOT NULL,
+    revision BIGINT NOT NULL,
+    encrypted_data TEXT,
+    PRIMARY KEY (user_id, id)
+);
+CREATE INDEX vault_items_revision ON vault_items (user_id, revision);
+";
+
+// The files are kept in the blob store, see attachments.rs. The size is declared when the
+// upload starts and counts against the quota right away, received grows as it goes on
+const ATTACHMENTS_TABLE: &str = "CREATE TABLE attachments (
+    user_id TEXT NOT NULL,
+    id TEXT NOT NULL,
+    item_id TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    received INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (user_id, id)
+);";
+
+const POSTGRES_ATTACHMENTS: &str = "
+CREATE TABLE attachments (
+    user_id TEXT NOT NULL,
+    id TEXT NOT NULL,
+    item_id TEXT NOT NULL,
+    size BIGINT NOT NULL,
+    received BIGINT NOT NULL DEFAULT 0,
+    created_at BIGINT NOT NULL,
+    PRIMARY KEY (user_id, id)
+);
+";
+
+// Tables that were keyed by email before accounts had ids, with their remaining columns
+const USER_TABLES: [(&str, &str, &str); 6] = [
+    (
+        "refresh_tokens",
+        REFRESH_TOKENS_TABLE,
+        "token_hash, family_id, expires_at, used",
+    ),
+    (
+        "sessions",
+        SESSIONS_TABLE,
+        "nonce, device_name, user_agent, ip, created_at, last_seen",
+    ),
+    ("totp", TOTP_TABLE, "secret, enabled, last_step"),
+    ("recovery_codes", RECOVERY_CODES_TABLE, "code_hash"),
+    (
+        "webauthn_credentials",
+        WEBAUTHN_CREDENTIALS_TABLE,
+        "credential_id, name, public_key, algorithm, sign_count, created_at, last_used",
+    ),
+    (
+        "webauthn_challenges",
+        WEBAUTHN_CHALLENGES_TABLE,
+        "challenge, purpose, pending_nonce, expires_at",
+    ),
+];
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> Result<bool> {
+    conn.query_row(
+        &format!(
+            "SELECT EXISTS(SELECT 1 FROM pragma_table_info('{}') WHERE name = ?1)",
+            table
+        ),
+        params![column],
+        |row| row.get(0),
+    )
+}
+
+// Databases created by older versions lack columns that were added later
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    if !has_column(conn, table, column)? {
+        info!("Adding column {} to table {}", column, table);
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+// Databases created before accounts had ids are keyed by email. The users get a random id
+// and every table that refers to them is rebuilt around it
+fn migrate_to_user_ids(tx: &Transaction) -> Result<()> {
+    if has_column(tx, "users", "id")? {
+        return Ok(());
+    }
+    info!("Migrating accounts from email to id primary keys");
+    tx.execute("ALTER TABLE users RENAME TO users_old", [])?;
+    tx.execute(USERS_TABLE, [])?;
+    tx.execute(
+        "CREATE TEMP TABLE user_ids (email TEXT PRIMARY KEY, id TEXT NOT NULL, normalized TEXT NOT NULL)",
+        [],
+    )?;
+    let emails = {
+        let mut stmt = tx.prepare("SELECT email FROM users_old")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect::<Result<Vec<String>>>()?
+    };
+    for email in &emails {
+        tx.execute(
+            "INSERT INTO user_ids (email, id, normalized) VALUES (?1, ?2, ?3)",
+            params![email, Uuid::new_v4().to_string(), normalize_email(email)],
+        )?;
+    }
+    // Fails on addresses that only differ in case, those accounts have to be merged by hand
+    tx.execute(
+        "INSERT INTO users (id, email, password_hash, encrypted_data, security_stamp,
+            kdf_algorithm, kdf_iterations, kdf_memory, kdf_parallelism, kdf_salt, email_verified)
+         SELECT i.id, i.normalized, o.password_hash, o.encrypted_data, o.security_stamp,
+            o.kdf_algorithm, o.kdf_iterations, o.kdf_memory, o.kdf_parallelism, o.kdf_salt,
+            o.email_verified
+         FROM users_old o JOIN user_ids i ON i.email = o.email",
+        [],
+    )?;
+    for (table, definition, columns) in USER_TABLES {
+        if !has_column(tx, table, "email")? {
+            continue;
+        }
+        let prefixed = columns
+            .split(", ")
+            .map(|column| format!("o.{}", column))
+            .collect::<Vec<_>>()
+            .join(", ");
+        tx.execute(
+            &format!("ALTER TABLE {} RENAME TO {}_old", table, table),
+            [],
+        )?;
+        tx.execute(definition, [])?;
+        tx.execute(
+            &format!(
+                "INSERT INTO {table} (user_id, {columns}) SELECT i.id, {prefixed}
+                 FROM {table}_old o JOIN user_ids i ON i.email = o.email"
+            ),
+            [],
+        )?;
+        tx.execute(&format!("DROP TABLE {}_old", table), [])?;
+    }
+    tx.execute("DROP TABLE users_old", [])?;
+    tx.execute("DROP TABLE user_ids", [])?;
+    info!("Migrated {} accounts", emails.len());
+    Ok(())
+}
+
+// Databases from before versioned migrations are at version 0 in any state of the earlier
+// releases, so the baseline only creates what is missing and upgrades what it finds
+fn baseline(tx: &Transaction) -> Result<()> {
+    tx.execute(USERS_TABLE, [])?;
+    add_column_if_missing(tx, "users", "security_stamp", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(tx, "users", "kdf_algorithm", "TEXT")?;
+    add_column_if_missing(tx, "users", "kdf_iterations", "INTEGER")?;
+    add_column_if_missing(tx, "users", "kdf_memory", "INTEGER")?;
+    add_column_if_missing(tx, "users", "kdf_parallelism", "INTEGER")?;
+    add_column_if_missing(tx, "users", "kdf_salt", "TEXT")?;
+    // Accounts created before verification existed are trusted as they are
+    add_column_if_missing(tx, "users", "email_verified", "INTEGER NOT NULL DEFAULT 1")?;
+    migrate_to_user_ids(tx)?;
+    for (_, definition, _) in USER_TABLES {
+        tx.execute(definition, [])?;
+    }
+    tx.execute(REVOKED_TOKENS_TABLE, [])?;
+    tx.execute(LOGIN_FAILURES_TABLE, [])?;
+    Ok(())
+}
+
+// Counts the updates of a vault, existing vaults start at 0 like new ones
+fn add_vault_revision(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "ALTER TABLE users ADD COLUMN revision INTEGER NOT NULL DEFAULT 0",
+        [],
+    )?;
+    Ok(())
+}
+
+// Vaults that exist already become the first snapshot, so the first update after the
+// upgrade can be rolled back as well
+fn add_vault_history(tx: &Transaction) -> Result<()> {
+    tx.execute(VAULT_HISTORY_TABLE, [])?;
+    tx.execute(
+        "INSERT INTO vault_history (user_id, revision, encrypted_data, size, created_at)
+         SELECT id, revision, encrypted_data, length(CAST(encrypted_data AS BLOB)),
+            CAST(strftime('%s', 'now') AS INTEGER)
+         FROM users WHERE encrypted_data != ''",
+        [],
+    )?;
+    Ok(())
+}
+
+// Items count their own revisions, apart from the one of the vault
+fn add_vault_items(tx: &Transaction) -> Result<()> {
+    tx.execute(
+        "ALTER TABLE users ADD COLUMN items_revision INTEGER NOT NULL DEFAULT 0",
+        [],
+    )?;
+    tx.execute(VAULT_ITEMS_TABLE, [])?;
+    tx.execute(VAULT_ITEMS_INDEX, [])?;
+    Ok(())
+}
+
+fn add_attachments(tx: &Transaction) -> Result<()> {
+    tx.execute(ATTACHMENTS_TABLE, [])?;
+    Ok(())
+}
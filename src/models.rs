@@ -1,9 +1,9 @@
 use serde::{Deserialize, Serialize};
 use utoipa::{
     openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
-    ToSchema,
+    IntoParams, ToSchema,
 };
-use validator::Validate;
+use validator::{Validate, ValidationError};
 
 pub struct SecurityAddon;
 
@@ -31,6 +31,58 @@ pub struct PreLoginRequest {
     pub email: String,
 }
 
+// Parameters the client uses to derive password_hash and the vault key from the master password
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema, Validate)]
+#[serde(deny_unknown_fields)]
+#[validate(schema(function = "validate_kdf_params"))]
+pub struct KdfParams {
+    #[schema(example = "argon2id")]
+    pub algorithm: String, // "pbkdf2-sha256" or "argon2id"
+    pub iterations: u32,
+    pub memory: Option<u32>,      // KiB, argon2id only
+    pub parallelism: Option<u32>, // argon2id only
+    #[validate(length(min = 16, max = 256))]
+    pub salt: Option<String>, // None: the client salts with the email address
+}
+
+// Accounts registered without explicit parameters use what clients hardcoded so far
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            algorithm: "pbkdf2-sha256".to_string(),
+            iterations: 600_000,
+            memory: None,
+            parallelism: None,
+            salt: None,
+        }
+    }
+}
+
+fn validate_kdf_params(kdf: &KdfParams) -> Result<(), ValidationError> {
+    let valid = match kdf.algorithm.as_str() {
+        "pbkdf2-sha256" => {
+            (100_000..=10_000_000).contains(&kdf.iterations)
+                && kdf.memory.is_none()
+                && kdf.parallelism.is_none()
+        }
+        "argon2id" => {
+            (2..=100).contains(&kdf.iterations)
+                && kdf
+                    .memory
+                    .is_some_and(|memory| (16_384..=4_194_304).contains(&memory))
+                && kdf
+                    .parallelism
+                    .is_some_and(|parallelism| (1..=16).contains(&parallelism))
+        }
+        _ => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(ValidationError::new("invalid_kdf_params"))
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
 #[serde(deny_unknown_fields)]
 pub struct LoginRequest {
@@ -39,11 +91,27 @@ pub struct LoginRequest {
     pub email: String,
     #[validate(length(min = 5, max = 1024))]
     pub password_hash: String,
+    #[validate(length(max = 128))]
+    pub device_name: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct LoginResponse {
     pub token: String,
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct RefreshRequest {
+    #[validate(length(min = 1, max = 256))]
+    pub refresh_token: String,
+}
+
+// Token from the link of the verification mail
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+pub struct VerifyEmailQuery {
+    pub token: String,
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
@@ -54,6 +122,10 @@ pub struct RegisterRequest {
     pub email: String,
     #[validate(length(min = 5, max = 1024))]
     pub password_hash: String,
+    #[validate(length(max = 128))]
+    pub device_name: Option<String>,
+    #[validate(nested)]
+    pub kdf: Option<KdfParams>,
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
@@ -61,16 +133,331 @@ pub struct RegisterRequest {
 pub struct ChangeRequest {
     #[validate(length(max = 1024))]
     pub password_hash: String,
+    #[validate(nested)]
+    pub kdf: Option<KdfParams>, // None keeps the current parameters
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ChangeEmailRequest {
+    #[schema(format = "email")]
+    #[validate(email, length(max = 320))]
+    pub new_email: String,
+}
+
+// Clients that salt their KDF with the email derive a new password_hash for the new address
+#[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ConfirmEmailChangeRequest {
+    #[validate(length(min = 1, max = 4096))]
+    pub token: String,
+    #[validate(length(max = 1024))]
+    pub password_hash: Option<String>, // None keeps the current password
+    #[validate(nested)]
+    pub kdf: Option<KdfParams>, // None keeps the current parameters
 }
 
 #[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct DataResponse {
     pub encrypted_data: String,
+    pub revision: u64, // also sent as the ETag
 }
 
+// The update only applies to the revision the client based its changes on, given here or
+// as If-Match
 #[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
 #[serde(deny_unknown_fields)]
 pub struct UpdateRequest {
     #[validate(length(max = 1048576))]
     pub encrypted_data: String,
+    pub base_revision: Option<u64>,
+}
+
+// A restore, like an update, names the revision it replaces here or as If-Match
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RestoreRequest {
+    pub base_revision: Option<u64>,
+}
+
+// Revision of the vault or the items after an update, or the current one if it was refused
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RevisionResponse {
+    pub revision: u64,
+}
+
+// Changes of items since a revision of the items, 0 or none for all items
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+pub struct ChangesQuery {
+    pub since: Option<u64>,
+}
+
+// Item as it is after the change with its revision, tombstones have no data
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ItemResponse {
+    pub id: String,
+    pub revision: u64,
+    pub encrypted_data: Option<String>,
+    pub deleted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ChangesResponse {
+    pub revision: u64, // pass as since to get the changes after this response
+    pub items: Vec<ItemResponse>,
+}
+
+// Upsert of an item, or its deletion without encrypted_data. base_revision is the revision
+// of the item the change is based on, 0 for a new item
+#[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct ItemChangeRequest {
+    #[validate(length(min = 1, max = 128))]
+    pub id: String, // chosen by the client, opaque to the server
+    #[validate(length(max = 1048576))]
+    pub encrypted_data: Option<String>,
+    pub base_revision: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
+#[serde(deny_unknown_fields)]
+#[validate(schema(function = "validate_unique_items"))]
+pub struct ItemChangesRequest {
+    #[validate(length(min = 1, max = 1000), nested)]
+    pub changes: Vec<ItemChangeRequest>,
+}
+
+fn validate_unique_items(request: &ItemChangesRequest) -> Result<(), ValidationError> {
+    let mut ids: Vec<&str> = request.changes.iter().map(|c| c.id.as_str()).collect();
+    ids.sort_unstable();
+    ids.dedup();
+    if ids.len() == request.changes.len() {
+        Ok(())
+    } else {
+        Err(ValidationError::new("duplicate_item"))
+    }
+}
+
+// Refused batch, with the current revision and the items changed since their base revision
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct ItemConflictResponse {
+    pub revision: u64,
+    pub conflicts: Vec<String>,
+}
+
+// Browsers can not set a header on a WebSocket or an EventSource, so they pass a ticket
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+pub struct NotificationsQuery {
+    pub ticket: Option<String>,
+}
+
+// Opens a notification stream of the session as ?ticket=, for STREAM_TICKET_TTL seconds
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct StreamTicketResponse {
+    pub ticket: String,
+    pub expires_in: usize, // seconds
+}
+
+// Snapshot of an earlier vault revision, session_id is the id of the session that wrote it
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct HistoryEntryResponse {
+    pub revision: u64,
+    pub size: usize,
+    pub created_at: usize,
+    pub session_id: Option<String>,
+    pub device_name: Option<String>,
+}
+
+// Starts the upload of an encrypted file for an item, size is the exact length it will have
+#[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct AttachmentCreateRequest {
+    #[validate(length(min = 1, max = 128))]
+    pub item_id: String,
+    #[validate(range(min = 1))]
+    pub size: u64,
+}
+
+// Uploads resume at received, the content can be downloaded once complete
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AttachmentResponse {
+    pub id: String,
+    pub item_id: String,
+    pub size: u64,
+    pub received: u64,
+    pub complete: bool,
+    pub created_at: usize,
+}
+
+// Usage is the sum of the declared sizes, incomplete uploads included
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct AttachmentListResponse {
+    pub usage: u64,
+    pub quota: u64,
+    pub attachments: Vec<AttachmentResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct QuotaExceededResponse {
+    pub usage: u64,
+    pub quota: u64,
+}
+
+// Position of the uploaded chunk, the received bytes of the attachment
+#[derive(Debug, Serialize, Deserialize, IntoParams)]
+pub struct UploadQuery {
+    pub offset: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct SessionResponse {
+    pub id: String,
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: usize,
+    pub last_seen: usize,
+    pub current: bool,
+}
+
+// Returned by login instead of tokens when the account has two-factor authentication enabled
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TwoFactorRequiredResponse {
+    pub pending_token: String,
+    #[schema(example = json!(["totp", "recovery_code", "webauthn"]))]
+    pub methods: Vec<String>,
+}
+
+// Second login step, either a TOTP code or one of the recovery codes
+#[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct TwoFactorLoginRequest {
+    #[validate(length(min = 1, max = 4096))]
+    pub pending_token: String,
+    #[schema(example = "123456")]
+    #[validate(length(max = 32))]
+    pub code: Option<String>,
+    #[validate(length(max = 32))]
+    pub recovery_code: Option<String>,
+    #[validate(length(max = 128))]
+    pub device_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct TotpSetupResponse {
+    pub secret: String,      // base32, for manual entry
+    pub otpauth_uri: String, // for QR codes
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct TotpCodeRequest {
+    #[schema(example = "123456")]
+    #[validate(length(min = 1, max = 32))]
+    pub code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+// Options for navigator.credentials.create(), named like the WebAuthn API so clients can
+// pass them on after decoding the base64url values
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnCreationOptions {
+    pub challenge: String,
+    pub rp: WebauthnRelyingParty,
+    pub user: WebauthnUser,
+    pub pub_key_cred_params: Vec<WebauthnCredentialParameters>,
+    pub exclude_credentials: Vec<WebauthnCredentialDescriptor>,
+    pub timeout: u32, // milliseconds
+    pub attestation: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct WebauthnRelyingParty {
+    pub id: String,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnUser {
+    pub id: String,
+    pub name: String,
+    pub display_name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct WebauthnCredentialParameters {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub alg: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct WebauthnCredentialDescriptor {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub id: String,
+}
+
+// Options for navigator.credentials.get()
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct WebauthnRequestOptions {
+    pub challenge: String,
+    pub rp_id: String,
+    pub allow_credentials: Vec<WebauthnCredentialDescriptor>,
+    pub timeout: u32, // milliseconds
+    pub user_verification: String,
+}
+
+// Binary values of the attestation response are base64url encoded
+#[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct WebauthnRegisterRequest {
+    #[validate(length(max = 128))]
+    pub name: Option<String>,
+    #[validate(length(min = 1, max = 4096))]
+    pub client_data_json: String,
+    #[validate(length(min = 1, max = 16384))]
+    pub attestation_object: String,
+}
+
+// Either the pending token of a login with password, or an email for a passwordless login
+#[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct WebauthnLoginBeginRequest {
+    #[validate(length(min = 1, max = 4096))]
+    pub pending_token: Option<String>,
+    #[schema(format = "email")]
+    #[validate(email, length(max = 320))]
+    pub email: Option<String>,
+}
+
+// Binary values of the assertion response are base64url encoded
+#[derive(Debug, Serialize, Deserialize, ToSchema, Validate)]
+#[serde(deny_unknown_fields)]
+pub struct WebauthnLoginRequest {
+    #[validate(length(min = 1, max = 1024))]
+    pub credential_id: String,
+    #[validate(length(min = 1, max = 4096))]
+    pub client_data_json: String,
+    #[validate(length(min = 1, max = 4096))]
+    pub authenticator_data: String,
+    #[validate(length(min = 1, max = 1024))]
+    pub signature: String,
+    #[validate(length(max = 128))]
+    pub device_name: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct WebauthnCredentialResponse {
+    pub id: String,
+    pub name: Option<String>,
+    pub created_at: usize,
+    pub last_used: Option<usize>,
 }
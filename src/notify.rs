@@ -0,0 +1,295 @@
+use actix_web::{
+    rt::{self, time},
+    web::Bytes,
+};
+use actix_ws::{CloseCode, CloseReason, Message};
+use futures_util::Stream;
+use log::{debug, error, info};
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+
+use crate::auth::session_active;
+use crate::store::Database;
+
+// Pushed to the notification streams of the sessions of a user
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notification {
+    VaultChanged { revision: u64 },
+    ItemsChanged { revision: u64 },
+    SessionEnded, // the stream closes after this one
+}
+
+impl Notification {
+    fn event(&self) -> &'static str {
+        match self {
+            Notification::VaultChanged { .. } => "vault_changed",
+            Notification::ItemsChanged { .. } => "items_changed",
+            Notification::SessionEnded => "session_ended",
+        }
+    }
+
+    fn data(&self) -> String {
+        match self {
+            Notification::VaultChanged { revision } | Notification::ItemsChanged { revision } => {
+                format!(
+                    "{{\"type\":\"{}\",\"revision\":{}}}",
+                    self.event(),
+                    revision
+                )
+            }
+            Notification::SessionEnded => format!("{{\"type\":\"{}\"}}", self.event()),
+        }
+    }
+}
+
+struct Subscriber {
+    id: u64,
+    nonce: String,
+    sender: mpsc::UnboundedSender<Notification>,
+}
+
+// Open notification streams of this process, by user id. Servers behind a load balancer
+// only reach the streams connected to them
+#[derive(Default)]
+pub struct Notifier {
+    subscribers: Mutex<HashMap<String, Vec<Subscriber>>>,
+    next_id: AtomicU64,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(
+        &self,
+        user_id: &str,
+        nonce: &str,
+    ) -> (u64, mpsc::UnboundedReceiver<Notification>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.subscribers
+            .lock()
+            .unwrap()
+            .entry(user_id.to_string())
+            .or_default()
+            .push(Subscriber {
+                id,
+                nonce: nonce.to_string(),
+                sender,
+            });
+        (id, receiver)
+    }
+
+    pub fn unsubscribe(&self, user_id: &str, id: u64) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(streams) = subscribers.get_mut(user_id) {
+            streams.retain(|subscriber| subscriber.id != id);
+            if streams.is_empty() {
+                subscribers.remove(user_id);
+            }
+        }
+    }
+
+    // Streams of the sessions of the user that match, gone ones are dropped on the way
+    fn publish(&self, user_id: &str, notification: Notification, matches: impl Fn(&str) -> bool) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if let Some(streams) = subscribers.get_mut(user_id) {
+            streams.retain(|subscriber| {
+                !matches(&subscriber.nonce) || subscriber.sender.send(notification.clone()).is_ok()
+            });
+        }
+    }
+
+    // Tells the other sessions of the user about a committed change
+    pub fn vault_changed(&self, user_id: &str, revision: u64, origin_nonce: &str) {
+        self.publish(user_id, Notification::VaultChanged { revision }, |nonce| {
+            nonce != origin_nonce
+        });
+    }
+
+    pub fn items_changed(&self, user_id: &str, revision: u64, origin_nonce: &str) {
+        self.publish(user_id, Notification::ItemsChanged { revision }, |nonce| {
+            nonce != origin_nonce
+        });
+    }
+
+    pub fn session_ended(&self, user_id: &str, nonce: &str) {
+        self.publish(user_id, Notification::SessionEnded, |other| other == nonce);
+    }
+
+    // Ends the streams of every session of the user, but the kept one
+    pub fn sessions_ended(&self, user_id: &str, keep_nonce: Option<&str>) {
+        self.publish(user_id, Notification::SessionEnded, |nonce| {
+            Some(nonce) != keep_nonce
+        });
+    }
+}
+
+// Seconds between heartbeats, the session is checked again with each of them
+pub fn heartbeat_interval() -> Duration {
+    let seconds = env::var("NOTIFICATION_HEARTBEAT")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .filter(|&seconds| seconds > 0)
+        .unwrap_or(30);
+    Duration::from_secs(seconds)
+}
+
+// What a stream needs to notice the end of its session. The stream belongs to the session,
+// not to the token that opened it, so it outlives that token as long as the session does
+pub struct StreamContext {
+    pub notifier: Arc<Notifier>,
+    pub db: Database,
+    pub user_id: String,
+    pub nonce: String,
+    pub stamp: String,
+}
+
+// Revocations without a notification, like a password change on another instance, are
+// noticed by checking the session again with every heartbeat
+async fn stream_active(context: &StreamContext) -> bool {
+    let (user_id, nonce, stamp) = (
+        context.user_id.clone(),
+        context.nonce.clone(),
+        context.stamp.clone(),
+    );
+    match context
+        .db
+        .run(move |db| session_active(db, &user_id, &nonce, &stamp))
+        .await
+    {
+        Ok(active) => active,
+        Err(e) => {
+            // Kept open, the next heartbeat checks again
+            error!("Failed to check session of notification stream: {}", e);
+            true
+        }
+    }
+}
+
+enum Outgoing {
+    Notification(Notification),
+    Heartbeat,
+}
+
+// Sends notifications and heartbeats until the client leaves or the session ends
+async fn pump<F, Fut>(context: StreamContext, gone: impl Future<Output = ()>, mut send: F)
+where
+    F: FnMut(Outgoing) -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let (id, mut receiver) = context.notifier.subscribe(&context.user_id, &context.nonce);
+    let mut heartbeat = time::interval(heartbeat_interval());
+    heartbeat.tick().await; // The first tick completes immediately
+    tokio::pin!(gone);
+    loop {
+        let outgoing = tokio::select! {
+            _ = &mut gone => break,
+            notification = receiver.recv() => match notification {
+                Some(notification) => Outgoing::Notification(notification),
+                None => break,
+            },
+            _ = heartbeat.tick() => {
+                if !stream_active(&context).await {
+                    Outgoing::Notification(Notification::SessionEnded)
+                } else {
+                    Outgoing::Heartbeat
+                }
+            }
+        };
+        let ended = matches!(outgoing, Outgoing::Notification(Notification::SessionEnded));
+        if !send(outgoing).await || ended {
+            break;
+        }
+    }
+    context.notifier.unsubscribe(&context.user_id, id);
+    debug!("Notification stream of session {} closed", &context.nonce);
+}
+
+// Serves the stream over an upgraded WebSocket, notifications go out as JSON text messages
+pub fn websocket(
+    context: StreamContext,
+    session: actix_ws::Session,
+    mut messages: actix_ws::MessageStream,
+) {
+    info!("Opening notification WebSocket of: {}", &context.user_id);
+    // Reads until the client closes, answering its pings
+    let mut reader_session = session.clone();
+    let gone = async move {
+        while let Some(Ok(message)) = messages.recv().await {
+            match message {
+                Message::Ping(bytes) if reader_session.pong(&bytes).await.is_err() => break,
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+    };
+    rt::spawn(async move {
+        let writer = session;
+        pump(context, gone, |outgoing| {
+            let mut writer = writer.clone();
+            async move {
+                match outgoing {
+                    Outgoing::Notification(notification) => {
+                        writer.text(notification.data()).await.is_ok()
+                    }
+                    Outgoing::Heartbeat => writer.ping(b"").await.is_ok(),
+                }
+            }
+        })
+        .await;
+        let reason = CloseReason {
+            code: CloseCode::Policy,
+            description: Some("Session ended".to_string()),
+        };
+        let _ = writer.close(Some(reason)).await;
+    });
+}
+
+// Body of a server-sent event response, fed by the stream task
+pub struct EventStream(mpsc::Receiver<Bytes>);
+
+impl Stream for EventStream {
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.0.poll_recv(cx).map(|event| event.map(Ok))
+    }
+}
+
+// Serves the stream as server-sent events, for clients that can not open a WebSocket
+pub fn event_stream(context: StreamContext) -> EventStream {
+    info!("Opening notification event stream of: {}", &context.user_id);
+    let (sender, receiver) = mpsc::channel(16);
+    let closed = sender.clone();
+    rt::spawn(async move {
+        pump(context, async move { closed.closed().await }, |outgoing| {
+            let sender = sender.clone();
+            async move {
+                let event = match outgoing {
+                    Outgoing::Notification(notification) => format!(
+                        "event: {}\ndata: {}\n\n",
+                        notification.event(),
+                        notification.data()
+                    ),
+                    Outgoing::Heartbeat => ": heartbeat\n\n".to_string(),
+                };
+                sender.send(Bytes::from(event)).await.is_ok()
+            }
+        })
+        .await;
+    });
+    EventStream(receiver)
+}
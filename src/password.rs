@@ -0,0 +1,81 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, Error as HashError, PasswordHash, SaltString},
+    Algorithm, Argon2, Params, PasswordHasher, PasswordVerifier, Version,
+};
+use log::warn;
+use std::sync::OnceLock;
+use subtle::ConstantTimeEq;
+
+use crate::config::env_param;
+
+// Outcome of checking a login attempt against the stored hash
+#[derive(Debug, PartialEq)]
+pub enum PasswordCheck {
+    Valid,
+    ValidNeedsRehash, // legacy row or outdated Argon2 parameters
+    Invalid,
+}
+
+// OWASP recommended minimum for Argon2id, tunable through the environment
+fn params() -> Params {
+    let params = Params::new(
+        env_param("ARGON2_MEMORY_KIB", 19456),
+        env_param("ARGON2_ITERATIONS", 2),
+        env_param("ARGON2_PARALLELISM", 1),
+        None,
+    );
+    params.unwrap_or_else(|e| {
+        warn!("Invalid Argon2 parameters ({}), using defaults", e);
+        Params::default()
+    })
+}
+
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params())
+}
+
+// Hashes the client-side password_hash again, the result is a PHC string with its own salt
+pub fn hash_password(password_hash: &str) -> Result<String, HashError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(argon2()
+        .hash_password(password_hash.as_bytes(), &salt)?
+        .to_string())
+}
+
+pub fn verify_password(password_hash: &str, stored: &str) -> PasswordCheck {
+    let Ok(parsed) = PasswordHash::new(stored) else {
+        // Rows written before server-side hashing store the client value verbatim
+        return if bool::from(password_hash.as_bytes().ct_eq(stored.as_bytes())) {
+            PasswordCheck::ValidNeedsRehash
+        } else {
+            PasswordCheck::Invalid
+        };
+    };
+
+    if argon2()
+        .verify_password(password_hash.as_bytes(), &parsed)
+        .is_err()
+    {
+        return PasswordCheck::Invalid;
+    }
+
+    let current = params();
+    let outdated = parsed.algorithm != Algorithm::Argon2id.ident()
+        || Params::try_from(&parsed).map_or(true, |stored| {
+            stored.m_cost() != current.m_cost()
+                || stored.t_cost() != current.t_cost()
+                || stored.p_cost() != current.p_cost()
+        });
+    if outdated {
+        PasswordCheck::ValidNeedsRehash
+    } else {
+        PasswordCheck::Valid
+    }
+}
+
+// Spends the time of a real verification, so unknown accounts can not be told apart by timing
+pub fn verify_dummy(password_hash: &str) {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    let dummy = DUMMY.get_or_init(|| hash_password("dummy password").unwrap_or_default());
+    verify_password(password_hash, dummy);
+}
@@ -0,0 +1,1207 @@
+use log::info;
+use postgres::{error::SqlState, Config, GenericClient, NoTls, Row, Transaction};
+use r2d2::{Pool, PooledConnection};
+use r2d2_postgres::PostgresConnectionManager;
+use std::{str::FromStr, thread};
+use uuid::Uuid;
+
+use crate::db::{pool_size, BUSY_TIMEOUT};
+use crate::migrations::{latest_version, Migration, MIGRATIONS};
+use crate::models::KdfParams;
+use crate::store::{
+    normalize_email, Attachment, AttachmentCreate, ItemChange, ItemsUpdate, LoginFailures,
+    RefreshTokenStatus, Result, Session, StoreError, Totp, Vault, VaultItem, VaultSnapshot,
+    VaultStore, VaultUpdate, WebauthnCeremony, WebauthnCredential, SESSION_TOUCH_INTERVAL,
+};
+
+type Manager = PostgresConnectionManager<NoTls>;
+
+// Key of the advisory lock servers hold while migrating, so those starting at the same
+// time take turns
+const MIGRATION_LOCK: i64 = 0x7273_7061_7373;
+
+// Conflicts between transactions are reported as busy, like a locked SQLite database
+impl From<postgres::Error> for StoreError {
+    fn from(e: postgres::Error) -> Self {
+        match e.code() {
+            Some(code)
+                if *code == SqlState::T_R_SERIALIZATION_FAILURE
+                    || *code == SqlState::T_R_DEADLOCK_DETECTED
+                    || *code == SqlState::LOCK_NOT_AVAILABLE =>
+            {
+                StoreError::Busy(e.to_string())
+            }
+            _ => StoreError::Backend(e.to_string()),
+        }
+    }
+}
+
+// Pool of connections to a PostgreSQL database, the schema is the one of SQLite in the
+// types of PostgreSQL. Numbers are BIGINT, as the driver maps no unsigned Rust types
+pub struct PostgresStore {
+    pool: Option<Pool<Manager>>, // only taken when dropped
+}
+
+// Connections block on a runtime of their own when closed, which panics on an async
+// worker. The last handle to a store may be dropped there, so the pool is closed on a
+// thread of its own
+impl Drop for PostgresStore {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            let _ = thread::spawn(move || drop(pool)).join();
+        }
+    }
+}
+
+impl PostgresStore {
+    // Takes a postgres:// URL, connections are not encrypted
+    pub fn open(url: &str) -> std::result::Result<Self, String> {
+        let mut config = Config::from_str(url).map_err(|e| e.to_string())?;
+        // Statements wait for row locks of other connections as long as SQLite does
+        let options = format!(
+            "{} -c lock_timeout={}",
+            config.get_options().unwrap_or_default(),
+            BUSY_TIMEOUT.as_millis()
+        );
+        config.options(options.trim_start());
+        let pool = Pool::builder()
+            .max_size(pool_size())
+            .connection_timeout(BUSY_TIMEOUT)
+            .build(PostgresConnectionManager::new(config, NoTls))
+            .map_err(|e| e.to_string())?;
+        Ok(PostgresStore { pool: Some(pool) })
+    }
+
+    fn connection(&self) -> Result<PooledConnection<Manager>> {
+        self.pool
+            .as_ref()
+            .expect("pool of a dropped store")
+            .get()
+            .map_err(|e| StoreError::Busy(format!("No database connection available: {}", e)))
+    }
+}
+
+fn schema_version(client: &mut impl GenericClient) -> std::result::Result<u32, postgres::Error> {
+    let exists: bool = client
+        .query_one("SELECT to_regclass('schema_version') IS NOT NULL", &[])?
+        .get(0);
+    if !exists {
+        return Ok(0);
+    }
+    let version: Option<i64> = client
+        .query_opt("SELECT version FROM schema_version", &[])?
+        .map(|row| row.get(0));
+    Ok(version.unwrap_or(0) as u32)
+}
+
+fn apply(client: &mut postgres::Client, migration: &Migration) -> Result<()> {
+    info!(
+        "Applying migration {}: {}",
+        migration.version, migration.description
+    );
+    let mut tx = client.transaction()?;
+    tx.batch_execute(migration.postgres)?;
+    tx.execute(
+        "UPDATE schema_version SET version = $1",
+        &[&(migration.version as i64)],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+fn set_kdf(tx: &mut Transaction, user_id: &str, kdf: &KdfParams) -> Result<()> {
+    tx.execute(
+        "UPDATE users SET kdf_algorithm = $1, kdf_iterations = $2, kdf_memory = $3,
+         kdf_parallelism = $4, kdf_salt = $5 WHERE id = $6",
+        &[
+            &kdf.algorithm,
+            &(kdf.iterations as i64),
+            &kdf.memory.map(i64::from),
+            &kdf.parallelism.map(i64::from),
+            &kdf.salt,
+            &user_id,
+        ],
+    )?;
+    Ok(())
+}
+
+// A new stamp invalidates every access token issued before, so all refresh tokens and
+// every session except the one to keep are dropped as well
+fn rotate_security_stamp(
+    tx: &mut Transaction,
+    user_id: &str,
+    keep_nonce: Option<&str>,
+) -> Result<()> {
+    tx.execute(
+        "UPDATE users SET security_stamp = $1 WHERE id = $2",
+        &[&Uuid::new_v4().to_string(), &user_id],
+    )?;
+    tx.execute("DELETE FROM refresh_tokens WHERE user_id = $1", &[&user_id])?;
+    tx.execute(
+        "DELETE FROM sessions WHERE user_id = $1 AND nonce IS DISTINCT FROM $2",
+        &[&user_id, &keep_nonce],
+    )?;
+    Ok(())
+}
+
+fn delete_totp(tx: &mut Transaction, user_id: &str) -> Result<()> {
+    tx.execute("DELETE FROM totp WHERE user_id = $1", &[&user_id])?;
+    tx.execute("DELETE FROM recovery_codes WHERE user_id = $1", &[&user_id])?;
+    Ok(())
+}
+
+fn session_from_row(row: &Row) -> Session {
+    Session {
+        nonce: row.get(0),
+        device_name: row.get(1),
+        user_agent: row.get(2),
+        ip: row.get(3),
+        created_at: row.get::<_, i64>(4) as usize,
+        last_seen: row.get::<_, i64>(5) as usize,
+    }
+}
+
+fn webauthn_credential_from_row(row: &Row) -> WebauthnCredential {
+    WebauthnCredential {
+        credential_id: row.get(0),
+        name: row.get(1),
+        public_key: row.get(2),
+        algorithm: row.get(3),
+        sign_count: row.get::<_, i64>(4) as u32,
+        created_at: row.get::<_, i64>(5) as usize,
+        last_used: row
+            .get::<_, Option<i64>>(6)
+            .map(|last_used| last_used as usize),
+    }
+}
+
+fn attachment_from_row(row: &Row) -> Attachment {
+    Attachment {
+        id: row.get(0),
+        item_id: row.get(1),
+        size: row.get::<_, i64>(2) as u64,
+        received: row.get::<_, i64>(3) as u64,
+        created_at: row.get::<_, i64>(4) as usize,
+    }
+}
+
+fn login_failures_from_row(row: &Row) -> LoginFailures {
+    LoginFailures {
+        kind: row.get(0),
+        subject: row.get(1),
+        failures: row.get::<_, i64>(2) as u32,
+        last_failure: row.get::<_, i64>(3) as usize,
+        locked_until: row.get::<_, i64>(4) as usize,
+    }
+}
+
+impl VaultStore for PostgresStore {
+    // PostgreSQL keeps the version in a table of its own, which is created before the
+    // first migration
+    fn migrate(&self) -> std::result::Result<Vec<u32>, String> {
+        let mut client = self.connection().map_err(|e| e.to_string())?;
+        client
+            .execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK])
+            .map_err(|e| e.to_string())?;
+        let applied = (|| {
+            client.batch_execute(
+                "CREATE TABLE IF NOT EXISTS schema_version (version BIGINT NOT NULL);
+                 INSERT INTO schema_version (version)
+                    SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM schema_version);",
+            )?;
+            let current = schema_version(&mut *client)?;
+            if current > latest_version() {
+                return Err(StoreError::Backend(format!(
+                    "Database schema version {} is newer than version {} of this binary",
+                    current,
+                    latest_version()
+                )));
+            }
+            let mut applied = Vec::new();
+            for migration in MIGRATIONS
+                .iter()
+                .filter(|migration| migration.version > current)
+            {
+                apply(&mut client, migration).map_err(|e| {
+                    StoreError::Backend(format!("Migration {} failed: {}", migration.version, e))
+                })?;
+                applied.push(migration.version);
+            }
+            Ok(applied)
+        })();
+        client
+            .execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK])
+            .map_err(|e| e.to_string())?;
+        applied.map_err(|e| e.to_string())
+    }
+
+    fn schema_version(&self) -> Result<u32> {
+        Ok(schema_version(&mut *self.connection()?)?)
+    }
+
+    fn user_id(&self, email: &str) -> Result<Option<String>> {
+        let id = self
+            .connection()?
+            .query_opt(
+                "SELECT id FROM users WHERE email = $1",
+                &[&normalize_email(email)],
+            )?
+            .map(|row| row.get(0));
+        Ok(id)
+    }
+
+    fn user_email(&self, user_id: &str) -> Result<Option<String>> {
+        let email = self
+            .connection()?
+            .query_opt("SELECT email FROM users WHERE id = $1", &[&user_id])?
+            .map(|row| row.get(0));
+        Ok(email)
+    }
+
+    fn user_password_hash(&self, user_id: &str) -> Result<Option<String>> {
+        let password_hash = self
+            .connection()?
+            .query_opt("SELECT password_hash FROM users WHERE id = $1", &[&user_id])?
+            .map(|row| row.get(0));
+        Ok(password_hash)
+    }
+
+    fn user_rehash_password(&self, user_id: &str, password_hash: &str) -> Result<()> {
+        self.connection()?.execute(
+            "UPDATE users SET password_hash = $1 WHERE id = $2",
+            &[&password_hash, &user_id],
+        )?;
+        Ok(())
+    }
+
+    fn user_register(
+        &self,
+        email: &str,
+        password_hash: &str,
+        kdf: Option<&KdfParams>,
+    ) -> Result<String> {
+        let mut conn = self.connection()?;
+        let mut tx = conn.transaction()?;
+        let user_id = Uuid::new_v4().to_string();
+        tx.execute(
+            "INSERT INTO users (id, email, password_hash, security_stamp, email_verified)
+             VALUES ($1, $2, $3, $4, FALSE)",
+            &[
+                &user_id,
+                &normalize_email(email),
+                &password_hash,
+                &Uuid::new_v4().to_string(),
+            ],
+        )?;
+        if let Some(kdf) = kdf {
+            set_kdf(&mut tx, &user_id, kdf)?;
+        }
+        tx.commit()?;
+        Ok(user_id)
+    }
+
+    fn user_kdf(&self, user_id: &str) -> Result<Option<KdfParams>> {
+        let kdf = self
+            .connection()?
+            .query_opt(
+                "SELECT kdf_algorithm, kdf_iterations, kdf_memory, kdf_parallelism, kdf_salt
+                 FROM users WHERE id = $1",
+                &[&user_id],
+            )?
+            .map(|row| match row.get::<_, Option<String>>(0) {
+                Some(algorithm) => KdfParams {
+                    algorithm,
+                    iterations: row.get::<_, i64>(1) as u32,
+                    memory: row.get::<_, Option<i64>>(2).map(|memory| memory as u32),
+                    parallelism: row
+                        .get::<_, Option<i64>>(3)
+                        .map(|parallelism| parallelism as u32),
+                    salt: row.get(4),
+                },
+                None => KdfParams::default(),
+            });
+        Ok(kdf)
+    }
+
+    fn user_email_verified(&self, user_id: &str) -> Result<Option<bool>> {
+        let verified = self
+            .connection()?
+            .query_opt(
+                "SELECT email_verified FROM users WHERE id = $1",
+                &[&user_id],
+            )?
+            .map(|row| row.get(0));
+        Ok(verified)
+    }
+
+    fn user_verify_email(&self, user_id: &str) -> Result<bool> {
+        let updated = self.connection()?.execute(
+            "UPDATE users SET email_verified = TRUE WHERE id = $1",
+            &[&user_id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    fn user_security_stamp(&self, user_id: &str) -> Result<Option<String>> {
+        let stamp = self
+            .connection()?
+            .query_opt(
+                "SELECT security_stamp FROM users WHERE id = $1",
+                &[&user_id],
+            )?
+            .map(|row| row.get(0));
+        Ok(stamp)
+    }
+
+    fn user_changepwd(
+        &self,
+        user_id: &str,
+        password_hash: &str,
+        kdf: Option<&KdfParams>,
+        current_nonce: &str,
+    ) -> Result<()> {
+        let mut conn = self.connection()?;
+        let mut tx = conn.transaction()?;
+        tx.execute(
+            "UPDATE users SET password_hash = $1 WHERE id = $2",
+            &[&password_hash, &user_id],
+        )?;
+        if let Some(kdf) = kdf {
+            set_kdf(&mut tx, user_id, kdf)?;
+        }
+        rotate_security_stamp(&mut tx, user_id, Some(current_nonce))?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn user_change_email(
+        &self,
+        user_id: &str,
+        new_email: &str,
+        password_hash: Option<&str>,
+        kdf: Option<&KdfParams>,
+        current_nonce: &str,
+    ) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let mut tx = conn.transaction()?;
+        let new_email = normalize_email(new_email);
+        let taken: bool = tx
+            .query_one(
+                "SELECT EXISTS(SELECT 1 FROM users WHERE email = $1)",
+                &[&new_email],
+            )?
+            .get(0);
+        if taken {
+            return Ok(false);
+        }
+        let old_email: String = tx
+            .query_one(
+                "SELECT email FROM users WHERE id = $1 FOR UPDATE",
+                &[&user_id],
+            )?
+            .get(0);
+        rotate_security_stamp(&mut tx, user_id, Some(current_nonce))?;
+        tx.execute(
+            "UPDATE users SET email = $1, email_verified = TRUE WHERE id = $2",
+            &[&new_email, &user_id],
+        )?;
+        tx.execute(
+            "DELETE FROM webauthn_challenges WHERE user_id = $1",
+            &[&user_id],
+        )?;
+        tx.execute(
+            "DELETE FROM login_failures WHERE kind = 'email' AND subject = $1",
+            &[&old_email],
+        )?;
+        if let Some(password_hash) = password_hash {
+            tx.execute(
+                "UPDATE users SET password_hash = $1 WHERE id = $2",
+                &[&password_hash, &user_id],
+            )?;
+        }
+        if let Some(kdf) = kdf {
+            set_kdf(&mut tx, user_id, kdf)?;
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn user_logout_all(&self, user_id: &str) -> Result<()> {
+        let mut conn = self.connection()?;
+        let mut tx = conn.transaction()?;
+        rotate_security_stamp(&mut tx, user_id, None)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn user_delete(&self, user_id: &str) -> Result<()> {
+        let mut conn = self.connection()?;
+        let mut tx = conn.transaction()?;
+        rotate_security_stamp(&mut tx, user_id, None)?;
+        delete_totp(&mut tx, user_id)?;
+        for table in [
+            "webauthn_credentials",
+            "webauthn_challenges",
+            "vault_history",
+            "vault_items",
+            "attachments",
+        ] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE user_id = $1", table),
+                &[&user_id],
+            )?;
+        }
+        tx.execute("DELETE FROM users WHERE id = $1", &[&user_id])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn data_get(&self, user_id: &str) -> Result<Vault> {
+        let row = self.connection()?.query_one(
+            "SELECT encrypted_data, revision FROM users WHERE id = $1",
+            &[&user_id],
+        )?;
+        Ok(Vault {
+            encrypted_data: row.get(0),
+            revision: row.get::<_, i64>(1) as u64,
+        })
+    }
+
+    fn data_update(
+        &self,
+        user_id: &str,
+        encrypted_data: &str,
+        base_revision: Option<u64>,
+        session_id: &str,
+        now: usize,
+    ) -> Result<VaultUpdate> {
+        let mut conn = self.connection()?;
+        let mut tx = conn.transaction()?;
+        // Concurrent updates wait for the row lock of the first, then see its revision
+        let updated = tx.query_opt(
+            "UPDATE users SET encrypted_data = $1, revision = revision + 1
+             WHERE id = $2 AND ($3::BIGINT IS NULL OR revision = $3) RETURNING revision",
+            &[
+                &encrypted_data,
+                &user_id,
+                &base_revision.map(|revision| revision as i64),
+            ],
+        )?;
+        let status = match updated {
+            Some(row) => {
+                let revision: i64 = row.get(0);
+                tx.execute(
+                    "INSERT INTO vault_history
+                     (user_id, revision, encrypted_data, size, created_at, session_id, device_name)
+                     VALUES ($1, $2, $3, $4, $5, $6,
+                        (SELECT device_name FROM sessions WHERE nonce = $6))",
+                    &[
+                        &user_id,
+                        &revision,
+                        &encrypted_data,
+                        &(encrypted_data.len() as i64),
+                        &(now as i64),
+                        &session_id,
+                    ],
+                )?;
+                VaultUpdate::Updated {
+                    revision: revision as u64,
+                }
+            }
+            None => VaultUpdate::Conflict {
+                revision: tx
+                    .query_one("SELECT revision FROM users WHERE id = $1", &[&user_id])?
+                    .get::<_, i64>(0) as u64,
+            },
+        };
+        tx.commit()?;
+        Ok(status)
+    }
+
+    fn vault_history_list(&self, user_id: &str) -> Result<Vec<VaultSnapshot>> {
+        let snapshots = self
+            .connection()?
+            .query(
+                "SELECT revision, size, created_at, session_id, device_name
+                 FROM vault_history WHERE user_id = $1 ORDER BY revision DESC",
+                &[&user_id],
+            )?
+            .iter()
+            .map(|row| VaultSnapshot {
+                revision: row.get::<_, i64>(0) as u64,
+                size: row.get::<_, i64>(1) as usize,
+                created_at: row.get::<_, i64>(2) as usize,
+                session_id: row.get(3),
+                device_name: row.get(4),
+            })
+            .collect();
+        Ok(snapshots)
+    }
+
+    fn vault_history_get(&self, user_id: &str, revision: u64) -> Result<Option<Vault>> {
+        let vault = self
+            .connection()?
+            .query_opt(
+                "SELECT encrypted_data, revision FROM vault_history
+                 WHERE user_id = $1 AND revision = $2",
+                &[&user_id, &(revision as i64)],
+            )?
+            .map(|row| Vault {
+                encrypted_data: row.get(0),
+                revision: row.get::<_, i64>(1) as u64,
+            });
+        Ok(vault)
+    }
+
+    fn vault_history_prune(
+        &self,
+        user_id: &str,
+        keep: usize,
+        created_before: usize,
+    ) -> Result<usize> {
+        let deleted = self.connection()?.execute(
+            "DELETE FROM vault_history WHERE user_id = $1 AND (created_at < $3
+                OR revision NOT IN (SELECT revision FROM vault_history
+                    WHERE user_id = $1 ORDER BY revision DESC LIMIT $2))",
+            &[&user_id, &(keep as i64), &(created_before as i64)],
+        )?;
+        Ok(deleted as usize)
+    }
+
+    fn vault_history_cleanup(&self, created_before: usize) -> Result<usize> {
+        let deleted = self.connection()?.execute(
+            "DELETE FROM vault_history WHERE created_at < $1",
+            &[&(created_before as i64)],
+        )?;
+        Ok(deleted as usize)
+    }
+
+    fn items_changed(&self, user_id: &str, since: u64) -> Result<(u64, Vec<VaultItem>)> {
+        let mut conn = self.connection()?;
+        let revision: i64 = conn
+            .query_one(
+                "SELECT items_revision FROM users WHERE id = $1",
+                &[&user_id],
+            )?
+            .get(0);
+        // Bounded by the revision read first, as changes may commit in between
+        let items = conn
+            .query(
+                "SELECT id, revision, encrypted_data FROM vault_items
+                 WHERE user_id = $1 AND revision > $2 AND revision <= $3
+                 ORDER BY revision, id",
+                &[&user_id, &(since as i64), &revision],
+            )?
+            .iter()
+            .map(|row| VaultItem {
+                id: row.get(0),
+                revision: row.get::<_, i64>(1) as u64,
+                encrypted_data: row.get(2),
+            })
+            .collect();
+        Ok((revision as u64, items))
+    }
+
+    fn items_apply(&self, user_id: &str, changes: &[ItemChange]) -> Result<ItemsUpdate> {
+        let mut conn = self.connection()?;
+        let mut tx = conn.transaction()?;
+        // The row lock on the account serializes changes of the same vault
+        let revision: i64 = tx
+            .query_one(
+                "UPDATE users SET items_revision = items_revision + 1 WHERE id = $1
+                 RETURNING items_revision",
+                &[&user_id],
+            )?
+            .get(0);
+        let mut ids = Vec::new();
+        for change in changes {
+            let current: i64 = tx
+                .query_opt(
+                    "SELECT revision FROM vault_items WHERE user_id = $1 AND id = $2",
+                    &[&user_id, &change.id],
+                )?
+                .map_or(0, |row| row.get(0));
+            if current as u64 != change.base_revision {
+                ids.push(change.id.clone());
+            }
+        }
+        if !ids.is_empty() {
+            // Dropping the transaction rolls back the new revision
+            return Ok(ItemsUpdate::Conflict {
+                revision: revision as u64 - 1,
+                ids,
+            });
+        }
+        let mut attachments = Vec::new();
+        for change in changes {
+            tx.execute(
+                "INSERT INTO vault_items (user_id, id, revision, encrypted_data)
+                 VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (user_id, id) DO UPDATE
+                 SET revision = excluded.revision, encrypted_data = excluded.encrypted_data",
+                &[&user_id, &change.id, &revision, &change.encrypted_data],
+            )?;
+            if change.encrypted_data.is_none() {
+                let removed = tx.query(
+                    "DELETE FROM attachments WHERE user_id = $1 AND item_id = $2 RETURNING id",
+                    &[&user_id, &change.id],
+                )?;
+                attachments.extend(removed.iter().map(|row| row.get::<_, String>(0)));
+            }
+        }
+        tx.commit()?;
+        Ok(ItemsUpdate::Applied {
+            revision: revision as u64,
+            attachments,
+        })
+    }
+
+    fn attachment_create(
+        &self,
+        user_id: &str,
+        attachment: &Attachment,
+        quota: u64,
+    ) -> Result<AttachmentCreate> {
+        let mut conn = self.connection()?;
+        let mut tx = conn.transaction()?;
+        // The row lock on the account serializes uploads starting at the same time
+        tx.execute("SELECT 1 FROM users WHERE id = $1 FOR UPDATE", &[&user_id])?;
+        let item: Option<bool> = tx
+            .query_opt(
+                "SELECT encrypted_data IS NOT NULL FROM vault_items WHERE user_id = $1 AND id = $2",
+                &[&user_id, &attachment.item_id],
+            )?
+            .map(|row| row.get(0));
+        if item != Some(true) {
+            return Ok(AttachmentCreate::ItemNotFound);
+        }
+        let usage: i64 = tx
+            .query_one(
+                "SELECT COALESCE(SUM(size), 0)::BIGINT FROM attachments WHERE user_id = $1",
+                &[&user_id],
+            )?
+            .get(0);
+        if usage as u64 + attachment.size > quota {
+            return Ok(AttachmentCreate::QuotaExceeded {
+                usage: usage as u64,
+            });
+        }
+        tx.execute(
+            "INSERT INTO attachments (user_id, id, item_id, size, received, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+            &[
+                &user_id,
+                &attachment.id,
+                &attachment.item_id,
+                &(attachment.size as i64),
+                &(attachment.received as i64),
+                &(attachment.created_at as i64),
+            ],
+        )?;
+        tx.commit()?;
+        Ok(AttachmentCreate::Created)
+    }
+
+    fn attachment_get(&self, user_id: &str, id: &str) -> Result<Option<Attachment>> {
+        let attachment = self
+            .connection()?
+            .query_opt(
+                "SELECT id, item_id, size, received, created_at FROM attachments
+                 WHERE user_id = $1 AND id = $2",
+                &[&user_id, &id],
+            )?
+            .map(|row| attachment_from_row(&row));
+        Ok(attachment)
+    }
+
+    fn attachments_list(&self, user_id: &str) -> Result<Vec<Attachment>> {
+        let attachments = self
+            .connection()?
+            .query(
+                "SELECT id, item_id, size, received, created_at FROM attachments
+                 WHERE user_id = $1 ORDER BY created_at, id",
+                &[&user_id],
+            )?
+            .iter()
+            .map(attachment_from_row)
+            .collect();
+        Ok(attachments)
+    }
+
+    fn attachment_received(&self, user_id: &str, id: &str, received: u64) -> Result<()> {
+        self.connection()?.execute(
+            "UPDATE attachments SET received = $3 WHERE user_id = $1 AND id = $2",
+            &[&user_id, &id, &(received as i64)],
+        )?;
+        Ok(())
+    }
+
+    fn attachment_delete(&self, user_id: &str, id: &str) -> Result<bool> {
+        let deleted = self.connection()?.execute(
+            "DELETE FROM attachments WHERE user_id = $1 AND id = $2",
+            &[&user_id, &id],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    fn attachments_cleanup(&self, created_before: usize) -> Result<Vec<(String, String)>> {
+        let removed = self
+            .connection()?
+            .query(
+                "DELETE FROM attachments WHERE received < size AND created_at < $1
+                 RETURNING user_id, id",
+                &[&(created_before as i64)],
+            )?
+            .iter()
+            .map(|row| (row.get(0), row.get(1)))
+            .collect();
+        Ok(removed)
+    }
+
+    fn refresh_token_store(
+        &self,
+        token_hash: &str,
+        user_id: &str,
+        family_id: &str,
+        expires_at: usize,
+    ) -> Result<()> {
+        self.connection()?.execute(
+            "INSERT INTO refresh_tokens (token_hash, user_id, family_id, expires_at)
+             VALUES ($1, $2, $3, $4)",
+            &[&token_hash, &user_id, &family_id, &(expires_at as i64)],
+        )?;
+        Ok(())
+    }
+
+    fn refresh_token_consume(&self, token_hash: &str, now: usize) -> Result<RefreshTokenStatus> {
+        let mut conn = self.connection()?;
+        let mut tx = conn.transaction()?;
+        // The row stays locked until the commit, so a token is consumed exactly once
+        let row = tx
+            .query_opt(
+                "SELECT user_id, family_id, expires_at, used FROM refresh_tokens
+                 WHERE token_hash = $1 FOR UPDATE",
+                &[&token_hash],
+            )?
+            .map(|row| {
+                (
+                    row.get::<_, String>(0),
+                    row.get::<_, String>(1),
+                    row.get::<_, i64>(2) as usize,
+                    row.get::<_, bool>(3),
+                )
+            });
+
+        let status = match row {
+            None => RefreshTokenStatus::Unknown,
+            Some((user_id, family_id, _, true)) => {
+                tx.execute(
+                    "DELETE FROM refresh_tokens WHERE family_id = $1",
+                    &[&family_id],
+                )?;
+                RefreshTokenStatus::Reused { user_id, family_id }
+            }
+            Some((_, _, expires_at, false)) if expires_at <= now => RefreshTokenStatus::Expired,
+            Some((user_id, family_id, _, false)) => {
+                tx.execute(
+                    "UPDATE refresh_tokens SET used = TRUE WHERE token_hash = $1",
+                    &[&token_hash],
+                )?;
+                RefreshTokenStatus::Valid { user_id, family_id }
+            }
+        };
+        tx.commit()?;
+        Ok(status)
+    }
+
+    fn refresh_tokens_cleanup(&self, now: usize) -> Result<usize> {
+        let deleted = self.connection()?.execute(
+            "DELETE FROM refresh_tokens WHERE expires_at <= $1",
+            &[&(now as i64)],
+        )?;
+        Ok(deleted as usize)
+    }
+
+    fn token_revoke(&self, nonce: &str, expires_at: usize) -> Result<()> {
+        self.connection()?.execute(
+            "INSERT INTO revoked_tokens (nonce, expires_at) VALUES ($1, $2)
+             ON CONFLICT (nonce) DO UPDATE
+                SET expires_at = GREATEST(revoked_tokens.expires_at, excluded.expires_at)",
+            &[&nonce, &(expires_at as i64)],
+        )?;
+        Ok(())
+    }
+
+    fn token_is_revoked(&self, nonce: &str) -> Result<bool> {
+        let revoked = self
+            .connection()?
+            .query_one(
+                "SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE nonce = $1)",
+                &[&nonce],
+            )?
+            .get(0);
+        Ok(revoked)
+    }
+
+    fn revoked_tokens_cleanup(&self, now: usize) -> Result<usize> {
+        let deleted = self.connection()?.execute(
+            "DELETE FROM revoked_tokens WHERE expires_at <= $1",
+            &[&(now as i64)],
+        )?;
+        Ok(deleted as usize)
+    }
+
+    fn session_create(&self, user_id: &str, session: &Session) -> Result<()> {
+        self.connection()?.execute(
+            "INSERT INTO sessions (nonce, user_id, device_name, user_agent, ip, created_at, last_seen)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            &[
+                &session.nonce,
+                &user_id,
+                &session.device_name,
+                &session.user_agent,
+                &session.ip,
+                &(session.created_at as i64),
+                &(session.last_seen as i64),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn session_touch(&self, nonce: &str, now: usize) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let stale = now.saturating_sub(SESSION_TOUCH_INTERVAL) as i64;
+        let updated = conn.execute(
+            "UPDATE sessions SET last_seen = $1 WHERE nonce = $2 AND last_seen < $3",
+            &[&(now as i64), &nonce, &stale],
+        )?;
+        if updated > 0 {
+            return Ok(true);
+        }
+        // Nothing to write for a recent last_seen, the session may still exist
+        let exists: bool = conn
+            .query_one(
+                "SELECT EXISTS(SELECT 1 FROM sessions WHERE nonce = $1)",
+                &[&nonce],
+            )?
+            .get(0);
+        Ok(exists)
+    }
+
+    fn sessions_list(&self, user_id: &str) -> Result<Vec<Session>> {
+        let sessions = self
+            .connection()?
+            .query(
+                "SELECT nonce, device_name, user_agent, ip, created_at, last_seen
+                 FROM sessions WHERE user_id = $1 ORDER BY created_at",
+                &[&user_id],
+            )?
+            .iter()
+            .map(session_from_row)
+            .collect();
+        Ok(sessions)
+    }
+
+    fn session_delete(&self, user_id: &str, nonce: &str) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let mut tx = conn.transaction()?;
+        let deleted = tx.execute(
+            "DELETE FROM sessions WHERE user_id = $1 AND nonce = $2",
+            &[&user_id, &nonce],
+        )?;
+        tx.execute(
+            "DELETE FROM refresh_tokens WHERE user_id = $1 AND family_id = $2",
+            &[&user_id, &nonce],
+        )?;
+        tx.commit()?;
+        Ok(deleted > 0)
+    }
+
+    fn sessions_delete_others(&self, user_id: &str, keep_nonce: &str) -> Result<Vec<String>> {
+        let mut conn = self.connection()?;
+        let mut tx = conn.transaction()?;
+        let nonces = tx
+            .query(
+                "DELETE FROM sessions WHERE user_id = $1 AND nonce != $2 RETURNING nonce",
+                &[&user_id, &keep_nonce],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect();
+        tx.execute(
+            "DELETE FROM refresh_tokens WHERE user_id = $1 AND family_id != $2",
+            &[&user_id, &keep_nonce],
+        )?;
+        tx.commit()?;
+        Ok(nonces)
+    }
+
+    fn totp_begin(&self, user_id: &str, secret: &str) -> Result<bool> {
+        let changed = self.connection()?.execute(
+            "INSERT INTO totp (user_id, secret) VALUES ($1, $2)
+             ON CONFLICT (user_id) DO UPDATE SET secret = excluded.secret, last_step = 0
+             WHERE totp.enabled = FALSE",
+            &[&user_id, &secret],
+        )?;
+        Ok(changed == 1)
+    }
+
+    fn totp_get(&self, user_id: &str) -> Result<Option<Totp>> {
+        let totp = self
+            .connection()?
+            .query_opt(
+                "SELECT secret, enabled, last_step FROM totp WHERE user_id = $1",
+                &[&user_id],
+            )?
+            .map(|row| Totp {
+                secret: row.get(0),
+                enabled: row.get(1),
+                last_step: row.get::<_, i64>(2) as u64,
+            });
+        Ok(totp)
+    }
+
+    fn totp_use_step(&self, user_id: &str, step: u64) -> Result<bool> {
+        let changed = self.connection()?.execute(
+            "UPDATE totp SET last_step = $1 WHERE user_id = $2 AND last_step < $1",
+            &[&(step as i64), &user_id],
+        )?;
+        Ok(changed == 1)
+    }
+
+    fn totp_enable(
+        &self,
+        user_id: &str,
+        step: u64,
+        recovery_code_hashes: &[String],
+    ) -> Result<bool> {
+        let mut conn = self.connection()?;
+        let mut tx = conn.transaction()?;
+        let changed = tx.execute(
+            "UPDATE totp SET enabled = TRUE, last_step = $1
+             WHERE user_id = $2 AND enabled = FALSE AND last_step < $1",
+            &[&(step as i64), &user_id],
+        )?;
+        if changed == 1 {
+            tx.execute("DELETE FROM recovery_codes WHERE user_id = $1", &[&user_id])?;
+            for code_hash in recovery_code_hashes {
+                tx.execute(
+                    "INSERT INTO recovery_codes (code_hash, user_id) VALUES ($1, $2)",
+                    &[code_hash, &user_id],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(changed == 1)
+    }
+
+    fn totp_disable(&self, user_id: &str) -> Result<()> {
+        let mut conn = self.connection()?;
+        let mut tx = conn.transaction()?;
+        delete_totp(&mut tx, user_id)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn recovery_code_consume(&self, user_id: &str, code_hash: &str) -> Result<bool> {
+        let changed = self.connection()?.execute(
+            "DELETE FROM recovery_codes WHERE code_hash = $1 AND user_id = $2",
+            &[&code_hash, &user_id],
+        )?;
+        Ok(changed == 1)
+    }
+
+    fn webauthn_credential_add(
+        &self,
+        user_id: &str,
+        credential: &WebauthnCredential,
+    ) -> Result<bool> {
+        let changed = self.connection()?.execute(
+            "INSERT INTO webauthn_credentials
+             (credential_id, user_id, name, public_key, algorithm, sign_count, created_at)
+             VALUES ($1, $2, $3, $4, $5, $6, $7)
+             ON CONFLICT (credential_id) DO NOTHING",
+            &[
+                &credential.credential_id,
+                &user_id,
+                &credential.name,
+                &credential.public_key,
+                &credential.algorithm,
+                &i64::from(credential.sign_count),
+                &(credential.created_at as i64),
+            ],
+        )?;
+        Ok(changed == 1)
+    }
+
+    fn webauthn_credentials_list(&self, user_id: &str) -> Result<Vec<WebauthnCredential>> {
+        let credentials = self
+            .connection()?
+            .query(
+                "SELECT credential_id, name, public_key, algorithm, sign_count, created_at, last_used
+                 FROM webauthn_credentials WHERE user_id = $1 ORDER BY created_at",
+                &[&user_id],
+            )?
+            .iter()
+            .map(webauthn_credential_from_row)
+            .collect();
+        Ok(credentials)
+    }
+
+    fn webauthn_credential_get(
+        &self,
+        user_id: &str,
+        credential_id: &str,
+    ) -> Result<Option<WebauthnCredential>> {
+        let credential = self
+            .connection()?
+            .query_opt(
+                "SELECT credential_id, name, public_key, algorithm, sign_count, created_at, last_used
+                 FROM webauthn_credentials WHERE credential_id = $1 AND user_id = $2",
+                &[&credential_id, &user_id],
+            )?
+            .as_ref()
+            .map(webauthn_credential_from_row);
+        Ok(credential)
+    }
+
+    fn webauthn_credential_used(
+        &self,
+        credential_id: &str,
+        sign_count: u32,
+        now: usize,
+    ) -> Result<bool> {
+        let changed = self.connection()?.execute(
+            "UPDATE webauthn_credentials SET sign_count = $1, last_used = $2
+             WHERE credential_id = $3 AND (sign_count < $1 OR $1 = 0)",
+            &[&i64::from(sign_count), &(now as i64), &credential_id],
+        )?;
+        Ok(changed == 1)
+    }
+
+    fn webauthn_credential_delete(&self, user_id: &str, credential_id: &str) -> Result<bool> {
+        let changed = self.connection()?.execute(
+            "DELETE FROM webauthn_credentials WHERE credential_id = $1 AND user_id = $2",
+            &[&credential_id, &user_id],
+        )?;
+        Ok(changed == 1)
+    }
+
+    fn webauthn_challenge_store(&self, challenge: &str, ceremony: &WebauthnCeremony) -> Result<()> {
+        self.connection()?.execute(
+            "INSERT INTO webauthn_challenges (challenge, user_id, purpose, pending_nonce, expires_at)
+             VALUES ($1, $2, $3, $4, $5)",
+            &[
+                &challenge,
+                &ceremony.user_id,
+                &ceremony.purpose,
+                &ceremony.pending_nonce,
+                &(ceremony.expires_at as i64),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn webauthn_challenge_consume(
+        &self,
+        challenge: &str,
+        now: usize,
+    ) -> Result<Option<WebauthnCeremony>> {
+        let ceremony = self
+            .connection()?
+            .query_opt(
+                "DELETE FROM webauthn_challenges WHERE challenge = $1
+                 RETURNING user_id, purpose, pending_nonce, expires_at",
+                &[&challenge],
+            )?
+            .map(|row| WebauthnCeremony {
+                user_id: row.get(0),
+                purpose: row.get(1),
+                pending_nonce: row.get(2),
+                expires_at: row.get::<_, i64>(3) as usize,
+            });
+        Ok(ceremony.filter(|ceremony| ceremony.expires_at > now))
+    }
+
+    fn webauthn_challenges_cleanup(&self, now: usize) -> Result<usize> {
+        let deleted = self.connection()?.execute(
+            "DELETE FROM webauthn_challenges WHERE expires_at <= $1",
+            &[&(now as i64)],
+        )?;
+        Ok(deleted as usize)
+    }
+
+    fn login_failures_get(&self, kind: &str, subject: &str) -> Result<Option<LoginFailures>> {
+        let failures = self
+            .connection()?
+            .query_opt(
+                "SELECT kind, subject, failures, last_failure, locked_until
+                 FROM login_failures WHERE kind = $1 AND subject = $2",
+                &[&kind, &subject],
+            )?
+            .as_ref()
+            .map(login_failures_from_row);
+        Ok(failures)
+    }
+
+    fn login_failure_record(
+        &self,
+        kind: &str,
+        subject: &str,
+        now: usize,
+        window: usize,
+    ) -> Result<u32> {
+        let failures: i64 = self
+            .connection()?
+            .query_one(
+                "INSERT INTO login_failures (kind, subject, failures, last_failure)
+                 VALUES ($1, $2, 1, $3)
+                 ON CONFLICT (kind, subject) DO UPDATE SET
+                    failures = CASE WHEN login_failures.last_failure + $4 < $3
+                        THEN 1 ELSE login_failures.failures + 1 END,
+                    last_failure = $3
+                 RETURNING failures",
+                &[&kind, &subject, &(now as i64), &(window as i64)],
+            )?
+            .get(0);
+        Ok(failures as u32)
+    }
+
+    fn login_lock(&self, kind: &str, subject: &str, locked_until: usize) -> Result<()> {
+        self.connection()?.execute(
+            "UPDATE login_failures SET locked_until = $1 WHERE kind = $2 AND subject = $3",
+            &[&(locked_until as i64), &kind, &subject],
+        )?;
+        Ok(())
+    }
+
+    fn login_failures_reset(&self, kind: &str, subject: &str) -> Result<bool> {
+        let changed = self.connection()?.execute(
+            "DELETE FROM login_failures WHERE kind = $1 AND subject = $2",
+            &[&kind, &subject],
+        )?;
+        Ok(changed == 1)
+    }
+
+    fn login_failures_list(&self) -> Result<Vec<LoginFailures>> {
+        let failures = self
+            .connection()?
+            .query(
+                "SELECT kind, subject, failures, last_failure, locked_until
+                 FROM login_failures ORDER BY locked_until DESC, failures DESC",
+                &[],
+            )?
+            .iter()
+            .map(login_failures_from_row)
+            .collect();
+        Ok(failures)
+    }
+
+    fn login_failures_cleanup(&self, now: usize, window: usize) -> Result<usize> {
+        let deleted = self.connection()?.execute(
+            "DELETE FROM login_failures WHERE last_failure + $2 < $1 AND locked_until <= $1",
+            &[&(now as i64), &(window as i64)],
+        )?;
+        Ok(deleted as usize)
+    }
+}
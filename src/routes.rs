@@ -1,34 +1,118 @@
-use actix_web::{get, post, web, HttpMessage, HttpRequest, HttpResponse, Responder};
+use actix_web::{
+    get,
+    http::{header, StatusCode},
+    post, web, HttpMessage, HttpRequest, HttpResponse, Responder,
+};
 use actix_web_httpauth::extractors::bearer::BearerAuth;
-use log::{debug, error, info};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, warn};
+use sha2::Sha256;
+use std::{
+    env,
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+};
+use tokio::io::AsyncWriteExt;
 use utoipa::OpenApi;
+use uuid::Uuid;
 use validator::Validate;
 
-use crate::auth::{Claims, JwtAuth};
-use crate::db::*;
+use crate::attachments::{attachment_quota, BlobStore};
+use crate::auth::{
+    check_stream_ticket, check_token, current_timestamp, email_verification_ttl,
+    generate_refresh_token, hash_refresh_token, refresh_token_ttl, Claims, JwtAuth, TokenCheck,
+    ACCESS_TOKEN_TTL, PENDING_TOKEN_TTL, STREAM_TICKET_TTL,
+};
+use crate::history::prune_history;
+use crate::mailer::Mailer;
 use crate::models::*;
+use crate::notify::{event_stream, websocket, Notifier, StreamContext};
+use crate::password::{hash_password, verify_dummy, verify_password, PasswordCheck};
+use crate::store::{
+    is_busy, normalize_email, Attachment, AttachmentCreate, Database, ItemChange, ItemsUpdate,
+    RefreshTokenStatus, Session, StoreError, VaultStore, VaultUpdate, WebauthnCeremony,
+    WebauthnCredential,
+};
+use crate::throttle::{address_failed, login_failed, login_retry_after, login_succeeded};
+use crate::totp::{
+    generate_recovery_codes, generate_secret, hash_recovery_code, otpauth_uri, verify_code,
+};
+use crate::webauthn::{
+    client_data_challenge, decode, generate_challenge, rp_id, verify_assertion,
+    verify_registration, COSE_EDDSA, COSE_ES256,
+};
 
 // API Documentation struct
 #[derive(OpenApi)]
 #[openapi(
-    paths(route_health, route_email, route_login, route_register, route_changepwd, route_logout, route_delete, route_fetch, route_update),
+    paths(route_health, route_jwks, route_email, route_login, route_login_2fa, route_webauthn_login_begin, route_webauthn_login, route_register, route_verify_email, route_verify_email_resend, route_refresh, route_changepwd, route_change_email, route_change_email_confirm, route_logout, route_logout_all, route_delete, route_sessions, route_session_revoke, route_sessions_revoke_others, route_totp_setup, route_totp_confirm, route_totp_disable, route_webauthn_register_begin, route_webauthn_register, route_webauthn_credentials, route_webauthn_credential_delete, route_fetch, route_update, route_history, route_history_get, route_history_restore, route_changes, route_changes_apply, route_notifications, route_notifications_ticket, route_attachment_create, route_attachments, route_attachment_get, route_attachment_upload, route_attachment_download, route_attachment_delete),
     tags(
         (name = "health", description = "Health check endpoints"),
         (name = "auth", description = "Authentication Endpoints"),
         (name = "accounts", description = "Account management endpoints"),
         (name = "sync", description = "Vault synchronization endpoints")
     ),
-    components(schemas(PreLoginRequest, LoginRequest, LoginResponse, ChangeRequest, UpdateRequest)),
+    components(schemas(PreLoginRequest, KdfParams, LoginRequest, LoginResponse, RefreshRequest, ChangeRequest, ChangeEmailRequest, ConfirmEmailChangeRequest, UpdateRequest, RestoreRequest, DataResponse, RevisionResponse, HistoryEntryResponse, ItemResponse, ChangesResponse, ItemChangeRequest, ItemChangesRequest, ItemConflictResponse, StreamTicketResponse, AttachmentCreateRequest, AttachmentResponse, AttachmentListResponse, QuotaExceededResponse, SessionResponse, TwoFactorRequiredResponse, TwoFactorLoginRequest, TotpSetupResponse, TotpCodeRequest, RecoveryCodesResponse, WebauthnCreationOptions, WebauthnRequestOptions, WebauthnRegisterRequest, WebauthnLoginBeginRequest, WebauthnLoginRequest, WebauthnCredentialResponse)),
     modifiers(&SecurityAddon)
 )]
 pub struct ApiDoc;
 
-// Helper to handle common database errors
-fn handle_db_error(e: impl std::fmt::Display) -> HttpResponse {
+// Helper to handle common database errors, an overloaded database asks to retry later
+fn handle_db_error(e: &StoreError) -> HttpResponse {
+    if is_busy(e) {
+        warn!("Database is busy: {}", e);
+        return HttpResponse::ServiceUnavailable()
+            .insert_header((header::RETRY_AFTER, "1"))
+            .finish();
+    }
     error!("Database error: {}", e);
     HttpResponse::InternalServerError().finish()
 }
 
+// Helper to handle password hashing errors
+fn handle_hash_error(e: impl std::fmt::Display) -> HttpResponse {
+    error!("Password hashing error: {}", e);
+    HttpResponse::InternalServerError().finish()
+}
+
+// Argon2 takes tens to hundreds of milliseconds of CPU, so it runs on the blocking pool
+// in the queue of the store, and an overloaded server answers 503
+async fn hash_blocking(db: &Database, password_hash: &str) -> Result<String, HttpResponse> {
+    let password_hash = password_hash.to_string();
+    match db.run_blocking(move || hash_password(&password_hash)).await {
+        Ok(Ok(hashed)) => Ok(hashed),
+        Ok(Err(e)) => Err(handle_hash_error(e)),
+        Err(e) => Err(handle_db_error(&e)),
+    }
+}
+
+// Checks a password on the blocking pool. Without a stored hash it spends the time of a
+// real verification all the same, and fails
+async fn verify_blocking(
+    db: &Database,
+    password_hash: &str,
+    stored: Option<String>,
+) -> Result<PasswordCheck, HttpResponse> {
+    let password_hash = password_hash.to_string();
+    db.run_blocking(move || match stored {
+        Some(stored) => verify_password(&password_hash, &stored),
+        None => {
+            verify_dummy(&password_hash);
+            PasswordCheck::Invalid
+        }
+    })
+    .await
+    .map_err(|e| handle_db_error(&e))
+}
+
+// Helper to handle failures of attachment files
+fn handle_file_error(e: std::io::Error) -> HttpResponse {
+    error!("Attachment file error: {}", e);
+    HttpResponse::InternalServerError().finish()
+}
+
 // Helper to validate json format
 fn validate_format<T: Validate>(req_body: &web::Json<T>) -> Result<(), HttpResponse> {
     if req_body.validate().is_err() {
@@ -38,6 +122,405 @@ fn validate_format<T: Validate>(req_body: &web::Json<T>) -> Result<(), HttpRespo
     Ok(())
 }
 
+// Helper to issue an access token together with a rotated refresh token of the same family
+async fn issue_tokens(
+    db: &Database,
+    jwt_auth: &JwtAuth,
+    user_id: &str,
+    family_id: &str,
+) -> HttpResponse {
+    let id = user_id.to_string();
+    let stamp = match db.run(move |db| db.user_security_stamp(&id)).await {
+        Ok(Some(stamp)) => stamp,
+        Ok(None) => return HttpResponse::Unauthorized().finish(),
+        Err(e) => return handle_db_error(&e),
+    };
+    let token = match jwt_auth.generate_token_with_nonce(user_id, family_id, &stamp) {
+        Ok(token) => token,
+        Err(e) => {
+            error!("Failed to generate token: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let refresh_token = generate_refresh_token();
+    let token_hash = hash_refresh_token(&refresh_token);
+    let (id, family_id) = (user_id.to_string(), family_id.to_string());
+    let expires_at = current_timestamp() + refresh_token_ttl();
+    let stored = db
+        .run(move |db| db.refresh_token_store(&token_hash, &id, &family_id, expires_at))
+        .await;
+    match stored {
+        Ok(()) => HttpResponse::Ok().json(LoginResponse {
+            token,
+            refresh_token,
+        }),
+        Err(e) => handle_db_error(&e),
+    }
+}
+
+// In enumeration-resistant mode, responses do not reveal whether an account exists
+fn enumeration_protection() -> bool {
+    env::var("ENUMERATION_PROTECTION").is_ok_and(|val| val == "true" || val == "1")
+}
+
+// Key of the made-up KDF parameters of unknown addresses. It has to be the same across
+// restarts and instances, since real accounts keep their parameters
+fn enumeration_secret() -> &'static [u8] {
+    static SECRET: OnceLock<Vec<u8>> = OnceLock::new();
+    SECRET.get_or_init(|| {
+        match env::var("ENUMERATION_SECRET").or_else(|_| env::var("JWT_SECRET")) {
+            Ok(secret) => secret.into_bytes(),
+            Err(_) => {
+                warn!("ENUMERATION_SECRET is not set, unknown addresses get other KDF parameters after a restart");
+                rand::random::<[u8; 32]>().to_vec()
+            }
+        }
+    })
+}
+
+// Plausible KDF parameters for an address without an account, the same with every request.
+// Half are the defaults, as for the many accounts that never changed theirs. None has a
+// salt, like the default client that salts with the address, so a salt never marks a fake
+fn fake_kdf_params(email: &str) -> KdfParams {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(enumeration_secret()).expect("HMAC takes keys of any size");
+    mac.update(b"checkmail:");
+    mac.update(normalize_email(email).as_bytes());
+    let digest = mac.finalize().into_bytes();
+    let pick = |i: usize, choices: &[u32]| choices[digest[i] as usize % choices.len()];
+    match digest[0] % 4 {
+        0 | 1 => KdfParams::default(),
+        2 => KdfParams {
+            algorithm: "pbkdf2-sha256".to_string(),
+            iterations: pick(1, &[600_000, 650_000, 700_000, 800_000, 1_000_000]),
+            memory: None,
+            parallelism: None,
+            salt: None,
+        },
+        _ => KdfParams {
+            algorithm: "argon2id".to_string(),
+            iterations: pick(1, &[2, 3, 4]),
+            memory: Some(pick(2, &[65_536, 131_072, 262_144])),
+            parallelism: Some(pick(3, &[1, 2, 4])),
+            salt: None,
+        },
+    }
+}
+
+// Sync can be restricted to accounts that proved they own their address
+fn require_email_verification() -> bool {
+    env::var("REQUIRE_EMAIL_VERIFICATION").is_ok_and(|val| val == "true" || val == "1")
+}
+
+// Helper to reject vault access of unverified accounts, if verification is required
+async fn check_email_verified(db: &Database, user_id: &str) -> Result<(), HttpResponse> {
+    if !require_email_verification() {
+        return Ok(());
+    }
+    let id = user_id.to_string();
+    match db.run(move |db| db.user_email_verified(&id)).await {
+        Ok(Some(true)) => Ok(()),
+        Ok(_) => {
+            debug!("Vault access of unverified account: {}", user_id);
+            Err(HttpResponse::Forbidden().finish())
+        }
+        Err(e) => Err(handle_db_error(&e)),
+    }
+}
+
+// Helper to look up the current address of an authenticated account
+async fn account_email(db: &Database, user_id: &str) -> Result<String, HttpResponse> {
+    let id = user_id.to_string();
+    match db.run(move |db| db.user_email(&id)).await {
+        Ok(Some(email)) => Ok(email),
+        Ok(None) => Err(HttpResponse::Unauthorized().finish()),
+        Err(e) => Err(handle_db_error(&e)),
+    }
+}
+
+// Helper to accept a pending token of the second login step, each one is only used once
+async fn check_pending_token(
+    db: &Database,
+    jwt_auth: &web::Data<JwtAuth>,
+    pending_token: &str,
+) -> Result<Claims, HttpResponse> {
+    let (jwt_auth, token) = (jwt_auth.clone(), pending_token.to_string());
+    let pending = db
+        .run(
+            move |db| match jwt_auth.validate_pending_token(db, &token) {
+                Ok(claims) => Ok(Some((db.token_is_revoked(&claims.nonce)?, claims))),
+                Err(_) => Ok(None),
+            },
+        )
+        .await;
+    match pending {
+        Ok(Some((false, claims))) => Ok(claims),
+        Ok(Some((true, _))) => Err(HttpResponse::Unauthorized().finish()), // Pending token was used already
+        Ok(None) => {
+            warn!("Invalid pending token");
+            Err(HttpResponse::Unauthorized().finish())
+        }
+        Err(e) => Err(handle_db_error(&e)),
+    }
+}
+
+// Base of the links sent by mail
+fn public_url() -> String {
+    env::var("PUBLIC_URL")
+        .unwrap_or_else(|_| "http://localhost:8080".to_string())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+// Helper to mail a verification link for the address of the account
+async fn send_verification_email(
+    db: &Database,
+    jwt_auth: &JwtAuth,
+    mailer: web::Data<dyn Mailer>,
+    user_id: &str,
+    email: &str,
+) -> Result<(), String> {
+    let id = user_id.to_string();
+    let stamp = db
+        .run(move |db| db.user_security_stamp(&id))
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or_else(|| format!("Unknown account {}", user_id))?;
+    let token = jwt_auth
+        .generate_verification_token(user_id, &stamp)
+        .map_err(|e| e.to_string())?;
+    let body = format!(
+        "Please confirm the email address of your rsPass account by opening this link:\n\n\
+         {}/api/v1/auth/verify-email?token={}\n\n\
+         The link expires in {} hours. If you did not create an account, ignore this mail.",
+        public_url(),
+        token,
+        email_verification_ttl() / 3600
+    );
+    send_mail(mailer, email, "Confirm your email address", body).await
+}
+
+// Helper to send a mail, the mailer blocks so it runs on the thread pool
+async fn send_mail(
+    mailer: web::Data<dyn Mailer>,
+    to: &str,
+    subject: &'static str,
+    body: String,
+) -> Result<(), String> {
+    let to = to.to_string();
+    web::block(move || mailer.send(&to, subject, &body))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+// Address or network (CIDR) of a reverse proxy whose forwarded headers are trusted
+struct TrustedProxy {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl TrustedProxy {
+    fn parse(val: &str) -> Option<Self> {
+        let (addr, prefix) = match val.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix.parse().ok()?)),
+            None => (val, None),
+        };
+        let network: IpAddr = addr.parse().ok()?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        (prefix <= bits).then_some(TrustedProxy { network, prefix })
+    }
+
+    fn contains(&self, addr: IpAddr) -> bool {
+        let (network, addr, bits) = match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                (u32::from(network) as u128, u32::from(addr) as u128, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => (u128::from(network), u128::from(addr), 128),
+            _ => return false,
+        };
+        let shift = bits - self.prefix;
+        shift >= bits || network >> shift == addr >> shift
+    }
+}
+
+// Comma separated addresses or networks of reverse proxies, none by default
+fn trusted_proxies() -> Vec<TrustedProxy> {
+    env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|val| !val.is_empty())
+        .filter_map(|val| {
+            let proxy = TrustedProxy::parse(val);
+            if proxy.is_none() {
+                warn!("Ignoring invalid entry of TRUSTED_PROXIES: {}", val);
+            }
+            proxy
+        })
+        .collect()
+}
+
+// Client address for per-IP throttling and sessions. X-Forwarded-For is only believed on
+// connections from a trusted proxy, and then only as far as the chain of trusted proxies
+// reaches, since anything before them was written by the client
+fn client_ip(req: &HttpRequest) -> Option<String> {
+    let peer = req.peer_addr()?.ip().to_canonical();
+    let proxies = trusted_proxies();
+    let trusted = |addr: IpAddr| proxies.iter().any(|proxy| proxy.contains(addr));
+    if !trusted(peer) {
+        return Some(peer.to_string());
+    }
+    let mut client = peer;
+    let forwarded = req
+        .headers()
+        .get_all("X-Forwarded-For")
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+    for hop in forwarded.iter().rev() {
+        let Some(addr) = parse_forwarded_addr(hop) else {
+            break;
+        };
+        client = addr;
+        if !trusted(addr) {
+            break;
+        }
+    }
+    Some(client.to_string())
+}
+
+// Entries are bare addresses, but some proxies append the port
+fn parse_forwarded_addr(hop: &str) -> Option<IpAddr> {
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|addr| addr.ip()))
+        .ok()
+        .map(|addr| addr.to_canonical())
+}
+
+// Helper to reject logins while the account or the client address is locked
+async fn check_throttle(db: &Database, req: &HttpRequest, email: &str) -> Result<(), HttpResponse> {
+    let (subject, ip) = (email.to_string(), client_ip(req));
+    let retry_after = db
+        .run(move |db| login_retry_after(db, &subject, ip.as_deref(), current_timestamp()))
+        .await;
+    match retry_after {
+        Ok(None) => Ok(()),
+        Ok(Some(retry_after)) => {
+            warn!(
+                "Login of {} is throttled for {} seconds",
+                email, retry_after
+            );
+            Err(HttpResponse::TooManyRequests()
+                .insert_header((header::RETRY_AFTER, retry_after.to_string()))
+                .finish())
+        }
+        Err(e) => Err(handle_db_error(&e)),
+    }
+}
+
+// Helper to count a failed login before answering it
+async fn reject_login(
+    db: &Database,
+    req: &HttpRequest,
+    email: &str,
+    response: HttpResponse,
+) -> HttpResponse {
+    let (subject, ip) = (email.to_string(), client_ip(req));
+    let counted = db
+        .run(move |db| login_failed(db, &subject, ip.as_deref(), current_timestamp()))
+        .await;
+    match counted {
+        Ok(()) => response,
+        Err(e) => handle_db_error(&e),
+    }
+}
+
+// Helper to register a new session for a successful login and issue its tokens
+async fn start_session(
+    db: &Database,
+    req: &HttpRequest,
+    jwt_auth: &JwtAuth,
+    user_id: &str,
+    email: &str,
+    device_name: Option<&str>,
+) -> HttpResponse {
+    let now = current_timestamp();
+    let session = Session {
+        nonce: Uuid::new_v4().to_string(),
+        device_name: device_name.map(str::to_string),
+        user_agent: req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string),
+        ip: client_ip(req),
+        created_at: now,
+        last_seen: now,
+    };
+    let nonce = session.nonce.clone();
+    let (id, subject) = (user_id.to_string(), email.to_string());
+    let started = db
+        .run(move |db| {
+            db.session_create(&id, &session)?;
+            login_succeeded(db, &subject)
+        })
+        .await;
+    if let Err(e) = started {
+        return handle_db_error(&e);
+    }
+    issue_tokens(db, jwt_auth, user_id, &nonce).await
+}
+
+// Helper to check a TOTP code of an enabled account, each code is only accepted once
+fn check_totp(db: &dyn VaultStore, user_id: &str, code: &str) -> Result<bool, StoreError> {
+    let Some(totp) = db.totp_get(user_id)? else {
+        return Ok(false);
+    };
+    if !totp.enabled {
+        return Ok(false);
+    }
+    match verify_code(&totp.secret, code, current_timestamp() as u64) {
+        Some(step) => db.totp_use_step(user_id, step),
+        None => Ok(false),
+    }
+}
+
+// How long a WebAuthn ceremony may take, in seconds
+const WEBAUTHN_TIMEOUT: usize = 300;
+
+fn webauthn_descriptors(credentials: &[WebauthnCredential]) -> Vec<WebauthnCredentialDescriptor> {
+    credentials
+        .iter()
+        .map(|credential| WebauthnCredentialDescriptor {
+            kind: "public-key".to_string(),
+            id: credential.credential_id.clone(),
+        })
+        .collect()
+}
+
+// Helper to start a WebAuthn ceremony, the client answers with the signed challenge
+fn start_ceremony(
+    db: &dyn VaultStore,
+    user_id: &str,
+    purpose: &str,
+    pending_nonce: Option<String>,
+) -> Result<String, StoreError> {
+    let challenge = generate_challenge();
+    db.webauthn_challenge_store(
+        &challenge,
+        &WebauthnCeremony {
+            user_id: user_id.to_string(),
+            purpose: purpose.to_string(),
+            pending_nonce,
+            expires_at: current_timestamp() + WEBAUTHN_TIMEOUT,
+        },
+    )?;
+    Ok(challenge)
+}
+
 // Health check endpoint
 #[utoipa::path(
     responses((status = 200, description = "API is healthy")),
@@ -48,27 +531,51 @@ pub async fn route_health() -> impl Responder {
     HttpResponse::Ok().finish()
 }
 
-// Check if the email exists for pre-login
+// Public signing keys so other services can verify issued JWTs
+#[utoipa::path(
+    responses((status = 200, description = "JSON Web Key Set with the public signing keys")),
+    tag = "auth"
+)]
+#[get("/.well-known/jwks.json")]
+pub async fn route_jwks(jwt_auth: web::Data<JwtAuth>) -> impl Responder {
+    HttpResponse::Ok().json(jwt_auth.jwks())
+}
+
+// Check if the email exists for pre-login, and tell the client how to derive its keys
 #[utoipa::path(
     request_body = PreLoginRequest,
     responses(
-        (status = 200, description = "User with this email already exists"),
-        (status = 404, description = "No User with this email exists"),
+        (status = 200, description = "User with this email already exists, returns the KDF parameters of the account", body = KdfParams),
+        (status = 404, description = "No User with this email exists, in enumeration-resistant mode made-up parameters that stay the same are returned instead"),
         (status = 400, description = "Invalid payload"),
         (status = 500, description = "Database Error")
     ),
     tag = "accounts"
 )]
 #[post("/api/v1/account/checkmail")]
-pub async fn route_email(req_body: web::Json<PreLoginRequest>) -> impl Responder {
+pub async fn route_email(
+    req_body: web::Json<PreLoginRequest>,
+    db: web::Data<Database>,
+) -> impl Responder {
     if let Err(response) = validate_format(&req_body) {
         return response;
     }
 
     debug!("Email check for: {}", req_body.email);
-    match user_exists(&req_body.email) {
-        Ok(true) => HttpResponse::Ok().finish(), // User exists
-        Ok(false) => HttpResponse::NotFound().finish(), // User does not exist
+    let email = req_body.email.clone();
+    let kdf = db
+        .run(move |db| match db.user_id(&email)? {
+            Some(id) => db.user_kdf(&id),
+            None => Ok(None),
+        })
+        .await;
+    match kdf {
+        Ok(Some(kdf)) => HttpResponse::Ok().json(kdf), // User exists
+        // Unknown addresses get parameters of their own, as accounts do
+        Ok(None) if enumeration_protection() => {
+            HttpResponse::Ok().json(fake_kdf_params(&req_body.email))
+        }
+        Ok(None) => HttpResponse::NotFound().finish(), // User does not exist
         Err(e) => handle_db_error(&e),
     }
 }
@@ -78,47 +585,384 @@ pub async fn route_email(req_body: web::Json<PreLoginRequest>) -> impl Responder
     request_body = LoginRequest,
     responses(
         (status = 200, description = "User authenticated, JWT generated", body=LoginResponse),
+        (status = 202, description = "Password is correct, complete the login with a second factor", body=TwoFactorRequiredResponse),
         (status = 400, description = "Invalid payload"),
         (status = 401, description = "Invalid email or password"),
-        (status = 404, description = "User with that email doesn't exist"),
+        (status = 404, description = "User with that email doesn't exist, 401 in enumeration-resistant mode"),
+        (status = 429, description = "Too many failed logins, retry after the seconds in the Retry-After header"),
         (status = 500, description = "Database Error or JWT Generation Error")
     ),
     tag = "auth"
 )]
 #[post("/api/v1/auth/login")]
 pub async fn route_login(
+    req: HttpRequest,
     req_body: web::Json<LoginRequest>,
     jwt_auth: web::Data<JwtAuth>,
+    db: web::Data<Database>,
 ) -> impl Responder {
     if let Err(response) = validate_format(&req_body) {
         return response;
     }
 
     debug!("Login attempt for email: {}", &req_body.email);
+    if let Err(response) = check_throttle(&db, &req, &req_body.email).await {
+        return response;
+    }
 
-    match user_login(&req_body.email, &req_body.password_hash) {
-        Ok(true) => match jwt_auth.generate_token(&req_body.email) {
-            Ok(token) => HttpResponse::Ok().json(LoginResponse { token }),
-            Err(e) => {
-                error!("Failed to generate token: {}", e);
-                HttpResponse::InternalServerError().finish()
+    let email = req_body.email.clone();
+    let user = db
+        .run(move |db| match db.user_id(&email)? {
+            Some(id) => Ok(db.user_password_hash(&id)?.map(|stored| (id, stored))),
+            None => Ok(None),
+        })
+        .await;
+    let (id, stored) = match user {
+        Ok(Some(user)) => user,
+        Ok(None) if enumeration_protection() => {
+            // Indistinguishable from a wrong password, including the time it takes
+            if let Err(response) = verify_blocking(&db, &req_body.password_hash, None).await {
+                return response;
             }
-        },
-        Ok(false) => {
-            match user_exists(&req_body.email) {
-                Ok(true) => HttpResponse::Unauthorized().finish(), // User exists but incorrect password
-                Ok(false) => HttpResponse::NotFound().finish(),    // User does not exist
-                Err(e) => handle_db_error(&e),
+            return reject_login(
+                &db,
+                &req,
+                &req_body.email,
+                HttpResponse::Unauthorized().finish(),
+            )
+            .await;
+        }
+        Ok(None) => {
+            // User does not exist
+            return reject_login(
+                &db,
+                &req,
+                &req_body.email,
+                HttpResponse::NotFound().finish(),
+            )
+            .await;
+        }
+        Err(e) => return handle_db_error(&e),
+    };
+
+    let check = match verify_blocking(&db, &req_body.password_hash, Some(stored)).await {
+        Ok(check) => check,
+        Err(response) => return response,
+    };
+    match check {
+        PasswordCheck::Valid => {}
+        PasswordCheck::ValidNeedsRehash => {
+            info!("Upgrading password hash of: {}", &id);
+            // The login itself is valid, the upgrade is retried next time
+            if let Ok(hashed) = hash_blocking(&db, &req_body.password_hash).await {
+                let user_id = id.clone();
+                let upgraded = db
+                    .run(move |db| db.user_rehash_password(&user_id, &hashed))
+                    .await;
+                if let Err(e) = upgraded {
+                    error!("Failed to upgrade password hash: {}", e);
+                }
             }
         }
+        PasswordCheck::Invalid => {
+            // Incorrect password
+            return reject_login(
+                &db,
+                &req,
+                &req_body.email,
+                HttpResponse::Unauthorized().finish(),
+            )
+            .await;
+        }
+    }
+
+    // Accounts with two-factor authentication only get a pending token for the second step
+    let user_id = id.clone();
+    let second_factor = db
+        .run(move |db| {
+            let methods = db.user_two_factor_methods(&user_id)?;
+            Ok((methods, db.user_security_stamp(&user_id)?))
+        })
+        .await;
+    let (methods, stamp) = match second_factor {
+        Ok((methods, _)) if methods.is_empty() => {
+            return start_session(
+                &db,
+                &req,
+                &jwt_auth,
+                &id,
+                &req_body.email,
+                req_body.device_name.as_deref(),
+            )
+            .await
+        }
+        Ok((methods, Some(stamp))) => (methods, stamp),
+        Ok((_, None)) => return HttpResponse::NotFound().finish(),
+        Err(e) => return handle_db_error(&e),
+    };
+    match jwt_auth.generate_pending_token(&id, &stamp) {
+        Ok(pending_token) => HttpResponse::Accepted().json(TwoFactorRequiredResponse {
+            pending_token,
+            methods,
+        }),
+        Err(e) => {
+            error!("Failed to generate token: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Second login step for accounts with two-factor authentication
+#[utoipa::path(
+    request_body = TwoFactorLoginRequest,
+    responses(
+        (status = 200, description = "Second factor verified, JWT generated", body=LoginResponse),
+        (status = 400, description = "Invalid payload, exactly one of code and recovery_code is required"),
+        (status = 401, description = "Pending token or code is invalid, expired or was already used"),
+        (status = 429, description = "Too many failed logins, retry after the seconds in the Retry-After header"),
+        (status = 500, description = "Database Error or JWT Generation Error")
+    ),
+    tag = "auth"
+)]
+#[post("/api/v1/auth/login/2fa")]
+pub async fn route_login_2fa(
+    req: HttpRequest,
+    req_body: web::Json<TwoFactorLoginRequest>,
+    jwt_auth: web::Data<JwtAuth>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if let Err(response) = validate_format(&req_body) {
+        return response;
+    }
+
+    let claims = match check_pending_token(&db, &jwt_auth, &req_body.pending_token).await {
+        Ok(claims) => claims,
+        Err(response) => return response,
+    };
+
+    debug!("Second factor for user: {}", &claims.sub);
+    // Failures count against the address the password was entered for
+    let email = match account_email(&db, &claims.sub).await {
+        Ok(email) => email,
+        Err(response) => return response,
+    };
+    if let Err(response) = check_throttle(&db, &req, &email).await {
+        return response;
+    }
+    let id = claims.sub.clone();
+    let verified = match (req_body.code.clone(), &req_body.recovery_code) {
+        (Some(code), None) => db.run(move |db| check_totp(db, &id, &code)).await,
+        (None, Some(recovery_code)) => {
+            let code_hash = hash_recovery_code(recovery_code);
+            db.run(move |db| db.recovery_code_consume(&id, &code_hash))
+                .await
+        }
+        _ => return HttpResponse::BadRequest().finish(),
+    };
+    match verified {
+        Ok(true) => {}
+        Ok(false) => {
+            // Incorrect code
+            return reject_login(&db, &req, &email, HttpResponse::Unauthorized().finish()).await;
+        }
+        Err(e) => return handle_db_error(&e),
+    }
+
+    let (nonce, exp) = (claims.nonce.clone(), claims.exp);
+    if let Err(e) = db.run(move |db| db.token_revoke(&nonce, exp)).await {
+        return handle_db_error(&e);
+    }
+    start_session(
+        &db,
+        &req,
+        &jwt_auth,
+        &claims.sub,
+        &email,
+        req_body.device_name.as_deref(),
+    )
+    .await
+}
+
+// Starts a WebAuthn login, as second factor after the password or passwordless
+#[utoipa::path(
+    request_body = WebauthnLoginBeginRequest,
+    responses(
+        (status = 200, description = "Options for navigator.credentials.get()", body=WebauthnRequestOptions),
+        (status = 400, description = "Invalid payload, exactly one of pending_token and email is required"),
+        (status = 401, description = "Pending token is invalid, expired or was already used"),
+        (status = 404, description = "The account has no WebAuthn credentials, never returned for passwordless logins in enumeration-resistant mode"),
+        (status = 500, description = "Database Error")
+    ),
+    tag = "auth"
+)]
+#[post("/api/v1/auth/login/webauthn/begin")]
+pub async fn route_webauthn_login_begin(
+    req_body: web::Json<WebauthnLoginBeginRequest>,
+    jwt_auth: web::Data<JwtAuth>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if let Err(response) = validate_format(&req_body) {
+        return response;
+    }
+
+    // Without a password, the authenticator has to verify the user itself
+    let (id, purpose, pending_nonce, user_verification) =
+        match (&req_body.pending_token, &req_body.email) {
+            (Some(pending_token), None) => {
+                let claims = match check_pending_token(&db, &jwt_auth, pending_token).await {
+                    Ok(claims) => claims,
+                    Err(response) => return response,
+                };
+                (claims.sub, "2fa", Some(claims.nonce), "discouraged")
+            }
+            (None, Some(email)) => {
+                let email = email.clone();
+                match db.run(move |db| db.user_id(&email)).await {
+                    Ok(Some(id)) => (id, "passwordless", None, "required"),
+                    // Unknown addresses get a ceremony no credential can ever complete
+                    Ok(None) => (Uuid::new_v4().to_string(), "passwordless", None, "required"),
+                    Err(e) => return handle_db_error(&e),
+                }
+            }
+            _ => return HttpResponse::BadRequest().finish(),
+        };
+
+    debug!("WebAuthn {} login for user: {}", purpose, &id);
+    let user_id = id.clone();
+    let credentials = match db
+        .run(move |db| db.webauthn_credentials_list(&user_id))
+        .await
+    {
+        // Passwordless logins can not fail here in enumeration-resistant mode, the
+        // allow list is left empty and the browser offers its discoverable credentials
+        Ok(_) if purpose == "passwordless" && enumeration_protection() => Vec::new(),
+        Ok(credentials) if credentials.is_empty() => return HttpResponse::NotFound().finish(),
+        Ok(credentials) => credentials,
+        Err(e) => return handle_db_error(&e),
+    };
+    match db
+        .run(move |db| start_ceremony(db, &id, purpose, pending_nonce))
+        .await
+    {
+        Ok(challenge) => HttpResponse::Ok().json(WebauthnRequestOptions {
+            challenge,
+            rp_id: rp_id(),
+            allow_credentials: webauthn_descriptors(&credentials),
+            timeout: (WEBAUTHN_TIMEOUT * 1000) as u32,
+            user_verification: user_verification.to_string(),
+        }),
         Err(e) => handle_db_error(&e),
     }
 }
 
+#[utoipa::path(
+    request_body = WebauthnLoginRequest,
+    responses(
+        (status = 200, description = "Assertion verified, JWT generated", body=LoginResponse),
+        (status = 400, description = "Invalid payload"),
+        (status = 401, description = "Assertion is invalid, or its challenge expired or was already used"),
+        (status = 500, description = "Database Error or JWT Generation Error")
+    ),
+    tag = "auth"
+)]
+#[post("/api/v1/auth/login/webauthn")]
+pub async fn route_webauthn_login(
+    req: HttpRequest,
+    req_body: web::Json<WebauthnLoginRequest>,
+    jwt_auth: web::Data<JwtAuth>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if let Err(response) = validate_format(&req_body) {
+        return response;
+    }
+    let (Ok(client_data_json), Ok(authenticator_data), Ok(signature)) = (
+        decode(&req_body.client_data_json),
+        decode(&req_body.authenticator_data),
+        decode(&req_body.signature),
+    ) else {
+        return HttpResponse::BadRequest().finish();
+    };
+
+    let challenge = match client_data_challenge(&client_data_json, "webauthn.get") {
+        Ok(challenge) => challenge,
+        Err(e) => {
+            warn!("Rejected WebAuthn assertion: {}", e);
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+    let credential_id = req_body.credential_id.clone();
+    let found = db
+        .run(move |db| {
+            let ceremony = match db.webauthn_challenge_consume(&challenge, current_timestamp())? {
+                Some(ceremony) if ceremony.purpose != "register" => ceremony,
+                _ => return Ok(None), // Unknown or expired challenge
+            };
+            if let Some(nonce) = &ceremony.pending_nonce {
+                if db.token_is_revoked(nonce)? {
+                    return Ok(None); // Pending token was used already
+                }
+            }
+            // Not a credential of this user, if there is none
+            let credential = db.webauthn_credential_get(&ceremony.user_id, &credential_id)?;
+            Ok(credential.map(|credential| (ceremony, credential)))
+        })
+        .await;
+    let (ceremony, credential) = match found {
+        Ok(Some(found)) => found,
+        Ok(None) => return HttpResponse::Unauthorized().finish(),
+        Err(e) => return handle_db_error(&e),
+    };
+    let sign_count = match verify_assertion(
+        &credential.public_key,
+        credential.sign_count,
+        &client_data_json,
+        &authenticator_data,
+        &signature,
+        ceremony.purpose == "passwordless",
+    ) {
+        Ok(sign_count) => sign_count,
+        Err(e) => {
+            warn!(
+                "Rejected WebAuthn assertion of {}: {}",
+                &ceremony.user_id, e
+            );
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    let (user_id, pending_nonce) = (ceremony.user_id.clone(), ceremony.pending_nonce);
+    let used = db
+        .run(move |db| {
+            let now = current_timestamp();
+            if !db.webauthn_credential_used(&credential.credential_id, sign_count, now)? {
+                return Ok(None); // Counter was raced
+            }
+            if let Some(nonce) = &pending_nonce {
+                db.token_revoke(nonce, now + PENDING_TOKEN_TTL)?;
+            }
+            db.user_email(&user_id)
+        })
+        .await;
+    let email = match used {
+        Ok(Some(email)) => email,
+        Ok(None) => return HttpResponse::Unauthorized().finish(),
+        Err(e) => return handle_db_error(&e),
+    };
+    start_session(
+        &db,
+        &req,
+        &jwt_auth,
+        &ceremony.user_id,
+        &email,
+        req_body.device_name.as_deref(),
+    )
+    .await
+}
+
 #[utoipa::path(
     request_body = RegisterRequest,
     responses(
-        (status = 200, description = "User created and authenticated, JWT generated", body=LoginResponse),
+        (status = 200, description = "User created and authenticated, JWT generated, a verification link is mailed to the address", body=LoginResponse),
         (status = 400, description = "Invalid payload"),
         (status = 409, description = "User already exists"),
         (status = 500, description = "Database Error or JWT Generation Error")
@@ -127,113 +971,546 @@ pub async fn route_login(
 )]
 #[post("/api/v1/auth/register")]
 pub async fn route_register(
+    req: HttpRequest,
     req_body: web::Json<RegisterRequest>,
     jwt_auth: web::Data<JwtAuth>,
+    mailer: web::Data<dyn Mailer>,
+    db: web::Data<Database>,
 ) -> impl Responder {
     if let Err(response) = validate_format(&req_body) {
         return response;
     }
 
     debug!("Register attempt for email: {}", &req_body.email);
-    match user_exists(&req_body.email) {
-        Ok(true) => HttpResponse::Conflict().finish(),
-        Ok(false) => match user_register(&req_body.email, &req_body.password_hash) {
-            Ok(()) => match jwt_auth.generate_token(&req_body.email) {
-                Ok(token) => HttpResponse::Ok().json(LoginResponse { token }),
-                Err(e) => {
-                    error!("Failed to generate token: {}", e);
-                    HttpResponse::InternalServerError().finish()
-                }
-            },
-            Err(e) => handle_db_error(&e),
-        },
+    // A conflict still reveals the account, but in enumeration-resistant mode probing
+    // is throttled per client address
+    let protection = enumeration_protection();
+    if protection {
+        if let Err(response) = check_throttle(&db, &req, &req_body.email).await {
+            return response;
+        }
+    }
+
+    // Hashed up front, so conflicts take as long as registrations
+    let hashed = match hash_blocking(&db, &req_body.password_hash).await {
+        Ok(hashed) => hashed,
+        Err(response) => return response,
+    };
+    let (email, kdf, ip) = (
+        req_body.email.clone(),
+        req_body.kdf.clone(),
+        client_ip(&req),
+    );
+    let registered = db
+        .run(move |db| {
+            if !db.user_exists(&email)? {
+                return db.user_register(&email, &hashed, kdf.as_ref()).map(Some);
+            }
+            if protection {
+                address_failed(db, ip.as_deref(), current_timestamp())?;
+            }
+            Ok(None)
+        })
+        .await;
+    match registered {
+        Ok(Some(id)) => {
+            // The account is usable right away, a failed mail can be requested again
+            if let Err(e) =
+                send_verification_email(&db, &jwt_auth, mailer, &id, &req_body.email).await
+            {
+                error!(
+                    "Failed to send verification mail to {}: {}",
+                    &req_body.email, e
+                );
+            }
+            start_session(
+                &db,
+                &req,
+                &jwt_auth,
+                &id,
+                &req_body.email,
+                req_body.device_name.as_deref(),
+            )
+            .await
+        }
+        Ok(None) => HttpResponse::Conflict().finish(),
+        Err(e) => handle_db_error(&e),
+    }
+}
+
+#[utoipa::path(
+    params(VerifyEmailQuery),
+    responses(
+        (status = 200, description = "Email address verified"),
+        (status = 400, description = "Link is invalid, expired or was issued before the last password change"),
+        (status = 500, description = "Database Error")
+    ),
+    tag = "auth"
+)]
+#[get("/api/v1/auth/verify-email")]
+pub async fn route_verify_email(
+    query: web::Query<VerifyEmailQuery>,
+    jwt_auth: web::Data<JwtAuth>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let token = query.into_inner().token;
+    let verified = db
+        .run(move |db| {
+            let Ok(claims) = jwt_auth.validate_verification_token(db, &token) else {
+                debug!("Invalid email verification token");
+                return Ok(false);
+            };
+            info!("Verifying email of: {}", &claims.sub);
+            db.user_verify_email(&claims.sub)
+        })
+        .await;
+    match verified {
+        Ok(true) => HttpResponse::Ok().finish(),
+        Ok(false) => HttpResponse::BadRequest().finish(),
         Err(e) => handle_db_error(&e),
     }
 }
 
 #[utoipa::path(
     post,
-    path = "/api/v1/account/changepwd",
-    request_body = ChangeRequest,
+    path = "/api/v1/account/verify-email",
     responses(
-        (status = 200, description = "Password changed successfully!"),
-        (status = 400, description = "Invalid payload"),
+        (status = 200, description = "Verification mail sent"),
         (status = 401, description = "JWT Token is invalid"),
-        (status = 500, description = "Database Error or JWT Generation Error")
+        (status = 409, description = "Email address is already verified"),
+        (status = 500, description = "Database Error, Mail Error or JWT Extraction Error")
     ),
     tag = "accounts",
     security(
         ("jwt_auth" = [])
     )
 )]
-pub async fn route_changepwd(
+pub async fn route_verify_email_resend(
     req: HttpRequest,
-    req_body: web::Json<ChangeRequest>,
-    auth: BearerAuth,
+    jwt_auth: web::Data<JwtAuth>,
+    mailer: web::Data<dyn Mailer>,
+    db: web::Data<Database>,
 ) -> impl Responder {
-    debug!("authenticated for token: {}", auth.token());
-    if let Some(claims) = req.extensions_mut().get::<Claims>() {
-        info!("Change Password of: {}", &claims.sub);
-        match user_changepwd(&claims.sub, &req_body.password_hash) {
-            Ok(()) => HttpResponse::Ok().finish(),
-            Err(e) => handle_db_error(&e),
+    let Some(id) = req
+        .extensions()
+        .get::<Claims>()
+        .map(|claims| claims.sub.clone())
+    else {
+        return HttpResponse::InternalServerError().finish();
+    };
+    let user_id = id.clone();
+    let account = db
+        .run(move |db| {
+            let verified = db.user_email_verified(&user_id)?;
+            Ok(verified.zip(db.user_email(&user_id)?))
+        })
+        .await;
+    let email = match account {
+        Ok(Some((false, email))) => email,
+        Ok(Some((true, _))) => return HttpResponse::Conflict().finish(),
+        Ok(None) => return HttpResponse::Unauthorized().finish(),
+        Err(e) => return handle_db_error(&e),
+    };
+    info!("Resending verification mail to: {}", &email);
+    match send_verification_email(&db, &jwt_auth, mailer, &id, &email).await {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            error!("Failed to send verification mail to {}: {}", &email, e);
+            HttpResponse::InternalServerError().finish()
         }
-    } else {
-        HttpResponse::Unauthorized().finish()
     }
 }
 
 #[utoipa::path(
-    get,
-    path = "/api/v1/account/logout",
+    request_body = RefreshRequest,
     responses(
-        (status = 200, description = "Logged out successfully!"),
-        (status = 401, description = "JWT Token is invalid or already blacklisted")
+        (status = 200, description = "Refresh token rotated, new JWT generated", body=LoginResponse),
+        (status = 400, description = "Invalid payload"),
+        (status = 401, description = "Refresh token is invalid, expired or was already used"),
+        (status = 500, description = "Database Error or JWT Generation Error")
     ),
-    tag = "accounts",
-    security(
-        ("jwt_auth" = [])
-    )
+    tag = "auth"
 )]
-pub async fn route_logout(auth: BearerAuth, jwt_auth: web::Data<JwtAuth>) -> impl Responder {
-    let token = auth.token();
-    debug!("Logging with token: {}", token);
+#[post("/api/v1/auth/refresh")]
+pub async fn route_refresh(
+    req_body: web::Json<RefreshRequest>,
+    jwt_auth: web::Data<JwtAuth>,
+    db: web::Data<Database>,
+    notifier: web::Data<Notifier>,
+) -> impl Responder {
+    if let Err(response) = validate_format(&req_body) {
+        return response;
+    }
 
-    if jwt_auth.is_blacklisted(token) {
-        return HttpResponse::Unauthorized().finish();
+    let token_hash = hash_refresh_token(&req_body.refresh_token);
+    let consumed = db
+        .run(move |db| db.refresh_token_consume(&token_hash, current_timestamp()))
+        .await;
+    match consumed {
+        Ok(RefreshTokenStatus::Valid { user_id, family_id }) => {
+            debug!("Refreshing token for user: {}", &user_id);
+            issue_tokens(&db, &jwt_auth, &user_id, &family_id).await
+        }
+        Ok(RefreshTokenStatus::Reused { user_id, family_id }) => {
+            warn!(
+                "Refresh token reuse detected, revoking session of: {}",
+                &user_id
+            );
+            let (id, nonce) = (user_id.clone(), family_id.clone());
+            let revoked = db
+                .run(move |db| {
+                    db.session_delete(&id, &nonce)?;
+                    db.token_revoke(&nonce, current_timestamp() + ACCESS_TOKEN_TTL)
+                })
+                .await;
+            match revoked {
+                Ok(()) => {
+                    notifier.session_ended(&user_id, &family_id);
+                    HttpResponse::Unauthorized().finish()
+                }
+                Err(e) => handle_db_error(&e),
+            }
+        }
+        Ok(RefreshTokenStatus::Expired) | Ok(RefreshTokenStatus::Unknown) => {
+            HttpResponse::Unauthorized().finish()
+        }
+        Err(e) => handle_db_error(&e),
     }
-    jwt_auth.blacklist_token(token);
-    HttpResponse::Ok().finish()
 }
 
 #[utoipa::path(
-    get,
-    path = "/api/v1/account/delete",
+    post,
+    path = "/api/v1/account/changepwd",
+    request_body = ChangeRequest,
     responses(
-        (status = 200, description = "Account deleted successfully!"),
+        (status = 200, description = "Password changed successfully, all other sessions were logged out and new tokens were issued for this one", body=LoginResponse),
         (status = 400, description = "Invalid payload"),
         (status = 401, description = "JWT Token is invalid"),
-        (status = 500, description = "Database Error or JWT Extraction Error")
+        (status = 500, description = "Database Error or JWT Generation Error")
     ),
     tag = "accounts",
     security(
         ("jwt_auth" = [])
     )
 )]
-pub async fn route_delete(
+pub async fn route_changepwd(
     req: HttpRequest,
+    req_body: web::Json<ChangeRequest>,
     jwt_auth: web::Data<JwtAuth>,
     auth: BearerAuth,
+    db: web::Data<Database>,
+    notifier: web::Data<Notifier>,
 ) -> impl Responder {
-    let token = auth.token();
+    if let Err(response) = validate_format(&req_body) {
+        return response;
+    }
+
+    debug!("authenticated for token: {}", auth.token());
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!("Change Password of: {}", &claims.sub);
+        let hashed = match hash_blocking(&db, &req_body.password_hash).await {
+            Ok(hashed) => hashed,
+            Err(response) => return response,
+        };
+        let (id, nonce, kdf) = (
+            claims.sub.clone(),
+            claims.nonce.clone(),
+            req_body.kdf.clone(),
+        );
+        match db
+            .run(move |db| db.user_changepwd(&id, &hashed, kdf.as_ref(), &nonce))
+            .await
+        {
+            Ok(()) => {
+                notifier.sessions_ended(&claims.sub, Some(&claims.nonce));
+                issue_tokens(&db, &jwt_auth, &claims.sub, &claims.nonce).await
+            }
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::Unauthorized().finish()
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/account/change-email",
+    request_body = ChangeEmailRequest,
+    responses(
+        (status = 200, description = "Confirmation token mailed to the new address"),
+        (status = 400, description = "Invalid payload"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 409, description = "New address already belongs to an account, in enumeration-resistant mode 200 is returned and no mail is sent"),
+        (status = 500, description = "Database Error, Mail Error or JWT Extraction Error")
+    ),
+    tag = "accounts",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_change_email(
+    req: HttpRequest,
+    req_body: web::Json<ChangeEmailRequest>,
+    jwt_auth: web::Data<JwtAuth>,
+    mailer: web::Data<dyn Mailer>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if let Err(response) = validate_format(&req_body) {
+        return response;
+    }
+    let Some(claims) = req
+        .extensions()
+        .get::<Claims>()
+        .map(|claims| (claims.sub.clone(), claims.stamp.clone()))
+    else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    let (id, stamp) = claims;
+    let email = match account_email(&db, &id).await {
+        Ok(email) => email,
+        Err(response) => return response,
+    };
+
+    info!("Email change of {} to {}", &email, &req_body.new_email);
+    let new_email = req_body.new_email.clone();
+    match db.run(move |db| db.user_exists(&new_email)).await {
+        Ok(false) => {}
+        Ok(true) if enumeration_protection() => return HttpResponse::Ok().finish(),
+        Ok(true) => return HttpResponse::Conflict().finish(),
+        Err(e) => return handle_db_error(&e),
+    }
+    let token = match jwt_auth.generate_email_change_token(&id, &req_body.new_email, &stamp) {
+        Ok(token) => token,
+        Err(e) => {
+            error!("Failed to generate token: {}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let body = format!(
+        "To move your rsPass account from {} to this address, confirm the change in your \
+         client with this token:\n\n{}\n\n\
+         The token expires in {} hours. If you did not request this, ignore this mail.",
+        &email,
+        token,
+        email_verification_ttl() / 3600
+    );
+    match send_mail(
+        mailer,
+        &req_body.new_email,
+        "Confirm your new email address",
+        body,
+    )
+    .await
+    {
+        Ok(()) => HttpResponse::Ok().finish(),
+        Err(e) => {
+            error!("Failed to send email change mail: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/account/change-email/confirm",
+    request_body = ConfirmEmailChangeRequest,
+    responses(
+        (status = 200, description = "Account moved to the new address, all other sessions were logged out and new tokens were issued for this one", body=LoginResponse),
+        (status = 400, description = "Invalid payload, or the token is invalid, expired or was issued before the last password change"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Token belongs to another account"),
+        (status = 409, description = "New address already belongs to an account"),
+        (status = 500, description = "Database Error or JWT Generation Error")
+    ),
+    tag = "accounts",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_change_email_confirm(
+    req: HttpRequest,
+    req_body: web::Json<ConfirmEmailChangeRequest>,
+    jwt_auth: web::Data<JwtAuth>,
+    db: web::Data<Database>,
+    notifier: web::Data<Notifier>,
+) -> impl Responder {
+    if let Err(response) = validate_format(&req_body) {
+        return response;
+    }
+    let Some(claims) = req.extensions_mut().remove::<Claims>() else {
+        return HttpResponse::Unauthorized().finish();
+    };
+    let (jwt, token) = (jwt_auth.clone(), req_body.token.clone());
+    let change = match db
+        .run(move |db| Ok(jwt.validate_email_change_token(db, &token).ok()))
+        .await
+    {
+        Ok(Some(change)) => change,
+        Ok(None) => {
+            debug!("Invalid email change token");
+            return HttpResponse::BadRequest().finish();
+        }
+        Err(e) => return handle_db_error(&e),
+    };
+    let Some(new_email) = change.new_email.filter(|_| change.sub == claims.sub) else {
+        warn!(
+            "Email change token of another account used by: {}",
+            &claims.sub
+        );
+        return HttpResponse::Forbidden().finish();
+    };
+
+    let hashed = match req_body.password_hash.as_deref() {
+        Some(password_hash) => match hash_blocking(&db, password_hash).await {
+            Ok(hashed) => Some(hashed),
+            Err(response) => return response,
+        },
+        None => None,
+    };
+    info!("Moving account {} to {}", &claims.sub, &new_email);
+    let (id, nonce, kdf) = (
+        claims.sub.clone(),
+        claims.nonce.clone(),
+        req_body.kdf.clone(),
+    );
+    let changed = db
+        .run(move |db| {
+            db.user_change_email(&id, &new_email, hashed.as_deref(), kdf.as_ref(), &nonce)
+        })
+        .await;
+    match changed {
+        Ok(true) => {
+            notifier.sessions_ended(&claims.sub, Some(&claims.nonce));
+            issue_tokens(&db, &jwt_auth, &claims.sub, &claims.nonce).await
+        }
+        Ok(false) => HttpResponse::Conflict().finish(),
+        Err(e) => handle_db_error(&e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/account/logout",
+    responses(
+        (status = 200, description = "Logged out successfully!"),
+        (status = 401, description = "JWT Token is invalid or already blacklisted")
+    ),
+    tag = "accounts",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_logout(
+    req: HttpRequest,
+    auth: BearerAuth,
+    jwt_auth: web::Data<JwtAuth>,
+    db: web::Data<Database>,
+    notifier: web::Data<Notifier>,
+) -> impl Responder {
+    let token = auth.token().to_string();
+    debug!("Logging with token: {}", token);
+
+    let claims = req.extensions().get::<Claims>().cloned();
+    let session = claims.clone();
+    let logged_out = db
+        .run(move |db| {
+            if jwt_auth.is_blacklisted(db, &token) {
+                return Ok(false);
+            }
+            jwt_auth.blacklist_token(db, &token)?;
+            // The session and its refresh token family share the nonce of the access token
+            if let Some(claims) = session {
+                db.session_delete(&claims.sub, &claims.nonce)?;
+            }
+            Ok(true)
+        })
+        .await;
+    match logged_out {
+        Ok(true) => {
+            if let Some(claims) = claims {
+                notifier.session_ended(&claims.sub, &claims.nonce);
+            }
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => HttpResponse::Unauthorized().finish(),
+        Err(e) => handle_db_error(&e),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/account/logout-all",
+    responses(
+        (status = 200, description = "Logged out of all sessions, every issued token is invalidated"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "accounts",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_logout_all(
+    req: HttpRequest,
+    db: web::Data<Database>,
+    notifier: web::Data<Notifier>,
+) -> impl Responder {
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!("Logging out all sessions of: {}", &claims.sub);
+        let user_id = claims.sub.clone();
+        match db.run(move |db| db.user_logout_all(&user_id)).await {
+            Ok(()) => {
+                notifier.sessions_ended(&claims.sub, None);
+                HttpResponse::Ok().finish()
+            }
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/account/delete",
+    responses(
+        (status = 200, description = "Account deleted successfully!"),
+        (status = 400, description = "Invalid payload"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "accounts",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_delete(
+    req: HttpRequest,
+    jwt_auth: web::Data<JwtAuth>,
+    auth: BearerAuth,
+    db: web::Data<Database>,
+    notifier: web::Data<Notifier>,
+    blobs: web::Data<BlobStore>,
+) -> impl Responder {
+    let token = auth.token().to_string();
     debug!("Deleting account with token: {}", token);
 
-    jwt_auth.blacklist_token(token);
+    if let Err(e) = db.run(move |db| jwt_auth.blacklist_token(db, &token)).await {
+        return handle_db_error(&e);
+    }
 
-    if let Some(claims) = req.extensions_mut().get::<Claims>() {
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
         info!("Deleting account of: {}", &claims.sub);
-        match user_delete(&claims.sub) {
-            Ok(()) => HttpResponse::Ok().finish(),
+        let user_id = claims.sub.clone();
+        match db.run(move |db| db.user_delete(&user_id)).await {
+            Ok(()) => {
+                notifier.sessions_ended(&claims.sub, None);
+                blobs.remove_user(&claims.sub).await;
+                HttpResponse::Ok().finish()
+            }
             Err(e) => handle_db_error(&e),
         }
     } else {
@@ -243,24 +1520,127 @@ pub async fn route_delete(
 
 #[utoipa::path(
     get,
-    path = "/api/v1/sync/fetch",
+    path = "/api/v1/account/sessions",
     responses(
-        (status = 200, description = "Fetched User Vault"),
+        (status = 200, description = "Active sessions of the account", body = [SessionResponse]),
         (status = 401, description = "JWT Token is invalid"),
         (status = 500, description = "Database Error or JWT Extraction Error")
     ),
-    tag = "sync",
+    tag = "accounts",
     security(
         ("jwt_auth" = [])
     )
 )]
-pub async fn route_fetch(req: HttpRequest, auth: BearerAuth) -> impl Responder {
-    let token = auth.token();
-    debug!("Fetching vault with token: {}", token);
-    if let Some(claims) = req.extensions_mut().get::<Claims>() {
-        info!("Fetching vault of user: {}", &claims.sub);
-        match data_get(&claims.sub) {
-            Ok(encrypted_data) => HttpResponse::Ok().json(DataResponse { encrypted_data }),
+pub async fn route_sessions(req: HttpRequest, db: web::Data<Database>) -> impl Responder {
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!("Listing sessions of: {}", &claims.sub);
+        let id = claims.sub.clone();
+        match db.run(move |db| db.sessions_list(&id)).await {
+            Ok(sessions) => HttpResponse::Ok().json(
+                sessions
+                    .into_iter()
+                    .map(|session| SessionResponse {
+                        current: session.nonce == claims.nonce,
+                        id: session.nonce,
+                        device_name: session.device_name,
+                        user_agent: session.user_agent,
+                        ip: session.ip,
+                        created_at: session.created_at,
+                        last_seen: session.last_seen,
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/account/sessions/{id}",
+    params(("id" = String, Path, description = "Id of the session to revoke")),
+    responses(
+        (status = 200, description = "Session revoked"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 404, description = "No session with this id exists for the account"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "accounts",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_session_revoke(
+    req: HttpRequest,
+    path: web::Path<String>,
+    db: web::Data<Database>,
+    notifier: web::Data<Notifier>,
+) -> impl Responder {
+    let session_id = path.into_inner();
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!("Revoking session {} of: {}", &session_id, &claims.sub);
+        let (user_id, nonce) = (claims.sub.clone(), session_id.clone());
+        let revoked = db
+            .run(move |db| {
+                if !db.session_delete(&user_id, &nonce)? {
+                    return Ok(false);
+                }
+                db.token_revoke(&nonce, current_timestamp() + ACCESS_TOKEN_TTL)?;
+                Ok(true)
+            })
+            .await;
+        match revoked {
+            Ok(true) => {
+                notifier.session_ended(&claims.sub, &session_id);
+                HttpResponse::Ok().finish()
+            }
+            Ok(false) => HttpResponse::NotFound().finish(),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/account/sessions",
+    responses(
+        (status = 200, description = "All other sessions revoked"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "accounts",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_sessions_revoke_others(
+    req: HttpRequest,
+    db: web::Data<Database>,
+    notifier: web::Data<Notifier>,
+) -> impl Responder {
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!("Revoking all other sessions of: {}", &claims.sub);
+        let revoked_until = current_timestamp() + ACCESS_TOKEN_TTL;
+        let (user_id, nonce) = (claims.sub.clone(), claims.nonce.clone());
+        let result = db
+            .run(move |db| {
+                db.sessions_delete_others(&user_id, &nonce)?
+                    .iter()
+                    .try_for_each(|nonce| db.token_revoke(nonce, revoked_until))
+            })
+            .await;
+        match result {
+            Ok(()) => {
+                notifier.sessions_ended(&claims.sub, Some(&claims.nonce));
+                HttpResponse::Ok().finish()
+            }
             Err(e) => handle_db_error(&e),
         }
     } else {
@@ -270,29 +1650,1365 @@ pub async fn route_fetch(req: HttpRequest, auth: BearerAuth) -> impl Responder {
 
 #[utoipa::path(
     post,
-    path = "/api/v1/sync/update",
-    request_body = UpdateRequest,
+    path = "/api/v1/account/2fa/totp",
     responses(
-        (status = 200, description = "Updated User Vault"),
+        (status = 200, description = "New TOTP secret generated, confirm it with a code to enable two-factor authentication", body = TotpSetupResponse),
         (status = 401, description = "JWT Token is invalid"),
+        (status = 409, description = "TOTP is already enabled"),
         (status = 500, description = "Database Error or JWT Extraction Error")
     ),
-    tag = "sync",
+    tag = "accounts",
     security(
         ("jwt_auth" = [])
     )
 )]
-pub async fn route_update(
+pub async fn route_totp_setup(req: HttpRequest, db: web::Data<Database>) -> impl Responder {
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!("TOTP enrollment of: {}", &claims.sub);
+        let email = match account_email(&db, &claims.sub).await {
+            Ok(email) => email,
+            Err(response) => return response,
+        };
+        let secret = generate_secret();
+        let uri = match otpauth_uri(&secret, &email) {
+            Ok(uri) => uri,
+            Err(e) => {
+                error!("Failed to build otpauth URI: {}", e);
+                return HttpResponse::InternalServerError().finish();
+            }
+        };
+        let pending_secret = secret.clone();
+        match db
+            .run(move |db| db.totp_begin(&claims.sub, &pending_secret))
+            .await
+        {
+            Ok(true) => HttpResponse::Ok().json(TotpSetupResponse {
+                secret,
+                otpauth_uri: uri,
+            }),
+            Ok(false) => HttpResponse::Conflict().finish(),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/account/2fa/totp/confirm",
+    request_body = TotpCodeRequest,
+    responses(
+        (status = 200, description = "TOTP enabled, the recovery codes are only shown once", body = RecoveryCodesResponse),
+        (status = 400, description = "Invalid payload"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Code is invalid"),
+        (status = 404, description = "No TOTP enrollment in progress"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "accounts",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_totp_confirm(
     req: HttpRequest,
-    req_body: web::Json<UpdateRequest>,
-    auth: BearerAuth,
+    req_body: web::Json<TotpCodeRequest>,
+    db: web::Data<Database>,
 ) -> impl Responder {
-    let token = auth.token();
-    debug!("Updating vault with token: {}", token);
-    if let Some(claims) = req.extensions_mut().get::<Claims>() {
-        info!("Updating vault of user: {}", &claims.sub);
-        match data_update(&claims.sub, &req_body.encrypted_data) {
-            Ok(()) => HttpResponse::Ok().finish(),
+    if let Err(response) = validate_format(&req_body) {
+        return response;
+    }
+
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        let id = claims.sub.clone();
+        let secret = match db.run(move |db| db.totp_get(&id)).await {
+            Ok(Some(totp)) if !totp.enabled => totp.secret,
+            Ok(_) => return HttpResponse::NotFound().finish(),
+            Err(e) => return handle_db_error(&e),
+        };
+        let Some(step) = verify_code(&secret, &req_body.code, current_timestamp() as u64) else {
+            return HttpResponse::Forbidden().finish();
+        };
+
+        let recovery_codes = generate_recovery_codes();
+        let hashes: Vec<String> = recovery_codes
+            .iter()
+            .map(|code| hash_recovery_code(code))
+            .collect();
+        let id = claims.sub.clone();
+        match db.run(move |db| db.totp_enable(&id, step, &hashes)).await {
+            Ok(true) => {
+                info!("TOTP enabled for: {}", &claims.sub);
+                HttpResponse::Ok().json(RecoveryCodesResponse { recovery_codes })
+            }
+            Ok(false) => HttpResponse::Forbidden().finish(), // Code was used already
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/account/2fa/totp/disable",
+    request_body = TotpCodeRequest,
+    responses(
+        (status = 200, description = "TOTP disabled and recovery codes removed"),
+        (status = 400, description = "Invalid payload"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Neither a valid code nor a recovery code"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "accounts",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_totp_disable(
+    req: HttpRequest,
+    req_body: web::Json<TotpCodeRequest>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if let Err(response) = validate_format(&req_body) {
+        return response;
+    }
+
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        let (id, code) = (claims.sub.clone(), req_body.into_inner().code);
+        let disabled = db
+            .run(move |db| {
+                // A stolen access token alone must not be enough to turn off the second factor
+                let valid = check_totp(db, &id, &code)?
+                    || db.recovery_code_consume(&id, &hash_recovery_code(&code))?;
+                if valid {
+                    db.totp_disable(&id)?;
+                }
+                Ok(valid)
+            })
+            .await;
+        match disabled {
+            Ok(true) => {
+                info!("TOTP disabled for: {}", &claims.sub);
+                HttpResponse::Ok().finish()
+            }
+            Ok(false) => HttpResponse::Forbidden().finish(),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/account/2fa/webauthn/register/begin",
+    responses(
+        (status = 200, description = "Options for navigator.credentials.create()", body = WebauthnCreationOptions),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "accounts",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_webauthn_register_begin(
+    req: HttpRequest,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!("WebAuthn registration of: {}", &claims.sub);
+        let id = claims.sub.clone();
+        let ceremony = db
+            .run(move |db| {
+                let credentials = db.webauthn_credentials_list(&id)?;
+                let Some(email) = db.user_email(&id)? else {
+                    return Ok(None);
+                };
+                let challenge = start_ceremony(db, &id, "register", None)?;
+                Ok(Some((credentials, email, challenge)))
+            })
+            .await;
+        match ceremony {
+            Ok(Some((credentials, email, challenge))) => {
+                HttpResponse::Ok().json(WebauthnCreationOptions {
+                    challenge,
+                    rp: WebauthnRelyingParty {
+                        id: rp_id(),
+                        name: "rsPass".to_string(),
+                    },
+                    // The user handle stays the same when the address changes
+                    user: WebauthnUser {
+                        id: URL_SAFE_NO_PAD.encode(claims.sub.as_bytes()),
+                        name: email.clone(),
+                        display_name: email,
+                    },
+                    pub_key_cred_params: [COSE_EDDSA, COSE_ES256]
+                        .into_iter()
+                        .map(|alg| WebauthnCredentialParameters {
+                            kind: "public-key".to_string(),
+                            alg,
+                        })
+                        .collect(),
+                    exclude_credentials: webauthn_descriptors(&credentials),
+                    timeout: (WEBAUTHN_TIMEOUT * 1000) as u32,
+                    attestation: "none".to_string(),
+                })
+            }
+            Ok(None) => HttpResponse::Unauthorized().finish(),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/account/2fa/webauthn/register",
+    request_body = WebauthnRegisterRequest,
+    responses(
+        (status = 200, description = "Credential registered, it is required as second factor from now on", body = WebauthnCredentialResponse),
+        (status = 400, description = "Invalid payload or attestation"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Challenge is unknown, expired or was already used"),
+        (status = 409, description = "Credential is already registered"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "accounts",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_webauthn_register(
+    req: HttpRequest,
+    req_body: web::Json<WebauthnRegisterRequest>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if let Err(response) = validate_format(&req_body) {
+        return response;
+    }
+    let (Ok(client_data_json), Ok(attestation_object)) = (
+        decode(&req_body.client_data_json),
+        decode(&req_body.attestation_object),
+    ) else {
+        return HttpResponse::BadRequest().finish();
+    };
+
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        let challenge = match client_data_challenge(&client_data_json, "webauthn.create") {
+            Ok(challenge) => challenge,
+            Err(e) => {
+                warn!("Rejected WebAuthn registration: {}", e);
+                return HttpResponse::BadRequest().finish();
+            }
+        };
+        match db
+            .run(move |db| db.webauthn_challenge_consume(&challenge, current_timestamp()))
+            .await
+        {
+            Ok(Some(ceremony))
+                if ceremony.purpose == "register" && ceremony.user_id == claims.sub => {}
+            Ok(_) => return HttpResponse::Forbidden().finish(),
+            Err(e) => return handle_db_error(&e),
+        }
+        let new = match verify_registration(&attestation_object) {
+            Ok(new) => new,
+            Err(e) => {
+                warn!("Rejected WebAuthn registration: {}", e);
+                return HttpResponse::BadRequest().finish();
+            }
+        };
+
+        let credential = WebauthnCredential {
+            credential_id: URL_SAFE_NO_PAD.encode(&new.credential_id),
+            name: req_body.name.clone(),
+            public_key: new.public_key,
+            algorithm: new.algorithm,
+            sign_count: new.sign_count,
+            created_at: current_timestamp(),
+            last_used: None,
+        };
+        let response = WebauthnCredentialResponse {
+            id: credential.credential_id.clone(),
+            name: credential.name.clone(),
+            created_at: credential.created_at,
+            last_used: None,
+        };
+        let id = claims.sub.clone();
+        match db
+            .run(move |db| db.webauthn_credential_add(&id, &credential))
+            .await
+        {
+            Ok(true) => {
+                info!("WebAuthn credential registered for: {}", &claims.sub);
+                HttpResponse::Ok().json(response)
+            }
+            Ok(false) => HttpResponse::Conflict().finish(),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/account/2fa/webauthn",
+    responses(
+        (status = 200, description = "Registered WebAuthn credentials of the account", body = [WebauthnCredentialResponse]),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "accounts",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_webauthn_credentials(
+    req: HttpRequest,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        match db
+            .run(move |db| db.webauthn_credentials_list(&claims.sub))
+            .await
+        {
+            Ok(credentials) => HttpResponse::Ok().json(
+                credentials
+                    .into_iter()
+                    .map(|credential| WebauthnCredentialResponse {
+                        id: credential.credential_id,
+                        name: credential.name,
+                        created_at: credential.created_at,
+                        last_used: credential.last_used,
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/account/2fa/webauthn/{id}",
+    params(("id" = String, Path, description = "Id of the credential to remove")),
+    responses(
+        (status = 200, description = "Credential removed"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 404, description = "No credential with this id exists for the account"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "accounts",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_webauthn_credential_delete(
+    req: HttpRequest,
+    path: web::Path<String>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let credential_id = path.into_inner();
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!(
+            "Removing WebAuthn credential {} of: {}",
+            &credential_id, &claims.sub
+        );
+        match db
+            .run(move |db| db.webauthn_credential_delete(&claims.sub, &credential_id))
+            .await
+        {
+            Ok(true) => HttpResponse::Ok().finish(),
+            Ok(false) => HttpResponse::NotFound().finish(),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+// Strong entity tag of a vault revision
+fn revision_etag(revision: u64) -> String {
+    format!("\"{}\"", revision)
+}
+
+// Revision an update is based on, from If-Match or the request body
+enum Precondition {
+    IfMatch(Option<u64>), // None for *, which matches any revision
+    BaseRevision(u64),
+}
+
+// Updates have to name the revision they are based on, so a client that missed the update
+// of another device cannot overwrite it
+fn update_precondition(
+    req: &HttpRequest,
+    base_revision: Option<u64>,
+) -> Result<Precondition, HttpResponse> {
+    let if_match = match req.headers().get(header::IF_MATCH) {
+        Some(value) => {
+            let value = value.to_str().unwrap_or_default().trim();
+            if value == "*" {
+                Some(None)
+            } else {
+                // Only a single strong tag can name a revision
+                let revision = value
+                    .strip_prefix('"')
+                    .and_then(|value| value.strip_suffix('"'))
+                    .and_then(|value| value.parse().ok());
+                match revision {
+                    Some(revision) => Some(Some(revision)),
+                    None => {
+                        warn!("Malformed If-Match header: {}", value);
+                        return Err(HttpResponse::BadRequest().finish());
+                    }
+                }
+            }
+        }
+        None => None,
+    };
+    match (if_match, base_revision) {
+        (Some(Some(tag)), Some(base)) if tag != base => {
+            warn!("If-Match and base_revision disagree");
+            Err(HttpResponse::BadRequest().finish())
+        }
+        (Some(revision), _) => Ok(Precondition::IfMatch(revision)),
+        (None, Some(base)) => Ok(Precondition::BaseRevision(base)),
+        (None, None) => Err(HttpResponse::build(StatusCode::PRECONDITION_REQUIRED).finish()),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sync/fetch",
+    responses(
+        (status = 200, description = "Fetched User Vault, with its revision as ETag", body = DataResponse),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_fetch(
+    req: HttpRequest,
+    auth: BearerAuth,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let token = auth.token();
+    debug!("Fetching vault with token: {}", token);
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!("Fetching vault of user: {}", &claims.sub);
+        if let Err(response) = check_email_verified(&db, &claims.sub).await {
+            return response;
+        }
+        match db.run(move |db| db.data_get(&claims.sub)).await {
+            Ok(vault) => HttpResponse::Ok()
+                .insert_header((header::ETAG, revision_etag(vault.revision)))
+                .json(DataResponse {
+                    encrypted_data: vault.encrypted_data,
+                    revision: vault.revision,
+                }),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/sync/update",
+    request_body = UpdateRequest,
+    params(
+        ("If-Match" = Option<String>, Header, description = "ETag of the revision the update is based on, * for any")
+    ),
+    responses(
+        (status = 200, description = "Updated User Vault, returns the new revision", body = RevisionResponse),
+        (status = 400, description = "Malformed If-Match, or one that disagrees with base_revision"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 409, description = "Vault changed since base_revision, returns the current revision", body = RevisionResponse),
+        (status = 412, description = "Vault changed since the If-Match revision, returns the current revision", body = RevisionResponse),
+        (status = 428, description = "Neither If-Match nor base_revision given"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_update(
+    req: HttpRequest,
+    req_body: web::Json<UpdateRequest>,
+    auth: BearerAuth,
+    db: web::Data<Database>,
+    notifier: web::Data<Notifier>,
+) -> impl Responder {
+    let token = auth.token();
+    debug!("Updating vault with token: {}", token);
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!("Updating vault of user: {}", &claims.sub);
+        if let Err(response) = check_email_verified(&db, &claims.sub).await {
+            return response;
+        }
+        let req_body = req_body.into_inner();
+        let precondition = match update_precondition(&req, req_body.base_revision) {
+            Ok(precondition) => precondition,
+            Err(response) => return response,
+        };
+        let (base_revision, conflict) = match precondition {
+            Precondition::IfMatch(revision) => (revision, StatusCode::PRECONDITION_FAILED),
+            Precondition::BaseRevision(revision) => (Some(revision), StatusCode::CONFLICT),
+        };
+        let encrypted_data = req_body.encrypted_data;
+        let user_id = claims.sub.clone();
+        let session_id = claims.nonce.clone();
+        let updated = db
+            .run(move |db| {
+                let now = current_timestamp();
+                let status =
+                    db.data_update(&user_id, &encrypted_data, base_revision, &session_id, now)?;
+                if let VaultUpdate::Updated { .. } = status {
+                    prune_history(db, &user_id, now)?;
+                }
+                Ok(status)
+            })
+            .await;
+        match updated {
+            Ok(VaultUpdate::Updated { revision }) => {
+                notifier.vault_changed(&claims.sub, revision, &claims.nonce);
+                HttpResponse::Ok()
+                    .insert_header((header::ETAG, revision_etag(revision)))
+                    .json(RevisionResponse { revision })
+            }
+            Ok(VaultUpdate::Conflict { revision }) => {
+                info!(
+                    "Refused update of vault of {}, it is at revision {}",
+                    &claims.sub, revision
+                );
+                HttpResponse::build(conflict)
+                    .insert_header((header::ETAG, revision_etag(revision)))
+                    .json(RevisionResponse { revision })
+            }
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sync/history",
+    responses(
+        (status = 200, description = "Snapshots of the vault, newest first", body = [HistoryEntryResponse]),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_history(req: HttpRequest, db: web::Data<Database>) -> impl Responder {
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!("Listing vault history of: {}", &claims.sub);
+        if let Err(response) = check_email_verified(&db, &claims.sub).await {
+            return response;
+        }
+        match db.run(move |db| db.vault_history_list(&claims.sub)).await {
+            Ok(snapshots) => HttpResponse::Ok().json(
+                snapshots
+                    .into_iter()
+                    .map(|snapshot| HistoryEntryResponse {
+                        revision: snapshot.revision,
+                        size: snapshot.size,
+                        created_at: snapshot.created_at,
+                        session_id: snapshot.session_id,
+                        device_name: snapshot.device_name,
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sync/history/{revision}",
+    params(("revision" = u64, Path, description = "Revision of the snapshot")),
+    responses(
+        (status = 200, description = "Encrypted vault as of this revision", body = DataResponse),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 404, description = "No snapshot of this revision is kept"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_history_get(
+    req: HttpRequest,
+    path: web::Path<u64>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let revision = path.into_inner();
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!(
+            "Fetching revision {} of vault of: {}",
+            revision, &claims.sub
+        );
+        if let Err(response) = check_email_verified(&db, &claims.sub).await {
+            return response;
+        }
+        match db
+            .run(move |db| db.vault_history_get(&claims.sub, revision))
+            .await
+        {
+            Ok(Some(vault)) => HttpResponse::Ok().json(DataResponse {
+                encrypted_data: vault.encrypted_data,
+                revision: vault.revision,
+            }),
+            Ok(None) => HttpResponse::NotFound().finish(),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/sync/history/{revision}/restore",
+    request_body(content = Option<RestoreRequest>),
+    params(
+        ("revision" = u64, Path, description = "Revision of the snapshot to restore"),
+        ("If-Match" = Option<String>, Header, description = "ETag of the revision the restore replaces, * for any")
+    ),
+    responses(
+        (status = 200, description = "Snapshot written as a new revision, which is returned", body = RevisionResponse),
+        (status = 400, description = "Malformed If-Match, or one that disagrees with base_revision"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 404, description = "No snapshot of this revision is kept"),
+        (status = 409, description = "Vault changed since base_revision, returns the current revision", body = RevisionResponse),
+        (status = 412, description = "Vault changed since the If-Match revision, returns the current revision", body = RevisionResponse),
+        (status = 428, description = "Neither If-Match nor base_revision given"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_history_restore(
+    req: HttpRequest,
+    path: web::Path<u64>,
+    req_body: Option<web::Json<RestoreRequest>>,
+    db: web::Data<Database>,
+    notifier: web::Data<Notifier>,
+) -> impl Responder {
+    let revision = path.into_inner();
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!(
+            "Restoring revision {} of vault of: {}",
+            revision, &claims.sub
+        );
+        if let Err(response) = check_email_verified(&db, &claims.sub).await {
+            return response;
+        }
+        // The body is optional, a client may restore with If-Match alone
+        let base_revision = req_body.and_then(|body| body.into_inner().base_revision);
+        let precondition = match update_precondition(&req, base_revision) {
+            Ok(precondition) => precondition,
+            Err(response) => return response,
+        };
+        let (base_revision, conflict) = match precondition {
+            Precondition::IfMatch(revision) => (revision, StatusCode::PRECONDITION_FAILED),
+            Precondition::BaseRevision(revision) => (Some(revision), StatusCode::CONFLICT),
+        };
+        // The restored snapshot becomes the newest revision, so later ones stay in the history
+        let (user_id, nonce) = (claims.sub.clone(), claims.nonce.clone());
+        let restored = db
+            .run(move |db| {
+                let Some(snapshot) = db.vault_history_get(&user_id, revision)? else {
+                    return Ok(None);
+                };
+                let now = current_timestamp();
+                let status = db.data_update(
+                    &user_id,
+                    &snapshot.encrypted_data,
+                    base_revision,
+                    &nonce,
+                    now,
+                )?;
+                if let VaultUpdate::Updated { .. } = status {
+                    prune_history(db, &user_id, now)?;
+                }
+                Ok(Some(status))
+            })
+            .await;
+        match restored {
+            Ok(Some(VaultUpdate::Updated { revision })) => {
+                notifier.vault_changed(&claims.sub, revision, &claims.nonce);
+                HttpResponse::Ok()
+                    .insert_header((header::ETAG, revision_etag(revision)))
+                    .json(RevisionResponse { revision })
+            }
+            Ok(Some(VaultUpdate::Conflict { revision })) => {
+                info!(
+                    "Refused restore of vault of {}, it is at revision {}",
+                    &claims.sub, revision
+                );
+                HttpResponse::build(conflict)
+                    .insert_header((header::ETAG, revision_etag(revision)))
+                    .json(RevisionResponse { revision })
+            }
+            Ok(None) => HttpResponse::NotFound().finish(),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sync/changes",
+    params(ChangesQuery),
+    responses(
+        (status = 200, description = "Items changed after the since revision, deleted ones as tombstones", body = ChangesResponse),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_changes(
+    req: HttpRequest,
+    query: web::Query<ChangesQuery>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let since = query.into_inner().since.unwrap_or(0);
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        debug!(
+            "Listing changes of vault of {} since {}",
+            &claims.sub, since
+        );
+        if let Err(response) = check_email_verified(&db, &claims.sub).await {
+            return response;
+        }
+        match db.run(move |db| db.items_changed(&claims.sub, since)).await {
+            Ok((revision, items)) => HttpResponse::Ok()
+                .insert_header((header::ETAG, revision_etag(revision)))
+                .json(ChangesResponse {
+                    revision,
+                    items: items
+                        .into_iter()
+                        .map(|item| ItemResponse {
+                            id: item.id,
+                            revision: item.revision,
+                            deleted: item.encrypted_data.is_none(),
+                            encrypted_data: item.encrypted_data,
+                        })
+                        .collect(),
+                }),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/sync/changes",
+    request_body = ItemChangesRequest,
+    responses(
+        (status = 200, description = "All changes applied as one new revision, which is returned", body = RevisionResponse),
+        (status = 400, description = "Invalid input, or an item changed twice"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 409, description = "Items changed since their base revision, nothing was applied", body = ItemConflictResponse),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_changes_apply(
+    req: HttpRequest,
+    req_body: web::Json<ItemChangesRequest>,
+    db: web::Data<Database>,
+    notifier: web::Data<Notifier>,
+    blobs: web::Data<BlobStore>,
+) -> impl Responder {
+    if let Err(response) = validate_format(&req_body) {
+        return response;
+    }
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!(
+            "Applying {} item changes to vault of: {}",
+            req_body.changes.len(),
+            &claims.sub
+        );
+        if let Err(response) = check_email_verified(&db, &claims.sub).await {
+            return response;
+        }
+        let changes: Vec<ItemChange> = req_body
+            .into_inner()
+            .changes
+            .into_iter()
+            .map(|change| ItemChange {
+                id: change.id,
+                encrypted_data: change.encrypted_data,
+                base_revision: change.base_revision,
+            })
+            .collect();
+        let user_id = claims.sub.clone();
+        match db.run(move |db| db.items_apply(&user_id, &changes)).await {
+            Ok(ItemsUpdate::Applied {
+                revision,
+                attachments,
+            }) => {
+                for id in &attachments {
+                    blobs.remove(&claims.sub, id).await;
+                }
+                notifier.items_changed(&claims.sub, revision, &claims.nonce);
+                HttpResponse::Ok()
+                    .insert_header((header::ETAG, revision_etag(revision)))
+                    .json(RevisionResponse { revision })
+            }
+            Ok(ItemsUpdate::Conflict { revision, ids }) => {
+                info!(
+                    "Refused item changes of {}, {} items changed in the meantime",
+                    &claims.sub,
+                    ids.len()
+                );
+                HttpResponse::Conflict()
+                    .insert_header((header::ETAG, revision_etag(revision)))
+                    .json(ItemConflictResponse {
+                        revision,
+                        conflicts: ids,
+                    })
+            }
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/sync/notifications/ticket",
+    responses(
+        (status = 200, description = "Ticket that opens a notification stream of the session", body = StreamTicketResponse),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 500, description = "Database Error or JWT Generation Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_notifications_ticket(
+    req: HttpRequest,
+    jwt_auth: web::Data<JwtAuth>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let claims = req.extensions().get::<Claims>().cloned();
+    let Some(claims) = claims else {
+        return HttpResponse::InternalServerError().finish();
+    };
+    if let Err(response) = check_email_verified(&db, &claims.sub).await {
+        return response;
+    }
+    match jwt_auth.generate_stream_ticket(&claims.sub, &claims.nonce, &claims.stamp) {
+        Ok(ticket) => HttpResponse::Ok().json(StreamTicketResponse {
+            ticket,
+            expires_in: STREAM_TICKET_TTL,
+        }),
+        Err(e) => {
+            error!("Failed to generate stream ticket: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+// Served outside of the authenticated scope, since browsers open it with a ticket instead
+// of the Authorization header
+#[utoipa::path(
+    params(NotificationsQuery),
+    responses(
+        (status = 101, description = "WebSocket opened, vault changes arrive as JSON text messages"),
+        (status = 200, description = "Without a WebSocket upgrade, vault changes arrive as server-sent events", content_type = "text/event-stream"),
+        (status = 400, description = "Malformed WebSocket handshake"),
+        (status = 401, description = "JWT Token or ticket is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+#[get("/api/v1/sync/notifications")]
+pub async fn route_notifications(
+    req: HttpRequest,
+    body: web::Payload,
+    query: web::Query<NotificationsQuery>,
+    auth: Option<BearerAuth>,
+    jwt_auth: web::Data<JwtAuth>,
+    db: web::Data<Database>,
+    notifier: web::Data<Notifier>,
+) -> impl Responder {
+    let ticket = query.into_inner().ticket;
+    let token = auth.map(|auth| auth.token().to_string());
+    let jwt = jwt_auth.into_inner();
+    let checked = match (token, ticket) {
+        (Some(token), _) => db.run(move |db| check_token(&jwt, db, &token)).await,
+        (None, Some(ticket)) => {
+            db.run(move |db| check_stream_ticket(&jwt, db, &ticket))
+                .await
+        }
+        (None, None) => return HttpResponse::Unauthorized().finish(),
+    };
+    let claims = match checked {
+        Ok(TokenCheck::Valid(claims)) => claims,
+        Ok(_) => return HttpResponse::Unauthorized().finish(),
+        Err(e) => return handle_db_error(&e),
+    };
+    if let Err(response) = check_email_verified(&db, &claims.sub).await {
+        return response;
+    }
+    let context = StreamContext {
+        notifier: notifier.into_inner(),
+        db: db.get_ref().clone(),
+        user_id: claims.sub,
+        nonce: claims.nonce,
+        stamp: claims.stamp,
+    };
+    let upgrade = req
+        .headers()
+        .get(header::UPGRADE)
+        .and_then(|upgrade| upgrade.to_str().ok())
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    if upgrade {
+        match actix_ws::handle(&req, body) {
+            Ok((response, session, messages)) => {
+                websocket(context, session, messages);
+                response
+            }
+            Err(e) => {
+                debug!("Refused WebSocket handshake: {}", e);
+                HttpResponse::BadRequest().finish()
+            }
+        }
+    } else {
+        HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .streaming(event_stream(context))
+    }
+}
+
+// Helper to look up an attachment of the user
+async fn get_attachment(
+    db: &Database,
+    user_id: &str,
+    id: &str,
+) -> Result<Option<Attachment>, HttpResponse> {
+    let (user_id, id) = (user_id.to_string(), id.to_string());
+    db.run(move |db| db.attachment_get(&user_id, &id))
+        .await
+        .map_err(|e| handle_db_error(&e))
+}
+
+fn attachment_response(attachment: Attachment) -> AttachmentResponse {
+    AttachmentResponse {
+        complete: attachment.received == attachment.size,
+        id: attachment.id,
+        item_id: attachment.item_id,
+        size: attachment.size,
+        received: attachment.received,
+        created_at: attachment.created_at,
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/sync/attachments",
+    request_body = AttachmentCreateRequest,
+    responses(
+        (status = 201, description = "Attachment created, its content is uploaded next", body = AttachmentResponse),
+        (status = 400, description = "Invalid input"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 404, description = "The item does not exist or is deleted"),
+        (status = 413, description = "The attachment does not fit in the quota of the user", body = QuotaExceededResponse),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_attachment_create(
+    req: HttpRequest,
+    req_body: web::Json<AttachmentCreateRequest>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    if let Err(response) = validate_format(&req_body) {
+        return response;
+    }
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!(
+            "Creating attachment of {} bytes for {}",
+            req_body.size, &claims.sub
+        );
+        if let Err(response) = check_email_verified(&db, &claims.sub).await {
+            return response;
+        }
+        let req_body = req_body.into_inner();
+        let attachment = Attachment {
+            id: Uuid::new_v4().to_string(),
+            item_id: req_body.item_id,
+            size: req_body.size,
+            received: 0,
+            created_at: current_timestamp(),
+        };
+        let quota = attachment_quota();
+        let (user_id, created) = (claims.sub.clone(), attachment.clone());
+        match db
+            .run(move |db| db.attachment_create(&user_id, &created, quota))
+            .await
+        {
+            Ok(AttachmentCreate::Created) => {
+                HttpResponse::Created().json(attachment_response(attachment))
+            }
+            Ok(AttachmentCreate::ItemNotFound) => HttpResponse::NotFound().finish(),
+            Ok(AttachmentCreate::QuotaExceeded { usage }) => {
+                info!("Attachment of {} exceeds quota", &claims.sub);
+                HttpResponse::PayloadTooLarge().json(QuotaExceededResponse { usage, quota })
+            }
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sync/attachments",
+    responses(
+        (status = 200, description = "Attachments of the user with the quota they count against", body = AttachmentListResponse),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_attachments(req: HttpRequest, db: web::Data<Database>) -> impl Responder {
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        debug!("Listing attachments of: {}", &claims.sub);
+        if let Err(response) = check_email_verified(&db, &claims.sub).await {
+            return response;
+        }
+        match db.run(move |db| db.attachments_list(&claims.sub)).await {
+            Ok(attachments) => HttpResponse::Ok().json(AttachmentListResponse {
+                usage: attachments.iter().map(|attachment| attachment.size).sum(),
+                quota: attachment_quota(),
+                attachments: attachments.into_iter().map(attachment_response).collect(),
+            }),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sync/attachments/{id}",
+    params(("id" = String, Path, description = "Id of the attachment")),
+    responses(
+        (status = 200, description = "Attachment with its upload progress", body = AttachmentResponse),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 404, description = "No such attachment"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_attachment_get(
+    req: HttpRequest,
+    path: web::Path<String>,
+    db: web::Data<Database>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        debug!("Fetching attachment {} of: {}", id, &claims.sub);
+        if let Err(response) = check_email_verified(&db, &claims.sub).await {
+            return response;
+        }
+        match db.run(move |db| db.attachment_get(&claims.sub, &id)).await {
+            Ok(Some(attachment)) => HttpResponse::Ok().json(attachment_response(attachment)),
+            Ok(None) => HttpResponse::NotFound().finish(),
+            Err(e) => handle_db_error(&e),
+        }
+    } else {
+        HttpResponse::InternalServerError().finish()
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/sync/attachments/{id}/content",
+    params(("id" = String, Path, description = "Id of the attachment"), UploadQuery),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "Bytes received so far, an interrupted upload resumes from there", body = AttachmentResponse),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 404, description = "No such attachment"),
+        (status = 409, description = "The offset is not the received bytes, the upload is complete or already running", body = AttachmentResponse),
+        (status = 413, description = "The content is longer than the declared size, the bytes within it were kept", body = AttachmentResponse),
+        (status = 500, description = "Database Error, File Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_attachment_upload(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<UploadQuery>,
+    mut body: web::Payload,
+    db: web::Data<Database>,
+    blobs: web::Data<BlobStore>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let offset = query.offset;
+    let claims = req.extensions().get::<Claims>().cloned();
+    let Some(claims) = claims else {
+        return HttpResponse::InternalServerError().finish();
+    };
+    info!(
+        "Uploading attachment {} of {} from {}",
+        id, &claims.sub, offset
+    );
+    if let Err(response) = check_email_verified(&db, &claims.sub).await {
+        return response;
+    }
+    let Some(_upload) = blobs.begin_upload(&claims.sub, &id) else {
+        info!("Attachment {} is already being uploaded", id);
+        return match get_attachment(&db, &claims.sub, &id).await {
+            Ok(Some(attachment)) => HttpResponse::Conflict().json(attachment_response(attachment)),
+            Ok(None) => HttpResponse::NotFound().finish(),
+            Err(response) => response,
+        };
+    };
+    let mut attachment = match get_attachment(&db, &claims.sub, &id).await {
+        Ok(Some(attachment)) => attachment,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(response) => return response,
+    };
+    if attachment.received == attachment.size || offset != attachment.received {
+        return HttpResponse::Conflict().json(attachment_response(attachment));
+    }
+
+    let mut file = match blobs.open_upload(&claims.sub, &id, offset).await {
+        Ok(file) => file,
+        Err(e) => return handle_file_error(e),
+    };
+    // Progress is recorded whatever ends the upload, so it can be resumed
+    let mut received = offset;
+    let mut too_long = false;
+    let mut failed = None;
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                debug!("Upload of attachment {} interrupted: {}", id, e);
+                break;
+            }
+        };
+        if received + chunk.len() as u64 > attachment.size {
+            too_long = true;
+            break;
+        }
+        if let Err(e) = file.write_all(&chunk).await {
+            failed = Some(e);
+            break;
+        }
+        received += chunk.len() as u64;
+    }
+    if let Err(e) = file.sync_data().await {
+        // Nothing past the offset is known to be on disk
+        received = offset;
+        failed.get_or_insert(e);
+    }
+    drop(file);
+
+    let (user_id, attachment_id) = (claims.sub.clone(), id.clone());
+    let recorded = db
+        .run(move |db| {
+            db.attachment_received(&user_id, &attachment_id, received)?;
+            db.attachment_get(&user_id, &attachment_id)
+        })
+        .await;
+    match recorded {
+        Ok(Some(recorded)) => attachment = recorded,
+        Ok(None) => {
+            // Deleted with its item during the upload
+            blobs.remove(&claims.sub, &id).await;
+            return HttpResponse::NotFound().finish();
+        }
+        Err(e) => return handle_db_error(&e),
+    }
+    if let Some(e) = failed {
+        return handle_file_error(e);
+    }
+    if too_long {
+        info!("Upload of attachment {} exceeds its size", id);
+        return HttpResponse::PayloadTooLarge().json(attachment_response(attachment));
+    }
+    HttpResponse::Ok().json(attachment_response(attachment))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/sync/attachments/{id}/content",
+    params(("id" = String, Path, description = "Id of the attachment")),
+    responses(
+        (status = 200, description = "Encrypted content of the attachment", content_type = "application/octet-stream"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 404, description = "No such attachment"),
+        (status = 409, description = "The upload is not complete", body = AttachmentResponse),
+        (status = 500, description = "Database Error, File Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_attachment_download(
+    req: HttpRequest,
+    path: web::Path<String>,
+    db: web::Data<Database>,
+    blobs: web::Data<BlobStore>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let claims = req.extensions().get::<Claims>().cloned();
+    let Some(claims) = claims else {
+        return HttpResponse::InternalServerError().finish();
+    };
+    debug!("Downloading attachment {} of: {}", id, &claims.sub);
+    if let Err(response) = check_email_verified(&db, &claims.sub).await {
+        return response;
+    }
+    let attachment = match get_attachment(&db, &claims.sub, &id).await {
+        Ok(Some(attachment)) => attachment,
+        Ok(None) => return HttpResponse::NotFound().finish(),
+        Err(response) => return response,
+    };
+    if attachment.received != attachment.size {
+        return HttpResponse::Conflict().json(attachment_response(attachment));
+    }
+    match blobs.open(&claims.sub, &id, attachment.size).await {
+        Ok(stream) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .no_chunking(attachment.size)
+            .streaming(stream),
+        Err(e) => handle_file_error(e),
+    }
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/sync/attachments/{id}",
+    params(("id" = String, Path, description = "Id of the attachment")),
+    responses(
+        (status = 200, description = "Attachment deleted, its size no longer counts against the quota"),
+        (status = 401, description = "JWT Token is invalid"),
+        (status = 403, description = "Email address is not verified, if verification is required"),
+        (status = 404, description = "No such attachment"),
+        (status = 500, description = "Database Error or JWT Extraction Error")
+    ),
+    tag = "sync",
+    security(
+        ("jwt_auth" = [])
+    )
+)]
+pub async fn route_attachment_delete(
+    req: HttpRequest,
+    path: web::Path<String>,
+    db: web::Data<Database>,
+    blobs: web::Data<BlobStore>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let claims = req.extensions().get::<Claims>().cloned();
+    if let Some(claims) = claims {
+        info!("Deleting attachment {} of: {}", id, &claims.sub);
+        if let Err(response) = check_email_verified(&db, &claims.sub).await {
+            return response;
+        }
+        let (user_id, attachment_id) = (claims.sub.clone(), id.clone());
+        match db
+            .run(move |db| db.attachment_delete(&user_id, &attachment_id))
+            .await
+        {
+            Ok(true) => {
+                blobs.remove(&claims.sub, &id).await;
+                HttpResponse::Ok().finish()
+            }
+            Ok(false) => HttpResponse::NotFound().finish(),
             Err(e) => handle_db_error(&e),
         }
     } else {
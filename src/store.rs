@@ -0,0 +1,506 @@
+use log::info;
+use std::{env, fmt, ops::Deref, sync::Arc};
+use tokio::{sync::Semaphore, task};
+
+use crate::db::{get_db_path, SqliteStore};
+use crate::migrations::latest_version;
+use crate::models::KdfParams;
+#[cfg(feature = "postgres")]
+use crate::pg::PostgresStore;
+
+// Failure of a storage backend, busy ones are worth retrying
+#[derive(Debug)]
+pub enum StoreError {
+    // Overloaded or locked for longer than calls wait
+    Busy(String),
+    Backend(String),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Busy(message) | StoreError::Backend(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+pub type Result<T> = std::result::Result<T, StoreError>;
+
+// Whether an error means the store is overloaded, rather than broken
+pub fn is_busy(e: &StoreError) -> bool {
+    matches!(e, StoreError::Busy(_))
+}
+
+// Emails are compared case-insensitively, so they are stored and looked up normalized
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+// Outcome of presenting a refresh token
+#[derive(Debug, PartialEq)]
+pub enum RefreshTokenStatus {
+    Valid { user_id: String, family_id: String },
+    Reused { user_id: String, family_id: String },
+    Expired,
+    Unknown,
+}
+
+// Encrypted vault of a user, the revision counts its updates
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vault {
+    pub encrypted_data: String,
+    pub revision: u64,
+}
+
+// Outcome of a conditional vault update
+#[derive(Debug, PartialEq)]
+pub enum VaultUpdate {
+    Updated { revision: u64 },
+    Conflict { revision: u64 }, // another update came first, nothing was written
+}
+
+// Vault as one update wrote it, kept for a rollback
+#[derive(Debug, Clone, PartialEq)]
+pub struct VaultSnapshot {
+    pub revision: u64,
+    pub size: usize, // bytes of the encrypted data
+    pub created_at: usize,
+    pub session_id: Option<String>, // nonce of the writing session, None from before history
+    pub device_name: Option<String>,
+}
+
+// Separately encrypted record of a vault, the revision is the one of the change that last
+// wrote it. Deleted items are tombstones without data
+#[derive(Debug, Clone, PartialEq)]
+pub struct VaultItem {
+    pub id: String,
+    pub revision: u64,
+    pub encrypted_data: Option<String>,
+}
+
+// Change of one item by a client, None deletes it. It only applies if the item is still
+// at base_revision, 0 for an item that does not exist yet
+#[derive(Debug, Clone)]
+pub struct ItemChange {
+    pub id: String,
+    pub encrypted_data: Option<String>,
+    pub base_revision: u64,
+}
+
+// Outcome of applying a batch of item changes, which applies completely or not at all
+#[derive(Debug, PartialEq)]
+pub enum ItemsUpdate {
+    // Attachments of deleted items go with them, their files are left to remove
+    Applied {
+        revision: u64,
+        attachments: Vec<String>,
+    },
+    Conflict {
+        revision: u64,
+        ids: Vec<String>,
+    }, // items changed since their base revision
+}
+
+// Client-encrypted file of a vault item, complete once every declared byte was received
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub id: String,
+    pub item_id: String,
+    pub size: u64,
+    pub received: u64,
+    pub created_at: usize,
+}
+
+// Outcome of starting the upload of an attachment
+#[derive(Debug, PartialEq)]
+pub enum AttachmentCreate {
+    Created,
+    ItemNotFound, // unknown or deleted
+    QuotaExceeded { usage: u64 },
+}
+
+// Seconds last_seen may lag behind, so not every authenticated request writes the session
+pub const SESSION_TOUCH_INTERVAL: usize = 60;
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub nonce: String,
+    pub device_name: Option<String>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: usize,
+    pub last_seen: usize,
+}
+
+// TOTP secret of a user, only enabled once the user confirmed a code
+#[derive(Clone)]
+pub struct Totp {
+    pub secret: String,
+    pub enabled: bool,
+    pub last_step: u64, // time step of the last accepted code, codes are single-use
+}
+
+#[derive(Clone)]
+pub struct WebauthnCredential {
+    pub credential_id: String, // base64url
+    pub name: Option<String>,
+    pub public_key: Vec<u8>, // COSE encoded
+    pub algorithm: i64,
+    pub sign_count: u32,
+    pub created_at: usize,
+    pub last_used: Option<usize>,
+}
+
+// Pending registration or login, keyed by its challenge
+#[derive(Clone)]
+pub struct WebauthnCeremony {
+    pub user_id: String,
+    pub purpose: String,               // "register", "2fa" or "passwordless"
+    pub pending_nonce: Option<String>, // nonce of the pending token of a 2fa login
+    pub expires_at: usize,
+}
+
+// Failed logins of an account ("email") or a client address ("ip")
+#[derive(Clone)]
+pub struct LoginFailures {
+    pub kind: String,
+    pub subject: String,
+    pub failures: u32,
+    pub last_failure: usize,
+    pub locked_until: usize,
+}
+
+// Everything the server persists: accounts with their vault blobs, sessions, tokens and
+// revocations, second factors and login throttling. Every call is atomic on its own.
+// Implementations block, callers on async workers go through Database::run
+pub trait VaultStore: Send + Sync {
+    // Brings the schema up to date, returns the versions that were applied. See migrations.rs
+    fn migrate(&self) -> std::result::Result<Vec<u32>, String>;
+    // Schema version of the store, without migrating it
+    fn schema_version(&self) -> Result<u32>;
+
+    // Id of the account with the given email, the only lookup by email
+    fn user_id(&self, email: &str) -> Result<Option<String>>;
+    fn user_email(&self, user_id: &str) -> Result<Option<String>>;
+    fn user_password_hash(&self, user_id: &str) -> Result<Option<String>>;
+    // Replaces the stored hash without touching sessions, used to upgrade legacy rows
+    fn user_rehash_password(&self, user_id: &str, password_hash: &str) -> Result<()>;
+    // Returns the id of the new account
+    fn user_register(
+        &self,
+        email: &str,
+        password_hash: &str,
+        kdf: Option<&KdfParams>,
+    ) -> Result<String>;
+    // Accounts without stored parameters use the defaults clients hardcoded so far
+    fn user_kdf(&self, user_id: &str) -> Result<Option<KdfParams>>;
+    fn user_email_verified(&self, user_id: &str) -> Result<Option<bool>>;
+    // Returns false if the account does not exist
+    fn user_verify_email(&self, user_id: &str) -> Result<bool>;
+    fn user_security_stamp(&self, user_id: &str) -> Result<Option<String>>;
+    // Rotates the security stamp, which drops all refresh tokens and every session but the
+    // one of the caller. That session survives, the client gets new tokens for it
+    fn user_changepwd(
+        &self,
+        user_id: &str,
+        password_hash: &str,
+        kdf: Option<&KdfParams>,
+        current_nonce: &str,
+    ) -> Result<()>;
+    // Moves the account to the new address, only the session of the caller survives.
+    // Returns false if the new address is already taken
+    fn user_change_email(
+        &self,
+        user_id: &str,
+        new_email: &str,
+        password_hash: Option<&str>,
+        kdf: Option<&KdfParams>,
+        current_nonce: &str,
+    ) -> Result<bool>;
+    fn user_logout_all(&self, user_id: &str) -> Result<()>;
+    fn user_delete(&self, user_id: &str) -> Result<()>;
+
+    fn user_exists(&self, email: &str) -> Result<bool> {
+        Ok(self.user_id(email)?.is_some())
+    }
+
+    fn data_get(&self, user_id: &str) -> Result<Vault>;
+    // Writes the vault if it is still at base_revision, or at any revision for None, and
+    // keeps a snapshot of it along with the session that wrote it
+    fn data_update(
+        &self,
+        user_id: &str,
+        encrypted_data: &str,
+        base_revision: Option<u64>,
+        session_id: &str,
+        now: usize,
+    ) -> Result<VaultUpdate>;
+
+    // Newest first, without their data
+    fn vault_history_list(&self, user_id: &str) -> Result<Vec<VaultSnapshot>>;
+    fn vault_history_get(&self, user_id: &str, revision: u64) -> Result<Option<Vault>>;
+    // Drops the snapshots of a user beyond the newest keep, and those created before
+    fn vault_history_prune(
+        &self,
+        user_id: &str,
+        keep: usize,
+        created_before: usize,
+    ) -> Result<usize>;
+    fn vault_history_cleanup(&self, created_before: usize) -> Result<usize>;
+    // Current revision of the items, with those written after since and up to that revision
+    fn items_changed(&self, user_id: &str, since: u64) -> Result<(u64, Vec<VaultItem>)>;
+    // Applies all changes as one new revision of the items, unless one of them conflicts.
+    // Items count their own revisions, apart from the vault of data_update
+    fn items_apply(&self, user_id: &str, changes: &[ItemChange]) -> Result<ItemsUpdate>;
+
+    // Registers an upload if the item exists and the declared size fits into the quota,
+    // which the sizes of all attachments of the user count against
+    fn attachment_create(
+        &self,
+        user_id: &str,
+        attachment: &Attachment,
+        quota: u64,
+    ) -> Result<AttachmentCreate>;
+    fn attachment_get(&self, user_id: &str, id: &str) -> Result<Option<Attachment>>;
+    fn attachments_list(&self, user_id: &str) -> Result<Vec<Attachment>>;
+    fn attachment_received(&self, user_id: &str, id: &str, received: u64) -> Result<()>;
+    fn attachment_delete(&self, user_id: &str, id: &str) -> Result<bool>;
+    // Drops uploads started before created_before and never completed, returns the user
+    // ids and ids of their files
+    fn attachments_cleanup(&self, created_before: usize) -> Result<Vec<(String, String)>>;
+
+    fn refresh_token_store(
+        &self,
+        token_hash: &str,
+        user_id: &str,
+        family_id: &str,
+        expires_at: usize,
+    ) -> Result<()>;
+    // Marks a refresh token as used. Presenting an already used token revokes its whole family.
+    fn refresh_token_consume(&self, token_hash: &str, now: usize) -> Result<RefreshTokenStatus>;
+    fn refresh_tokens_cleanup(&self, now: usize) -> Result<usize>;
+
+    // Revoked access tokens by nonce, kept until the token would have expired anyway
+    fn token_revoke(&self, nonce: &str, expires_at: usize) -> Result<()>;
+    fn token_is_revoked(&self, nonce: &str) -> Result<bool>;
+    fn revoked_tokens_cleanup(&self, now: usize) -> Result<usize>;
+
+    fn session_create(&self, user_id: &str, session: &Session) -> Result<()>;
+    // Returns false if the session does not exist (anymore), otherwise refreshes last_seen
+    // once it is older than SESSION_TOUCH_INTERVAL
+    fn session_touch(&self, nonce: &str, now: usize) -> Result<bool>;
+    // Oldest first
+    fn sessions_list(&self, user_id: &str) -> Result<Vec<Session>>;
+    // Removes a session of the given user together with its refresh token family
+    fn session_delete(&self, user_id: &str, nonce: &str) -> Result<bool>;
+    // Removes every session of the user except the given one, returns the removed nonces
+    fn sessions_delete_others(&self, user_id: &str, keep_nonce: &str) -> Result<Vec<String>>;
+
+    // Starts a new enrollment, returns false if TOTP is already enabled
+    fn totp_begin(&self, user_id: &str, secret: &str) -> Result<bool>;
+    fn totp_get(&self, user_id: &str) -> Result<Option<Totp>>;
+    // Marks the step of an accepted code as used, returns false if it was used already
+    fn totp_use_step(&self, user_id: &str, step: u64) -> Result<bool>;
+    // Finishes the enrollment and replaces the recovery codes, returns false if there was
+    // nothing to confirm or the code was used already
+    fn totp_enable(
+        &self,
+        user_id: &str,
+        step: u64,
+        recovery_code_hashes: &[String],
+    ) -> Result<bool>;
+    // Also drops the recovery codes
+    fn totp_disable(&self, user_id: &str) -> Result<()>;
+    // Recovery codes are single-use, returns false if the code is unknown or used already
+    fn recovery_code_consume(&self, user_id: &str, code_hash: &str) -> Result<bool>;
+
+    fn totp_enabled(&self, user_id: &str) -> Result<bool> {
+        Ok(self.totp_get(user_id)?.is_some_and(|totp| totp.enabled))
+    }
+
+    // Second factors the user can complete a login with, empty if two-factor authentication is off
+    fn user_two_factor_methods(&self, user_id: &str) -> Result<Vec<String>> {
+        let mut methods = Vec::new();
+        if self.totp_enabled(user_id)? {
+            methods.push("totp".to_string());
+            methods.push("recovery_code".to_string());
+        }
+        if !self.webauthn_credentials_list(user_id)?.is_empty() {
+            methods.push("webauthn".to_string());
+        }
+        Ok(methods)
+    }
+
+    // Returns false if the credential is registered already
+    fn webauthn_credential_add(
+        &self,
+        user_id: &str,
+        credential: &WebauthnCredential,
+    ) -> Result<bool>;
+    // Oldest first
+    fn webauthn_credentials_list(&self, user_id: &str) -> Result<Vec<WebauthnCredential>>;
+    fn webauthn_credential_get(
+        &self,
+        user_id: &str,
+        credential_id: &str,
+    ) -> Result<Option<WebauthnCredential>>;
+    // Stores the counter of an accepted assertion, returns false if a concurrent assertion
+    // already stored a counter at least as high
+    fn webauthn_credential_used(
+        &self,
+        credential_id: &str,
+        sign_count: u32,
+        now: usize,
+    ) -> Result<bool>;
+    // Returns false if the credential does not exist or belongs to another user
+    fn webauthn_credential_delete(&self, user_id: &str, credential_id: &str) -> Result<bool>;
+    fn webauthn_challenge_store(&self, challenge: &str, ceremony: &WebauthnCeremony) -> Result<()>;
+    // Challenges are single-use, expired ones are treated as unknown
+    fn webauthn_challenge_consume(
+        &self,
+        challenge: &str,
+        now: usize,
+    ) -> Result<Option<WebauthnCeremony>>;
+    fn webauthn_challenges_cleanup(&self, now: usize) -> Result<usize>;
+
+    fn login_failures_get(&self, kind: &str, subject: &str) -> Result<Option<LoginFailures>>;
+    // Counts a failed login, failures older than the window are forgotten.
+    // Returns the number of failures in a row.
+    fn login_failure_record(
+        &self,
+        kind: &str,
+        subject: &str,
+        now: usize,
+        window: usize,
+    ) -> Result<u32>;
+    fn login_lock(&self, kind: &str, subject: &str, locked_until: usize) -> Result<()>;
+    // Returns false if there were no failures to reset
+    fn login_failures_reset(&self, kind: &str, subject: &str) -> Result<bool>;
+    // Longest lockouts first
+    fn login_failures_list(&self) -> Result<Vec<LoginFailures>>;
+    // Removes counters without recent failures whose lockout is over
+    fn login_failures_cleanup(&self, now: usize, window: usize) -> Result<usize>;
+}
+
+// Calls that may wait for a blocking thread at once, further callers are turned away
+fn queue_size() -> usize {
+    env::var("DB_QUEUE_SIZE")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .filter(|&size| size > 0)
+        .unwrap_or(64)
+}
+
+// The store of the server, registered as app data next to JwtAuth. Derefs to the
+// store for blocking callers such as the CLI commands
+#[derive(Clone)]
+pub struct Database {
+    store: Arc<dyn VaultStore>,
+    queue: Arc<Semaphore>,
+}
+
+impl Database {
+    pub fn new(store: impl VaultStore + 'static) -> Self {
+        Database {
+            store: Arc::new(store),
+            queue: Arc::new(Semaphore::new(queue_size())),
+        }
+    }
+
+    // Runs store calls on the blocking thread pool, so async workers never wait for
+    // the backend. Once DB_QUEUE_SIZE calls are in flight, further ones fail right away
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&dyn VaultStore) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = self.store.clone();
+        self.run_blocking(move || f(&*store)).await?
+    }
+
+    // Runs other blocking work, like password hashing, in the same queue as store calls
+    pub async fn run_blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let Ok(permit) = self.queue.clone().try_acquire_owned() else {
+            return Err(StoreError::Busy(
+                "Too many queued database calls".to_string(),
+            ));
+        };
+        task::spawn_blocking(move || {
+            let _permit = permit;
+            f()
+        })
+        .await
+        .map_err(|e| StoreError::Backend(format!("Database call failed: {}", e)))
+    }
+}
+
+impl Deref for Database {
+    type Target = dyn VaultStore;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.store
+    }
+}
+
+// Where the data lives: sqlite://<path> or postgres://<user>@<host>/<database>. Without
+// DATABASE_URL it is the SQLite file at DB_FILE, as before the URL existed
+pub fn database_url() -> String {
+    env::var("DATABASE_URL").unwrap_or_else(|_| format!("sqlite://{}", get_db_path()))
+}
+
+// Opens the store the scheme of the URL selects, without migrating it
+pub fn open_database(url: &str) -> std::result::Result<Database, String> {
+    if let Some(path) = url.strip_prefix("sqlite://") {
+        info!("Opening SQLite database at {}", path);
+        return Ok(Database::new(SqliteStore::open(path)?));
+    }
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        #[cfg(feature = "postgres")]
+        {
+            info!("Connecting to PostgreSQL");
+            return Ok(Database::new(PostgresStore::open(url)?));
+        }
+        #[cfg(not(feature = "postgres"))]
+        return Err("PostgreSQL requires a build with the postgres feature".to_string());
+    }
+    Err(format!(
+        "Unsupported DATABASE_URL, expected a sqlite:// or postgres:// URL: {}",
+        url.split("://").next().unwrap_or_default()
+    ))
+}
+
+// Opens the store at DATABASE_URL for admin commands, which leave upgrades to migrate
+pub fn open_migrated_database() -> std::result::Result<Database, String> {
+    let db = open_database(&database_url())?;
+    let version = db.schema_version().map_err(|e| e.to_string())?;
+    if version < latest_version() {
+        return Err(format!(
+            "Database is at schema version {}, run `migrate` first",
+            version
+        ));
+    }
+    if version > latest_version() {
+        return Err(format!(
+            "Database is at schema version {}, newer than this binary",
+            version
+        ));
+    }
+    Ok(db)
+}
+
+// Opens the store at DATABASE_URL and brings its schema up to date
+pub fn initialize_database() -> std::result::Result<Database, String> {
+    let db = open_database(&database_url())?;
+    db.migrate()?;
+    info!(
+        "Database initialized successfully at schema version {}.",
+        latest_version()
+    );
+    Ok(db)
+}
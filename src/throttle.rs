@@ -0,0 +1,93 @@
+use log::warn;
+
+use crate::config::env_param;
+use crate::store::{normalize_email, Result, VaultStore};
+
+// First lockout after the free attempts, doubled with every further failure
+const LOCKOUT_BASE: usize = 30;
+
+// Failed logins allowed before the account or address is locked
+fn free_attempts(kind: &str) -> u32 {
+    match kind {
+        "ip" => env_param("LOGIN_MAX_ATTEMPTS_PER_IP", 20),
+        _ => env_param("LOGIN_MAX_ATTEMPTS", 5),
+    }
+}
+
+fn max_lockout() -> usize {
+    env_param("LOGIN_LOCKOUT_MAX", 3600)
+}
+
+// Failures are forgotten after this long without another one
+pub fn failure_window() -> usize {
+    env_param("LOGIN_FAILURE_WINDOW", 24 * 3600)
+}
+
+fn lockout_duration(failures: u32, free: u32) -> Option<usize> {
+    let over = failures.checked_sub(free).filter(|over| *over > 0)?;
+    let factor = 1usize.checked_shl(over - 1).unwrap_or(usize::MAX);
+    Some(LOCKOUT_BASE.saturating_mul(factor).min(max_lockout()))
+}
+
+// Login attempts are counted per account and per client address, unknown accounts
+// included, so the lockout does not reveal which emails exist
+fn subjects(email: &str, ip: Option<&str>) -> Vec<(&'static str, String)> {
+    let mut subjects = vec![("email", normalize_email(email))];
+    if let Some(ip) = ip {
+        subjects.push(("ip", ip.to_string()));
+    }
+    subjects
+}
+
+// Returns the seconds until the next attempt is allowed, if the login is locked
+pub fn login_retry_after(
+    db: &dyn VaultStore,
+    email: &str,
+    ip: Option<&str>,
+    now: usize,
+) -> Result<Option<usize>> {
+    let mut retry_after = None;
+    for (kind, subject) in subjects(email, ip) {
+        if let Some(failures) = db.login_failures_get(kind, &subject)? {
+            if failures.locked_until > now {
+                retry_after = retry_after.max(Some(failures.locked_until - now));
+            }
+        }
+    }
+    Ok(retry_after)
+}
+
+fn record_failure(db: &dyn VaultStore, kind: &str, subject: &str, now: usize) -> Result<()> {
+    let failures = db.login_failure_record(kind, subject, now, failure_window())?;
+    if let Some(duration) = lockout_duration(failures, free_attempts(kind)) {
+        warn!(
+            "Locking logins of {} {} for {} seconds after {} failures",
+            kind, subject, duration, failures
+        );
+        db.login_lock(kind, subject, now + duration)?;
+    }
+    Ok(())
+}
+
+pub fn login_failed(db: &dyn VaultStore, email: &str, ip: Option<&str>, now: usize) -> Result<()> {
+    for (kind, subject) in subjects(email, ip) {
+        record_failure(db, kind, &subject, now)?;
+    }
+    Ok(())
+}
+
+// For probes that reveal something about an account without guessing its password,
+// only the client address is counted, so they can not lock out the account
+pub fn address_failed(db: &dyn VaultStore, ip: Option<&str>, now: usize) -> Result<()> {
+    match ip {
+        Some(ip) => record_failure(db, "ip", ip, now),
+        None => Ok(()),
+    }
+}
+
+// Only the account is forgiven. The address keeps its failures until they expire, otherwise
+// logging into an own account between guesses would keep the per-IP counter at zero
+pub fn login_succeeded(db: &dyn VaultStore, email: &str) -> Result<()> {
+    db.login_failures_reset("email", &normalize_email(email))?;
+    Ok(())
+}
@@ -0,0 +1,82 @@
+use rand::{distributions::Uniform, Rng};
+use sha2::{Digest, Sha256};
+use std::env;
+use subtle::ConstantTimeEq;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+// RFC 6238 defaults, which every authenticator app supports
+const DIGITS: usize = 6;
+const STEP: u64 = 30;
+// Codes of the previous and next step are accepted as well, for clock drift
+const SKEW: u64 = 1;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+fn issuer() -> String {
+    env::var("TOTP_ISSUER").unwrap_or_else(|_| "rsPass".to_string())
+}
+
+fn totp(secret: &str, email: &str) -> Result<TOTP, String> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| format!("Invalid TOTP secret: {:?}", e))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        SKEW as u8,
+        STEP,
+        secret,
+        Some(issuer()),
+        email.to_string(),
+    )
+    .map_err(|e| format!("Invalid TOTP parameters: {}", e))
+}
+
+// Base32 encoded 160 bit secret, as recommended by RFC 4226
+pub fn generate_secret() -> String {
+    Secret::generate_secret().to_encoded().to_string()
+}
+
+// URI for authenticator apps, usually shown as a QR code by the client
+pub fn otpauth_uri(secret: &str, email: &str) -> Result<String, String> {
+    Ok(totp(secret, email)?.get_url())
+}
+
+// Returns the time step the code belongs to, so the caller can reject reused codes
+pub fn verify_code(secret: &str, code: &str, now: u64) -> Option<u64> {
+    let totp = totp(secret, "").ok()?;
+    let current = now / STEP;
+    (current.saturating_sub(SKEW)..=current + SKEW).find(|step| {
+        let expected = totp.generate(step * STEP);
+        bool::from(expected.as_bytes().ct_eq(code.trim().as_bytes()))
+    })
+}
+
+// Recovery codes look like "k7pm-2xq9-vh4d", they are shown once and stored hashed
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+    let alphabet = Uniform::from(0..RECOVERY_CODE_ALPHABET.len());
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let chars: Vec<char> = (0..12)
+                .map(|_| RECOVERY_CODE_ALPHABET[rng.sample(alphabet)] as char)
+                .collect();
+            chars
+                .chunks(4)
+                .map(|chunk| chunk.iter().collect::<String>())
+                .collect::<Vec<_>>()
+                .join("-")
+        })
+        .collect()
+}
+
+// Codes are random enough that a fast hash is sufficient, as for refresh tokens
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
@@ -0,0 +1,239 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+use p256::ecdsa::{signature::Verifier, Signature as P256Signature, VerifyingKey as P256Key};
+use rand::RngCore;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::env;
+
+// COSE algorithm identifiers of the supported credential keys
+pub const COSE_ES256: i64 = -7;
+pub const COSE_EDDSA: i64 = -8;
+
+// Authenticator data flags
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+// Relying party id, the registrable domain the client runs on
+pub fn rp_id() -> String {
+    env::var("WEBAUTHN_RP_ID").unwrap_or_else(|_| "localhost".to_string())
+}
+
+// Origins the client is served from, comma separated
+fn allowed_origins() -> Vec<String> {
+    env::var("WEBAUTHN_ORIGINS")
+        .unwrap_or_else(|_| "http://localhost:8080".to_string())
+        .split(',')
+        .map(|origin| origin.trim().to_string())
+        .collect()
+}
+
+pub fn generate_challenge() -> String {
+    let mut challenge = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut challenge);
+    URL_SAFE_NO_PAD.encode(challenge)
+}
+
+pub fn decode(value: &str) -> Result<Vec<u8>, String> {
+    URL_SAFE_NO_PAD
+        .decode(value.trim_end_matches('='))
+        .map_err(|e| format!("Invalid base64url: {}", e))
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+// Checks type and origin of the clientDataJSON, returns the challenge so the caller can
+// look up the ceremony it belongs to
+pub fn client_data_challenge(
+    client_data_json: &[u8],
+    expected_type: &str,
+) -> Result<String, String> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)
+        .map_err(|e| format!("Invalid clientDataJSON: {}", e))?;
+    if client_data.kind != expected_type {
+        return Err(format!("Unexpected ceremony type: {}", client_data.kind));
+    }
+    if !allowed_origins().contains(&client_data.origin) {
+        return Err(format!("Unexpected origin: {}", client_data.origin));
+    }
+    Ok(client_data.challenge)
+}
+
+struct AuthenticatorData {
+    flags: u8,
+    sign_count: u32,
+    credential: Option<(Vec<u8>, Vec<u8>)>, // credential id and COSE public key
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData, String> {
+    if data.len() < 37 {
+        return Err("Authenticator data is too short".to_string());
+    }
+    if data[..32] != Sha256::digest(rp_id().as_bytes())[..] {
+        return Err("Authenticator data is for another relying party".to_string());
+    }
+    let flags = data[32];
+    if flags & FLAG_USER_PRESENT == 0 {
+        return Err("User presence is required".to_string());
+    }
+    let sign_count = u32::from_be_bytes(data[33..37].try_into().unwrap());
+
+    let mut credential = None;
+    if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+        // aaguid (16 bytes), credential id length (2 bytes), credential id, COSE key
+        let rest = &data[37..];
+        if rest.len() < 18 {
+            return Err("Attested credential data is too short".to_string());
+        }
+        let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+        let Some(credential_id) = rest.get(18..18 + id_len) else {
+            return Err("Attested credential data is too short".to_string());
+        };
+        let mut key = &rest[18 + id_len..];
+        let start = key.len();
+        let _: Value =
+            ciborium::from_reader(&mut key).map_err(|e| format!("Invalid COSE key: {}", e))?;
+        let public_key = rest[18 + id_len..18 + id_len + start - key.len()].to_vec();
+        credential = Some((credential_id.to_vec(), public_key));
+    }
+
+    Ok(AuthenticatorData {
+        flags,
+        sign_count,
+        credential,
+    })
+}
+
+fn cbor_map_get(map: &[(Value, Value)], key: i64) -> Option<&Value> {
+    map.iter()
+        .find(|(k, _)| k.as_integer() == Some(key.into()))
+        .map(|(_, v)| v)
+}
+
+fn cbor_map_bytes(map: &[(Value, Value)], key: i64) -> Result<&[u8], String> {
+    cbor_map_get(map, key)
+        .and_then(Value::as_bytes)
+        .map(Vec::as_slice)
+        .ok_or_else(|| format!("COSE key is missing parameter {}", key))
+}
+
+enum PublicKey {
+    Es256(P256Key),
+    EdDsa(ed25519_dalek::VerifyingKey),
+}
+
+// Supports the algorithms every platform authenticator offers, ES256 and Ed25519
+fn parse_public_key(cose_key: &[u8]) -> Result<(i64, PublicKey), String> {
+    let key: Value =
+        ciborium::from_reader(cose_key).map_err(|e| format!("Invalid COSE key: {}", e))?;
+    let Some(map) = key.as_map() else {
+        return Err("COSE key is not a map".to_string());
+    };
+    let alg = cbor_map_get(map, 3)
+        .and_then(Value::as_integer)
+        .and_then(|alg| i64::try_from(alg).ok());
+    match alg {
+        Some(COSE_ES256) => {
+            let (x, y) = (cbor_map_bytes(map, -2)?, cbor_map_bytes(map, -3)?);
+            if x.len() != 32 || y.len() != 32 {
+                return Err("Invalid P-256 key length".to_string());
+            }
+            let point = p256::EncodedPoint::from_affine_coordinates(x.into(), y.into(), false);
+            let key = P256Key::from_encoded_point(&point)
+                .map_err(|e| format!("Invalid P-256 key: {}", e))?;
+            Ok((COSE_ES256, PublicKey::Es256(key)))
+        }
+        Some(COSE_EDDSA) => {
+            let x: [u8; 32] = cbor_map_bytes(map, -2)?
+                .try_into()
+                .map_err(|_| "Invalid Ed25519 key length".to_string())?;
+            let key = ed25519_dalek::VerifyingKey::from_bytes(&x)
+                .map_err(|e| format!("Invalid Ed25519 key: {}", e))?;
+            Ok((COSE_EDDSA, PublicKey::EdDsa(key)))
+        }
+        _ => Err(format!("Unsupported COSE algorithm: {:?}", alg)),
+    }
+}
+
+// Credential created by a registration ceremony
+pub struct NewCredential {
+    pub credential_id: Vec<u8>,
+    pub public_key: Vec<u8>, // COSE encoded
+    pub algorithm: i64,
+    pub sign_count: u32,
+}
+
+// Verifies the attestation object of a registration, its client data has to be checked
+// with client_data_challenge before. Attestation statements are not verified, the server
+// asks for "none" attestation.
+pub fn verify_registration(attestation_object: &[u8]) -> Result<NewCredential, String> {
+    let object: Value = ciborium::from_reader(attestation_object)
+        .map_err(|e| format!("Invalid attestation object: {}", e))?;
+    let auth_data = object
+        .as_map()
+        .and_then(|map| {
+            map.iter()
+                .find(|(k, _)| k.as_text() == Some("authData"))
+                .and_then(|(_, v)| v.as_bytes())
+        })
+        .ok_or_else(|| "Attestation object is missing authData".to_string())?;
+    let auth_data = parse_authenticator_data(auth_data)?;
+
+    let Some((credential_id, public_key)) = auth_data.credential else {
+        return Err("Attestation object has no credential".to_string());
+    };
+    let (algorithm, _) = parse_public_key(&public_key)?;
+    Ok(NewCredential {
+        credential_id,
+        public_key,
+        algorithm,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+// Verifies an assertion signature of a stored credential, its client data has to be
+// checked with client_data_challenge before. Returns the new signature counter.
+pub fn verify_assertion(
+    public_key: &[u8],
+    stored_sign_count: u32,
+    client_data_json: &[u8],
+    authenticator_data: &[u8],
+    signature: &[u8],
+    require_user_verification: bool,
+) -> Result<u32, String> {
+    let auth_data = parse_authenticator_data(authenticator_data)?;
+    if require_user_verification && auth_data.flags & FLAG_USER_VERIFIED == 0 {
+        return Err("User verification is required".to_string());
+    }
+
+    let mut signed = authenticator_data.to_vec();
+    signed.extend_from_slice(&Sha256::digest(client_data_json));
+    let valid = match parse_public_key(public_key)?.1 {
+        PublicKey::Es256(key) => P256Signature::from_der(signature)
+            .is_ok_and(|signature| key.verify(&signed, &signature).is_ok()),
+        PublicKey::EdDsa(key) => ed25519_dalek::Signature::from_slice(signature)
+            .is_ok_and(|signature| key.verify_strict(&signed, &signature).is_ok()),
+    };
+    if !valid {
+        return Err("Invalid assertion signature".to_string());
+    }
+
+    // Authenticators without a counter always report 0, otherwise it has to grow,
+    // a counter that did not is a sign of a cloned authenticator
+    if (auth_data.sign_count != 0 || stored_sign_count != 0)
+        && auth_data.sign_count <= stored_sign_count
+    {
+        return Err(format!(
+            "Signature counter did not increase ({} <= {})",
+            auth_data.sign_count, stored_sign_count
+        ));
+    }
+    Ok(auth_data.sign_count)
+}
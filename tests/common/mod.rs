@@ -5,62 +5,202 @@ use actix_web::web::Data;
 use actix_web::App;
 use actix_web_httpauth::middleware::HttpAuthentication;
 use backend_rspass::auth::validator;
-use backend_rspass::{auth::JwtAuth, db::initialize_database, routes::*};
-use std::{env, fs, sync::Once};
+use backend_rspass::{
+    attachments::BlobStore, auth::JwtAuth, mailer::mailer_from_env, memory::MemoryStore,
+    notify::Notifier, routes::*, store::Database,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Once,
+};
 use uuid::Uuid;
 //use env_logger::Env;
 
 static INIT: Once = Once::new();
 
-pub fn setup() -> (Data<JwtAuth>, String) {
+// Every test gets a store of its own, so tests do not share state. That is an in-memory
+// one, or a fresh schema in the database at TEST_DATABASE_URL for a postgres:// URL
+#[allow(dead_code)]
+pub fn setup() -> (Data<JwtAuth>, Database) {
     INIT.call_once(|| {
         //let log_level = env::var("LOG_LEVEL").unwrap_or_else(|_| "debug".to_string());
         //env_logger::Builder::from_env(Env::default().default_filter_or("debug")).init();
     });
 
-    let test_db = format!("./test_{}.db", Uuid::new_v4());
-    env::set_var("DB_FILE", &test_db);
-    if let Err(e) = initialize_database() {
-        panic!("Failed to initialize test database: {}", e);
-    }
-
     // Initialize JwtAuth with test secret
     std::env::set_var("JWT_SECRET", "test_secret_length_16");
 
-    (Data::new(JwtAuth::new()), test_db)
+    (Data::new(JwtAuth::new()), test_database())
 }
 
-pub fn cleanup(db_file: &str) {
-    if let Ok(conn) = rusqlite::Connection::open(db_file) {
-        let _ = conn.close();
+fn test_database() -> Database {
+    #[cfg(feature = "postgres")]
+    if let Ok(url) = std::env::var("TEST_DATABASE_URL") {
+        // The blocking client runs a runtime of its own, which must not start on a test's
+        let store = std::thread::spawn(move || postgres_schema(&url))
+            .join()
+            .unwrap();
+        return Database::new(store);
     }
-    if fs::remove_file(db_file).is_err() {
-        println!("Failed to delete {}", db_file);
+    Database::new(MemoryStore::new())
+}
+
+// A store in a new, migrated schema of the database at url
+#[cfg(feature = "postgres")]
+#[allow(dead_code)]
+pub fn postgres_schema(url: &str) -> backend_rspass::pg::PostgresStore {
+    use backend_rspass::{pg::PostgresStore, store::VaultStore};
+
+    let schema = format!("test_{}", Uuid::new_v4().simple());
+    postgres::Client::connect(url, postgres::NoTls)
+        .unwrap()
+        .batch_execute(&format!("CREATE SCHEMA {}", schema))
+        .unwrap();
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let store = PostgresStore::open(&format!(
+        "{}{}options=-c%20search_path%3D{}",
+        url, separator, schema
+    ))
+    .unwrap();
+    store.migrate().unwrap();
+    store
+}
+
+// Directory of its own under the system temp dir, removed with all it holds when dropped,
+// so the files of a failing test go away too
+#[allow(dead_code)]
+pub struct TempDir(PathBuf);
+
+#[allow(dead_code)]
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("rspass_test_{}", Uuid::new_v4()));
+        fs::create_dir_all(&path).unwrap();
+        Self(path)
     }
-    env::remove_var("DB_FILE");
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// Path of a fresh database file, for the tests of the SQLite store itself. The file lives
+// as long as the returned directory
+#[allow(dead_code)]
+pub fn sqlite_file() -> (TempDir, String) {
+    let dir = TempDir::new();
+    let path = dir.path().join("test.db").to_string_lossy().into_owned();
+    (dir, path)
+}
+
+// Tests that upload no attachments share one directory for them, which stays empty
+#[allow(dead_code)]
+pub fn create_server(jwt_auth: Data<JwtAuth>, db: &Database) -> TestServer {
+    let blob_dir = std::env::temp_dir().join("rspass_test_attachments");
+    create_server_with_blobs(jwt_auth, db, &blob_dir)
 }
 
-pub fn create_server(jwt_auth: Data<JwtAuth>) -> TestServer {
+#[allow(dead_code)]
+pub fn create_server_with_blobs(
+    jwt_auth: Data<JwtAuth>,
+    db: &Database,
+    blob_dir: &Path,
+) -> TestServer {
+    let mailer = Data::from(mailer_from_env().unwrap());
+    let db = Data::new(db.clone());
+    let notifier = Data::new(Notifier::new());
+    let blobs = Data::new(BlobStore::new(blob_dir).unwrap());
     actix_test::start(move || {
         let auth = HttpAuthentication::with_fn(validator);
         App::new()
             .app_data(jwt_auth.clone())
+            .app_data(mailer.clone())
+            .app_data(notifier.clone())
+            .app_data(blobs.clone())
+            .app_data(db.clone())
             .service(route_health)
+            .service(route_jwks)
             .service(route_email)
             .service(route_login)
+            .service(route_login_2fa)
+            .service(route_webauthn_login_begin)
+            .service(route_webauthn_login)
             .service(route_register)
+            .service(route_verify_email)
+            .service(route_refresh)
+            .service(route_notifications)
             .service(
                 scope("/api/v1/account")
                     .wrap(auth.clone())
                     .route("/changepwd", web::post().to(route_changepwd))
+                    .route("/verify-email", web::post().to(route_verify_email_resend))
+                    .route("/change-email", web::post().to(route_change_email))
+                    .route(
+                        "/change-email/confirm",
+                        web::post().to(route_change_email_confirm),
+                    )
                     .route("/logout", web::get().to(route_logout))
-                    .route("/delete", web::get().to(route_delete)),
+                    .route("/logout-all", web::get().to(route_logout_all))
+                    .route("/delete", web::get().to(route_delete))
+                    .route("/sessions", web::get().to(route_sessions))
+                    .route("/sessions", web::delete().to(route_sessions_revoke_others))
+                    .route("/sessions/{id}", web::delete().to(route_session_revoke))
+                    .route("/2fa/totp", web::post().to(route_totp_setup))
+                    .route("/2fa/totp/confirm", web::post().to(route_totp_confirm))
+                    .route("/2fa/totp/disable", web::post().to(route_totp_disable))
+                    .route("/2fa/webauthn", web::get().to(route_webauthn_credentials))
+                    .route(
+                        "/2fa/webauthn/register/begin",
+                        web::post().to(route_webauthn_register_begin),
+                    )
+                    .route(
+                        "/2fa/webauthn/register",
+                        web::post().to(route_webauthn_register),
+                    )
+                    .route(
+                        "/2fa/webauthn/{id}",
+                        web::delete().to(route_webauthn_credential_delete),
+                    ),
             )
             .service(
                 scope("/api/v1/sync")
                     .wrap(auth)
                     .route("/fetch", web::get().to(route_fetch))
-                    .route("/update", web::post().to(route_update)),
+                    .route("/update", web::post().to(route_update))
+                    .route(
+                        "/notifications/ticket",
+                        web::post().to(route_notifications_ticket),
+                    )
+                    .route("/changes", web::get().to(route_changes))
+                    .route("/changes", web::post().to(route_changes_apply))
+                    .route("/history", web::get().to(route_history))
+                    .route("/history/{revision}", web::get().to(route_history_get))
+                    .route(
+                        "/history/{revision}/restore",
+                        web::post().to(route_history_restore),
+                    )
+                    .route("/attachments", web::post().to(route_attachment_create))
+                    .route("/attachments", web::get().to(route_attachments))
+                    .route("/attachments/{id}", web::get().to(route_attachment_get))
+                    .route(
+                        "/attachments/{id}",
+                        web::delete().to(route_attachment_delete),
+                    )
+                    .route(
+                        "/attachments/{id}/content",
+                        web::put().to(route_attachment_upload),
+                    )
+                    .route(
+                        "/attachments/{id}/content",
+                        web::get().to(route_attachment_download),
+                    ),
             )
     })
 }
@@ -0,0 +1,244 @@
+use actix_web::http::StatusCode;
+use backend_rspass::models::*;
+use serde_json::json;
+
+mod common;
+
+async fn register(server: &actix_test::TestServer, email: &str) -> String {
+    let mut register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::OK);
+    let body: LoginResponse = register.json().await.unwrap();
+    body.token
+}
+
+async fn add_item(server: &actix_test::TestServer, token: &str, id: &str) {
+    let apply = server
+        .post("/api/v1/sync/changes")
+        .bearer_auth(token)
+        .send_json(&json!({ "changes": [
+            { "id": id, "encrypted_data": "item", "base_revision": 0 }
+        ]}))
+        .await
+        .unwrap();
+    assert_eq!(apply.status(), StatusCode::OK);
+}
+
+async fn create(
+    server: &actix_test::TestServer,
+    token: &str,
+    item_id: &str,
+    size: u64,
+) -> AttachmentResponse {
+    let mut create = server
+        .post("/api/v1/sync/attachments")
+        .bearer_auth(token)
+        .send_json(&json!({ "item_id": item_id, "size": size }))
+        .await
+        .unwrap();
+    assert_eq!(create.status(), StatusCode::CREATED);
+    create.json().await.unwrap()
+}
+
+async fn upload(
+    server: &actix_test::TestServer,
+    token: &str,
+    id: &str,
+    offset: u64,
+    content: &'static [u8],
+) -> (StatusCode, AttachmentResponse) {
+    let mut upload = server
+        .put(format!(
+            "/api/v1/sync/attachments/{}/content?offset={}",
+            id, offset
+        ))
+        .bearer_auth(token)
+        .send_body(content)
+        .await
+        .unwrap();
+    (upload.status(), upload.json().await.unwrap())
+}
+
+#[actix_rt::test]
+async fn test_attachment_upload_resume() {
+    let (jwt_auth, db) = common::setup();
+    let blob_dir = common::TempDir::new();
+    let server = common::create_server_with_blobs(jwt_auth, &db, blob_dir.path());
+    let token = register(&server, "attach1@example.com").await;
+    add_item(&server, &token, "login-1").await;
+
+    let attachment = create(&server, &token, "login-1", 10).await;
+    assert_eq!(attachment.received, 0);
+    assert!(!attachment.complete);
+
+    // Nothing to download until every byte arrived
+    let download = server
+        .get(format!(
+            "/api/v1/sync/attachments/{}/content",
+            attachment.id
+        ))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(download.status(), StatusCode::CONFLICT);
+
+    let (status, progress) = upload(&server, &token, &attachment.id, 0, b"0123").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(progress.received, 4);
+
+    // Resuming elsewhere than at the received bytes is refused with the progress
+    let (status, progress) = upload(&server, &token, &attachment.id, 2, b"23456789").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(progress.received, 4);
+
+    let (status, progress) = upload(&server, &token, &attachment.id, 4, b"456789").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(progress.received, 10);
+    assert!(progress.complete);
+
+    let mut download = server
+        .get(format!(
+            "/api/v1/sync/attachments/{}/content",
+            attachment.id
+        ))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(download.status(), StatusCode::OK);
+    assert_eq!(&download.body().await.unwrap()[..], b"0123456789");
+
+    // Complete attachments are not written again
+    let (status, _) = upload(&server, &token, &attachment.id, 10, b"x").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+}
+
+#[actix_rt::test]
+async fn test_attachment_size_and_quota() {
+    let (jwt_auth, db) = common::setup();
+    let blob_dir = common::TempDir::new();
+    let server = common::create_server_with_blobs(jwt_auth, &db, blob_dir.path());
+    let token = register(&server, "attach2@example.com").await;
+    add_item(&server, &token, "login-1").await;
+
+    // Only live items get attachments
+    let missing = server
+        .post("/api/v1/sync/attachments")
+        .bearer_auth(&token)
+        .send_json(&json!({ "item_id": "login-2", "size": 10 }))
+        .await
+        .unwrap();
+    assert_eq!(missing.status(), StatusCode::NOT_FOUND);
+
+    // Bytes past the declared size are refused, those within it are kept
+    let attachment = create(&server, &token, "login-1", 4).await;
+    let (status, progress) = upload(&server, &token, &attachment.id, 0, b"0123456789").await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert!(progress.received <= 4);
+
+    // Declared sizes count against the default quota of 100 MiB, before any upload
+    create(&server, &token, "login-1", 60 * 1024 * 1024).await;
+    let mut over = server
+        .post("/api/v1/sync/attachments")
+        .bearer_auth(&token)
+        .send_json(&json!({ "item_id": "login-1", "size": 60 * 1024 * 1024 }))
+        .await
+        .unwrap();
+    assert_eq!(over.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: QuotaExceededResponse = over.json().await.unwrap();
+    assert_eq!(body.usage, 60 * 1024 * 1024 + 4);
+
+    let mut list = server
+        .get("/api/v1/sync/attachments")
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(list.status(), StatusCode::OK);
+    let body: AttachmentListResponse = list.json().await.unwrap();
+    assert_eq!(body.attachments.len(), 2);
+    assert_eq!(body.usage, 60 * 1024 * 1024 + 4);
+    assert_eq!(body.quota, 100 * 1024 * 1024);
+
+    // Deleting frees the quota
+    let delete = server
+        .delete(format!("/api/v1/sync/attachments/{}", attachment.id))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(delete.status(), StatusCode::OK);
+    let delete = server
+        .delete(format!("/api/v1/sync/attachments/{}", attachment.id))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(delete.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn test_attachment_cleanup() {
+    let (jwt_auth, db) = common::setup();
+    let blob_dir = common::TempDir::new();
+    let server = common::create_server_with_blobs(jwt_auth, &db, blob_dir.path());
+    let token = register(&server, "attach3@example.com").await;
+    add_item(&server, &token, "login-1").await;
+    add_item(&server, &token, "login-2").await;
+
+    let first = create(&server, &token, "login-1", 3).await;
+    let second = create(&server, &token, "login-2", 3).await;
+    assert_eq!(
+        upload(&server, &token, &first.id, 0, b"abc").await.0,
+        StatusCode::OK
+    );
+
+    // Attachments go with the deletion of their item
+    let apply = server
+        .post("/api/v1/sync/changes")
+        .bearer_auth(&token)
+        .send_json(&json!({ "changes": [{ "id": "login-1", "base_revision": 1 }] }))
+        .await
+        .unwrap();
+    assert_eq!(apply.status(), StatusCode::OK);
+    let gone = server
+        .get(format!("/api/v1/sync/attachments/{}", first.id))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(gone.status(), StatusCode::NOT_FOUND);
+    let kept = server
+        .get(format!("/api/v1/sync/attachments/{}", second.id))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(kept.status(), StatusCode::OK);
+
+    // And all of them with the account
+    let delete = server
+        .get("/api/v1/account/delete")
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(delete.status(), StatusCode::OK);
+    let token = register(&server, "attach3@example.com").await;
+    let mut list = server
+        .get("/api/v1/sync/attachments")
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    let body: AttachmentListResponse = list.json().await.unwrap();
+    assert!(body.attachments.is_empty());
+    assert_eq!(body.usage, 0);
+}
@@ -5,9 +5,9 @@ mod common;
 
 #[actix_rt::test]
 async fn test_register_success() {
-    let (jwt_auth, db_file) = common::setup();
+    let (jwt_auth, db) = common::setup();
     let jwt_auth_clone = jwt_auth.clone();
-    let server = common::create_server(jwt_auth);
+    let server = common::create_server(jwt_auth, &db);
     let req = server.post("/api/v1/auth/register").send_json(&json!({
         "email": "test@example.com",
         "password_hash": "hash123"
@@ -21,8 +21,18 @@ async fn test_register_success() {
     assert!(!body.token.is_empty());
 
     // Validate the JWT token
-    let claims = jwt_auth_clone.validate_token(&body.token).unwrap();
-    assert_eq!(claims.sub, "test@example.com");
-
-    common::cleanup(&db_file);
+    // The subject is the id of the account, lookups by address ignore the case
+    let token = body.token.clone();
+    let claims = db
+        .run(move |db| Ok(jwt_auth_clone.validate_token(db, &token)))
+        .await
+        .unwrap()
+        .unwrap();
+    let id = db
+        .run(|db| db.user_id("Test@Example.com"))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(claims.sub, id);
+    assert!(uuid::Uuid::parse_str(&id).is_ok());
 }
@@ -0,0 +1,147 @@
+use actix_web::http::{header, StatusCode};
+use backend_rspass::{
+    db::SqliteStore,
+    memory::MemoryStore,
+    models::*,
+    store::{is_busy, Database, VaultStore},
+};
+use rusqlite::Connection;
+use serde_json::json;
+use std::{
+    env,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+mod common;
+
+#[actix_rt::test]
+async fn test_full_queue_rejected() {
+    env::set_var("DB_QUEUE_SIZE", "1");
+    let db = Database::new(MemoryStore::new());
+    env::remove_var("DB_QUEUE_SIZE");
+
+    // The only slot is taken by a call that waits until it is released
+    let (release, released) = mpsc::channel::<()>();
+    let (started, running) = mpsc::channel();
+    let pending = db.clone();
+    let first = actix_rt::spawn(async move {
+        pending
+            .run(move |_| {
+                started.send(()).unwrap();
+                released.recv().unwrap();
+                Ok(1)
+            })
+            .await
+    });
+    actix_rt::task::spawn_blocking(move || running.recv().unwrap())
+        .await
+        .unwrap();
+
+    let rejected = db.run(|_| Ok(2)).await.unwrap_err();
+    assert!(is_busy(&rejected));
+
+    release.send(()).unwrap();
+    assert_eq!(first.await.unwrap().unwrap(), 1);
+    assert_eq!(db.run(|_| Ok(3)).await.unwrap(), 3);
+}
+
+#[actix_rt::test]
+async fn test_locked_database_answers_503() {
+    let (jwt_auth, _) = common::setup();
+    let (_dir, db_file) = common::sqlite_file();
+    let store = SqliteStore::open(&db_file).unwrap();
+    store.migrate().unwrap();
+    let server = common::create_server(jwt_auth, &Database::new(store));
+
+    let mut register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": "busy1@example.com",
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    let tokens: LoginResponse = register.json().await.unwrap();
+
+    // Another process holds the write lock for longer than requests wait for it
+    let lock = Connection::open(&db_file).unwrap();
+    lock.execute_batch("BEGIN IMMEDIATE").unwrap();
+
+    let update = server
+        .post("/api/v1/sync/update")
+        .bearer_auth(&tokens.token)
+        .timeout(Duration::from_secs(30))
+        .send_json(&json!({ "encrypted_data": "vault", "base_revision": 0 }));
+    // The waiting request must not stall the only worker of the test server
+    let health = async {
+        let start = Instant::now();
+        let response = server.get("/api/v1/health").send().await.unwrap();
+        (response.status(), start.elapsed())
+    };
+    let (update, (health, elapsed)) = tokio::join!(update, health);
+    let update = update.unwrap();
+    assert_eq!(update.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(update.headers().contains_key(header::RETRY_AFTER));
+    assert_eq!(health, StatusCode::OK);
+    assert!(elapsed < Duration::from_secs(2));
+
+    lock.execute_batch("ROLLBACK").unwrap();
+    let update = server
+        .post("/api/v1/sync/update")
+        .bearer_auth(&tokens.token)
+        .send_json(&json!({ "encrypted_data": "vault", "base_revision": 0 }))
+        .await
+        .unwrap();
+    assert_eq!(update.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn test_login_hashes_in_the_queue() {
+    let (jwt_auth, _) = common::setup();
+    env::set_var("DB_QUEUE_SIZE", "1");
+    let db = Database::new(MemoryStore::new());
+    env::remove_var("DB_QUEUE_SIZE");
+    let server = common::create_server(jwt_auth, &db);
+
+    // Stored with a slow hash, so verifying the login takes a while
+    env::set_var("ARGON2_ITERATIONS", "40");
+    let login = || {
+        server
+            .post("/api/v1/auth/login")
+            .timeout(Duration::from_secs(30))
+            .send_json(&json!({
+                "email": "busy2@example.com",
+                "password_hash": "hash123"
+            }))
+    };
+    let register = server
+        .post("/api/v1/auth/register")
+        .timeout(Duration::from_secs(30))
+        .send_json(&json!({
+            "email": "busy2@example.com",
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    env::remove_var("ARGON2_ITERATIONS");
+    assert_eq!(register.status(), StatusCode::OK);
+
+    // While the login verifies, the worker stays free and the queue is full
+    let others = async {
+        actix_rt::time::sleep(Duration::from_millis(200)).await;
+        let start = Instant::now();
+        let health = server.get("/api/v1/health").send().await.unwrap();
+        let elapsed = start.elapsed();
+        let rejected = login().await.unwrap();
+        (health.status(), elapsed, rejected)
+    };
+    let (first, (health, elapsed, rejected)) = tokio::join!(login(), others);
+    assert_eq!(first.unwrap().status(), StatusCode::OK);
+    assert_eq!(health, StatusCode::OK);
+    assert!(elapsed < Duration::from_millis(200));
+    assert_eq!(rejected.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(rejected.headers().contains_key(header::RETRY_AFTER));
+
+    assert_eq!(login().await.unwrap().status(), StatusCode::OK);
+}
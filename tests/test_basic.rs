@@ -6,8 +6,8 @@ mod common;
 
 #[actix_rt::test]
 async fn test_register_login_logout_flow() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // Register
     let register_resp = server
@@ -49,14 +49,12 @@ async fn test_register_login_logout_flow() {
         .await
         .unwrap();
     assert_eq!(verify_logout.status(), StatusCode::UNAUTHORIZED);
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_register_login_change_password_flow() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // Register
     let mut register_resp = server
@@ -103,14 +101,12 @@ async fn test_register_login_change_password_flow() {
         .await
         .unwrap();
     assert_eq!(new_login_resp.status(), StatusCode::OK);
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_register_login_delete_flow() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // Register
     let mut register_resp = server
@@ -143,14 +139,12 @@ async fn test_register_login_delete_flow() {
         .await
         .unwrap();
     assert_eq!(login_resp.status(), StatusCode::NOT_FOUND);
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_register_duplicate_email() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // First registration
     let first_register = server
@@ -173,14 +167,12 @@ async fn test_register_duplicate_email() {
         .await
         .unwrap();
     assert_eq!(second_register.status(), 409);
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_login_nonexistent_email() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     let login_resp = server
         .post("/api/v1/auth/login")
@@ -191,14 +183,12 @@ async fn test_login_nonexistent_email() {
         .await
         .unwrap();
     assert_eq!(login_resp.status(), StatusCode::NOT_FOUND);
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_login_wrong_password() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // Register
     let register_resp = server
@@ -221,6 +211,4 @@ async fn test_login_wrong_password() {
         .await
         .unwrap();
     assert_eq!(login_resp.status(), StatusCode::UNAUTHORIZED);
-
-    common::cleanup(&db_file);
 }
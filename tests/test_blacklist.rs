@@ -0,0 +1,63 @@
+use actix_web::http::StatusCode;
+use backend_rspass::{
+    auth::{current_timestamp, JwtAuth},
+    models::*,
+};
+use serde_json::json;
+
+mod common;
+
+#[actix_rt::test]
+async fn test_blacklist_survives_restart() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let mut register_resp = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": "blacklist1@example.com",
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register_resp.status(), StatusCode::OK);
+    let body: LoginResponse = register_resp.json().await.unwrap();
+
+    let logout = server
+        .get("/api/v1/account/logout")
+        .bearer_auth(&body.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(logout.status(), StatusCode::OK);
+
+    // A fresh instance reads the revocation from the database
+    let restarted = JwtAuth::new();
+    let blacklisted = db
+        .run(move |db| Ok(restarted.is_blacklisted(db, &body.token)))
+        .await
+        .unwrap();
+    assert!(blacklisted);
+}
+
+#[actix_rt::test]
+async fn test_blacklist_cleanup_removes_expired() {
+    let (jwt_auth, db) = common::setup();
+
+    db.run(move |db| {
+        let now = current_timestamp();
+        db.token_revoke("expired-nonce", now - 10)?;
+        db.token_revoke("active-nonce", now + 3600)?;
+
+        assert_eq!(db.revoked_tokens_cleanup(now)?, 1);
+        assert!(!db.token_is_revoked("expired-nonce")?);
+        assert!(db.token_is_revoked("active-nonce")?);
+
+        // Cleanup through JwtAuth keeps entries that have not expired yet
+        jwt_auth.cleanup_blacklist(db);
+        assert!(db.token_is_revoked("active-nonce")?);
+        Ok(())
+    })
+    .await
+    .unwrap();
+}
@@ -0,0 +1,213 @@
+use actix_web::http::StatusCode;
+use backend_rspass::models::*;
+use serde_json::json;
+use std::{env, fs};
+
+mod common;
+
+const MAIL_DIR: &str = "./test_mail";
+
+fn setup() -> (
+    actix_web::web::Data<backend_rspass::auth::JwtAuth>,
+    backend_rspass::store::Database,
+) {
+    env::set_var("MAILER", "file");
+    env::set_var("MAIL_DIR", MAIL_DIR);
+    common::setup()
+}
+
+// Removes the mails sent to the address and returns the tokens they contain
+fn take_tokens(email: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    for entry in fs::read_dir(MAIL_DIR).unwrap() {
+        let path = entry.unwrap().path();
+        let content = fs::read_to_string(&path).unwrap();
+        if !content.starts_with(&format!("To: {}\n", email)) {
+            continue;
+        }
+        // JWTs start with the encoded header, both as link parameter and on their own
+        let token = content
+            .split(|c: char| c.is_whitespace() || c == '=')
+            .find(|word| word.starts_with("eyJ"))
+            .unwrap();
+        tokens.push(token.to_string());
+        fs::remove_file(path).unwrap();
+    }
+    tokens
+}
+
+async fn register(server: &actix_test::TestServer, email: &str) -> LoginResponse {
+    let mut register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::OK);
+    take_tokens(email);
+    register.json().await.unwrap()
+}
+
+async fn login(server: &actix_test::TestServer, email: &str, password: &str) -> StatusCode {
+    server
+        .post("/api/v1/auth/login")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": password
+        }))
+        .await
+        .unwrap()
+        .status()
+}
+
+async fn fetch(server: &actix_test::TestServer, token: &str) -> StatusCode {
+    server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+async fn request_change(
+    server: &actix_test::TestServer,
+    tokens: &LoginResponse,
+    new_email: &str,
+) -> StatusCode {
+    server
+        .post("/api/v1/account/change-email")
+        .bearer_auth(&tokens.token)
+        .send_json(&json!({ "new_email": new_email }))
+        .await
+        .unwrap()
+        .status()
+}
+
+#[actix_rt::test]
+async fn test_change_email() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let tokens = register(&server, "change1@example.com").await;
+    let mut other = server
+        .post("/api/v1/auth/login")
+        .send_json(&json!({
+            "email": "change1@example.com",
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    let other: LoginResponse = other.json().await.unwrap();
+
+    assert_eq!(
+        request_change(&server, &tokens, "change1-new@example.com").await,
+        StatusCode::OK
+    );
+    let mailed = take_tokens("change1-new@example.com");
+    assert_eq!(mailed.len(), 1);
+
+    // The new password hash is derived for the new address
+    let mut confirm = server
+        .post("/api/v1/account/change-email/confirm")
+        .bearer_auth(&tokens.token)
+        .send_json(&json!({
+            "token": mailed[0],
+            "password_hash": "hash456"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(confirm.status(), StatusCode::OK);
+    let moved: LoginResponse = confirm.json().await.unwrap();
+
+    // Every token issued for the old address is stale
+    assert_eq!(
+        fetch(&server, &tokens.token).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(fetch(&server, &other.token).await, StatusCode::UNAUTHORIZED);
+    let refresh = server
+        .post("/api/v1/auth/refresh")
+        .send_json(&json!({ "refresh_token": other.refresh_token }))
+        .await
+        .unwrap();
+    assert_eq!(refresh.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(fetch(&server, &moved.token).await, StatusCode::OK);
+
+    // The caller keeps its session under the new address
+    let mut sessions = server
+        .get("/api/v1/account/sessions")
+        .bearer_auth(&moved.token)
+        .send()
+        .await
+        .unwrap();
+    let sessions: Vec<SessionResponse> = sessions.json().await.unwrap();
+    assert_eq!(sessions.len(), 1);
+
+    assert_eq!(
+        login(&server, "change1@example.com", "hash123").await,
+        StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        login(&server, "change1-new@example.com", "hash456").await,
+        StatusCode::OK
+    );
+
+    // The token is bound to the security stamp, so it only works once
+    let replay = server
+        .post("/api/v1/account/change-email/confirm")
+        .bearer_auth(&moved.token)
+        .send_json(&json!({ "token": mailed[0] }))
+        .await
+        .unwrap();
+    assert_eq!(replay.status(), StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn test_change_email_conflicts() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let first = register(&server, "change2@example.com").await;
+    let second = register(&server, "change3@example.com").await;
+    assert_eq!(
+        request_change(&server, &first, "change3@example.com").await,
+        StatusCode::CONFLICT
+    );
+
+    // Both accounts race for the same address, the first confirmation wins
+    assert_eq!(
+        request_change(&server, &first, "change2-new@example.com").await,
+        StatusCode::OK
+    );
+    let first_mailed = take_tokens("change2-new@example.com");
+    assert_eq!(
+        request_change(&server, &second, "change2-new@example.com").await,
+        StatusCode::OK
+    );
+    let second_mailed = take_tokens("change2-new@example.com");
+
+    // A token is only accepted from the account that requested it
+    let confirm = |tokens: &LoginResponse, token: &str| {
+        server
+            .post("/api/v1/account/change-email/confirm")
+            .bearer_auth(&tokens.token)
+            .send_json(&json!({ "token": token }))
+    };
+    let stolen = confirm(&second, &first_mailed[0]).await.unwrap();
+    assert_eq!(stolen.status(), StatusCode::FORBIDDEN);
+    let won = confirm(&first, &first_mailed[0]).await.unwrap();
+    assert_eq!(won.status(), StatusCode::OK);
+    let lost = confirm(&second, &second_mailed[0]).await.unwrap();
+    assert_eq!(lost.status(), StatusCode::CONFLICT);
+
+    let invalid = server
+        .post("/api/v1/account/change-email/confirm")
+        .bearer_auth(&second.token)
+        .send_json(&json!({ "token": "invalid" }))
+        .await
+        .unwrap();
+    assert_eq!(invalid.status(), StatusCode::BAD_REQUEST);
+}
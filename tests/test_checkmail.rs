@@ -4,8 +4,8 @@ mod common;
 
 #[actix_rt::test]
 async fn test_check_existing_email() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     let req = server.post("/api/v1/auth/register").send_json(&json!({
         "email": "existing@example.com",
@@ -21,14 +21,12 @@ async fn test_check_existing_email() {
 
     let response = req.await.unwrap();
     assert!(response.status().is_success());
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_check_nonexisting_email() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     let req = server.post("/api/v1/account/checkmail").send_json(&json!({
         "email": "nonexisting@example.com"
@@ -36,14 +34,12 @@ async fn test_check_nonexisting_email() {
 
     let response = req.await.unwrap();
     assert_eq!(response.status(), 404);
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_check_malformed_email() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     let req = server.post("/api/v1/account/checkmail").send_json(&json!({
         "email": "not_an_email"
@@ -51,14 +47,12 @@ async fn test_check_malformed_email() {
 
     let response = req.await.unwrap();
     assert_eq!(response.status(), 400);
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_check_empty_email() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     let req = server.post("/api/v1/account/checkmail").send_json(&json!({
         "email": ""
@@ -66,14 +60,12 @@ async fn test_check_empty_email() {
 
     let response = req.await.unwrap();
     assert_eq!(response.status(), 400);
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_check_very_long_email() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     let long_local_part: String = "a".repeat(300);
     let long_email = format!("{}@example.com", long_local_part);
@@ -84,14 +76,12 @@ async fn test_check_very_long_email() {
 
     let response = req.await.unwrap();
     assert_eq!(response.status(), 400);
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_check_unexpected_json_structure() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     let req = server.post("/api/v1/auth/register").send_json(&json!({
         "email": "test@example.com",
@@ -117,6 +107,4 @@ async fn test_check_unexpected_json_structure() {
 
     let response = req.await.unwrap();
     assert_eq!(response.status(), 400);
-
-    common::cleanup(&db_file);
 }
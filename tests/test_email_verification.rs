@@ -0,0 +1,131 @@
+use actix_web::http::StatusCode;
+use backend_rspass::models::*;
+use serde_json::json;
+use std::{env, fs};
+
+mod common;
+
+const MAIL_DIR: &str = "./test_mail";
+
+// Every test of this binary drops mails into MAIL_DIR and requires verified addresses
+fn setup() -> (
+    actix_web::web::Data<backend_rspass::auth::JwtAuth>,
+    backend_rspass::store::Database,
+) {
+    env::set_var("MAILER", "file");
+    env::set_var("MAIL_DIR", MAIL_DIR);
+    env::set_var("REQUIRE_EMAIL_VERIFICATION", "true");
+    common::setup()
+}
+
+// Removes the mails sent to the address and returns the tokens of their links
+fn take_tokens(email: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    for entry in fs::read_dir(MAIL_DIR).unwrap() {
+        let path = entry.unwrap().path();
+        let content = fs::read_to_string(&path).unwrap();
+        if !content.starts_with(&format!("To: {}\n", email)) {
+            continue;
+        }
+        let link = content.split("token=").nth(1).unwrap();
+        tokens.push(link.split_whitespace().next().unwrap().to_string());
+        fs::remove_file(path).unwrap();
+    }
+    tokens
+}
+
+async fn register(server: &actix_test::TestServer, email: &str) -> LoginResponse {
+    let mut register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::OK);
+    register.json().await.unwrap()
+}
+
+async fn fetch(server: &actix_test::TestServer, tokens: &LoginResponse) -> StatusCode {
+    server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(&tokens.token)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+async fn verify(server: &actix_test::TestServer, token: &str) -> StatusCode {
+    server
+        .get(format!("/api/v1/auth/verify-email?token={}", token))
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+async fn resend(server: &actix_test::TestServer, tokens: &LoginResponse) -> StatusCode {
+    server
+        .post("/api/v1/account/verify-email")
+        .bearer_auth(&tokens.token)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+#[actix_rt::test]
+async fn test_verify_email() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let tokens = register(&server, "verify1@example.com").await;
+    assert_eq!(fetch(&server, &tokens).await, StatusCode::FORBIDDEN);
+    let mailed = take_tokens("verify1@example.com");
+    assert_eq!(mailed.len(), 1);
+
+    // Access tokens are not accepted as verification tokens
+    assert_eq!(
+        verify(&server, &tokens.token).await,
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(verify(&server, &mailed[0]).await, StatusCode::OK);
+    assert_eq!(fetch(&server, &tokens).await, StatusCode::OK);
+    assert_eq!(resend(&server, &tokens).await, StatusCode::CONFLICT);
+}
+
+#[actix_rt::test]
+async fn test_resend_verification() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let tokens = register(&server, "verify2@example.com").await;
+    assert_eq!(take_tokens("verify2@example.com").len(), 1);
+    assert_eq!(resend(&server, &tokens).await, StatusCode::OK);
+    let mailed = take_tokens("verify2@example.com");
+    assert_eq!(mailed.len(), 1);
+
+    assert_eq!(verify(&server, &mailed[0]).await, StatusCode::OK);
+    assert_eq!(fetch(&server, &tokens).await, StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn test_password_change_invalidates_link() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let tokens = register(&server, "verify3@example.com").await;
+    let mailed = take_tokens("verify3@example.com");
+    let change = server
+        .post("/api/v1/account/changepwd")
+        .bearer_auth(&tokens.token)
+        .send_json(&json!({ "password_hash": "hash456" }))
+        .await
+        .unwrap();
+    assert_eq!(change.status(), StatusCode::OK);
+
+    assert_eq!(verify(&server, &mailed[0]).await, StatusCode::BAD_REQUEST);
+    assert_eq!(verify(&server, "invalid").await, StatusCode::BAD_REQUEST);
+}
@@ -0,0 +1,159 @@
+use actix_web::http::StatusCode;
+use backend_rspass::models::*;
+use serde_json::json;
+use std::{
+    env,
+    time::{Duration, Instant},
+};
+use validator::Validate;
+
+mod common;
+
+// Every test of this binary runs in enumeration-resistant mode, behind a trusted proxy
+fn setup() -> (
+    actix_web::web::Data<backend_rspass::auth::JwtAuth>,
+    backend_rspass::store::Database,
+) {
+    env::set_var("ENUMERATION_PROTECTION", "true");
+    env::set_var("TRUSTED_PROXIES", "127.0.0.1");
+    common::setup()
+}
+
+async fn register(server: &actix_test::TestServer, email: &str) -> StatusCode {
+    server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap()
+        .status()
+}
+
+async fn checkmail(server: &actix_test::TestServer, email: &str) -> (StatusCode, KdfParams) {
+    let mut response = server
+        .post("/api/v1/account/checkmail")
+        .send_json(&json!({ "email": email }))
+        .await
+        .unwrap();
+    (response.status(), response.json().await.unwrap())
+}
+
+async fn login(
+    server: &actix_test::TestServer,
+    ip: &str,
+    email: &str,
+    password: &str,
+) -> (StatusCode, Duration) {
+    let start = Instant::now();
+    let response = server
+        .post("/api/v1/auth/login")
+        .insert_header(("X-Forwarded-For", ip))
+        .send_json(&json!({
+            "email": email,
+            "password_hash": password
+        }))
+        .await
+        .unwrap();
+    (response.status(), start.elapsed())
+}
+
+#[actix_rt::test]
+async fn test_checkmail_answers_for_unknown_accounts() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    assert_eq!(register(&server, "enum1@example.com").await, StatusCode::OK);
+    let known = checkmail(&server, "enum1@example.com").await;
+    assert_eq!(known, (StatusCode::OK, KdfParams::default()));
+
+    // Unknown addresses get valid parameters of their own, the same with every request
+    let mut answers = Vec::new();
+    for i in 0..8 {
+        let email = format!("enum1-unknown{}@example.com", i);
+        let (status, kdf) = checkmail(&server, &email).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(kdf.validate().is_ok());
+        // Without a salt, like the parameters of the default client
+        assert!(kdf.salt.is_none());
+        assert_eq!(
+            checkmail(&server, &email).await,
+            (StatusCode::OK, kdf.clone())
+        );
+        answers.push(kdf);
+    }
+    assert!(answers.iter().any(|kdf| *kdf != answers[0]));
+    assert_eq!(
+        checkmail(&server, "Enum1-Unknown0@Example.com").await,
+        (StatusCode::OK, answers[0].clone())
+    );
+}
+
+#[actix_rt::test]
+async fn test_uniform_login_failures() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+    assert_eq!(register(&server, "enum2@example.com").await, StatusCode::OK);
+
+    let mut wrong_password = Duration::ZERO;
+    let mut unknown_account = Duration::ZERO;
+    for _ in 0..3 {
+        let (status, elapsed) = login(&server, "198.51.100.1", "enum2@example.com", "wrong").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        wrong_password += elapsed;
+
+        let (status, elapsed) = login(
+            &server,
+            "198.51.100.1",
+            "enum2-unknown@example.com",
+            "wrong",
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        unknown_account += elapsed;
+    }
+    // Both run a full password hash verification, a missing one would be orders faster
+    assert!(
+        unknown_account * 4 > wrong_password,
+        "unknown account: {:?}, wrong password: {:?}",
+        unknown_account,
+        wrong_password
+    );
+}
+
+#[actix_rt::test]
+async fn test_passwordless_begin_for_unknown_accounts() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let mut begin = server
+        .post("/api/v1/auth/login/webauthn/begin")
+        .send_json(&json!({ "email": "enum3-unknown@example.com" }))
+        .await
+        .unwrap();
+    assert_eq!(begin.status(), StatusCode::OK);
+    let options: WebauthnRequestOptions = begin.json().await.unwrap();
+    assert!(options.allow_credentials.is_empty());
+}
+
+#[actix_rt::test]
+async fn test_register_conflicts_throttled() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+    assert_eq!(register(&server, "enum4@example.com").await, StatusCode::OK);
+
+    // Conflicts count against the client address only
+    for _ in 0..21 {
+        assert_eq!(
+            register(&server, "enum4@example.com").await,
+            StatusCode::CONFLICT
+        );
+    }
+    assert_eq!(
+        register(&server, "enum4@example.com").await,
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    let (status, _) = login(&server, "198.51.100.2", "enum4@example.com", "hash123").await;
+    assert_eq!(status, StatusCode::OK);
+}
@@ -0,0 +1,194 @@
+use actix_web::http::{header, StatusCode};
+use backend_rspass::models::*;
+use serde_json::json;
+
+mod common;
+
+async fn register(server: &actix_test::TestServer, email: &str) -> String {
+    let mut register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::OK);
+    let body: LoginResponse = register.json().await.unwrap();
+    body.token
+}
+
+async fn update(server: &actix_test::TestServer, token: &str, data: &str) -> u64 {
+    let mut update = server
+        .post("/api/v1/sync/update")
+        .bearer_auth(token)
+        .insert_header((header::IF_MATCH, "*"))
+        .send_json(&json!({ "encrypted_data": data }))
+        .await
+        .unwrap();
+    assert_eq!(update.status(), StatusCode::OK);
+    let body: RevisionResponse = update.json().await.unwrap();
+    body.revision
+}
+
+async fn history(server: &actix_test::TestServer, token: &str) -> Vec<HistoryEntryResponse> {
+    let mut history = server
+        .get("/api/v1/sync/history")
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(history.status(), StatusCode::OK);
+    history.json().await.unwrap()
+}
+
+#[actix_rt::test]
+async fn test_history_restore() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let token = register(&server, "history1@example.com").await;
+
+    update(&server, &token, "first").await;
+    update(&server, &token, "second").await;
+
+    let entries = history(&server, &token).await;
+    assert_eq!(
+        entries.iter().map(|e| e.revision).collect::<Vec<_>>(),
+        vec![2, 1]
+    );
+    assert_eq!(entries[1].size, "first".len());
+    assert!(entries[1].session_id.is_some());
+
+    let mut snapshot = server
+        .get("/api/v1/sync/history/1")
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(snapshot.status(), StatusCode::OK);
+    let body: DataResponse = snapshot.json().await.unwrap();
+    assert_eq!(body.encrypted_data, "first");
+    assert_eq!(body.revision, 1);
+
+    // Restoring writes the snapshot as a new revision, the replaced one stays in the history
+    let mut restore = server
+        .post("/api/v1/sync/history/1/restore")
+        .bearer_auth(&token)
+        .insert_header((header::IF_MATCH, "\"2\""))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(restore.status(), StatusCode::OK);
+    assert_eq!(restore.headers().get(header::ETAG).unwrap(), "\"3\"");
+    let body: RevisionResponse = restore.json().await.unwrap();
+    assert_eq!(body.revision, 3);
+
+    let mut fetch = server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    let body: DataResponse = fetch.json().await.unwrap();
+    assert_eq!(body.encrypted_data, "first");
+    assert_eq!(history(&server, &token).await.len(), 3);
+}
+
+#[actix_rt::test]
+async fn test_history_restore_conflict() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let token = register(&server, "history5@example.com").await;
+    update(&server, &token, "first").await;
+    update(&server, &token, "second").await;
+
+    let restore = |base: Option<u64>, if_match: Option<&'static str>| {
+        let mut request = server
+            .post("/api/v1/sync/history/1/restore")
+            .bearer_auth(&token);
+        if let Some(if_match) = if_match {
+            request = request.insert_header((header::IF_MATCH, if_match));
+        }
+        match base {
+            Some(base) => request.send_json(&json!({ "base_revision": base })),
+            None => request.send(),
+        }
+    };
+
+    // Without a precondition a restore could silently replace what another device wrote
+    let missing = restore(None, None).await.unwrap();
+    assert_eq!(missing.status(), StatusCode::PRECONDITION_REQUIRED);
+
+    let mut stale = restore(Some(1), None).await.unwrap();
+    assert_eq!(stale.status(), StatusCode::CONFLICT);
+    assert_eq!(stale.headers().get(header::ETAG).unwrap(), "\"2\"");
+    let body: RevisionResponse = stale.json().await.unwrap();
+    assert_eq!(body.revision, 2);
+
+    let mut stale = restore(None, Some("\"1\"")).await.unwrap();
+    assert_eq!(stale.status(), StatusCode::PRECONDITION_FAILED);
+    let body: RevisionResponse = stale.json().await.unwrap();
+    assert_eq!(body.revision, 2);
+
+    // Refused restores leave the vault and its history alone
+    assert_eq!(history(&server, &token).await.len(), 2);
+
+    let mut restored = restore(Some(2), None).await.unwrap();
+    assert_eq!(restored.status(), StatusCode::OK);
+    let body: RevisionResponse = restored.json().await.unwrap();
+    assert_eq!(body.revision, 3);
+}
+
+#[actix_rt::test]
+async fn test_history_unknown_revision() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let token = register(&server, "history2@example.com").await;
+    let other = register(&server, "history3@example.com").await;
+    update(&server, &token, "first").await;
+
+    // Snapshots of other users are not visible
+    assert!(history(&server, &other).await.is_empty());
+    let snapshot = server
+        .get("/api/v1/sync/history/1")
+        .bearer_auth(&other)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(snapshot.status(), StatusCode::NOT_FOUND);
+    let restore = server
+        .post("/api/v1/sync/history/1/restore")
+        .bearer_auth(&other)
+        .insert_header((header::IF_MATCH, "*"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(restore.status(), StatusCode::NOT_FOUND);
+
+    let unauthorized = server.get("/api/v1/sync/history").send().await.unwrap();
+    assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn test_history_retention() {
+    std::env::set_var("VAULT_HISTORY_COUNT", "3");
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let token = register(&server, "history4@example.com").await;
+
+    for data in ["one", "two", "three", "four", "five"] {
+        update(&server, &token, data).await;
+    }
+    let entries = history(&server, &token).await;
+    assert_eq!(
+        entries.iter().map(|e| e.revision).collect::<Vec<_>>(),
+        vec![5, 4, 3]
+    );
+    let pruned = server
+        .get("/api/v1/sync/history/2")
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(pruned.status(), StatusCode::NOT_FOUND);
+}
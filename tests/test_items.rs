@@ -0,0 +1,217 @@
+use actix_web::http::{header, StatusCode};
+use backend_rspass::models::*;
+use serde_json::json;
+
+mod common;
+
+async fn register(server: &actix_test::TestServer, email: &str) -> String {
+    let mut register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::OK);
+    let body: LoginResponse = register.json().await.unwrap();
+    body.token
+}
+
+async fn changes(server: &actix_test::TestServer, token: &str, since: u64) -> ChangesResponse {
+    let mut changes = server
+        .get(format!("/api/v1/sync/changes?since={}", since))
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(changes.status(), StatusCode::OK);
+    changes.json().await.unwrap()
+}
+
+#[actix_rt::test]
+async fn test_item_changes() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let token = register(&server, "items1@example.com").await;
+
+    let mut apply = server
+        .post("/api/v1/sync/changes")
+        .bearer_auth(&token)
+        .send_json(&json!({ "changes": [
+            { "id": "login-1", "encrypted_data": "one", "base_revision": 0 },
+            { "id": "login-2", "encrypted_data": "two", "base_revision": 0 }
+        ]}))
+        .await
+        .unwrap();
+    assert_eq!(apply.status(), StatusCode::OK);
+    assert_eq!(apply.headers().get(header::ETAG).unwrap(), "\"1\"");
+    let body: RevisionResponse = apply.json().await.unwrap();
+    assert_eq!(body.revision, 1);
+
+    let apply = server
+        .post("/api/v1/sync/changes")
+        .bearer_auth(&token)
+        .send_json(&json!({ "changes": [
+            { "id": "login-1", "encrypted_data": "one, changed", "base_revision": 1 },
+            { "id": "login-2", "base_revision": 1 }
+        ]}))
+        .await
+        .unwrap();
+    assert_eq!(apply.status(), StatusCode::OK);
+
+    // A client at revision 1 only gets what changed after it, including the deletion
+    let delta = changes(&server, &token, 1).await;
+    assert_eq!(delta.revision, 2);
+    assert_eq!(delta.items.len(), 2);
+    assert_eq!(delta.items[0].id, "login-1");
+    assert_eq!(
+        delta.items[0].encrypted_data.as_deref(),
+        Some("one, changed")
+    );
+    assert!(delta.items[1].deleted);
+    assert!(delta.items[1].encrypted_data.is_none());
+    assert!(changes(&server, &token, 2).await.items.is_empty());
+
+    let mut full = server
+        .get("/api/v1/sync/changes")
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(full.headers().get(header::ETAG).unwrap(), "\"2\"");
+    let full: ChangesResponse = full.json().await.unwrap();
+    assert_eq!(full.items.len(), 2);
+}
+
+#[actix_rt::test]
+async fn test_item_conflict() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let token = register(&server, "items2@example.com").await;
+
+    let apply = server
+        .post("/api/v1/sync/changes")
+        .bearer_auth(&token)
+        .send_json(&json!({ "changes": [
+            { "id": "login-1", "encrypted_data": "laptop", "base_revision": 0 }
+        ]}))
+        .await
+        .unwrap();
+    assert_eq!(apply.status(), StatusCode::OK);
+
+    // A device that missed the change cannot overwrite it, and nothing of its batch applies
+    let mut stale = server
+        .post("/api/v1/sync/changes")
+        .bearer_auth(&token)
+        .send_json(&json!({ "changes": [
+            { "id": "login-2", "encrypted_data": "new", "base_revision": 0 },
+            { "id": "login-1", "encrypted_data": "phone", "base_revision": 0 }
+        ]}))
+        .await
+        .unwrap();
+    assert_eq!(stale.status(), StatusCode::CONFLICT);
+    let body: ItemConflictResponse = stale.json().await.unwrap();
+    assert_eq!(body.revision, 1);
+    assert_eq!(body.conflicts, vec!["login-1".to_string()]);
+
+    let delta = changes(&server, &token, 0).await;
+    assert_eq!(delta.revision, 1);
+    assert_eq!(delta.items.len(), 1);
+    assert_eq!(delta.items[0].encrypted_data.as_deref(), Some("laptop"));
+}
+
+#[actix_rt::test]
+async fn test_item_changes_invalid() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let token = register(&server, "items3@example.com").await;
+
+    for body in [
+        json!({ "changes": [] }),
+        json!({ "changes": [{ "id": "", "encrypted_data": "x", "base_revision": 0 }] }),
+        json!({ "changes": [
+            { "id": "login-1", "encrypted_data": "x", "base_revision": 0 },
+            { "id": "login-1", "base_revision": 0 }
+        ]}),
+    ] {
+        let apply = server
+            .post("/api/v1/sync/changes")
+            .bearer_auth(&token)
+            .send_json(&body)
+            .await
+            .unwrap();
+        assert_eq!(apply.status(), StatusCode::BAD_REQUEST);
+    }
+    assert_eq!(changes(&server, &token, 0).await.revision, 0);
+
+    let unauthorized = server.get("/api/v1/sync/changes").send().await.unwrap();
+    assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn test_items_and_vault_revisions_apart() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let token = register(&server, "items4@example.com").await;
+
+    let update = |data: &'static str, revision: u64| {
+        server
+            .post("/api/v1/sync/update")
+            .bearer_auth(&token)
+            .insert_header((header::IF_MATCH, format!("\"{}\"", revision)))
+            .send_json(&json!({ "encrypted_data": data }))
+    };
+    let apply = |change: serde_json::Value| {
+        server
+            .post("/api/v1/sync/changes")
+            .bearer_auth(&token)
+            .send_json(&json!({ "changes": [change] }))
+    };
+
+    assert_eq!(
+        update("vault one", 0).await.unwrap().status(),
+        StatusCode::OK
+    );
+    let mut applied =
+        apply(json!({ "id": "login-1", "encrypted_data": "one", "base_revision": 0 }))
+            .await
+            .unwrap();
+    assert_eq!(applied.status(), StatusCode::OK);
+    let body: RevisionResponse = applied.json().await.unwrap();
+    assert_eq!(body.revision, 1);
+
+    // Item batches leave the vault revision alone, and the other way round
+    assert_eq!(
+        update("vault two", 1).await.unwrap().status(),
+        StatusCode::OK
+    );
+    let applied = apply(json!({ "id": "login-1", "encrypted_data": "two", "base_revision": 1 }))
+        .await
+        .unwrap();
+    assert_eq!(applied.status(), StatusCode::OK);
+    let mut fetch = server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(fetch.headers().get(header::ETAG).unwrap(), "\"2\"");
+    let vault: DataResponse = fetch.json().await.unwrap();
+    assert_eq!(vault.encrypted_data, "vault two");
+    let delta = changes(&server, &token, 0).await;
+    assert_eq!(delta.revision, 2);
+
+    // Restoring the vault does not touch the items
+    let restore = server
+        .post("/api/v1/sync/history/1/restore")
+        .bearer_auth(&token)
+        .insert_header((header::IF_MATCH, "\"2\""))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(restore.status(), StatusCode::OK);
+    let delta = changes(&server, &token, 0).await;
+    assert_eq!(delta.revision, 2);
+    assert_eq!(delta.items[0].encrypted_data.as_deref(), Some("two"));
+}
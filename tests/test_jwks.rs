@@ -0,0 +1,167 @@
+use actix_web::{http::StatusCode, web::Data};
+use backend_rspass::{auth::JwtAuth, keys::JwtKey, models::*, store::Database};
+use ed25519_dalek::pkcs8::{spki::der::pem::LineEnding, EncodePrivateKey};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde_json::json;
+use std::fs;
+use uuid::Uuid;
+
+mod common;
+
+fn random_seed() -> [u8; 32] {
+    let mut seed = [0u8; 32];
+    seed[..16].copy_from_slice(Uuid::new_v4().as_bytes());
+    seed[16..].copy_from_slice(Uuid::new_v4().as_bytes());
+    seed
+}
+
+fn ed25519_pem() -> String {
+    ed25519_dalek::SigningKey::from_bytes(&random_seed())
+        .to_pkcs8_pem(LineEnding::LF)
+        .unwrap()
+        .to_string()
+}
+
+fn p256_pem() -> String {
+    p256::SecretKey::from_slice(&random_seed())
+        .unwrap()
+        .to_pkcs8_pem(LineEnding::LF)
+        .unwrap()
+        .to_string()
+}
+
+async fn register(server: &actix_test::TestServer, email: &str) -> LoginResponse {
+    let mut response = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+async fn fetch_jwks(server: &actix_test::TestServer) -> JwkSet {
+    let mut response = server.get("/.well-known/jwks.json").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+// Verifies a token the way another service would, using only the published JWKS
+async fn verify_with_jwks(
+    server: &actix_test::TestServer,
+    db: &Database,
+    token: &str,
+    algorithm: Algorithm,
+) {
+    let header = decode_header(token).unwrap();
+    assert_eq!(header.alg, algorithm);
+
+    let jwks = fetch_jwks(server).await;
+    let jwk = jwks.find(&header.kid.unwrap()).unwrap();
+    let claims = decode::<serde_json::Value>(
+        token,
+        &DecodingKey::from_jwk(jwk).unwrap(),
+        &Validation::new(algorithm),
+    )
+    .unwrap()
+    .claims;
+    let id = db.run(|db| db.user_id("jwks@example.com")).await.unwrap();
+    assert_eq!(claims["sub"], id.unwrap().as_str());
+}
+
+#[actix_rt::test]
+async fn test_ed25519_signed_tokens() {
+    let (_, db) = common::setup();
+    let key = JwtKey::from_private_pem(&ed25519_pem(), None).unwrap();
+    let server = common::create_server(Data::new(JwtAuth::from_key(key)), &db);
+
+    let tokens = register(&server, "jwks@example.com").await;
+    verify_with_jwks(&server, &db, &tokens.token, Algorithm::EdDSA).await;
+
+    let fetch = server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(&tokens.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(fetch.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn test_es256_signed_tokens() {
+    let (_, db) = common::setup();
+    let key = JwtKey::from_private_pem(&p256_pem(), Some("es256-key".to_string())).unwrap();
+    let server = common::create_server(Data::new(JwtAuth::from_key(key)), &db);
+
+    let tokens = register(&server, "jwks@example.com").await;
+    assert_eq!(
+        decode_header(&tokens.token).unwrap().kid.as_deref(),
+        Some("es256-key")
+    );
+    verify_with_jwks(&server, &db, &tokens.token, Algorithm::ES256).await;
+
+    let fetch = server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(&tokens.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(fetch.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn test_token_of_other_key_rejected() {
+    let (_, db) = common::setup();
+    let pem = ed25519_pem();
+    let key = JwtKey::from_private_pem(&pem, Some("shared-kid".to_string())).unwrap();
+    let server = common::create_server(Data::new(JwtAuth::from_key(key)), &db);
+    register(&server, "jwks@example.com").await;
+
+    // Same kid, different private key
+    let forged_key = JwtKey::from_private_pem(&ed25519_pem(), Some("shared-kid".to_string()));
+    let forged = JwtAuth::from_key(forged_key.unwrap())
+        .generate_token("jwks@example.com", "stamp")
+        .unwrap();
+    // Same private key, unknown kid
+    let renamed_key = JwtKey::from_private_pem(&pem, Some("other-kid".to_string()));
+    let renamed = JwtAuth::from_key(renamed_key.unwrap())
+        .generate_token("jwks@example.com", "stamp")
+        .unwrap();
+
+    for token in [forged, renamed] {
+        let fetch = server
+            .get("/api/v1/sync/fetch")
+            .bearer_auth(&token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(fetch.status(), StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[actix_rt::test]
+async fn test_shared_secret_not_published() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let jwks = fetch_jwks(&server).await;
+    assert!(jwks.keys.is_empty());
+}
+
+#[actix_rt::test]
+async fn test_load_private_key_file() {
+    let key_file = format!("./test_{}.pem", Uuid::new_v4());
+    fs::write(&key_file, p256_pem()).unwrap();
+    let key = JwtKey::from_private_pem_file(&key_file, None);
+    fs::remove_file(&key_file).unwrap();
+
+    let key = key.unwrap();
+    assert_eq!(key.algorithm, Algorithm::ES256);
+    assert!(key.kid.is_some());
+
+    assert!(JwtKey::from_private_pem_file("./does_not_exist.pem", None).is_err());
+    assert!(JwtKey::from_private_pem("not a key", None).is_err());
+}
@@ -0,0 +1,170 @@
+use actix_web::http::StatusCode;
+use backend_rspass::models::*;
+use serde_json::json;
+
+mod common;
+
+async fn checkmail(
+    server: &actix_test::TestServer,
+    email: &str,
+) -> (StatusCode, Option<KdfParams>) {
+    let mut response = server
+        .post("/api/v1/account/checkmail")
+        .send_json(&json!({ "email": email }))
+        .await
+        .unwrap();
+    if response.status() != StatusCode::OK {
+        return (response.status(), None);
+    }
+    (response.status(), Some(response.json().await.unwrap()))
+}
+
+fn argon2id_params() -> serde_json::Value {
+    json!({
+        "algorithm": "argon2id",
+        "iterations": 3,
+        "memory": 65536,
+        "parallelism": 4,
+        "salt": "c2FsdHNhbHRzYWx0c2FsdA"
+    })
+}
+
+#[actix_rt::test]
+async fn test_checkmail_returns_registered_params() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    assert_eq!(
+        checkmail(&server, "kdf1@example.com").await,
+        (StatusCode::NOT_FOUND, None)
+    );
+
+    let register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": "kdf1@example.com",
+            "password_hash": "hash123",
+            "kdf": argon2id_params()
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::OK);
+
+    let (status, kdf) = checkmail(&server, "kdf1@example.com").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        serde_json::to_value(kdf.unwrap()).unwrap(),
+        argon2id_params()
+    );
+}
+
+#[actix_rt::test]
+async fn test_accounts_without_params_use_defaults() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": "kdf2@example.com",
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::OK);
+
+    let (status, kdf) = checkmail(&server, "kdf2@example.com").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(kdf.unwrap(), KdfParams::default());
+}
+
+#[actix_rt::test]
+async fn test_changepwd_updates_params() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let mut register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": "kdf3@example.com",
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::OK);
+    let tokens: LoginResponse = register.json().await.unwrap();
+
+    let change = server
+        .post("/api/v1/account/changepwd")
+        .bearer_auth(&tokens.token)
+        .send_json(&json!({
+            "password_hash": "hash456",
+            "kdf": { "algorithm": "argon2id", "iterations": 1 }
+        }))
+        .await
+        .unwrap();
+    assert_eq!(change.status(), StatusCode::BAD_REQUEST);
+
+    // Raising the cost of the account
+    let mut change = server
+        .post("/api/v1/account/changepwd")
+        .bearer_auth(&tokens.token)
+        .send_json(&json!({
+            "password_hash": "hash456",
+            "kdf": argon2id_params()
+        }))
+        .await
+        .unwrap();
+    assert_eq!(change.status(), StatusCode::OK);
+    let tokens: LoginResponse = change.json().await.unwrap();
+
+    let (_, kdf) = checkmail(&server, "kdf3@example.com").await;
+    assert_eq!(kdf.unwrap().algorithm, "argon2id");
+
+    // A password change without parameters keeps them
+    let change = server
+        .post("/api/v1/account/changepwd")
+        .bearer_auth(&tokens.token)
+        .send_json(&json!({ "password_hash": "hash789" }))
+        .await
+        .unwrap();
+    assert_eq!(change.status(), StatusCode::OK);
+
+    let (_, kdf) = checkmail(&server, "kdf3@example.com").await;
+    assert_eq!(
+        serde_json::to_value(kdf.unwrap()).unwrap(),
+        argon2id_params()
+    );
+}
+
+#[actix_rt::test]
+async fn test_invalid_params_rejected() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let invalid = [
+        json!({ "algorithm": "md5", "iterations": 1 }),
+        json!({ "algorithm": "pbkdf2-sha256", "iterations": 1000 }),
+        json!({ "algorithm": "pbkdf2-sha256", "iterations": 600000, "memory": 65536 }),
+        json!({ "algorithm": "argon2id", "iterations": 3 }),
+        json!({ "algorithm": "argon2id", "iterations": 3, "memory": 1024, "parallelism": 4 }),
+        json!({ "algorithm": "pbkdf2-sha256", "iterations": 600000, "salt": "short" }),
+    ];
+    for kdf in invalid {
+        let register = server
+            .post("/api/v1/auth/register")
+            .send_json(&json!({
+                "email": "kdf4@example.com",
+                "password_hash": "hash123",
+                "kdf": kdf
+            }))
+            .await
+            .unwrap();
+        assert_eq!(register.status(), StatusCode::BAD_REQUEST, "{}", kdf);
+    }
+
+    assert_eq!(
+        checkmail(&server, "kdf4@example.com").await,
+        (StatusCode::NOT_FOUND, None)
+    );
+}
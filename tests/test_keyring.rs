@@ -0,0 +1,171 @@
+use actix_web::{http::StatusCode, web::Data};
+use backend_rspass::{
+    auth::JwtAuth,
+    keys::{generate_key_file, pin_active_key, promote_key_file, Keyring},
+    models::*,
+};
+use jsonwebtoken::decode_header;
+use serde_json::json;
+use std::{fs, path::Path};
+use uuid::Uuid;
+
+mod common;
+
+fn keyring_dir() -> String {
+    let dir = format!("./test_keyring_{}", Uuid::new_v4());
+    fs::create_dir(&dir).unwrap();
+    dir
+}
+
+async fn login(server: &actix_test::TestServer, email: &str) -> LoginResponse {
+    let mut response = server
+        .post("/api/v1/auth/login")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+async fn register(server: &actix_test::TestServer, email: &str) -> LoginResponse {
+    let mut response = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+async fn fetch_status(server: &actix_test::TestServer, token: &str) -> StatusCode {
+    server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+fn kid(token: &str) -> Option<String> {
+    decode_header(token).unwrap().kid
+}
+
+#[actix_rt::test]
+async fn test_promoted_key_keeps_old_tokens_valid() {
+    let (_, db) = common::setup();
+    let dir = keyring_dir();
+    generate_key_file(&dir).unwrap();
+    let keyring = Keyring::load_dir(&dir).unwrap();
+    let jwt_auth = Data::new(JwtAuth::from_keyring(keyring, Some(dir.clone())));
+    let server = common::create_server(jwt_auth.clone(), &db);
+
+    let old = register(&server, "keyring1@example.com").await;
+
+    pin_active_key(&dir).unwrap();
+    fs::write(format!("{}/new.key", dir), "new_secret_length_16\n").unwrap();
+    promote_key_file(&dir, Some("new"), true).unwrap();
+    jwt_auth.reload().unwrap();
+
+    let new = login(&server, "keyring1@example.com").await;
+    assert_eq!(kid(&new.token).as_deref(), Some("new"));
+    assert_ne!(kid(&old.token), kid(&new.token));
+
+    // Both the retired and the active key verify their tokens
+    assert_eq!(fetch_status(&server, &old.token).await, StatusCode::OK);
+    assert_eq!(fetch_status(&server, &new.token).await, StatusCode::OK);
+
+    // The retired public key stays published, the new shared secret is never published
+    let mut jwks = server.get("/.well-known/jwks.json").send().await.unwrap();
+    let jwks: serde_json::Value = jwks.json().await.unwrap();
+    assert_eq!(jwks["keys"].as_array().unwrap().len(), 1);
+    assert_eq!(jwks["keys"][0]["kid"], json!(kid(&old.token)));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[actix_rt::test]
+async fn test_keyring_dir_reload() {
+    let (_, db) = common::setup();
+    let dir = keyring_dir();
+    fs::write(format!("{}/a.key", dir), "first_secret_length_16\n").unwrap();
+    fs::write(format!("{}/b.key", dir), "second_secret_length_16\n").unwrap();
+
+    let keyring = Keyring::load_dir(&dir).unwrap();
+    assert_eq!(keyring.active().kid.as_deref(), Some("b"));
+    let jwt_auth = Data::new(JwtAuth::from_keyring(keyring, Some(dir.clone())));
+    let server = common::create_server(jwt_auth.clone(), &db);
+
+    let first = register(&server, "keyring2@example.com").await;
+    assert_eq!(kid(&first.token).as_deref(), Some("b"));
+
+    // A key added to the directory is promoted on reload
+    fs::write(format!("{}/c.key", dir), "third_secret_length_16\n").unwrap();
+    jwt_auth.reload().unwrap();
+    let second = login(&server, "keyring2@example.com").await;
+    assert_eq!(kid(&second.token).as_deref(), Some("c"));
+    assert_eq!(fetch_status(&server, &first.token).await, StatusCode::OK);
+
+    // Tokens of a key removed from the directory stop working
+    fs::remove_file(format!("{}/b.key", dir)).unwrap();
+    jwt_auth.reload().unwrap();
+    assert_eq!(
+        fetch_status(&server, &first.token).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(fetch_status(&server, &second.token).await, StatusCode::OK);
+
+    // A broken keyring keeps the current keys
+    fs::write(format!("{}/d.key", dir), "too_short\n").unwrap();
+    assert!(jwt_auth.reload().is_err());
+    assert_eq!(fetch_status(&server, &second.token).await, StatusCode::OK);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[actix_rt::test]
+async fn test_empty_keyring_dir() {
+    let dir = keyring_dir();
+    assert!(Keyring::load_dir(&dir).is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[actix_rt::test]
+async fn test_key_rotation_in_two_steps() {
+    let (_, db) = common::setup();
+    let dir = keyring_dir();
+    fs::write(format!("{}/100.key", dir), "first_secret_length_16\n").unwrap();
+    let keyring = Keyring::load_dir(&dir).unwrap();
+    let jwt_auth = Data::new(JwtAuth::from_keyring(keyring, Some(dir.clone())));
+    let server = common::create_server(jwt_auth.clone(), &db);
+    let first = register(&server, "keyring3@example.com").await;
+
+    // rotate-key publishes the new key, the current one keeps signing after a reload
+    pin_active_key(&dir).unwrap();
+    let path = generate_key_file(&dir).unwrap();
+    let new_kid = Path::new(&path).file_stem().unwrap().to_str().unwrap();
+    jwt_auth.reload().unwrap();
+    let second = login(&server, "keyring3@example.com").await;
+    assert_eq!(kid(&second.token).as_deref(), Some("100"));
+    let mut jwks = server.get("/.well-known/jwks.json").send().await.unwrap();
+    let jwks: serde_json::Value = jwks.json().await.unwrap();
+    assert_eq!(jwks["keys"][0]["kid"], json!(new_kid));
+
+    // Promoting before every server could have reloaded is refused, unless forced
+    assert!(promote_key_file(&dir, None, false).is_err());
+    assert_eq!(promote_key_file(&dir, None, true).unwrap(), new_kid);
+    jwt_auth.reload().unwrap();
+    let third = login(&server, "keyring3@example.com").await;
+    assert_eq!(kid(&third.token).as_deref(), Some(new_kid));
+    assert_eq!(fetch_status(&server, &first.token).await, StatusCode::OK);
+    assert!(promote_key_file(&dir, Some("100"), true).is_ok());
+    assert!(promote_key_file(&dir, Some("missing"), true).is_err());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,157 @@
+use actix_web::{http::StatusCode, web::Data};
+use backend_rspass::{
+    auth::JwtAuth,
+    db::SqliteStore,
+    migrations::latest_version,
+    models::LoginResponse,
+    store::{open_migrated_database, Database, VaultStore},
+};
+use rusqlite::Connection;
+use serde_json::json;
+
+mod common;
+
+// Schema of the tables that referred to accounts by email, before users had ids
+const EMAIL_KEYED_SCHEMA: &str = "
+    CREATE TABLE users (
+        email TEXT PRIMARY KEY,
+        password_hash TEXT NOT NULL,
+        encrypted_data TEXT DEFAULT '',
+        security_stamp TEXT NOT NULL DEFAULT ''
+    );
+    CREATE TABLE sessions (
+        nonce TEXT PRIMARY KEY,
+        email TEXT NOT NULL,
+        device_name TEXT,
+        user_agent TEXT,
+        ip TEXT,
+        created_at INTEGER NOT NULL,
+        last_seen INTEGER NOT NULL
+    );
+    CREATE TABLE totp (
+        email TEXT PRIMARY KEY,
+        secret TEXT NOT NULL,
+        enabled INTEGER NOT NULL DEFAULT 0,
+        last_step INTEGER NOT NULL DEFAULT 0
+    );
+    INSERT INTO users (email, password_hash, encrypted_data, security_stamp)
+        VALUES ('Legacy@Example.com', 'legacyhash123', 'vault', 'stamp');
+    INSERT INTO sessions (nonce, email, device_name, created_at, last_seen)
+        VALUES ('nonce1', 'Legacy@Example.com', 'laptop', 1, 1);
+    INSERT INTO totp (email, secret, enabled) VALUES ('Legacy@Example.com', 'SECRET', 0);
+";
+
+// A database file in the email keyed schema
+fn setup_legacy() -> (Data<JwtAuth>, common::TempDir, String) {
+    let (jwt_auth, _) = common::setup();
+    let (dir, db_file) = common::sqlite_file();
+    Connection::open(&db_file)
+        .unwrap()
+        .execute_batch(EMAIL_KEYED_SCHEMA)
+        .unwrap();
+    (jwt_auth, dir, db_file)
+}
+
+#[actix_rt::test]
+async fn test_email_keyed_database_migrated() {
+    let (jwt_auth, _dir, db_file) = setup_legacy();
+    let db = SqliteStore::open(&db_file).unwrap();
+    db.migrate().unwrap();
+
+    // The account got an id, and the rows that belonged to it moved along
+    let id = db.user_id("legacy@example.com").unwrap().unwrap();
+    assert_eq!(db.user_id("LEGACY@example.com").unwrap(), Some(id.clone()));
+    let sessions = db.sessions_list(&id).unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].device_name.as_deref(), Some("laptop"));
+    assert!(!db.totp_enabled(&id).unwrap());
+
+    let conn = Connection::open(&db_file).unwrap();
+    let (email, verified): (String, bool) = conn
+        .query_row(
+            "SELECT email, email_verified FROM users WHERE id = ?1",
+            [&id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .unwrap();
+    assert_eq!(email, "legacy@example.com");
+    assert!(verified);
+
+    // Migrating the migrated database again changes nothing
+    assert_eq!(db.schema_version().unwrap(), latest_version());
+    assert!(db.migrate().unwrap().is_empty());
+    assert_eq!(db.user_id("legacy@example.com").unwrap(), Some(id));
+
+    let server = common::create_server(jwt_auth, &Database::new(db));
+    let mut login = server
+        .post("/api/v1/auth/login")
+        .send_json(&json!({
+            "email": "Legacy@example.COM",
+            "password_hash": "legacyhash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(login.status(), StatusCode::OK);
+    let login: LoginResponse = login.json().await.unwrap();
+    let fetch = server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(&login.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(fetch.status(), StatusCode::OK);
+
+    // Addresses are unique regardless of their case
+    let register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": "LEGACY@EXAMPLE.COM",
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::CONFLICT);
+}
+
+#[actix_rt::test]
+async fn test_new_database_at_latest_version() {
+    let (_dir, db_file) = common::sqlite_file();
+    let db = SqliteStore::open(&db_file).unwrap();
+    assert_eq!(db.schema_version().unwrap(), 0);
+
+    db.migrate().unwrap();
+    assert_eq!(db.schema_version().unwrap(), latest_version());
+}
+
+#[actix_rt::test]
+async fn test_newer_database_refused() {
+    let (_dir, db_file) = common::sqlite_file();
+    let conn = Connection::open(&db_file).unwrap();
+    conn.pragma_update(None, "user_version", latest_version() + 1)
+        .unwrap();
+
+    // The schema is unknown to this binary, so it is neither used nor touched
+    let db = SqliteStore::open(&db_file).unwrap();
+    let Err(error) = db.migrate() else {
+        panic!("Migrated a database of a newer version");
+    };
+    assert!(error.contains("newer"));
+    assert_eq!(db.schema_version().unwrap(), latest_version() + 1);
+}
+
+#[actix_rt::test]
+async fn test_admin_commands_do_not_migrate() {
+    let (_dir, db_file) = common::sqlite_file();
+    std::env::set_var("DATABASE_URL", format!("sqlite://{}", db_file));
+
+    // Admin commands refuse an outdated schema instead of upgrading it
+    let Err(error) = open_migrated_database() else {
+        panic!("Opened a database that was not migrated");
+    };
+    assert!(error.contains("migrate"));
+    let db = SqliteStore::open(&db_file).unwrap();
+    assert_eq!(db.schema_version().unwrap(), 0);
+
+    db.migrate().unwrap();
+    assert!(open_migrated_database().is_ok());
+}
@@ -0,0 +1,95 @@
+use actix_web::{http::StatusCode, web::Bytes};
+use backend_rspass::models::*;
+use futures_util::StreamExt;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use serde_json::{json, Value};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod common;
+
+// Apart from the other notification tests, which need long heartbeats
+#[actix_rt::test]
+async fn test_stream_outlives_access_token() {
+    std::env::set_var("NOTIFICATION_HEARTBEAT", "1");
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let mut register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": "session1@example.com",
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::OK);
+    let phone: LoginResponse = register.json().await.unwrap();
+    let mut login = server
+        .post("/api/v1/auth/login")
+        .send_json(&json!({
+            "email": "session1@example.com",
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    let laptop: LoginResponse = login.json().await.unwrap();
+
+    // The access token of the session, re-signed to expire within seconds, counting the
+    // leeway of the validation
+    let secret = b"test_secret_length_16";
+    let mut claims = decode::<Value>(
+        &laptop.token,
+        &DecodingKey::from_secret(secret),
+        &Validation::default(),
+    )
+    .unwrap()
+    .claims;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    claims["exp"] = json!(now - 58);
+    let token = encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(secret),
+    )
+    .unwrap();
+
+    let mut stream = server
+        .get("/api/v1/sync/notifications")
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(stream.status(), StatusCode::OK);
+
+    // Heartbeats check the session, which is still signed in after its token expired
+    actix_rt::time::sleep(Duration::from_secs(4)).await;
+    let fetch = server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(fetch.status(), StatusCode::UNAUTHORIZED);
+    let update = server
+        .post("/api/v1/sync/update")
+        .bearer_auth(&phone.token)
+        .insert_header(("If-Match", "*"))
+        .send_json(&json!({ "encrypted_data": "phone" }))
+        .await
+        .unwrap();
+    assert_eq!(update.status(), StatusCode::OK);
+    loop {
+        let chunk: Bytes = actix_rt::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("No event within 5 seconds")
+            .expect("Stream ended")
+            .unwrap();
+        let event = String::from_utf8(chunk.to_vec()).unwrap();
+        if !event.starts_with(": heartbeat") {
+            assert!(event.starts_with("event: vault_changed\n"), "{}", event);
+            break;
+        }
+    }
+}
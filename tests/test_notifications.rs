@@ -0,0 +1,359 @@
+use actix_web::{http::StatusCode, web::Bytes};
+use awc::ws::Frame;
+use backend_rspass::models::*;
+use futures_util::StreamExt;
+use serde_json::json;
+use std::time::Duration;
+
+mod common;
+
+async fn register(server: &actix_test::TestServer, email: &str) -> String {
+    // Long heartbeats, so a stream only ends in time if the revocation notified it
+    std::env::set_var("NOTIFICATION_HEARTBEAT", "60");
+    let mut register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::OK);
+    let body: LoginResponse = register.json().await.unwrap();
+    body.token
+}
+
+async fn login(server: &actix_test::TestServer, email: &str) -> String {
+    let mut login = server
+        .post("/api/v1/auth/login")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(login.status(), StatusCode::OK);
+    let body: LoginResponse = login.json().await.unwrap();
+    body.token
+}
+
+async fn update(server: &actix_test::TestServer, token: &str, data: &str) {
+    let update = server
+        .post("/api/v1/sync/update")
+        .bearer_auth(token)
+        .insert_header(("If-Match", "*"))
+        .send_json(&json!({ "encrypted_data": data }))
+        .await
+        .unwrap();
+    assert_eq!(update.status(), StatusCode::OK);
+}
+
+// Next event of a server-sent event stream that is not a heartbeat, None once it ended
+async fn next_event<S, E>(stream: &mut S) -> Option<String>
+where
+    S: futures_util::Stream<Item = Result<Bytes, E>> + Unpin,
+    E: std::fmt::Debug,
+{
+    loop {
+        let chunk = actix_rt::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .expect("No event within 5 seconds")?
+            .unwrap();
+        let event = String::from_utf8(chunk.to_vec()).unwrap();
+        if !event.starts_with(": heartbeat") {
+            return Some(event);
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn test_event_stream() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let laptop = register(&server, "notify1@example.com").await;
+    let phone = login(&server, "notify1@example.com").await;
+
+    let mut stream = server
+        .get("/api/v1/sync/notifications")
+        .bearer_auth(&laptop)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(stream.status(), StatusCode::OK);
+    assert_eq!(
+        stream.headers().get("content-type").unwrap(),
+        "text/event-stream"
+    );
+
+    // Updates of the session itself are not pushed back to it
+    update(&server, &laptop, "laptop").await;
+    update(&server, &phone, "phone").await;
+    let event = next_event(&mut stream).await.unwrap();
+    assert_eq!(
+        event,
+        "event: vault_changed\ndata: {\"type\":\"vault_changed\",\"revision\":2}\n\n"
+    );
+
+    // Logging out ends the stream
+    let logout = server
+        .get("/api/v1/account/logout")
+        .bearer_auth(&laptop)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(logout.status(), StatusCode::OK);
+    let event = next_event(&mut stream).await.unwrap();
+    assert!(event.starts_with("event: session_ended\n"));
+    assert!(next_event(&mut stream).await.is_none());
+}
+
+#[actix_rt::test]
+async fn test_websocket() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let laptop = register(&server, "notify2@example.com").await;
+    let phone = login(&server, "notify2@example.com").await;
+
+    let (response, mut socket) = awc::Client::new()
+        .ws(server.url("/api/v1/sync/notifications"))
+        .bearer_auth(&laptop)
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+
+    let change = server
+        .post("/api/v1/sync/changes")
+        .bearer_auth(&phone)
+        .send_json(&json!({ "changes": [
+            { "id": "login-1", "encrypted_data": "one", "base_revision": 0 }
+        ]}))
+        .await
+        .unwrap();
+    assert_eq!(change.status(), StatusCode::OK);
+
+    let mut closed = false;
+    let mut changed = false;
+    while let Some(frame) = actix_rt::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .expect("No frame within 5 seconds")
+    {
+        match frame.unwrap() {
+            Frame::Text(text) if text == "{\"type\":\"items_changed\",\"revision\":1}" => {
+                changed = true;
+                // Revoking the session from the other one closes the socket
+                let revoke = server
+                    .delete("/api/v1/account/sessions")
+                    .bearer_auth(&phone)
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(revoke.status(), StatusCode::OK);
+            }
+            Frame::Close(_) => {
+                closed = true;
+                break;
+            }
+            _ => {}
+        }
+    }
+    assert!(changed);
+    assert!(closed);
+}
+
+#[actix_rt::test]
+async fn test_stream_closes_on_password_change() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let laptop = register(&server, "notify3@example.com").await;
+    let phone = login(&server, "notify3@example.com").await;
+
+    let mut stream = server
+        .get("/api/v1/sync/notifications")
+        .bearer_auth(&laptop)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(stream.status(), StatusCode::OK);
+
+    let change = server
+        .post("/api/v1/account/changepwd")
+        .bearer_auth(&phone)
+        .send_json(&json!({ "password_hash": "hash456" }))
+        .await
+        .unwrap();
+    assert_eq!(change.status(), StatusCode::OK);
+    let event = next_event(&mut stream).await.unwrap();
+    assert!(event.starts_with("event: session_ended\n"));
+    assert!(next_event(&mut stream).await.is_none());
+
+    let unauthorized = server
+        .get("/api/v1/sync/notifications")
+        .bearer_auth(&laptop)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn test_stream_closes_on_email_change() {
+    std::env::set_var("MAIL_DIR", "./test_mail");
+    std::env::set_var("MAILER", "file");
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let laptop = register(&server, "notify4@example.com").await;
+    let phone = login(&server, "notify4@example.com").await;
+
+    let mut stream = server
+        .get("/api/v1/sync/notifications")
+        .bearer_auth(&laptop)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(stream.status(), StatusCode::OK);
+
+    let change = server
+        .post("/api/v1/account/change-email")
+        .bearer_auth(&phone)
+        .send_json(&json!({ "new_email": "notify4-new@example.com" }))
+        .await
+        .unwrap();
+    assert_eq!(change.status(), StatusCode::OK);
+    let mut token = None;
+    for entry in std::fs::read_dir("./test_mail").unwrap() {
+        let path = entry.unwrap().path();
+        let content = std::fs::read_to_string(&path).unwrap();
+        if content.starts_with("To: notify4-new@example.com\n") {
+            token = content
+                .split(|c: char| c.is_whitespace() || c == '=')
+                .find(|word| word.starts_with("eyJ"))
+                .map(str::to_string);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    let confirm = server
+        .post("/api/v1/account/change-email/confirm")
+        .bearer_auth(&phone)
+        .send_json(&json!({ "token": token.unwrap() }))
+        .await
+        .unwrap();
+    assert_eq!(confirm.status(), StatusCode::OK);
+    let event = next_event(&mut stream).await.unwrap();
+    assert!(event.starts_with("event: session_ended\n"));
+    assert!(next_event(&mut stream).await.is_none());
+}
+
+#[actix_rt::test]
+async fn test_stream_closes_on_refresh_token_reuse() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    register(&server, "notify5@example.com").await;
+    let mut login = server
+        .post("/api/v1/auth/login")
+        .send_json(&json!({
+            "email": "notify5@example.com",
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    let tokens: LoginResponse = login.json().await.unwrap();
+
+    let mut stream = server
+        .get("/api/v1/sync/notifications")
+        .bearer_auth(&tokens.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(stream.status(), StatusCode::OK);
+
+    // A refresh token presented twice revokes its session right away
+    for expected in [StatusCode::OK, StatusCode::UNAUTHORIZED] {
+        let refresh = server
+            .post("/api/v1/auth/refresh")
+            .send_json(&json!({ "refresh_token": tokens.refresh_token }))
+            .await
+            .unwrap();
+        assert_eq!(refresh.status(), expected);
+    }
+    let event = next_event(&mut stream).await.unwrap();
+    assert!(event.starts_with("event: session_ended\n"));
+    assert!(next_event(&mut stream).await.is_none());
+}
+
+#[actix_rt::test]
+async fn test_stream_ticket() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let laptop = register(&server, "notify6@example.com").await;
+    let phone = login(&server, "notify6@example.com").await;
+
+    let ticket = || async {
+        let mut ticket = server
+            .post("/api/v1/sync/notifications/ticket")
+            .bearer_auth(&laptop)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(ticket.status(), StatusCode::OK);
+        let body: StreamTicketResponse = ticket.json().await.unwrap();
+        assert_eq!(body.expires_in, 30);
+        body.ticket
+    };
+
+    // Browsers open the stream with a ticket, as they can not set the Authorization header
+    let mut stream = server
+        .get(format!(
+            "/api/v1/sync/notifications?ticket={}",
+            ticket().await
+        ))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(stream.status(), StatusCode::OK);
+    let (response, _socket) = awc::Client::new()
+        .ws(server.url(&format!(
+            "/api/v1/sync/notifications?ticket={}",
+            ticket().await
+        )))
+        .connect()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+    update(&server, &phone, "phone").await;
+    let event = next_event(&mut stream).await.unwrap();
+    assert!(event.starts_with("event: vault_changed\n"));
+
+    // Tickets only open streams, and access tokens are no tickets
+    let kept = ticket().await;
+    let fetch = server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(&kept)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(fetch.status(), StatusCode::UNAUTHORIZED);
+    for url in [
+        format!("/api/v1/sync/notifications?ticket={}", laptop),
+        "/api/v1/sync/notifications".to_string(),
+    ] {
+        let refused = server.get(url).send().await.unwrap();
+        assert_eq!(refused.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // A ticket ends with its session
+    let logout = server
+        .get("/api/v1/account/logout")
+        .bearer_auth(&laptop)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(logout.status(), StatusCode::OK);
+    let refused = server
+        .get(format!("/api/v1/sync/notifications?ticket={}", kept))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(refused.status(), StatusCode::UNAUTHORIZED);
+}
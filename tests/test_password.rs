@@ -0,0 +1,116 @@
+use actix_web::http::StatusCode;
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Algorithm, Argon2, Params, PasswordHasher, Version,
+};
+use backend_rspass::{
+    password::{hash_password, verify_password, PasswordCheck},
+    store::Database,
+};
+use serde_json::json;
+
+mod common;
+
+async fn stored_hash(db: &Database, email: &'static str) -> String {
+    db.run(move |db| {
+        let user_id = db.user_id(email)?.unwrap();
+        db.user_password_hash(&user_id)
+    })
+    .await
+    .unwrap()
+    .unwrap()
+}
+
+async fn login_status(server: &actix_test::TestServer, email: &str, password: &str) -> StatusCode {
+    server
+        .post("/api/v1/auth/login")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": password
+        }))
+        .await
+        .unwrap()
+        .status()
+}
+
+#[actix_rt::test]
+async fn test_register_stores_argon2id_hash() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": "argon1@example.com",
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::OK);
+
+    let stored = stored_hash(&db, "argon1@example.com").await;
+    assert!(stored.starts_with("$argon2id$"));
+    assert!(!stored.contains("hash123"));
+
+    assert_eq!(
+        login_status(&server, "argon1@example.com", "hash123").await,
+        StatusCode::OK
+    );
+}
+
+#[actix_rt::test]
+async fn test_legacy_hash_upgraded_on_login() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    // Account stored before server-side hashing
+    db.run(|db| db.user_register("argon2@example.com", "legacyhash123", None))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        login_status(&server, "argon2@example.com", "wronghash123").await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        stored_hash(&db, "argon2@example.com").await,
+        "legacyhash123"
+    );
+
+    assert_eq!(
+        login_status(&server, "argon2@example.com", "legacyhash123").await,
+        StatusCode::OK
+    );
+    assert!(stored_hash(&db, "argon2@example.com")
+        .await
+        .starts_with("$argon2id$"));
+
+    // The upgraded row keeps working
+    assert_eq!(
+        login_status(&server, "argon2@example.com", "legacyhash123").await,
+        StatusCode::OK
+    );
+}
+
+#[test]
+fn test_verify_password() {
+    let hashed = hash_password("hash123").unwrap();
+    assert_ne!(hashed, hash_password("hash123").unwrap()); // per-user salt
+    assert_eq!(verify_password("hash123", &hashed), PasswordCheck::Valid);
+    assert_eq!(verify_password("hash124", &hashed), PasswordCheck::Invalid);
+
+    // Hashes with weaker parameters than configured are upgraded
+    let weak = Argon2::new(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(1024, 1, 1, None).unwrap(),
+    )
+    .hash_password(b"hash123", &SaltString::generate(&mut OsRng))
+    .unwrap()
+    .to_string();
+    assert_eq!(
+        verify_password("hash123", &weak),
+        PasswordCheck::ValidNeedsRehash
+    );
+    assert_eq!(verify_password("hash124", &weak), PasswordCheck::Invalid);
+}
@@ -0,0 +1,124 @@
+use actix_web::http::StatusCode;
+use backend_rspass::models::*;
+use serde_json::json;
+
+mod common;
+
+async fn register(server: &actix_test::TestServer, email: &str) -> LoginResponse {
+    let mut response = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+async fn refresh(
+    server: &actix_test::TestServer,
+    refresh_token: &str,
+) -> (StatusCode, Option<LoginResponse>) {
+    let mut response = server
+        .post("/api/v1/auth/refresh")
+        .send_json(&json!({
+            "refresh_token": refresh_token
+        }))
+        .await
+        .unwrap();
+    let status = response.status();
+    if status.is_success() {
+        (status, Some(response.json().await.unwrap()))
+    } else {
+        (status, None)
+    }
+}
+
+#[actix_rt::test]
+async fn test_refresh_rotates_tokens() {
+    let (jwt_auth, db) = common::setup();
+    let jwt_auth_clone = jwt_auth.clone();
+    let server = common::create_server(jwt_auth, &db);
+
+    let tokens = register(&server, "refresh1@example.com").await;
+    assert!(!tokens.refresh_token.is_empty());
+
+    let (status, rotated) = refresh(&server, &tokens.refresh_token).await;
+    assert_eq!(status, StatusCode::OK);
+    let rotated = rotated.unwrap();
+    assert_ne!(rotated.refresh_token, tokens.refresh_token);
+
+    // The new access token belongs to the same user
+    let token = rotated.token.clone();
+    let claims = db
+        .run(move |db| Ok(jwt_auth_clone.validate_token(db, &token)))
+        .await
+        .unwrap()
+        .unwrap();
+    let id = db
+        .run(|db| db.user_id("refresh1@example.com"))
+        .await
+        .unwrap();
+    assert_eq!(claims.sub, id.unwrap());
+
+    let fetch = server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(&rotated.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(fetch.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn test_refresh_token_reuse_revokes_family() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let tokens = register(&server, "refresh2@example.com").await;
+
+    let (status, rotated) = refresh(&server, &tokens.refresh_token).await;
+    assert_eq!(status, StatusCode::OK);
+    let rotated = rotated.unwrap();
+
+    // Replaying the first refresh token is rejected...
+    let (status, _) = refresh(&server, &tokens.refresh_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // ...and revokes the token that was issued in its place
+    let (status, _) = refresh(&server, &rotated.refresh_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn test_refresh_unknown_token() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let (status, _) = refresh(&server, "not_a_refresh_token").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = refresh(&server, "").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn test_logout_revokes_refresh_token() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let tokens = register(&server, "refresh3@example.com").await;
+
+    let logout = server
+        .get("/api/v1/account/logout")
+        .bearer_auth(&tokens.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(logout.status(), StatusCode::OK);
+
+    let (status, _) = refresh(&server, &tokens.refresh_token).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
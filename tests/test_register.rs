@@ -5,9 +5,9 @@ mod common;
 
 #[actix_rt::test]
 async fn test_register_valid_credentials() {
-    let (jwt_auth, db_file) = common::setup();
+    let (jwt_auth, db) = common::setup();
     let jwt_auth_clone = jwt_auth.clone();
-    let server = common::create_server(jwt_auth);
+    let server = common::create_server(jwt_auth, &db);
 
     let req = server.post("/api/v1/auth/register").send_json(&json!({
         "email": "test@example.com",
@@ -21,16 +21,20 @@ async fn test_register_valid_credentials() {
     assert!(!body.token.is_empty());
 
     // Validate the JWT token
-    let claims = jwt_auth_clone.validate_token(&body.token).unwrap();
-    assert_eq!(claims.sub, "test@example.com");
-
-    common::cleanup(&db_file);
+    let token = body.token.clone();
+    let claims = db
+        .run(move |db| Ok(jwt_auth_clone.validate_token(db, &token)))
+        .await
+        .unwrap()
+        .unwrap();
+    let id = db.run(|db| db.user_id("test@example.com")).await.unwrap();
+    assert_eq!(claims.sub, id.unwrap());
 }
 
 #[actix_rt::test]
 async fn test_register_invalid_email_format() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     let req = server.post("/api/v1/auth/register").send_json(&json!({
         "email": "invalid-email-format",
@@ -39,14 +43,12 @@ async fn test_register_invalid_email_format() {
 
     let response = req.await.unwrap();
     assert_eq!(response.status(), 400);
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_register_empty_password() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     let req = server.post("/api/v1/auth/register").send_json(&json!({
         "email": "test@example.com",
@@ -55,14 +57,12 @@ async fn test_register_empty_password() {
 
     let response = req.await.unwrap();
     assert_eq!(response.status(), 400);
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_register_very_long_password() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     let long_password = "a".repeat(1100);
     let req = server.post("/api/v1/auth/register").send_json(&json!({
@@ -72,14 +72,12 @@ async fn test_register_very_long_password() {
 
     let response = req.await.unwrap();
     assert_eq!(response.status(), 400);
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_register_very_long_email() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     let long_local_part: String = "a".repeat(200);
     let long_email = format!("{}@example.com", long_local_part);
@@ -91,14 +89,12 @@ async fn test_register_very_long_email() {
 
     let response = req.await.unwrap();
     assert_eq!(response.status(), 400);
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_register_same_email_twice() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // First registration
     let req = server.post("/api/v1/auth/register").send_json(&json!({
@@ -117,14 +113,12 @@ async fn test_register_same_email_twice() {
 
     let response = req.await.unwrap();
     assert_eq!(response.status(), 409); // Conflict status code
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_register_special_characters_email() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     let req = server.post("/api/v1/auth/register").send_json(&json!({
         "email": "test.name+tag@example.com",
@@ -136,14 +130,12 @@ async fn test_register_special_characters_email() {
 
     let body: LoginResponse = response.json().await.unwrap();
     assert!(!body.token.is_empty());
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_register_unexpected_json_structure() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // Test with missing field
     let req = server.post("/api/v1/auth/register").send_json(&json!({
@@ -171,6 +163,4 @@ async fn test_register_unexpected_json_structure() {
 
     let response = req.await.unwrap();
     assert_eq!(response.status(), 400);
-
-    common::cleanup(&db_file);
 }
@@ -0,0 +1,153 @@
+use actix_web::http::{header, StatusCode};
+use backend_rspass::models::*;
+use serde_json::json;
+
+mod common;
+
+async fn register(server: &actix_test::TestServer, email: &str) -> String {
+    let mut register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::OK);
+    let body: LoginResponse = register.json().await.unwrap();
+    body.token
+}
+
+async fn fetch(server: &actix_test::TestServer, token: &str) -> (String, DataResponse) {
+    let mut fetch = server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(fetch.status(), StatusCode::OK);
+    let etag = fetch
+        .headers()
+        .get(header::ETAG)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+    (etag, fetch.json().await.unwrap())
+}
+
+#[actix_rt::test]
+async fn test_update_based_on_revision() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let token = register(&server, "revision1@example.com").await;
+
+    let (etag, vault) = fetch(&server, &token).await;
+    assert_eq!(etag, "\"0\"");
+    assert_eq!(vault.revision, 0);
+
+    let mut update = server
+        .post("/api/v1/sync/update")
+        .bearer_auth(&token)
+        .send_json(&json!({ "encrypted_data": "laptop", "base_revision": 0 }))
+        .await
+        .unwrap();
+    assert_eq!(update.status(), StatusCode::OK);
+    assert_eq!(update.headers().get(header::ETAG).unwrap(), "\"1\"");
+    let body: RevisionResponse = update.json().await.unwrap();
+    assert_eq!(body.revision, 1);
+
+    // A device that missed the update cannot overwrite it
+    let mut stale = server
+        .post("/api/v1/sync/update")
+        .bearer_auth(&token)
+        .send_json(&json!({ "encrypted_data": "phone", "base_revision": 0 }))
+        .await
+        .unwrap();
+    assert_eq!(stale.status(), StatusCode::CONFLICT);
+    let body: RevisionResponse = stale.json().await.unwrap();
+    assert_eq!(body.revision, 1);
+
+    let (etag, vault) = fetch(&server, &token).await;
+    assert_eq!(etag, "\"1\"");
+    assert_eq!(vault.encrypted_data, "laptop");
+}
+
+#[actix_rt::test]
+async fn test_update_if_match() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let token = register(&server, "revision2@example.com").await;
+
+    let update = server
+        .post("/api/v1/sync/update")
+        .bearer_auth(&token)
+        .insert_header((header::IF_MATCH, "\"0\""))
+        .send_json(&json!({ "encrypted_data": "laptop" }))
+        .await
+        .unwrap();
+    assert_eq!(update.status(), StatusCode::OK);
+
+    let mut stale = server
+        .post("/api/v1/sync/update")
+        .bearer_auth(&token)
+        .insert_header((header::IF_MATCH, "\"0\""))
+        .send_json(&json!({ "encrypted_data": "phone" }))
+        .await
+        .unwrap();
+    assert_eq!(stale.status(), StatusCode::PRECONDITION_FAILED);
+    assert_eq!(stale.headers().get(header::ETAG).unwrap(), "\"1\"");
+    let body: RevisionResponse = stale.json().await.unwrap();
+    assert_eq!(body.revision, 1);
+
+    // * matches any revision
+    let update = server
+        .post("/api/v1/sync/update")
+        .bearer_auth(&token)
+        .insert_header((header::IF_MATCH, "*"))
+        .send_json(&json!({ "encrypted_data": "phone" }))
+        .await
+        .unwrap();
+    assert_eq!(update.status(), StatusCode::OK);
+    let (etag, vault) = fetch(&server, &token).await;
+    assert_eq!(etag, "\"2\"");
+    assert_eq!(vault.encrypted_data, "phone");
+}
+
+#[actix_rt::test]
+async fn test_update_requires_precondition() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let token = register(&server, "revision3@example.com").await;
+
+    let update = server
+        .post("/api/v1/sync/update")
+        .bearer_auth(&token)
+        .send_json(&json!({ "encrypted_data": "laptop" }))
+        .await
+        .unwrap();
+    assert_eq!(update.status(), StatusCode::PRECONDITION_REQUIRED);
+
+    for if_match in ["W/\"0\"", "\"zero\"", "0"] {
+        let update = server
+            .post("/api/v1/sync/update")
+            .bearer_auth(&token)
+            .insert_header((header::IF_MATCH, if_match))
+            .send_json(&json!({ "encrypted_data": "laptop" }))
+            .await
+            .unwrap();
+        assert_eq!(update.status(), StatusCode::BAD_REQUEST);
+    }
+
+    let update = server
+        .post("/api/v1/sync/update")
+        .bearer_auth(&token)
+        .insert_header((header::IF_MATCH, "\"0\""))
+        .send_json(&json!({ "encrypted_data": "laptop", "base_revision": 1 }))
+        .await
+        .unwrap();
+    assert_eq!(update.status(), StatusCode::BAD_REQUEST);
+
+    let (_, vault) = fetch(&server, &token).await;
+    assert_eq!(vault.revision, 0);
+}
@@ -0,0 +1,151 @@
+use actix_web::http::StatusCode;
+use backend_rspass::models::*;
+use serde_json::json;
+
+mod common;
+
+async fn register(server: &actix_test::TestServer, email: &str) -> LoginResponse {
+    let mut response = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+async fn login(server: &actix_test::TestServer, email: &str) -> LoginResponse {
+    let mut response = server
+        .post("/api/v1/auth/login")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+async fn fetch_status(server: &actix_test::TestServer, token: &str) -> StatusCode {
+    server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+async fn refresh_status(server: &actix_test::TestServer, refresh_token: &str) -> StatusCode {
+    server
+        .post("/api/v1/auth/refresh")
+        .send_json(&json!({ "refresh_token": refresh_token }))
+        .await
+        .unwrap()
+        .status()
+}
+
+#[actix_rt::test]
+async fn test_changepwd_invalidates_other_tokens() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let laptop = register(&server, "stamp1@example.com").await;
+    let stolen = login(&server, "stamp1@example.com").await;
+
+    let mut change_pwd = server
+        .post("/api/v1/account/changepwd")
+        .bearer_auth(&laptop.token)
+        .send_json(&json!({
+            "password_hash": "newhash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(change_pwd.status(), StatusCode::OK);
+    let renewed: LoginResponse = change_pwd.json().await.unwrap();
+
+    // Every token issued before the change is rejected
+    assert_eq!(
+        fetch_status(&server, &stolen.token).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        fetch_status(&server, &laptop.token).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        refresh_status(&server, &stolen.refresh_token).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        refresh_status(&server, &laptop.refresh_token).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    // The caller continues with the tokens returned by the password change
+    assert_eq!(fetch_status(&server, &renewed.token).await, StatusCode::OK);
+    assert_eq!(
+        refresh_status(&server, &renewed.refresh_token).await,
+        StatusCode::OK
+    );
+}
+
+#[actix_rt::test]
+async fn test_logout_all_invalidates_every_token() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let laptop = register(&server, "stamp2@example.com").await;
+    let phone = login(&server, "stamp2@example.com").await;
+
+    let logout_all = server
+        .get("/api/v1/account/logout-all")
+        .bearer_auth(&laptop.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(logout_all.status(), StatusCode::OK);
+
+    for tokens in [&laptop, &phone] {
+        assert_eq!(
+            fetch_status(&server, &tokens.token).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            refresh_status(&server, &tokens.refresh_token).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    // Logging in again works as usual
+    let fresh = login(&server, "stamp2@example.com").await;
+    assert_eq!(fetch_status(&server, &fresh.token).await, StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn test_token_of_deleted_account_stays_invalid_after_reregister() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let old = register(&server, "stamp3@example.com").await;
+    let other = login(&server, "stamp3@example.com").await;
+
+    let delete = server
+        .get("/api/v1/account/delete")
+        .bearer_auth(&old.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(delete.status(), StatusCode::OK);
+
+    let new = register(&server, "stamp3@example.com").await;
+    assert_eq!(
+        fetch_status(&server, &other.token).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(fetch_status(&server, &new.token).await, StatusCode::OK);
+}
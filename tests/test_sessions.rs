@@ -0,0 +1,199 @@
+use actix_web::http::StatusCode;
+use backend_rspass::models::*;
+use serde_json::json;
+
+mod common;
+
+async fn login(server: &actix_test::TestServer, email: &str, device_name: &str) -> LoginResponse {
+    let mut response = server
+        .post("/api/v1/auth/login")
+        .insert_header(("User-Agent", "rsPass-test"))
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123",
+            "device_name": device_name
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+async fn register(server: &actix_test::TestServer, email: &str) -> LoginResponse {
+    let mut response = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123",
+            "device_name": "laptop"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+async fn list_sessions(server: &actix_test::TestServer, token: &str) -> Vec<SessionResponse> {
+    let mut response = server
+        .get("/api/v1/account/sessions")
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+async fn fetch_status(server: &actix_test::TestServer, token: &str) -> StatusCode {
+    server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+#[actix_rt::test]
+async fn test_list_sessions() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    register(&server, "sessions1@example.com").await;
+    let phone = login(&server, "sessions1@example.com", "phone").await;
+
+    let sessions = list_sessions(&server, &phone.token).await;
+    assert_eq!(sessions.len(), 2);
+
+    let current: Vec<_> = sessions.iter().filter(|session| session.current).collect();
+    assert_eq!(current.len(), 1);
+    assert_eq!(current[0].device_name.as_deref(), Some("phone"));
+    assert_eq!(current[0].user_agent.as_deref(), Some("rsPass-test"));
+    assert!(current[0].ip.is_some());
+}
+
+#[actix_rt::test]
+async fn test_revoke_single_session() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let laptop = register(&server, "sessions2@example.com").await;
+    let phone = login(&server, "sessions2@example.com", "phone").await;
+
+    let sessions = list_sessions(&server, &laptop.token).await;
+    let phone_session = sessions
+        .iter()
+        .find(|session| session.device_name.as_deref() == Some("phone"))
+        .unwrap();
+
+    let revoke = server
+        .delete(format!("/api/v1/account/sessions/{}", phone_session.id))
+        .bearer_auth(&laptop.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(revoke.status(), StatusCode::OK);
+
+    // The revoked session can neither be used nor refreshed
+    assert_eq!(
+        fetch_status(&server, &phone.token).await,
+        StatusCode::UNAUTHORIZED
+    );
+    let refresh = server
+        .post("/api/v1/auth/refresh")
+        .send_json(&json!({ "refresh_token": phone.refresh_token }))
+        .await
+        .unwrap();
+    assert_eq!(refresh.status(), StatusCode::UNAUTHORIZED);
+
+    // The revoking session stays active
+    assert_eq!(fetch_status(&server, &laptop.token).await, StatusCode::OK);
+
+    // Revoking it again reports that it no longer exists
+    let revoke = server
+        .delete(format!("/api/v1/account/sessions/{}", phone_session.id))
+        .bearer_auth(&laptop.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(revoke.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_rt::test]
+async fn test_revoke_session_of_other_user() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let victim = register(&server, "sessions3@example.com").await;
+    let attacker = register(&server, "sessions4@example.com").await;
+
+    let victim_session = &list_sessions(&server, &victim.token).await[0];
+    let revoke = server
+        .delete(format!("/api/v1/account/sessions/{}", victim_session.id))
+        .bearer_auth(&attacker.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(revoke.status(), StatusCode::NOT_FOUND);
+    assert_eq!(fetch_status(&server, &victim.token).await, StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn test_revoke_other_sessions() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let laptop = register(&server, "sessions5@example.com").await;
+    let phone = login(&server, "sessions5@example.com", "phone").await;
+    let tablet = login(&server, "sessions5@example.com", "tablet").await;
+
+    let revoke = server
+        .delete("/api/v1/account/sessions")
+        .bearer_auth(&laptop.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(revoke.status(), StatusCode::OK);
+
+    assert_eq!(
+        fetch_status(&server, &phone.token).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        fetch_status(&server, &tablet.token).await,
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(fetch_status(&server, &laptop.token).await, StatusCode::OK);
+
+    let sessions = list_sessions(&server, &laptop.token).await;
+    assert_eq!(sessions.len(), 1);
+    assert!(sessions[0].current);
+}
+
+#[actix_rt::test]
+async fn test_forwarded_header_not_trusted() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let tokens = register(&server, "sessions6@example.com").await;
+
+    // Without TRUSTED_PROXIES the header is the client's word, the connection address counts
+    for ip in ["203.0.113.1", "203.0.113.2"] {
+        let response = server
+            .post("/api/v1/auth/login")
+            .insert_header(("X-Forwarded-For", ip))
+            .send_json(&json!({
+                "email": "sessions6@example.com",
+                "password_hash": "wrong123"
+            }))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+    let failures = db
+        .run(|db| db.login_failures_get("ip", "127.0.0.1"))
+        .await
+        .unwrap();
+    assert_eq!(failures.map(|failures| failures.failures), Some(2));
+    let sessions = list_sessions(&server, &tokens.token).await;
+    assert_eq!(sessions[0].ip.as_deref(), Some("127.0.0.1"));
+}
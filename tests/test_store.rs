@@ -0,0 +1,315 @@
+use backend_rspass::{
+    db::SqliteStore,
+    memory::MemoryStore,
+    store::{
+        Attachment, AttachmentCreate, ItemChange, ItemsUpdate, RefreshTokenStatus, Session,
+        VaultStore, VaultUpdate, WebauthnCeremony,
+    },
+};
+
+mod common;
+
+fn session(nonce: &str, created_at: usize) -> Session {
+    Session {
+        nonce: nonce.to_string(),
+        device_name: None,
+        user_agent: None,
+        ip: None,
+        created_at,
+        last_seen: created_at,
+    }
+}
+
+// The behaviour routes rely on, which every store has to share
+fn check_store(db: &dyn VaultStore) {
+    let id = db
+        .user_register(" Store@Example.com", "hash123", None)
+        .unwrap();
+    assert_eq!(db.user_id("store@example.COM").unwrap(), Some(id.clone()));
+    assert_eq!(db.user_email_verified(&id).unwrap(), Some(false));
+    assert_eq!(db.data_get(&id).unwrap().revision, 0);
+
+    // Updates based on an older revision are refused
+    assert_eq!(
+        db.data_update(&id, "vault", Some(0), "nonce1", 10).unwrap(),
+        VaultUpdate::Updated { revision: 1 }
+    );
+    assert_eq!(
+        db.data_update(&id, "stale", Some(0), "nonce1", 20).unwrap(),
+        VaultUpdate::Conflict { revision: 1 }
+    );
+    assert_eq!(db.data_get(&id).unwrap().encrypted_data, "vault");
+    assert_eq!(
+        db.data_update(&id, "forced", None, "nonce1", 30).unwrap(),
+        VaultUpdate::Updated { revision: 2 }
+    );
+    assert_eq!(db.data_get(&id).unwrap().encrypted_data, "forced");
+
+    // Every revision written is kept as a snapshot, until it is pruned
+    let history = db.vault_history_list(&id).unwrap();
+    assert_eq!(
+        history.iter().map(|s| s.revision).collect::<Vec<_>>(),
+        vec![2, 1]
+    );
+    assert_eq!(history[1].size, 5);
+    assert_eq!(history[1].created_at, 10);
+    assert_eq!(history[1].session_id.as_deref(), Some("nonce1"));
+    assert_eq!(
+        db.vault_history_get(&id, 1)
+            .unwrap()
+            .unwrap()
+            .encrypted_data,
+        "vault"
+    );
+    assert!(db.vault_history_get(&id, 3).unwrap().is_none());
+    db.data_update(&id, "third", None, "nonce1", 40).unwrap();
+    assert_eq!(db.vault_history_prune(&id, 2, 0).unwrap(), 1);
+    assert!(db.vault_history_get(&id, 1).unwrap().is_none());
+    assert_eq!(db.vault_history_prune(&id, 2, 35).unwrap(), 1);
+    assert_eq!(db.vault_history_list(&id).unwrap().len(), 1);
+    assert_eq!(db.vault_history_cleanup(35).unwrap(), 0);
+
+    // Item changes count their own revisions and apply all or nothing
+    let change = |id: &str, data: Option<&str>, base_revision| ItemChange {
+        id: id.to_string(),
+        encrypted_data: data.map(str::to_string),
+        base_revision,
+    };
+    assert_eq!(
+        db.items_apply(
+            &id,
+            &[change("a", Some("a1"), 0), change("b", Some("b1"), 0)]
+        )
+        .unwrap(),
+        ItemsUpdate::Applied {
+            revision: 1,
+            attachments: vec![]
+        }
+    );
+    assert_eq!(
+        db.items_apply(
+            &id,
+            &[change("c", Some("c1"), 0), change("a", Some("a2"), 3)]
+        )
+        .unwrap(),
+        ItemsUpdate::Conflict {
+            revision: 1,
+            ids: vec!["a".to_string()]
+        }
+    );
+    // The vault keeps its revision, so updates based on it still apply
+    assert_eq!(
+        db.data_update(&id, "fourth", Some(3), "nonce1", 50)
+            .unwrap(),
+        VaultUpdate::Updated { revision: 4 }
+    );
+
+    // Attachments belong to live items and count against the quota by their declared size
+    let attachment = |id: &str, item_id: &str, size, created_at| Attachment {
+        id: id.to_string(),
+        item_id: item_id.to_string(),
+        size,
+        received: 0,
+        created_at,
+    };
+    assert_eq!(
+        db.attachment_create(&id, &attachment("f1", "b", 10, 10), 15)
+            .unwrap(),
+        AttachmentCreate::Created
+    );
+    assert_eq!(
+        db.attachment_create(&id, &attachment("f2", "a", 6, 20), 15)
+            .unwrap(),
+        AttachmentCreate::QuotaExceeded { usage: 10 }
+    );
+    assert_eq!(
+        db.attachment_create(&id, &attachment("f2", "c", 5, 20), 15)
+            .unwrap(),
+        AttachmentCreate::ItemNotFound
+    );
+    assert_eq!(
+        db.attachment_create(&id, &attachment("f2", "a", 5, 20), 15)
+            .unwrap(),
+        AttachmentCreate::Created
+    );
+    db.attachment_received(&id, "f2", 5).unwrap();
+    assert_eq!(db.attachment_get(&id, "f2").unwrap().unwrap().received, 5);
+    assert!(db.attachment_get(&id, "f3").unwrap().is_none());
+    assert_eq!(db.attachments_list(&id).unwrap().len(), 2);
+
+    // Deleting an item deletes its attachments and names them for their files
+    assert_eq!(
+        db.items_apply(&id, &[change("b", None, 1)]).unwrap(),
+        ItemsUpdate::Applied {
+            revision: 2,
+            attachments: vec!["f1".to_string()]
+        }
+    );
+    assert_eq!(
+        db.attachment_create(&id, &attachment("f3", "b", 1, 30), 15)
+            .unwrap(),
+        AttachmentCreate::ItemNotFound
+    );
+    assert_eq!(
+        db.attachment_create(&id, &attachment("f3", "a", 1, 30), 15)
+            .unwrap(),
+        AttachmentCreate::Created
+    );
+    // Only stale uploads are cleaned up, complete attachments are kept
+    assert_eq!(
+        db.attachments_cleanup(40).unwrap(),
+        vec![(id.clone(), "f3".to_string())]
+    );
+    assert_eq!(
+        db.attachments_list(&id)
+            .unwrap()
+            .iter()
+            .map(|attachment| attachment.id.as_str())
+            .collect::<Vec<_>>(),
+        vec!["f2"]
+    );
+    assert!(db.attachment_delete(&id, "f2").unwrap());
+    assert!(!db.attachment_delete(&id, "f2").unwrap());
+    let (revision, items) = db.items_changed(&id, 0).unwrap();
+    assert_eq!(revision, 2);
+    assert_eq!(
+        items
+            .iter()
+            .map(|item| (
+                item.id.as_str(),
+                item.revision,
+                item.encrypted_data.as_deref()
+            ))
+            .collect::<Vec<_>>(),
+        vec![("a", 1, Some("a1")), ("b", 2, None)]
+    );
+    assert_eq!(db.items_changed(&id, 1).unwrap().1.len(), 1);
+    assert!(db.items_changed(&id, 2).unwrap().1.is_empty());
+    assert_eq!(db.data_get(&id).unwrap().revision, 4);
+
+    // Refresh tokens are single-use, reusing one revokes its family
+    db.refresh_token_store("rt1", &id, "nonce1", 100).unwrap();
+    db.refresh_token_store("rt2", &id, "nonce1", 100).unwrap();
+    assert!(matches!(
+        db.refresh_token_consume("rt1", 10).unwrap(),
+        RefreshTokenStatus::Valid { .. }
+    ));
+    assert!(matches!(
+        db.refresh_token_consume("rt1", 10).unwrap(),
+        RefreshTokenStatus::Reused { .. }
+    ));
+    assert_eq!(
+        db.refresh_token_consume("rt2", 10).unwrap(),
+        RefreshTokenStatus::Unknown
+    );
+
+    // A password change keeps only the session of the caller
+    db.session_create(&id, &session("nonce1", 1)).unwrap();
+    db.session_create(&id, &session("nonce2", 2)).unwrap();
+    let stamp = db.user_security_stamp(&id).unwrap();
+    db.user_changepwd(&id, "hash456", None, "nonce2").unwrap();
+    assert_ne!(db.user_security_stamp(&id).unwrap(), stamp);
+    let sessions = db.sessions_list(&id).unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].nonce, "nonce2");
+    assert!(!db.session_touch("nonce1", 5).unwrap());
+    // last_seen is only written again once it is a minute old
+    assert!(db.session_touch("nonce2", 5).unwrap());
+    assert_eq!(db.sessions_list(&id).unwrap()[0].last_seen, 2);
+    assert!(db.session_touch("nonce2", 100).unwrap());
+    assert_eq!(db.sessions_list(&id).unwrap()[0].last_seen, 100);
+
+    // Addresses stay unique across an email change
+    let other = db
+        .user_register("other@example.com", "hash123", None)
+        .unwrap();
+    assert!(!db
+        .user_change_email(&other, "STORE@example.com", None, None, "none")
+        .unwrap());
+    assert!(db
+        .user_change_email(&id, "moved@example.com", None, None, "nonce2")
+        .unwrap());
+    assert_eq!(db.user_id("store@example.com").unwrap(), None);
+    assert_eq!(db.user_email_verified(&id).unwrap(), Some(true));
+
+    // TOTP codes and recovery codes are single-use
+    assert!(db.totp_begin(&id, "SECRET").unwrap());
+    assert!(db.totp_enable(&id, 7, &["code1".to_string()]).unwrap());
+    assert!(!db.totp_begin(&id, "OTHER").unwrap());
+    assert!(!db.totp_use_step(&id, 7).unwrap());
+    assert!(db.totp_use_step(&id, 8).unwrap());
+    assert!(!db.recovery_code_consume(&other, "code1").unwrap());
+    assert!(db.recovery_code_consume(&id, "code1").unwrap());
+    assert!(!db.recovery_code_consume(&id, "code1").unwrap());
+
+    // Expired challenges are consumed but not returned
+    let ceremony = WebauthnCeremony {
+        user_id: id.clone(),
+        purpose: "register".to_string(),
+        pending_nonce: None,
+        expires_at: 10,
+    };
+    db.webauthn_challenge_store("challenge", &ceremony).unwrap();
+    assert!(db
+        .webauthn_challenge_consume("challenge", 20)
+        .unwrap()
+        .is_none());
+
+    // Failures outside of the window start counting anew
+    assert_eq!(
+        db.login_failure_record("ip", "1.2.3.4", 100, 50).unwrap(),
+        1
+    );
+    assert_eq!(
+        db.login_failure_record("ip", "1.2.3.4", 120, 50).unwrap(),
+        2
+    );
+    assert_eq!(
+        db.login_failure_record("ip", "1.2.3.4", 200, 50).unwrap(),
+        1
+    );
+    db.login_lock("ip", "1.2.3.4", 300).unwrap();
+    assert_eq!(db.login_failures_cleanup(280, 50).unwrap(), 0);
+    assert_eq!(db.login_failures_cleanup(300, 50).unwrap(), 1);
+
+    // Deleting an account removes everything that belonged to it
+    db.attachment_create(&id, &attachment("f4", "a", 1, 50), 15)
+        .unwrap();
+    db.user_delete(&id).unwrap();
+    assert_eq!(db.user_email(&id).unwrap(), None);
+    assert!(db.sessions_list(&id).unwrap().is_empty());
+    assert!(db.totp_get(&id).unwrap().is_none());
+    assert!(db.data_get(&id).is_err());
+    assert!(db.vault_history_list(&id).unwrap().is_empty());
+    assert!(db.items_changed(&id, 0).is_err());
+    assert!(db.attachments_list(&id).unwrap().is_empty());
+}
+
+#[test]
+fn test_memory_store() {
+    check_store(&MemoryStore::new());
+}
+
+#[test]
+fn test_sqlite_store() {
+    let (_dir, db_file) = common::sqlite_file();
+    let store = SqliteStore::open(&db_file).unwrap();
+    store.migrate().unwrap();
+    check_store(&store);
+}
+
+// Runs against the database at TEST_DATABASE_URL, in a schema of its own
+#[cfg(feature = "postgres")]
+#[test]
+fn test_postgres_store() {
+    use backend_rspass::migrations::latest_version;
+
+    let Ok(url) = std::env::var("TEST_DATABASE_URL") else {
+        println!("TEST_DATABASE_URL is not set, skipping");
+        return;
+    };
+    let store = common::postgres_schema(&url);
+    assert_eq!(store.schema_version().unwrap(), latest_version());
+    assert!(store.migrate().unwrap().is_empty());
+    check_store(&store);
+}
@@ -0,0 +1,179 @@
+use actix_web::http::{header, StatusCode};
+use backend_rspass::store::Database;
+use serde_json::json;
+
+mod common;
+
+// Clients of this binary connect through the test server, as if it was a trusted proxy
+fn setup() -> (
+    actix_web::web::Data<backend_rspass::auth::JwtAuth>,
+    Database,
+) {
+    std::env::set_var("TRUSTED_PROXIES", "127.0.0.1");
+    common::setup()
+}
+
+// Every test uses its own client address, so the per-IP counters do not add up
+async fn login(
+    server: &actix_test::TestServer,
+    ip: &str,
+    email: &str,
+    password: &str,
+) -> (StatusCode, Option<String>) {
+    let response = server
+        .post("/api/v1/auth/login")
+        .insert_header(("X-Forwarded-For", ip))
+        .send_json(&json!({
+            "email": email,
+            "password_hash": password
+        }))
+        .await
+        .unwrap();
+    let retry_after = response
+        .headers()
+        .get(header::RETRY_AFTER)
+        .map(|value| value.to_str().unwrap().to_string());
+    (response.status(), retry_after)
+}
+
+async fn register(server: &actix_test::TestServer, email: &str) {
+    let register = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(register.status(), StatusCode::OK);
+}
+
+async fn failure_count(db: &Database, kind: &'static str, subject: &str) -> Option<u32> {
+    let subject = subject.to_string();
+    db.run(move |db| db.login_failures_get(kind, &subject))
+        .await
+        .unwrap()
+        .map(|failures| failures.failures)
+}
+
+async fn expire_lock(db: &Database, kind: &'static str, subject: &'static str) {
+    db.run(move |db| db.login_lock(kind, subject, 0))
+        .await
+        .unwrap();
+}
+
+#[actix_rt::test]
+async fn test_account_lockout() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+    let ip = "192.0.2.1";
+    register(&server, "throttle1@example.com").await;
+
+    for _ in 0..5 {
+        let (status, _) = login(&server, ip, "throttle1@example.com", "wrong123").await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    // The sixth failure locks the account, even the correct password is rejected
+    let (status, _) = login(&server, ip, "throttle1@example.com", "wrong123").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, retry_after) = login(&server, ip, "throttle1@example.com", "hash123").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: usize = retry_after.unwrap().parse().unwrap();
+    assert!(retry_after > 0 && retry_after <= 30);
+
+    // The lockout doubles with every further failure
+    expire_lock(&db, "email", "throttle1@example.com").await;
+    let (status, _) = login(&server, ip, "throttle1@example.com", "wrong123").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, retry_after) = login(&server, ip, "throttle1@example.com", "hash123").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(retry_after.unwrap().parse::<usize>().unwrap() > 30);
+
+    // A successful login resets the counter of the account, not the one of the address
+    expire_lock(&db, "email", "throttle1@example.com").await;
+    let (status, _) = login(&server, ip, "throttle1@example.com", "hash123").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        failure_count(&db, "email", "throttle1@example.com").await,
+        None
+    );
+    assert_eq!(failure_count(&db, "ip", ip).await, Some(7));
+}
+
+#[actix_rt::test]
+async fn test_unknown_accounts_throttled() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+    let ip = "192.0.2.2";
+
+    for _ in 0..6 {
+        let (status, _) = login(&server, ip, "throttle2@example.com", "hash123").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+    let (status, retry_after) = login(&server, ip, "throttle2@example.com", "hash123").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert!(retry_after.is_some());
+}
+
+#[actix_rt::test]
+async fn test_ip_lockout() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+    let ip = "192.0.2.3";
+    register(&server, "throttle3@example.com").await;
+
+    // Spread over many accounts, so only the per-IP counter is exceeded
+    for i in 0..21 {
+        let email = format!("throttle3-{}@example.com", i);
+        let (status, _) = login(&server, ip, &email, "hash123").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+    assert_eq!(failure_count(&db, "ip", ip).await, Some(21));
+
+    let (status, _) = login(&server, ip, "throttle3@example.com", "hash123").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    let (status, _) = login(&server, "192.0.2.4", "throttle3@example.com", "hash123").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn test_own_logins_keep_ip_counter() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+    let ip = "192.0.2.5";
+    register(&server, "throttle4@example.com").await;
+
+    // Logging into an own account between guesses does not buy more guesses
+    for i in 0..21 {
+        let email = format!("throttle4-{}@example.com", i);
+        let (status, _) = login(&server, ip, &email, "hash123").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        if i < 20 {
+            let (status, _) = login(&server, ip, "throttle4@example.com", "hash123").await;
+            assert_eq!(status, StatusCode::OK);
+        }
+    }
+    assert_eq!(failure_count(&db, "ip", ip).await, Some(21));
+    let (status, _) = login(&server, ip, "throttle4@example.com", "hash123").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[actix_rt::test]
+async fn test_forwarded_chain() {
+    let (jwt_auth, db) = setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    // The address the trusted proxy appended counts, not the one the client made up
+    let response = server
+        .post("/api/v1/auth/login")
+        .insert_header(("X-Forwarded-For", "203.0.113.7, 192.0.2.6"))
+        .send_json(&json!({
+            "email": "throttle5@example.com",
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert_eq!(failure_count(&db, "ip", "192.0.2.6").await, Some(1));
+    assert_eq!(failure_count(&db, "ip", "203.0.113.7").await, None);
+}
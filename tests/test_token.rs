@@ -35,19 +35,6 @@ async fn try_authenticated_endpoints(
     token: &str,
     expected_status: StatusCode,
 ) {
-    // Test change password
-    let change_pwd = server
-        .post("/api/v1/account/changepwd")
-        .insert_header(("Authorization", format!("Bearer {}", token)))
-        .send_json(&json!({
-            "password_hash": "newhash123"
-        }))
-        .await
-        .unwrap();
-    println!("changepwd: {}: ", change_pwd.status());
-    println!("token: {}: ", token);
-    assert_eq!(change_pwd.status(), expected_status);
-
     // Test fetch vault
     let fetch = server
         .get("/api/v1/sync/fetch")
@@ -63,18 +50,32 @@ async fn try_authenticated_endpoints(
         .post("/api/v1/sync/update")
         .insert_header(("Authorization", format!("Bearer {}", token)))
         .send_json(&json!({
-            "encrypted_data": "encrypted123"
+            "encrypted_data": "encrypted123",
+            "base_revision": 0
         }))
         .await
         .unwrap();
     println!("update: {}: ", update.status());
     assert_eq!(update.status(), expected_status);
+
+    // Test change password last, it invalidates the token
+    let change_pwd = server
+        .post("/api/v1/account/changepwd")
+        .insert_header(("Authorization", format!("Bearer {}", token)))
+        .send_json(&json!({
+            "password_hash": "newhash123"
+        }))
+        .await
+        .unwrap();
+    println!("changepwd: {}: ", change_pwd.status());
+    println!("token: {}: ", token);
+    assert_eq!(change_pwd.status(), expected_status);
 }
 
 #[actix_rt::test]
 async fn test_valid_token_flow() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // Register and login
     register(&server).await;
@@ -82,14 +83,12 @@ async fn test_valid_token_flow() {
 
     // Try all authenticated endpoints with valid token
     try_authenticated_endpoints(&server, &token, StatusCode::OK).await;
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_blacklisted_token() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // Register and login
     register(&server).await;
@@ -106,14 +105,12 @@ async fn test_blacklisted_token() {
 
     // Try all authenticated endpoints with blacklisted token
     try_authenticated_endpoints(&server, &token, StatusCode::UNAUTHORIZED).await;
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_deleted_account_token() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // Register and login
     register(&server).await;
@@ -130,25 +127,21 @@ async fn test_deleted_account_token() {
 
     // Try all authenticated endpoints with token of deleted account
     try_authenticated_endpoints(&server, &token, StatusCode::UNAUTHORIZED).await;
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_malformed_token() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // Test with malformed token
     try_authenticated_endpoints(&server, "malformed.token.here", StatusCode::UNAUTHORIZED).await;
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_invalid_signature() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // Create token with different secret
     let claims = backend_rspass::auth::Claims {
@@ -159,6 +152,9 @@ async fn test_invalid_signature() {
             .as_secs()
             + 3600) as usize, // 1 hour from now
         nonce: Uuid::new_v4().to_string(),
+        stamp: Uuid::new_v4().to_string(),
+        purpose: None,
+        new_email: None,
     };
 
     let token = encode(
@@ -170,14 +166,12 @@ async fn test_invalid_signature() {
 
     // Try all authenticated endpoints with invalid signature
     try_authenticated_endpoints(&server, &token, StatusCode::UNAUTHORIZED).await;
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_modified_claims() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // Register and login
     register(&server).await;
@@ -191,6 +185,9 @@ async fn test_modified_claims() {
             .as_secs()
             + 3600) as usize, // 1 hour from now
         nonce: Uuid::new_v4().to_string(),
+        stamp: Uuid::new_v4().to_string(),
+        purpose: None,
+        new_email: None,
     };
 
     let modified_token = encode(
@@ -202,14 +199,12 @@ async fn test_modified_claims() {
 
     // Try all authenticated endpoints with modified claims
     try_authenticated_endpoints(&server, &modified_token, StatusCode::UNAUTHORIZED).await;
-
-    common::cleanup(&db_file);
 }
 
 #[actix_rt::test]
 async fn test_expired_token() {
-    let (jwt_auth, db_file) = common::setup();
-    let server = common::create_server(jwt_auth);
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
 
     // Register and login
     register(&server).await;
@@ -223,6 +218,9 @@ async fn test_expired_token() {
             .as_secs()
             - 3600) as usize, // expired 1 hour ago
         nonce: Uuid::new_v4().to_string(),
+        stamp: Uuid::new_v4().to_string(),
+        purpose: None,
+        new_email: None,
     };
 
     let expired_token = encode(
@@ -234,6 +232,4 @@ async fn test_expired_token() {
 
     // Try all authenticated endpoints with the expired token
     try_authenticated_endpoints(&server, &expired_token, StatusCode::UNAUTHORIZED).await;
-
-    common::cleanup(&db_file);
 }
@@ -0,0 +1,256 @@
+use actix_web::http::StatusCode;
+use backend_rspass::models::*;
+use serde_json::json;
+use std::time::{SystemTime, UNIX_EPOCH};
+use totp_rs::TOTP;
+
+mod common;
+
+async fn register(server: &actix_test::TestServer, email: &str) -> LoginResponse {
+    let mut response = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+// Password step of a login for an account with two-factor authentication
+async fn login_pending(server: &actix_test::TestServer, email: &str) -> String {
+    let mut response = server
+        .post("/api/v1/auth/login")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let pending: TwoFactorRequiredResponse = response.json().await.unwrap();
+    pending.pending_token
+}
+
+async fn login_2fa(
+    server: &actix_test::TestServer,
+    body: serde_json::Value,
+) -> (StatusCode, Option<LoginResponse>) {
+    let mut response = server
+        .post("/api/v1/auth/login/2fa")
+        .send_json(&body)
+        .await
+        .unwrap();
+    if response.status() != StatusCode::OK {
+        return (response.status(), None);
+    }
+    (response.status(), Some(response.json().await.unwrap()))
+}
+
+async fn fetch_status(server: &actix_test::TestServer, token: &str) -> StatusCode {
+    server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap()
+        .status()
+}
+
+// Code of the step `offset` seconds away, every step is only accepted once
+fn code(totp: &TOTP, offset: i64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    totp.generate((now + offset) as u64)
+}
+
+async fn enroll(server: &actix_test::TestServer, token: &str) -> (TOTP, Vec<String>) {
+    let mut setup = server
+        .post("/api/v1/account/2fa/totp")
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(setup.status(), StatusCode::OK);
+    let setup: TotpSetupResponse = setup.json().await.unwrap();
+    let totp = TOTP::from_url(&setup.otpauth_uri).unwrap();
+    assert_eq!(totp.get_secret_base32(), setup.secret);
+
+    let wrong = server
+        .post("/api/v1/account/2fa/totp/confirm")
+        .bearer_auth(token)
+        .send_json(&json!({ "code": "000000x" }))
+        .await
+        .unwrap();
+    assert_eq!(wrong.status(), StatusCode::FORBIDDEN);
+
+    let mut confirm = server
+        .post("/api/v1/account/2fa/totp/confirm")
+        .bearer_auth(token)
+        .send_json(&json!({ "code": code(&totp, -30) }))
+        .await
+        .unwrap();
+    assert_eq!(confirm.status(), StatusCode::OK);
+    let confirm: RecoveryCodesResponse = confirm.json().await.unwrap();
+    assert_eq!(confirm.recovery_codes.len(), 10);
+
+    (totp, confirm.recovery_codes)
+}
+
+#[actix_rt::test]
+async fn test_totp_login() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let tokens = register(&server, "totp1@example.com").await;
+    let (totp, _) = enroll(&server, &tokens.token).await;
+
+    // The pending token grants no API access
+    let pending = login_pending(&server, "totp1@example.com").await;
+    assert_eq!(
+        fetch_status(&server, &pending).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    let (status, _) = login_2fa(
+        &server,
+        json!({ "pending_token": pending, "code": "000000x" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let current = code(&totp, 0);
+    let (status, tokens) = login_2fa(
+        &server,
+        json!({ "pending_token": pending, "code": current }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(
+        fetch_status(&server, &tokens.unwrap().token).await,
+        StatusCode::OK
+    );
+
+    // Pending tokens are single-use
+    let (status, _) = login_2fa(
+        &server,
+        json!({ "pending_token": pending, "code": code(&totp, 30) }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // So are codes
+    let pending = login_pending(&server, "totp1@example.com").await;
+    let (status, _) = login_2fa(
+        &server,
+        json!({ "pending_token": pending, "code": current }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = login_2fa(&server, json!({ "pending_token": pending })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn test_recovery_codes() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let tokens = register(&server, "totp2@example.com").await;
+    let (_, recovery_codes) = enroll(&server, &tokens.token).await;
+
+    // Recovery codes are case and dash insensitive, but single-use
+    let pending = login_pending(&server, "totp2@example.com").await;
+    let (status, _) = login_2fa(
+        &server,
+        json!({
+            "pending_token": pending,
+            "recovery_code": recovery_codes[0].to_uppercase().replace('-', "")
+        }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let pending = login_pending(&server, "totp2@example.com").await;
+    let (status, _) = login_2fa(
+        &server,
+        json!({ "pending_token": pending, "recovery_code": recovery_codes[0] }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // Disabling requires a second factor as well
+    let disable = server
+        .post("/api/v1/account/2fa/totp/disable")
+        .bearer_auth(&tokens.token)
+        .send_json(&json!({ "code": "000000x" }))
+        .await
+        .unwrap();
+    assert_eq!(disable.status(), StatusCode::FORBIDDEN);
+
+    let disable = server
+        .post("/api/v1/account/2fa/totp/disable")
+        .bearer_auth(&tokens.token)
+        .send_json(&json!({ "code": recovery_codes[1] }))
+        .await
+        .unwrap();
+    assert_eq!(disable.status(), StatusCode::OK);
+
+    let login = server
+        .post("/api/v1/auth/login")
+        .send_json(&json!({
+            "email": "totp2@example.com",
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(login.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn test_enrollment_states() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+
+    let tokens = register(&server, "totp3@example.com").await;
+
+    let confirm = server
+        .post("/api/v1/account/2fa/totp/confirm")
+        .bearer_auth(&tokens.token)
+        .send_json(&json!({ "code": "123456" }))
+        .await
+        .unwrap();
+    assert_eq!(confirm.status(), StatusCode::NOT_FOUND);
+
+    let (totp, _) = enroll(&server, &tokens.token).await;
+
+    // An enabled secret can not be replaced without disabling it first
+    let setup = server
+        .post("/api/v1/account/2fa/totp")
+        .bearer_auth(&tokens.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(setup.status(), StatusCode::CONFLICT);
+
+    let disable = server
+        .post("/api/v1/account/2fa/totp/disable")
+        .bearer_auth(&tokens.token)
+        .send_json(&json!({ "code": code(&totp, 0) }))
+        .await
+        .unwrap();
+    assert_eq!(disable.status(), StatusCode::OK);
+
+    let setup = server
+        .post("/api/v1/account/2fa/totp")
+        .bearer_auth(&tokens.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(setup.status(), StatusCode::OK);
+}
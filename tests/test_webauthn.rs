@@ -0,0 +1,374 @@
+use actix_web::http::StatusCode;
+use backend_rspass::models::*;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+mod common;
+
+// Software authenticator, so the ceremonies run without hardware
+struct SoftAuthenticator {
+    key: SigningKey,
+    credential_id: Vec<u8>,
+    counter: u32,
+    origin: String,
+}
+
+impl SoftAuthenticator {
+    fn new() -> Self {
+        SoftAuthenticator {
+            key: SigningKey::random(&mut rand::thread_rng()),
+            credential_id: Uuid::new_v4().as_bytes().to_vec(),
+            counter: 0,
+            origin: "http://localhost:8080".to_string(),
+        }
+    }
+
+    fn id(&self) -> String {
+        URL_SAFE_NO_PAD.encode(&self.credential_id)
+    }
+
+    fn cose_key(&self) -> Vec<u8> {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let key = Value::Map(vec![
+            (Value::from(1), Value::from(2)),  // kty: EC2
+            (Value::from(3), Value::from(-7)), // alg: ES256
+            (Value::from(-1), Value::from(1)), // crv: P-256
+            (Value::from(-2), Value::from(point.x().unwrap().to_vec())),
+            (Value::from(-3), Value::from(point.y().unwrap().to_vec())),
+        ]);
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&key, &mut encoded).unwrap();
+        encoded
+    }
+
+    fn authenticator_data(&self, flags: u8, attested: bool) -> Vec<u8> {
+        let mut data = Sha256::digest(b"localhost").to_vec();
+        data.push(flags);
+        data.extend_from_slice(&self.counter.to_be_bytes());
+        if attested {
+            data.extend_from_slice(&[0u8; 16]); // aaguid
+            data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+            data.extend_from_slice(&self.credential_id);
+            data.extend_from_slice(&self.cose_key());
+        }
+        data
+    }
+
+    fn client_data(&self, kind: &str, challenge: &str) -> Vec<u8> {
+        serde_json::to_vec(&json!({
+            "type": kind,
+            "challenge": challenge,
+            "origin": self.origin
+        }))
+        .unwrap()
+    }
+
+    fn create(&mut self, options: &WebauthnCreationOptions) -> serde_json::Value {
+        let attestation = Value::Map(vec![
+            (Value::from("fmt"), Value::from("none")),
+            (Value::from("attStmt"), Value::Map(vec![])),
+            (
+                Value::from("authData"),
+                Value::from(self.authenticator_data(0x45, true)), // UP, UV, AT
+            ),
+        ]);
+        let mut attestation_object = Vec::new();
+        ciborium::into_writer(&attestation, &mut attestation_object).unwrap();
+        json!({
+            "name": "Soft key",
+            "client_data_json": URL_SAFE_NO_PAD.encode(self.client_data("webauthn.create", &options.challenge)),
+            "attestation_object": URL_SAFE_NO_PAD.encode(attestation_object)
+        })
+    }
+
+    fn get(&mut self, options: &WebauthnRequestOptions, user_verified: bool) -> serde_json::Value {
+        self.counter += 1;
+        let flags = if user_verified { 0x05 } else { 0x01 };
+        let authenticator_data = self.authenticator_data(flags, false);
+        let client_data_json = self.client_data("webauthn.get", &options.challenge);
+        let mut signed = authenticator_data.clone();
+        signed.extend_from_slice(&Sha256::digest(&client_data_json));
+        let signature: Signature = self.key.sign(&signed);
+        json!({
+            "credential_id": self.id(),
+            "client_data_json": URL_SAFE_NO_PAD.encode(client_data_json),
+            "authenticator_data": URL_SAFE_NO_PAD.encode(authenticator_data),
+            "signature": URL_SAFE_NO_PAD.encode(signature.to_der())
+        })
+    }
+}
+
+async fn register(server: &actix_test::TestServer, email: &str) -> LoginResponse {
+    let mut response = server
+        .post("/api/v1/auth/register")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    response.json().await.unwrap()
+}
+
+async fn register_credential(
+    server: &actix_test::TestServer,
+    token: &str,
+    authenticator: &mut SoftAuthenticator,
+) -> StatusCode {
+    let mut begin = server
+        .post("/api/v1/account/2fa/webauthn/register/begin")
+        .bearer_auth(token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(begin.status(), StatusCode::OK);
+    let options: WebauthnCreationOptions = begin.json().await.unwrap();
+    assert_eq!(options.rp.id, "localhost");
+
+    server
+        .post("/api/v1/account/2fa/webauthn/register")
+        .bearer_auth(token)
+        .send_json(&authenticator.create(&options))
+        .await
+        .unwrap()
+        .status()
+}
+
+async fn login(server: &actix_test::TestServer, email: &str) -> (StatusCode, serde_json::Value) {
+    let mut response = server
+        .post("/api/v1/auth/login")
+        .send_json(&json!({
+            "email": email,
+            "password_hash": "hash123"
+        }))
+        .await
+        .unwrap();
+    (response.status(), response.json().await.unwrap())
+}
+
+async fn begin_login(
+    server: &actix_test::TestServer,
+    body: serde_json::Value,
+) -> (StatusCode, Option<WebauthnRequestOptions>) {
+    let mut response = server
+        .post("/api/v1/auth/login/webauthn/begin")
+        .send_json(&body)
+        .await
+        .unwrap();
+    if response.status() != StatusCode::OK {
+        return (response.status(), None);
+    }
+    (response.status(), Some(response.json().await.unwrap()))
+}
+
+async fn finish_login(
+    server: &actix_test::TestServer,
+    assertion: &serde_json::Value,
+) -> StatusCode {
+    server
+        .post("/api/v1/auth/login/webauthn")
+        .send_json(assertion)
+        .await
+        .unwrap()
+        .status()
+}
+
+#[actix_rt::test]
+async fn test_webauthn_second_factor() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let mut authenticator = SoftAuthenticator::new();
+
+    let tokens = register(&server, "webauthn1@example.com").await;
+    assert_eq!(
+        register_credential(&server, &tokens.token, &mut authenticator).await,
+        StatusCode::OK
+    );
+
+    let mut list = server
+        .get("/api/v1/account/2fa/webauthn")
+        .bearer_auth(&tokens.token)
+        .send()
+        .await
+        .unwrap();
+    let credentials: Vec<WebauthnCredentialResponse> = list.json().await.unwrap();
+    assert_eq!(credentials.len(), 1);
+    assert_eq!(credentials[0].id, authenticator.id());
+
+    let (status, pending) = login(&server, "webauthn1@example.com").await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(pending["methods"], json!(["webauthn"]));
+    let pending_token = pending["pending_token"].clone();
+
+    let (status, options) = begin_login(&server, json!({ "pending_token": pending_token })).await;
+    assert_eq!(status, StatusCode::OK);
+    let options = options.unwrap();
+    assert_eq!(options.allow_credentials[0].id, authenticator.id());
+
+    let assertion = authenticator.get(&options, false);
+    assert_eq!(finish_login(&server, &assertion).await, StatusCode::OK);
+
+    // Challenges and pending tokens are single-use
+    assert_eq!(
+        finish_login(&server, &assertion).await,
+        StatusCode::UNAUTHORIZED
+    );
+    let (status, _) = begin_login(&server, json!({ "pending_token": pending_token })).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[actix_rt::test]
+async fn test_cloned_authenticator_rejected() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let mut authenticator = SoftAuthenticator::new();
+
+    let tokens = register(&server, "webauthn2@example.com").await;
+    register_credential(&server, &tokens.token, &mut authenticator).await;
+
+    let (_, pending) = login(&server, "webauthn2@example.com").await;
+    let (_, options) = begin_login(
+        &server,
+        json!({ "pending_token": pending["pending_token"] }),
+    )
+    .await;
+    assert_eq!(
+        finish_login(&server, &authenticator.get(&options.unwrap(), false)).await,
+        StatusCode::OK
+    );
+
+    // A clone of the key does not know the counter moved on
+    authenticator.counter = 0;
+    let (_, pending) = login(&server, "webauthn2@example.com").await;
+    let (_, options) = begin_login(
+        &server,
+        json!({ "pending_token": pending["pending_token"] }),
+    )
+    .await;
+    assert_eq!(
+        finish_login(&server, &authenticator.get(&options.unwrap(), false)).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    // Assertions for another origin are rejected
+    let (_, pending) = login(&server, "webauthn2@example.com").await;
+    let (_, options) = begin_login(
+        &server,
+        json!({ "pending_token": pending["pending_token"] }),
+    )
+    .await;
+    authenticator.counter = 10;
+    authenticator.origin = "https://evil.example".to_string();
+    assert_eq!(
+        finish_login(&server, &authenticator.get(&options.unwrap(), false)).await,
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[actix_rt::test]
+async fn test_passwordless_login() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let mut authenticator = SoftAuthenticator::new();
+
+    let tokens = register(&server, "webauthn3@example.com").await;
+    let (status, _) = begin_login(&server, json!({ "email": "webauthn3@example.com" })).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    register_credential(&server, &tokens.token, &mut authenticator).await;
+
+    // Without the password the authenticator has to verify the user
+    let (_, options) = begin_login(&server, json!({ "email": "webauthn3@example.com" })).await;
+    let options = options.unwrap();
+    assert_eq!(options.user_verification, "required");
+    assert_eq!(
+        finish_login(&server, &authenticator.get(&options, false)).await,
+        StatusCode::UNAUTHORIZED
+    );
+
+    let (_, options) = begin_login(&server, json!({ "email": "webauthn3@example.com" })).await;
+    let mut finish = server
+        .post("/api/v1/auth/login/webauthn")
+        .send_json(&authenticator.get(&options.unwrap(), true))
+        .await
+        .unwrap();
+    assert_eq!(finish.status(), StatusCode::OK);
+    let tokens: LoginResponse = finish.json().await.unwrap();
+
+    let fetch = server
+        .get("/api/v1/sync/fetch")
+        .bearer_auth(&tokens.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(fetch.status(), StatusCode::OK);
+
+    let (status, _) = begin_login(
+        &server,
+        json!({ "email": "webauthn3@example.com", "pending_token": "token" }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[actix_rt::test]
+async fn test_credential_management() {
+    let (jwt_auth, db) = common::setup();
+    let server = common::create_server(jwt_auth, &db);
+    let mut authenticator = SoftAuthenticator::new();
+
+    let tokens = register(&server, "webauthn4@example.com").await;
+    assert_eq!(
+        register_credential(&server, &tokens.token, &mut authenticator).await,
+        StatusCode::OK
+    );
+    assert_eq!(
+        register_credential(&server, &tokens.token, &mut authenticator).await,
+        StatusCode::CONFLICT
+    );
+
+    // The challenge of a registration can not be replayed
+    let mut begin = server
+        .post("/api/v1/account/2fa/webauthn/register/begin")
+        .bearer_auth(&tokens.token)
+        .send()
+        .await
+        .unwrap();
+    let options: WebauthnCreationOptions = begin.json().await.unwrap();
+    assert_eq!(options.exclude_credentials[0].id, authenticator.id());
+    let mut other = SoftAuthenticator::new();
+    let attestation = other.create(&options);
+    for expected in [StatusCode::OK, StatusCode::FORBIDDEN] {
+        let register = server
+            .post("/api/v1/account/2fa/webauthn/register")
+            .bearer_auth(&tokens.token)
+            .send_json(&attestation)
+            .await
+            .unwrap();
+        assert_eq!(register.status(), expected);
+    }
+
+    for id in [authenticator.id(), other.id()] {
+        let delete = server
+            .delete(format!("/api/v1/account/2fa/webauthn/{}", id))
+            .bearer_auth(&tokens.token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(delete.status(), StatusCode::OK);
+    }
+    let delete = server
+        .delete(format!("/api/v1/account/2fa/webauthn/{}", other.id()))
+        .bearer_auth(&tokens.token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(delete.status(), StatusCode::NOT_FOUND);
+
+    let (status, _) = login(&server, "webauthn4@example.com").await;
+    assert_eq!(status, StatusCode::OK);
+}